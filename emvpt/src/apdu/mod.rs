@@ -0,0 +1,306 @@
+use std::fmt;
+
+// ref. ISO/IEC 7816-4, 5.1 Command-response pairs
+const SHORT_MAX_LC: usize = 0xFF;
+const SHORT_MAX_LE: usize = 0x100;
+const EXTENDED_MAX_LC: usize = 0xFFFF;
+const EXTENDED_MAX_LE: usize = 0x10000;
+
+// ref. EMV Book 1, Table 42 SELECT Command Options Parameter
+pub const SELECT_FIRST_OCCURRENCE: u8 = 0b0000_0000;
+pub const SELECT_NEXT_OCCURRENCE: u8 = 0b0000_0010;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CommandCase {
+    // no command data, no response data
+    Case1,
+    // no command data, response data expected
+    Case2,
+    // command data, no response data
+    Case3,
+    // command data, response data expected
+    Case4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandApdu {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Vec<u8>,
+    // Ne, maximum number of response data bytes expected. Some(256) is encoded as short Le 00.
+    pub le: Option<usize>,
+}
+
+impl CommandApdu {
+    pub fn new(cla: u8, ins: u8, p1: u8, p2: u8) -> CommandApdu {
+        CommandApdu {
+            cla,
+            ins,
+            p1,
+            p2,
+            data: Vec::new(),
+            le: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: &[u8]) -> CommandApdu {
+        self.data = data.to_vec();
+        self
+    }
+
+    pub fn with_le(mut self, le: usize) -> CommandApdu {
+        self.le = Some(le);
+        self
+    }
+
+    // Le 00 asks for up to 256 bytes, Le 00 00 of an extended length command for all the response
+    // data up to 65536 bytes
+    pub fn with_extended_le(mut self) -> CommandApdu {
+        if self.le == Some(SHORT_MAX_LE) {
+            self.le = Some(EXTENDED_MAX_LE);
+        }
+        self
+    }
+
+    pub fn lc(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn case(&self) -> CommandCase {
+        match (self.data.is_empty(), self.le.is_some()) {
+            (true, false) => CommandCase::Case1,
+            (true, true) => CommandCase::Case2,
+            (false, false) => CommandCase::Case3,
+            (false, true) => CommandCase::Case4,
+        }
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.data.len() > SHORT_MAX_LC || matches!(self.le, Some(le) if le > SHORT_MAX_LE)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ApduError> {
+        if self.data.len() > EXTENDED_MAX_LC {
+            return Err(ApduError::DataTooLong(self.data.len()));
+        }
+
+        if let Some(le) = self.le {
+            if le == 0 || le > EXTENDED_MAX_LE {
+                return Err(ApduError::InvalidLe(le));
+            }
+        }
+
+        let mut apdu: Vec<u8> = Vec::with_capacity(4 + 3 + self.data.len() + 3);
+        apdu.push(self.cla);
+        apdu.push(self.ins);
+        apdu.push(self.p1);
+        apdu.push(self.p2);
+
+        if self.is_extended() {
+            // ref. ISO/IEC 7816-4, 5.1 extended length fields
+            if !self.data.is_empty() {
+                apdu.push(0x00);
+                apdu.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+                apdu.extend_from_slice(&self.data[..]);
+            }
+
+            if let Some(le) = self.le {
+                if self.data.is_empty() {
+                    apdu.push(0x00);
+                }
+                // 65536 is encoded as 00 00
+                apdu.extend_from_slice(&((le % EXTENDED_MAX_LE) as u16).to_be_bytes());
+            }
+        } else {
+            if !self.data.is_empty() {
+                apdu.push(self.data.len() as u8);
+                apdu.extend_from_slice(&self.data[..]);
+            }
+
+            if let Some(le) = self.le {
+                // 256 is encoded as 00
+                apdu.push((le % SHORT_MAX_LE) as u8);
+            }
+        }
+
+        Ok(apdu)
+    }
+
+    // ref. EMV Book 1, 11.3 SELECT Command-Response APDUs
+    pub fn select(aid: &[u8], p2_selection_options: u8) -> CommandApdu {
+        let p1_reference_control_parameter: u8 = 0b0000_0100; // "Select by name"
+        CommandApdu::new(
+            0x00,
+            0xA4,
+            p1_reference_control_parameter,
+            p2_selection_options,
+        )
+        .with_data(aid)
+        .with_le(SHORT_MAX_LE)
+    }
+
+    // ref. EMV Book 1, 11.2 READ RECORD Command-Response APDUs
+    pub fn read_record(short_file_identifier: u8, record_index: u8) -> CommandApdu {
+        CommandApdu::new(
+            0x00,
+            0xB2,
+            record_index,
+            (short_file_identifier << 3) | 0x04,
+        )
+        .with_le(SHORT_MAX_LE)
+    }
+
+    // ref. ISO/IEC 7816-4, 7.6.1 GET RESPONSE command
+    pub fn get_response(le: usize) -> CommandApdu {
+        CommandApdu::new(0x00, 0xC0, 0x00, 0x00).with_le(le)
+    }
+
+    // ref. EMV Book 3, 6.5.8 GET PROCESSING OPTIONS Command-Response APDUs
+    pub fn get_processing_options(pdol_data_template: &[u8]) -> CommandApdu {
+        CommandApdu::new(0x80, 0xA8, 0x00, 0x00)
+            .with_data(pdol_data_template)
+            .with_le(SHORT_MAX_LE)
+    }
+
+    // ref. EMV Book 3, 6.5.5 GENERATE APPLICATION CRYPTOGRAM Command-Response APDUs
+    pub fn generate_ac(p1_reference_control_parameter: u8, cdol_data: &[u8]) -> CommandApdu {
+        CommandApdu::new(0x80, 0xAE, p1_reference_control_parameter, 0x00)
+            .with_data(cdol_data)
+            .with_le(SHORT_MAX_LE)
+    }
+
+    // ref. EMV Book 3, 6.5.12 VERIFY Command-Response APDUs
+    pub fn verify(p2_pin_type_qualifier: u8, pin_data: &[u8]) -> CommandApdu {
+        CommandApdu::new(0x00, 0x20, 0x00, p2_pin_type_qualifier).with_data(pin_data)
+    }
+
+    // ref. EMV Book 3, 6.5.7 GET DATA Command-Response APDUs
+    pub fn get_data(tag: [u8; 2], le: usize) -> CommandApdu {
+        CommandApdu::new(0x80, 0xCA, tag[0], tag[1]).with_le(le)
+    }
+
+    // ref. EMV Book 3, 6.5.6 GET CHALLENGE Command-Response APDUs
+    pub fn get_challenge() -> CommandApdu {
+        CommandApdu::new(0x00, 0x84, 0x00, 0x00).with_le(SHORT_MAX_LE)
+    }
+
+    // ref. EMV Book 3, 6.5.9 INTERNAL AUTHENTICATE Command-Response APDUs
+    pub fn internal_authenticate(authentication_data: &[u8]) -> CommandApdu {
+        CommandApdu::new(0x00, 0x88, 0x00, 0x00)
+            .with_data(authentication_data)
+            .with_le(SHORT_MAX_LE)
+    }
+
+    // ref. EMV Book 3, 6.5.4 EXTERNAL AUTHENTICATE Command-Response APDUs
+    pub fn external_authenticate(issuer_authentication_data: &[u8]) -> CommandApdu {
+        CommandApdu::new(0x00, 0x82, 0x00, 0x00).with_data(issuer_authentication_data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApduError {
+    DataTooLong(usize),
+    InvalidLe(usize),
+    MissingStatusWord,
+}
+
+impl fmt::Display for ApduError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApduError::DataTooLong(length) => write!(f, "command data too long: {} bytes", length),
+            ApduError::InvalidLe(le) => write!(f, "invalid expected response length: {}", le),
+            ApduError::MissingStatusWord => write!(f, "response without status word"),
+        }
+    }
+}
+
+impl std::error::Error for ApduError {}
+
+// ref. ISO/IEC 7816-4, 5.1.3 Status bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StatusWord {
+    pub sw1: u8,
+    pub sw2: u8,
+}
+
+impl StatusWord {
+    pub const SUCCESS: StatusWord = StatusWord::new(0x90, 0x00);
+
+    pub const fn new(sw1: u8, sw2: u8) -> StatusWord {
+        StatusWord { sw1, sw2 }
+    }
+
+    pub fn is_success(&self) -> bool {
+        *self == StatusWord::SUCCESS
+    }
+
+    // 61XX, SW2 encodes the number of response bytes still available
+    pub fn is_bytes_available(&self) -> bool {
+        self.sw1 == 0x61
+    }
+
+    // 6CXX, SW2 encodes the exact length of the available data
+    pub fn is_wrong_length(&self) -> bool {
+        self.sw1 == 0x6C
+    }
+
+    // 62XX and 63XX, warning processing
+    pub fn is_warning(&self) -> bool {
+        self.sw1 == 0x62 || self.sw1 == 0x63
+    }
+
+    // 64XX to 6FXX, execution and checking errors
+    pub fn is_error(&self) -> bool {
+        (0x64..=0x6F).contains(&self.sw1)
+    }
+
+    // 63CX, counter provided by X, e.g. PIN tries left after an unsuccessful VERIFY
+    pub fn retries_left(&self) -> Option<u8> {
+        if self.sw1 == 0x63 && self.sw2 & 0xF0 == 0xC0 {
+            return Some(self.sw2 & 0x0F);
+        }
+
+        None
+    }
+
+    pub fn to_bytes(&self) -> [u8; 2] {
+        [self.sw1, self.sw2]
+    }
+}
+
+impl fmt::Display for StatusWord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}{:02X}", self.sw1, self.sw2)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseApdu {
+    pub data: Vec<u8>,
+    pub status: StatusWord,
+}
+
+impl ResponseApdu {
+    pub fn new(data: Vec<u8>, status: StatusWord) -> ResponseApdu {
+        ResponseApdu { data, status }
+    }
+
+    pub fn parse(response: &[u8]) -> Result<ResponseApdu, ApduError> {
+        if response.len() < 2 {
+            return Err(ApduError::MissingStatusWord);
+        }
+
+        let (data, trailer) = response.split_at(response.len() - 2);
+
+        Ok(ResponseApdu {
+            data: data.to_vec(),
+            status: StatusWord::new(trailer[0], trailer[1]),
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+}
@@ -0,0 +1,399 @@
+use iso7816_tlv::ber::{Tlv, Value};
+use log::warn;
+use serde::Serialize;
+use std::convert::TryFrom;
+
+use crate::{
+    get_truncated_pan, ApplicationInterchangeProfile, CryptogramInformationData, CvmCode,
+    CvmConditionCode, DataObjectList, EmvConnection, EmvTag, FieldFormat, FieldSensitivity,
+    IssuerApplicationData, TerminalVerificationResults, Track2, UsageControl,
+};
+
+// Machine-readable interpretation of a data object, e.g. for serializing field 55 or a card dump
+#[derive(Serialize, Debug, Clone)]
+pub struct DecodedTag {
+    pub tag: String,
+    pub name: String,
+    // None when the value is censored
+    pub raw: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<FieldFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<DecodedValue>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<DecodedTag>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "value")]
+pub enum DecodedValue {
+    Numeric(String),
+    Text(String),
+    // YYYY-MM-DD
+    Date(String),
+    // HH:MM:SS
+    Time(String),
+    CountryCode {
+        code: String,
+        country: Option<String>,
+    },
+    CurrencyCode {
+        code: String,
+        currency: Option<String>,
+    },
+    ServiceCode {
+        code: String,
+        interchange: String,
+        authorization_processing: String,
+        allowed_services: String,
+    },
+    Track2(Track2),
+    KeyCertificate {
+        bits: usize,
+    },
+    DataObjectList(Vec<DecodedDataObject>),
+    TagList(Vec<String>),
+    TerminalVerificationResults(TerminalVerificationResults),
+    ApplicationUsageControl(UsageControl),
+    ApplicationInterchangeProfile(ApplicationInterchangeProfile),
+    CvmList(DecodedCvmList),
+    IssuerApplicationData(IssuerApplicationData),
+    CryptogramInformationData(CryptogramInformationData),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DecodedDataObject {
+    pub tag: String,
+    pub name: String,
+    pub length: usize,
+}
+
+// ref. EMV Book 3, C3 Cardholder Verification Rule Format
+#[derive(Serialize, Debug, Clone)]
+pub struct DecodedCvmList {
+    pub amount_x: u32,
+    pub amount_y: u32,
+    pub rules: Vec<DecodedCvmRule>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DecodedCvmRule {
+    pub raw: String,
+    pub fail_if_unsuccessful: bool,
+    // None for codes and conditions the terminal does not recognise
+    pub code: Option<CvmCode>,
+    pub condition: Option<CvmConditionCode>,
+}
+
+// ref. ISO/IEC 7813, service code positions 1-3
+pub fn decode_service_code(v: &[u8]) -> Option<DecodedValue> {
+    if v.len() != 2 {
+        return None;
+    }
+
+    let interchange = match v[0] {
+        1 => "International".to_string(),
+        2 => "International (prefer ICC)".to_string(),
+        5 => "National".to_string(),
+        6 => "National (prefer ICC)".to_string(),
+        7 => "Private".to_string(),
+        9 => "Test".to_string(),
+        _ => format!("N/A {}", v[0]),
+    };
+
+    let position_2: u8 = v[1] >> 4;
+    let authorization_processing = match position_2 {
+        0 => "Normal".to_string(),
+        2 => "By Issuer".to_string(),
+        4 => "By Issuer (unless bileteral agreement exists)".to_string(),
+        _ => format!("N/A {}", position_2),
+    };
+
+    let position_3: u8 = v[1] & 0b0000_1111;
+    let allowed_services = match position_3 {
+        0 => "No restrictions (PIN required)".to_string(),
+        1 => "No restrictions".to_string(),
+        2 => "Goods and services only".to_string(),
+        3 => "ATM only (PIN required)".to_string(),
+        4 => "Cash only".to_string(),
+        5 => "Goods and services only (PIN required)".to_string(),
+        6 => "No restrictions (PIN prompt if PED)".to_string(),
+        7 => "Goods and services only (PIN prompt if PED)".to_string(),
+        _ => format!("N/A {}", position_3),
+    };
+
+    Some(DecodedValue::ServiceCode {
+        code: format!("{}{:02X}", v[0], v[1]),
+        interchange,
+        authorization_processing,
+        allowed_services,
+    })
+}
+
+// ref. EMV Book 4, Annex B - the characters of the ISO/IEC 8859 part named by the Issuer Code
+// Table Index, read as ISO/IEC 8859-1 which shares the common character set with all the parts
+pub fn decode_issuer_code_table_text(v: &[u8]) -> String {
+    v.iter().map(|&c| c as char).collect()
+}
+
+fn decode_digits(v: &[u8]) -> Option<String> {
+    let digits = hex::encode_upper(v);
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(digits)
+}
+
+// ref. EMV Book 4, 6.7.3 - YY 00 to 49 is 20YY, 50 to 99 is 19YY
+pub(crate) fn decode_date(v: &[u8]) -> Option<String> {
+    let digits = decode_digits(v).filter(|digits| digits.len() == 6)?;
+    let century = if &digits[0..2] < "50" { "20" } else { "19" };
+
+    Some(format!(
+        "{}{}-{}-{}",
+        century,
+        &digits[0..2],
+        &digits[2..4],
+        &digits[4..6]
+    ))
+}
+
+fn decode_cvm_list(v: &[u8]) -> Option<DecodedCvmList> {
+    let rules = v.get(8..)?.chunks_exact(2);
+    if !rules.remainder().is_empty() {
+        return None;
+    }
+
+    let rules = rules
+        .map(|rule| DecodedCvmRule {
+            raw: hex::encode_upper(rule),
+            fail_if_unsuccessful: rule[0] & 0b0100_0000 == 0,
+            code: CvmCode::try_from(rule[0] & 0b0011_1111).ok(),
+            condition: CvmConditionCode::try_from(rule[1]).ok(),
+        })
+        .collect();
+
+    Some(DecodedCvmList {
+        amount_x: u32::from_be_bytes([v[0], v[1], v[2], v[3]]),
+        amount_y: u32::from_be_bytes([v[4], v[5], v[6], v[7]]),
+        rules,
+    })
+}
+
+impl EmvConnection {
+    // Decodes a sequence of BER-TLV data objects, e.g. field 55 or a response message
+    pub fn decode_tlv(&self, data: &[u8]) -> Vec<DecodedTag> {
+        let mut decoded_tags: Vec<DecodedTag> = Vec::new();
+
+        let mut read_buffer = data;
+        while !read_buffer.is_empty() {
+            let (tlv_data, leftover_buffer) = Tlv::parse(read_buffer);
+            match tlv_data {
+                Ok(tlv) => decoded_tags.push(self.decode_data_object(&tlv)),
+                Err(err) => {
+                    warn!("Could not decode TLV: {:?}", err);
+                    break;
+                }
+            }
+
+            read_buffer = leftover_buffer;
+        }
+
+        decoded_tags
+    }
+
+    // All data objects of the transaction, ordered by tag
+    pub fn decode_tags(&self) -> Vec<DecodedTag> {
+        let mut tag_names: Vec<&String> = self.tags.iter().map(|(tag_name, _)| tag_name).collect();
+        tag_names.sort();
+
+        tag_names
+            .into_iter()
+            .map(|tag_name| self.decode_tag(tag_name, &self.tags.get(tag_name).unwrap()[..]))
+            .collect()
+    }
+
+    pub fn decode_tag(&self, tag_name: &str, value: &[u8]) -> DecodedTag {
+        let unknown_tag = EmvTag::new(tag_name);
+        let emv_tag = self.get_emv_tag(tag_name).unwrap_or(&unknown_tag);
+
+        let constructed = hex::decode(tag_name)
+            .ok()
+            .and_then(|tag| tag.first().map(|b| b & 0x20 != 0))
+            .unwrap_or(false);
+        if constructed {
+            // the template would repeat the censored values of its children
+            let raw = if self.settings.censor_sensitive_fields {
+                None
+            } else {
+                Some(hex::encode_upper(value))
+            };
+
+            return DecodedTag {
+                tag: tag_name.to_string(),
+                name: emv_tag.name.clone(),
+                raw,
+                format: None,
+                value: None,
+                children: self.decode_tlv(value),
+            };
+        }
+
+        let mut decoded_tag = DecodedTag {
+            tag: tag_name.to_string(),
+            name: emv_tag.name.clone(),
+            raw: Some(hex::encode_upper(value)),
+            format: emv_tag.format,
+            value: self.decode_value(emv_tag, value),
+            children: Vec::new(),
+        };
+
+        if self.settings.censor_sensitive_fields {
+            censor_decoded_tag(emv_tag, &mut decoded_tag);
+        }
+
+        decoded_tag
+    }
+
+    fn decode_data_object(&self, tlv: &Tlv) -> DecodedTag {
+        let tag_name = hex::encode_upper(tlv.tag().to_bytes());
+
+        match tlv.value() {
+            Value::Constructed(children) => {
+                let value: Vec<u8> = children.iter().flat_map(|child| child.to_vec()).collect();
+                self.decode_tag(&tag_name, &value[..])
+            }
+            Value::Primitive(value) => self.decode_tag(&tag_name, &value[..]),
+        }
+    }
+
+    fn decode_value(&self, emv_tag: &EmvTag, v: &[u8]) -> Option<DecodedValue> {
+        // Binary data objects with a defined bit layout
+        match emv_tag.tag.as_str() {
+            "82" if v.len() == 2 => {
+                return Some(DecodedValue::ApplicationInterchangeProfile(
+                    v.to_vec().into(),
+                ))
+            }
+            "8E" => return decode_cvm_list(v).map(DecodedValue::CvmList),
+            "9F10" => {
+                if let Some(iad) = IssuerApplicationData::parse(v, self.tag_scope.as_ref()) {
+                    return Some(DecodedValue::IssuerApplicationData(iad));
+                }
+            }
+            "9F27" if v.len() == 1 => {
+                return Some(DecodedValue::CryptogramInformationData(v[0].into()))
+            }
+            _ => {}
+        }
+
+        match emv_tag.format? {
+            FieldFormat::Numeric => decode_digits(v).map(|digits| {
+                let number = digits.trim_start_matches('0');
+                DecodedValue::Numeric(if number.is_empty() { "0" } else { number }.to_string())
+            }),
+            FieldFormat::CompressedNumeric => Some(DecodedValue::Numeric(
+                hex::encode_upper(v).trim_end_matches('F').to_string(),
+            )),
+            FieldFormat::Alphabetic
+            | FieldFormat::Alphanumeric
+            | FieldFormat::AlphanumericSpecial => {
+                Some(DecodedValue::Text(String::from_utf8_lossy(v).to_string()))
+            }
+            FieldFormat::IssuerCodeTableText => {
+                Some(DecodedValue::Text(decode_issuer_code_table_text(v)))
+            }
+            FieldFormat::TerminalVerificationResults if v.len() == 5 => {
+                Some(DecodedValue::TerminalVerificationResults(v.to_vec().into()))
+            }
+            FieldFormat::ApplicationUsageControl if v.len() == 2 => {
+                Some(DecodedValue::ApplicationUsageControl(v.to_vec().into()))
+            }
+            FieldFormat::KeyCertificate => Some(DecodedValue::KeyCertificate { bits: v.len() * 8 }),
+            FieldFormat::ServiceCodeIso7813 => decode_service_code(v),
+            FieldFormat::NumericCountryCode if v.len() == 2 => {
+                let code = decode_digits(v)?[1..].to_string();
+                Some(DecodedValue::CountryCode {
+                    country: self.constants.numeric_country_codes.get(&code).cloned(),
+                    code,
+                })
+            }
+            FieldFormat::NumericCurrencyCode if v.len() == 2 => {
+                let code = decode_digits(v)?[1..].to_string();
+                Some(DecodedValue::CurrencyCode {
+                    currency: self.constants.numeric_currency_codes.get(&code).cloned(),
+                    code,
+                })
+            }
+            FieldFormat::DataObjectList => DataObjectList::process_data_object_list(self, v)
+                .ok()
+                .map(|dol| {
+                    DecodedValue::DataObjectList(
+                        dol.data_objects()
+                            .iter()
+                            .map(|data_object| DecodedDataObject {
+                                tag: data_object.emv_tag.tag.clone(),
+                                name: data_object.emv_tag.name.clone(),
+                                length: data_object.length,
+                            })
+                            .collect(),
+                    )
+                }),
+            FieldFormat::TagList => {
+                DataObjectList::process_tag_list(self, v)
+                    .ok()
+                    .map(|tag_list| {
+                        DecodedValue::TagList(
+                            tag_list
+                                .data_objects()
+                                .iter()
+                                .map(|data_object| data_object.emv_tag.tag.clone())
+                                .collect(),
+                        )
+                    })
+            }
+            FieldFormat::Track2 => Track2::parse(&hex::encode_upper(v)).map(DecodedValue::Track2),
+            FieldFormat::Date => decode_date(v).map(DecodedValue::Date),
+            FieldFormat::Time => {
+                decode_digits(v)
+                    .filter(|digits| digits.len() == 6)
+                    .map(|digits| {
+                        DecodedValue::Time(format!(
+                            "{}:{}:{}",
+                            &digits[0..2],
+                            &digits[2..4],
+                            &digits[4..6]
+                        ))
+                    })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn censor_decoded_tag(emv_tag: &EmvTag, decoded_tag: &mut DecodedTag) {
+    let sensitivity = match emv_tag.sensitivity {
+        None | Some(FieldSensitivity::Public) => return,
+        Some(sensitivity) => sensitivity,
+    };
+
+    decoded_tag.raw = None;
+    decoded_tag.value = match (sensitivity, decoded_tag.value.take()) {
+        (FieldSensitivity::PrimaryAccountNumber, Some(DecodedValue::Numeric(pan))) => {
+            Some(DecodedValue::Numeric(get_truncated_pan(&pan)))
+        }
+        (FieldSensitivity::Track2, Some(DecodedValue::Track2(mut track2))) => {
+            track2.censor();
+            Some(DecodedValue::Track2(track2))
+        }
+        // keeps the separators, e.g. the '/' of the cardholder name
+        (FieldSensitivity::PersonallyIdentifiableInformation, Some(DecodedValue::Text(text))) => {
+            Some(DecodedValue::Text(text.replace(
+                |c: char| !(c.is_ascii_whitespace() || c.is_ascii_punctuation()),
+                "*",
+            )))
+        }
+        _ => None,
+    };
+}
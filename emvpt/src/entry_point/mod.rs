@@ -0,0 +1,257 @@
+use iso7816_tlv::ber::Value;
+use log::{debug, warn};
+
+use crate::tags::{international_kernel_id, CardCommand, TagOrigin, TagStore};
+use crate::{
+    find_tlv_tag, parse_application_template, EmvApplication, Terminal, TerminalApplication,
+    TerminalTransactionQualifiers,
+};
+
+// ref. EMV Contactless Book B, Table 3-7 Default Kernel Identifier by RID
+const DEFAULT_KERNEL_IDS: [([u8; 5], u8); 6] = [
+    ([0xA0, 0x00, 0x00, 0x00, 0x04], 2),
+    ([0xA0, 0x00, 0x00, 0x00, 0x03], 3),
+    ([0xA0, 0x00, 0x00, 0x00, 0x25], 4),
+    ([0xA0, 0x00, 0x00, 0x00, 0x65], 5),
+    ([0xA0, 0x00, 0x00, 0x01, 0x52], 6),
+    ([0xA0, 0x00, 0x00, 0x03, 0x33], 7),
+];
+
+// Kernels reading the Terminal Transaction Qualifiers (9F66) from the terminal
+const TTQ_KERNEL_IDS: [u8; 4] = [1, 3, 6, 7];
+
+// ref. EMV Contactless Book B, 3.1.1 Pre-Processing - indicators of a combination for the transaction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreProcessingIndicators {
+    // the combination is not added to the candidate list
+    pub contactless_application_not_allowed: bool,
+    pub zero_amount: bool,
+    pub reader_contactless_floor_limit_exceeded: bool,
+    pub reader_cvm_required_limit_exceeded: bool,
+    // copy of the TTQ for the kernel, None when the kernel does not use it
+    pub terminal_transaction_qualifiers: Option<Vec<u8>>,
+}
+
+// AID and kernel supported by the reader, ref. EMV Contactless Book B, 3.3.2 Combination Selection
+#[derive(Debug, Clone)]
+pub struct Combination {
+    pub kernel_id: u8,
+    pub terminal_application: TerminalApplication,
+    pub indicators: PreProcessingIndicators,
+}
+
+// Directory entry of the PPSE matching a combination
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub combination: Combination,
+    // ADF Name, label and priority of the directory entry
+    pub application: EmvApplication,
+    // appended to the ADF Name in the final SELECT
+    pub extended_selection: Option<Vec<u8>>,
+}
+
+// ref. EMV Contactless Book B, 3.4 Kernel Activation - what the kernel of the selected combination starts with
+#[derive(Debug, Clone)]
+pub struct KernelActivation {
+    pub kernel_id: u8,
+    pub application: EmvApplication,
+    pub indicators: PreProcessingIndicators,
+    // FCI returned in the final SELECT
+    pub fci: Vec<u8>,
+}
+
+// ref. EMV Contactless Book B, 3.1.1 Pre-Processing - indicators of an AID and kernel combination
+fn pre_process_combination(
+    terminal: &Terminal,
+    terminal_application: &TerminalApplication,
+    kernel_id: u8,
+    amount: u64,
+) -> PreProcessingIndicators {
+    let mut indicators = PreProcessingIndicators::default();
+
+    // 3.1.1.1 - the transient bits of the TTQ copy are set by the pre-processing only
+    let mut ttq: TerminalTransactionQualifiers = terminal.terminal_transaction_qualifiers;
+    ttq.online_cryptogram_required = false;
+    ttq.cvm_required = false;
+
+    // 3.1.1.3
+    if amount == 0 {
+        if terminal_application.zero_amount_allowed {
+            indicators.zero_amount = true;
+        } else {
+            indicators.contactless_application_not_allowed = true;
+        }
+    }
+
+    // 3.1.1.4
+    if matches!(terminal_application.contactless_transaction_limit, Some(limit) if amount >= limit)
+    {
+        indicators.contactless_application_not_allowed = true;
+    }
+
+    // 3.1.1.5 and 3.1.1.6 - the terminal floor limit applies when the reader has no contactless floor limit
+    let floor_limit = terminal_application
+        .contactless_floor_limit
+        .unwrap_or(terminal_application.floor_limit as u64);
+    if amount > floor_limit {
+        indicators.reader_contactless_floor_limit_exceeded = true;
+    }
+
+    // 3.1.1.7
+    if matches!(terminal_application.contactless_cvm_required_limit, Some(limit) if amount >= limit)
+    {
+        indicators.reader_cvm_required_limit_exceeded = true;
+    }
+
+    // 3.1.1.8
+    if indicators.reader_contactless_floor_limit_exceeded {
+        ttq.online_cryptogram_required = true;
+    }
+
+    // 3.1.1.10 - an offline-only reader can't process a zero amount
+    if indicators.zero_amount {
+        if ttq.offline_only_reader {
+            indicators.contactless_application_not_allowed = true;
+        } else {
+            ttq.online_cryptogram_required = true;
+        }
+    }
+
+    // 3.1.1.11
+    if indicators.reader_cvm_required_limit_exceeded {
+        ttq.cvm_required = true;
+    }
+
+    if TTQ_KERNEL_IDS.contains(&kernel_id) {
+        indicators.terminal_transaction_qualifiers = Some(ttq.into());
+    }
+
+    indicators
+}
+
+// ref. EMV Contactless Book B, 3.1 Pre-Processing
+pub fn pre_processing(terminal: &Terminal, amount: u64) -> Vec<Combination> {
+    terminal
+        .applications
+        .iter()
+        .filter_map(|terminal_application| {
+            let kernel_id = terminal_application.kernel_id?;
+            let indicators =
+                pre_process_combination(terminal, terminal_application, kernel_id, amount);
+            if indicators.contactless_application_not_allowed {
+                debug!(
+                    "Contactless application not allowed. AID:{}, kernel:{}",
+                    terminal_application.aid, kernel_id
+                );
+            }
+
+            Some(Combination {
+                kernel_id,
+                terminal_application: terminal_application.clone(),
+                indicators,
+            })
+        })
+        .collect()
+}
+
+// ref. EMV Contactless Book B, 3.1.1.13 - the transaction is not possible in contactless
+pub fn is_contactless_allowed(combinations: &[Combination]) -> bool {
+    combinations
+        .iter()
+        .any(|combination| !combination.indicators.contactless_application_not_allowed)
+}
+
+// ref. EMV Contactless Book B, 3.3.2.5 - Requested Kernel ID of a directory entry, None for domestic kernels
+fn requested_kernel_id(adf_name: &[u8], directory_entry: &TagStore) -> Option<u8> {
+    match directory_entry.get("9F2A") {
+        Some(kernel_identifier) if kernel_identifier.first().is_some_and(|&b| b != 0x00) => {
+            international_kernel_id(kernel_identifier)
+        }
+        // the default kernel of the RID
+        _ => DEFAULT_KERNEL_IDS
+            .iter()
+            .find(|(rid, _)| adf_name.starts_with(rid))
+            .map(|(_, kernel_id)| *kernel_id),
+    }
+}
+
+// ref. EMV Contactless Book B, 3.3.2 Combination Selection - candidates in the order of the directory
+pub fn build_candidate_list(combinations: &[Combination], ppse_fci: &[u8]) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    let tag_bf0c = match find_tlv_tag(ppse_fci, "BF0C") {
+        Some(tag_bf0c) => tag_bf0c,
+        None => {
+            warn!("Expected tag BF0C not found in the PPSE");
+            return candidates;
+        }
+    };
+
+    let directory_entries = match tag_bf0c.value() {
+        Value::Constructed(directory_entries) => directory_entries,
+        Value::Primitive(_) => return candidates,
+    };
+
+    for tag_61_directory_entry in directory_entries {
+        let directory_entry = match tag_61_directory_entry.value() {
+            Value::Constructed(directory_entry) => directory_entry,
+            Value::Primitive(_) => continue,
+        };
+
+        let entry_tags =
+            parse_application_template(directory_entry, TagOrigin::icc(Some(CardCommand::Select)));
+        let tag_4f_adf_name = match entry_tags.get("4F") {
+            Some(adf_name) if !adf_name.is_empty() => adf_name.clone(),
+            _ => {
+                warn!("Directory entry without ADF Name");
+                continue;
+            }
+        };
+
+        let kernel_id = match requested_kernel_id(&tag_4f_adf_name, &entry_tags) {
+            Some(kernel_id) => kernel_id,
+            None => {
+                debug!("Unsupported kernel for ADF Name {:02X?}", tag_4f_adf_name);
+                continue;
+            }
+        };
+
+        for combination in combinations {
+            if combination.kernel_id != kernel_id
+                || combination.indicators.contactless_application_not_allowed
+                || !combination.terminal_application.matches(&tag_4f_adf_name)
+            {
+                continue;
+            }
+
+            let extended_selection = if combination.terminal_application.extended_selection {
+                entry_tags.get("9F29").cloned()
+            } else {
+                None
+            };
+
+            let default_label = "UNKNOWN".as_bytes().to_vec();
+            candidates.push(Candidate {
+                combination: combination.clone(),
+                application: EmvApplication {
+                    aid: tag_4f_adf_name.clone(),
+                    label: entry_tags.get("50").unwrap_or(&default_label).clone(),
+                    priority: entry_tags.get("87").cloned().unwrap_or_default(),
+                    tags: entry_tags.clone(),
+                },
+                extended_selection,
+            });
+        }
+    }
+
+    candidates
+}
+
+// ref. EMV Contactless Book B, 3.3.3 Final Combination Selection - the first of the highest priority
+pub fn highest_priority_candidate(candidates: &[Candidate]) -> Option<usize> {
+    candidates
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, candidate)| candidate.application.priority_order())
+        .map(|(index, _)| index)
+}
@@ -0,0 +1,266 @@
+use serde::Serialize;
+
+use crate::{CryptogramType, TagScope};
+
+const VISA_RID: [u8; 5] = [0xA0, 0x00, 0x00, 0x00, 0x03];
+const MASTERCARD_RID: [u8; 5] = [0xA0, 0x00, 0x00, 0x00, 0x04];
+
+// Cryptogram Version Numbers of the supported layouts
+const VISA_CVNS: [u8; 3] = [0x0A, 0x11, 0x12];
+const VISA_FORMAT_2_CVN: u8 = 0x22;
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IssuerApplicationDataFormat {
+    // VIS formats 0 and 1, CVN 10, 17 and 18
+    Visa,
+    // VIS format 2, CVN 22
+    VisaFormat2,
+    // M/Chip, CVN 10 to 17
+    MChip,
+    // ref. EMV Book 3, Annex C7 Common Core Definitions
+    CommonCoreDefinitions,
+}
+
+// Card Verification Results, the card's own record of the risk management it performed.
+// Bits without a counterpart in the layout stay false.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CardVerificationResults {
+    pub raw: String,
+    pub first_generate_ac: Option<CryptogramType>,
+    // None when the second GENERATE AC was not requested
+    pub second_generate_ac: Option<CryptogramType>,
+    pub offline_pin_verification_performed: bool,
+    pub offline_pin_verification_failed: bool,
+    pub pin_try_limit_exceeded: bool,
+    // low order nibble of the PIN Try Counter, None when the layout does not carry it
+    pub pin_try_counter: Option<u8>,
+    pub issuer_authentication_failed: bool,
+    pub offline_data_authentication_failed_on_previous_transaction: bool,
+    pub last_online_transaction_not_completed: bool,
+    pub unable_to_go_online: bool,
+    pub go_online_on_next_transaction: bool,
+    // any of the offline velocity limits, the only indication in the Visa layout
+    pub offline_limits_exceeded: bool,
+    pub lower_consecutive_offline_limit_exceeded: bool,
+    pub upper_consecutive_offline_limit_exceeded: bool,
+    pub lower_cumulative_offline_limit_exceeded: bool,
+    pub upper_cumulative_offline_limit_exceeded: bool,
+    // script counter, issuer script commands processed on the last online transaction
+    pub issuer_script_commands: u8,
+    pub issuer_script_processing_failed: bool,
+}
+
+// Issuer Application Data (9F10), proprietary to the card application
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct IssuerApplicationData {
+    pub format: IssuerApplicationDataFormat,
+    pub cryptogram_version_number: u8,
+    pub derivation_key_index: u8,
+    pub card_verification_results: CardVerificationResults,
+    // e.g. counters and the DAC, None when the card did not return any
+    pub issuer_discretionary_data: Option<String>,
+}
+
+// b8-b7 and b6-b5 of the first CVR byte, '10' is ARQC for the first and not requested for the second GENERATE AC
+fn generate_ac_types(b1: u8) -> (Option<CryptogramType>, Option<CryptogramType>) {
+    let first = match (b1 >> 4) & 0b11 {
+        0b00 => Some(CryptogramType::ApplicationAuthenticationCryptogram),
+        0b01 => Some(CryptogramType::TransactionCertificate),
+        0b10 => Some(CryptogramType::AuthorisationRequestCryptogram),
+        _ => None,
+    };
+    let second = match b1 >> 6 {
+        0b00 => Some(CryptogramType::ApplicationAuthenticationCryptogram),
+        0b01 => Some(CryptogramType::TransactionCertificate),
+        _ => None,
+    };
+
+    (first, second)
+}
+
+fn non_empty_hex(data: &[u8]) -> Option<String> {
+    if data.is_empty() {
+        return None;
+    }
+
+    Some(hex::encode_upper(data))
+}
+
+// ref. EMV Book 3, C7.3 Card Verification Results, also VIS format 2
+fn parse_common_core_cvr(cvr: &[u8]) -> CardVerificationResults {
+    let (b1, b2, b3, b4) = (cvr[0], cvr[1], cvr[2], cvr[3]);
+    let (first_generate_ac, second_generate_ac) = generate_ac_types(b1);
+
+    CardVerificationResults {
+        raw: hex::encode_upper(cvr),
+        first_generate_ac,
+        second_generate_ac,
+        issuer_authentication_failed: b1 & 0b0000_0001 != 0,
+        pin_try_counter: Some(b2 >> 4),
+        offline_pin_verification_performed: b2 & 0b0000_1000 != 0,
+        offline_pin_verification_failed: b2 & 0b0000_0100 != 0,
+        pin_try_limit_exceeded: b2 & 0b0000_0010 != 0,
+        last_online_transaction_not_completed: b2 & 0b0000_0001 != 0,
+        offline_limits_exceeded: b3 & 0b1111_0000 != 0,
+        lower_consecutive_offline_limit_exceeded: b3 & 0b1000_0000 != 0,
+        upper_consecutive_offline_limit_exceeded: b3 & 0b0100_0000 != 0,
+        lower_cumulative_offline_limit_exceeded: b3 & 0b0010_0000 != 0,
+        upper_cumulative_offline_limit_exceeded: b3 & 0b0001_0000 != 0,
+        issuer_script_commands: b4 >> 4,
+        issuer_script_processing_failed: b4 & 0b0000_1000 != 0,
+        offline_data_authentication_failed_on_previous_transaction: b4 & 0b0000_0100 != 0,
+        go_online_on_next_transaction: b4 & 0b0000_0010 != 0,
+        unable_to_go_online: b4 & 0b0000_0001 != 0,
+    }
+}
+
+// ref. Visa Integrated Circuit Card Specification (VIS), Appendix A Card Verification Results
+fn parse_visa_cvr(cvr: &[u8]) -> CardVerificationResults {
+    let (b1, b2, b3) = (cvr[0], cvr[1], cvr[2]);
+    let (first_generate_ac, second_generate_ac) = generate_ac_types(b1);
+
+    CardVerificationResults {
+        raw: hex::encode_upper(cvr),
+        first_generate_ac,
+        second_generate_ac,
+        issuer_authentication_failed: b1 & 0b0000_1000 != 0 || b2 & 0b0000_1000 != 0,
+        offline_pin_verification_performed: b1 & 0b0000_0100 != 0,
+        offline_pin_verification_failed: b1 & 0b0000_0010 != 0,
+        unable_to_go_online: b1 & 0b0000_0001 != 0,
+        last_online_transaction_not_completed: b2 & 0b1000_0000 != 0,
+        // PIN Try Limit exceeded, or the application blocked because of it
+        pin_try_limit_exceeded: b2 & 0b0100_0010 != 0,
+        offline_limits_exceeded: b2 & 0b0010_0000 != 0,
+        issuer_script_commands: b3 >> 4,
+        issuer_script_processing_failed: b3 & 0b0000_1000 != 0,
+        // SDA failure in the second byte, DDA failure in the third
+        offline_data_authentication_failed_on_previous_transaction: b2 & 0b0000_0001 != 0
+            || b3 & 0b0000_0100 != 0,
+        ..CardVerificationResults::default()
+    }
+}
+
+// ref. M/Chip Advance Card Application Specification, Card Verification Results
+fn parse_mchip_cvr(cvr: &[u8]) -> CardVerificationResults {
+    let (b1, b3, b4, b5) = (cvr[0], cvr[2], cvr[3], cvr[4]);
+    let (first_generate_ac, second_generate_ac) = generate_ac_types(b1);
+
+    CardVerificationResults {
+        raw: hex::encode_upper(cvr),
+        first_generate_ac,
+        second_generate_ac,
+        offline_pin_verification_performed: b1 & 0b0000_0100 != 0,
+        offline_pin_verification_failed: b4 & 0b0001_0000 != 0,
+        issuer_script_commands: b3 >> 4,
+        pin_try_counter: Some(b3 & 0b0000_1111),
+        last_online_transaction_not_completed: b4 & 0b1000_0000 != 0,
+        unable_to_go_online: b4 & 0b0100_0000 != 0,
+        pin_try_limit_exceeded: b4 & 0b0000_1000 != 0,
+        offline_limits_exceeded: b5 & 0b1111_0000 != 0,
+        lower_consecutive_offline_limit_exceeded: b5 & 0b1000_0000 != 0,
+        upper_consecutive_offline_limit_exceeded: b5 & 0b0100_0000 != 0,
+        lower_cumulative_offline_limit_exceeded: b5 & 0b0010_0000 != 0,
+        upper_cumulative_offline_limit_exceeded: b5 & 0b0001_0000 != 0,
+        go_online_on_next_transaction: b5 & 0b0000_1000 != 0,
+        issuer_authentication_failed: b5 & 0b0000_0100 != 0,
+        issuer_script_processing_failed: b5 & 0b0000_0001 != 0,
+        ..CardVerificationResults::default()
+    }
+}
+
+impl IssuerApplicationData {
+    // The layout is recognised from the data, the scope of the selected application resolves the
+    // layouts of different schemes sharing a CVN
+    pub fn parse(v: &[u8], scope: Option<&TagScope>) -> Option<IssuerApplicationData> {
+        let (visa, mastercard) = match scope {
+            Some(TagScope::KernelId(3)) => (true, false),
+            Some(TagScope::KernelId(2)) => (false, true),
+            Some(TagScope::Rid(rid)) if rid[..] == VISA_RID => (true, false),
+            Some(TagScope::Rid(rid)) if rid[..] == MASTERCARD_RID => (false, true),
+            _ => (true, true),
+        };
+
+        IssuerApplicationData::parse_common_core(v)
+            .or_else(|| {
+                if visa {
+                    IssuerApplicationData::parse_visa(v)
+                } else {
+                    None
+                }
+            })
+            .or_else(|| {
+                if mastercard {
+                    IssuerApplicationData::parse_mchip(v)
+                } else {
+                    None
+                }
+            })
+    }
+
+    // ref. EMV Book 3, C7.1 Issuer Application Data for Format Code 'A'
+    fn parse_common_core(v: &[u8]) -> Option<IssuerApplicationData> {
+        if v.len() != 32 || v[0] != 0x0F || v[1] >> 4 != 0x0A || v[16] != 0x0F {
+            return None;
+        }
+
+        Some(IssuerApplicationData {
+            format: IssuerApplicationDataFormat::CommonCoreDefinitions,
+            // Common Core Identifier, b4-b1 is the cryptogram version
+            cryptogram_version_number: v[1],
+            derivation_key_index: v[2],
+            card_verification_results: parse_common_core_cvr(&v[3..8]),
+            // counter bytes and the issuer-discretionary data
+            issuer_discretionary_data: non_empty_hex(&[&v[8..16], &v[17..]].concat()),
+        })
+    }
+
+    fn parse_visa(v: &[u8]) -> Option<IssuerApplicationData> {
+        // format 2: length, CVN, DKI, 5 byte CVR, issuer discretionary data
+        if v.len() == 32 && v[0] == 0x1F && v[1] == VISA_FORMAT_2_CVN {
+            return Some(IssuerApplicationData {
+                format: IssuerApplicationDataFormat::VisaFormat2,
+                cryptogram_version_number: v[1],
+                derivation_key_index: v[2],
+                card_verification_results: parse_common_core_cvr(&v[3..8]),
+                issuer_discretionary_data: non_empty_hex(&v[8..]),
+            });
+        }
+
+        // formats 0 and 1: length, DKI, CVN, CVR with its length, optionally issuer discretionary data with its length
+        let visa_discretionary_data_length = *v.first()? as usize;
+        if !(6..=7).contains(&visa_discretionary_data_length)
+            || v.len() < 1 + visa_discretionary_data_length
+            || !VISA_CVNS.contains(&v[2])
+            || v[3] != 0x03
+        {
+            return None;
+        }
+
+        let issuer_discretionary_data = v[1 + visa_discretionary_data_length..]
+            .split_first()
+            .and_then(|(&length, data)| non_empty_hex(&data[..data.len().min(length as usize)]));
+
+        Some(IssuerApplicationData {
+            format: IssuerApplicationDataFormat::Visa,
+            cryptogram_version_number: v[2],
+            derivation_key_index: v[1],
+            card_verification_results: parse_visa_cvr(&v[4..7]),
+            issuer_discretionary_data,
+        })
+    }
+
+    // KDI, CVN, 6 byte CVR, then the DAC/ICC Dynamic Number and counters
+    fn parse_mchip(v: &[u8]) -> Option<IssuerApplicationData> {
+        if v.len() < 8 || !(0x10..=0x17).contains(&v[1]) {
+            return None;
+        }
+
+        Some(IssuerApplicationData {
+            format: IssuerApplicationDataFormat::MChip,
+            cryptogram_version_number: v[1],
+            derivation_key_index: v[0],
+            card_verification_results: parse_mchip_cvr(&v[2..8]),
+            issuer_discretionary_data: non_empty_hex(&v[8..]),
+        })
+    }
+}
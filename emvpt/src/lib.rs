@@ -1,7 +1,7 @@
 use chrono::{Datelike, NaiveDate, Timelike, Utc};
 use hex;
 use hexplay::HexViewBuilder;
-use iso7816_tlv::ber::{Tag, Tlv, Value};
+use iso7816_tlv::ber::{Tlv, Value};
 use log::{debug, info, trace, warn};
 use openssl::bn::BigNum;
 use openssl::rsa::{Padding, Rsa};
@@ -10,6 +10,7 @@ use rand::prelude::*;
 use rand::Rng;
 use rand_chacha::ChaCha20Rng;
 use regex::Regex;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryFrom;
@@ -17,9 +18,32 @@ use std::convert::TryInto;
 use std::error;
 use std::fmt;
 use std::fs::{self};
+#[cfg(feature = "async")]
+use std::ops::{Deref, DerefMut};
 use std::str;
+use std::sync::Arc;
 
+#[cfg(feature = "async")]
+use async_trait::async_trait;
+
+pub mod apdu;
 pub mod bcdutil;
+pub mod decoder;
+pub mod entry_point;
+pub mod iad;
+pub mod tags;
+pub mod tlv;
+
+use apdu::{
+    CommandApdu, ResponseApdu, StatusWord, SELECT_FIRST_OCCURRENCE, SELECT_NEXT_OCCURRENCE,
+};
+pub use decoder::{DecodedTag, DecodedValue};
+use entry_point::{Candidate, Combination};
+pub use entry_point::{KernelActivation, PreProcessingIndicators};
+pub use iad::{CardVerificationResults, IssuerApplicationData};
+use tags::{CardCommand, RejectedValue, TagOrigin, TagStore};
+pub use tags::{KernelTags, TagScope};
+pub use tlv::{encode_ber_length, TlvBuilder};
 
 macro_rules! get_bit {
     ($byte:expr, $bit:expr) => {
@@ -41,13 +65,9 @@ macro_rules! set_bit {
     };
 }
 
-macro_rules! serialize_yaml {
-    ($file:expr, $static_resource:expr) => {
-        serde_yaml::from_str(
-            &fs::read_to_string($file)
-                .unwrap_or(String::from_utf8_lossy(include_bytes!($static_resource)).to_string()),
-        )
-        .unwrap()
+macro_rules! bundled_yaml {
+    ($static_resource:expr) => {
+        parse_yaml($static_resource, include_str!($static_resource))
     };
 }
 
@@ -83,20 +103,25 @@ pub struct Track1 {
 
 impl Track1 {
     pub fn new(track_data: &str) -> Track1 {
+        Track1::parse(track_data).unwrap()
+    }
+
+    pub fn parse(track_data: &str) -> Option<Track1> {
         // %B4321432143214321^Mc'Doe/JOHN^2609101123456789012345678901234?
         println!("Track: {}", track_data);
         let re = Regex::new(r"^(%B)?(\d+)\^(.+)?/(.+)?\^(\d{2})(\d{2})(\d{3})(\d+)\??$").unwrap();
-        let cap = re.captures(track_data).unwrap();
-
-        Track1 {
-            primary_account_number: cap.get(2).unwrap().as_str().to_string(),
-            last_name: cap.get(3).unwrap().as_str().to_string(),
-            first_name: cap.get(4).unwrap().as_str().to_string(),
-            expiry_year: cap.get(5).unwrap().as_str().to_string(),
-            expiry_month: cap.get(6).unwrap().as_str().to_string(),
-            service_code: cap.get(7).unwrap().as_str().to_string(),
-            discretionary_data: cap.get(8).unwrap().as_str().to_string(),
-        }
+        let cap = re.captures(track_data)?;
+        let group = |i: usize| cap.get(i).map_or("", |m| m.as_str()).to_string();
+
+        Some(Track1 {
+            primary_account_number: group(2),
+            last_name: group(3),
+            first_name: group(4),
+            expiry_year: group(5),
+            expiry_month: group(6),
+            service_code: group(7),
+            discretionary_data: group(8),
+        })
     }
 
     pub fn censor(&mut self) {
@@ -123,7 +148,7 @@ impl fmt::Display for Track1 {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct Track2 {
     pub primary_account_number: String,
     pub expiry_year: String,
@@ -134,20 +159,25 @@ pub struct Track2 {
 
 impl Track2 {
     pub fn new(track_data: &str) -> Track2 {
+        Track2::parse(track_data).unwrap()
+    }
+
+    pub fn parse(track_data: &str) -> Option<Track2> {
         // Supports human readable and ICC formats
         // human readable: ;4321432143214321=2612101123456789123?
         // ICC: 4321432143214321D2612101123456789123F
 
         let re = Regex::new(r"^;?(\d+)(=|D)(\d{2})(\d{2})(\d{3})(\d+)F?\??$").unwrap();
-        let cap = re.captures(track_data).unwrap();
-
-        Track2 {
-            primary_account_number: cap.get(1).unwrap().as_str().to_string(),
-            expiry_year: cap.get(3).unwrap().as_str().to_string(),
-            expiry_month: cap.get(4).unwrap().as_str().to_string(),
-            service_code: cap.get(5).unwrap().as_str().to_string(),
-            discretionary_data: cap.get(6).unwrap().as_str().to_string(),
-        }
+        let cap = re.captures(track_data)?;
+        let group = |i: usize| cap.get(i).map_or("", |m| m.as_str()).to_string();
+
+        Some(Track2 {
+            primary_account_number: group(1),
+            expiry_year: group(3),
+            expiry_month: group(4),
+            service_code: group(5),
+            discretionary_data: group(6),
+        })
     }
 
     pub fn censor(&mut self) {
@@ -171,7 +201,7 @@ impl fmt::Display for Track2 {
 }
 
 #[repr(u8)]
-#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum CryptogramType {
     // bits 6-7 are relevant
     ApplicationAuthenticationCryptogram = 0b0000_0000, // AAC, transaction declined
@@ -202,8 +232,94 @@ impl TryFrom<u8> for CryptogramType {
     }
 }
 
+// ref. EMV Book 3, 6.5.5.4 Data Field Returned in the Response Message, Table 14 - reason/advice code
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CidReasonCode {
+    NoInformationGiven = 0b000,
+    ServiceNotAllowed = 0b001,
+    PinTryLimitExceeded = 0b010,
+    IssuerAuthenticationFailed = 0b011,
+}
+
+impl TryFrom<u8> for CidReasonCode {
+    type Error = &'static str;
+
+    fn try_from(orig: u8) -> Result<Self, Self::Error> {
+        match orig & 0b0000_0111 {
+            0b000 => Ok(CidReasonCode::NoInformationGiven),
+            0b001 => Ok(CidReasonCode::ServiceNotAllowed),
+            0b010 => Ok(CidReasonCode::PinTryLimitExceeded),
+            0b011 => Ok(CidReasonCode::IssuerAuthenticationFailed),
+            _ => Err("Unknown code!"),
+        }
+    }
+}
+
+impl fmt::Display for CidReasonCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            CidReasonCode::NoInformationGiven => "no information given",
+            CidReasonCode::ServiceNotAllowed => "service not allowed",
+            CidReasonCode::PinTryLimitExceeded => "PIN try limit exceeded",
+            CidReasonCode::IssuerAuthenticationFailed => "issuer authentication failed",
+        };
+
+        write!(f, "{}", reason)
+    }
+}
+
+// ref. EMV Book 3, 6.5.5.4 Data Field Returned in the Response Message, Table 14 Coding of Cryptogram Information Data
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CryptogramInformationData {
+    // None for the RFU cryptogram type
+    pub cryptogram_type: Option<CryptogramType>,
+    // b6-b5, payment system-specific cryptogram
+    pub payment_system_specific: u8,
+    pub advice_required: bool,
+    // None for RFU codes
+    pub reason_code: Option<CidReasonCode>,
+}
+
+impl From<u8> for CryptogramInformationData {
+    fn from(cid: u8) -> Self {
+        CryptogramInformationData {
+            cryptogram_type: CryptogramType::try_from(cid).ok(),
+            payment_system_specific: (cid >> 4) & 0b0000_0011,
+            advice_required: get_bit!(cid, 3),
+            reason_code: CidReasonCode::try_from(cid).ok(),
+        }
+    }
+}
+
+// Party declining the transaction with an AAC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeclineReason {
+    // AAC requested by the terminal, e.g. a Terminal Action Code - Denial match
+    Terminal,
+    // Authorisation Response Code (8A) other than approved
+    Issuer,
+    // AAC returned by the ICC, with the reason of the Cryptogram Information Data
+    Card(Option<CidReasonCode>),
+}
+
+impl fmt::Display for DeclineReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeclineReason::Terminal => write!(f, "declined by terminal"),
+            DeclineReason::Issuer => write!(f, "declined by issuer"),
+            DeclineReason::Card(Some(reason_code))
+                if *reason_code != CidReasonCode::NoInformationGiven =>
+            {
+                write!(f, "declined by ICC ({})", reason_code)
+            }
+            DeclineReason::Card(_) => write!(f, "declined by ICC"),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum CvmCode {
     FailCvmProcessing = 0b0000_0000,
     PlaintextPin = 0b0000_0001,
@@ -213,6 +329,8 @@ pub enum CvmCode {
     EncipheredPinOfflineAndSignature = 0b0000_0101,
     Signature = 0b0001_1110,
     NoCvm = 0b0001_1111,
+    // CVM code not recognised by the terminal, kept for the CVM processing and the CVM Results
+    Unrecognised(u8),
 }
 
 impl From<CvmCode> for u8 {
@@ -226,6 +344,7 @@ impl From<CvmCode> for u8 {
             CvmCode::EncipheredPinOfflineAndSignature => 0b0000_0101,
             CvmCode::Signature => 0b0001_1110,
             CvmCode::NoCvm => 0b0001_1111,
+            CvmCode::Unrecognised(code) => code,
         }
     }
 }
@@ -249,7 +368,7 @@ impl TryFrom<u8> for CvmCode {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum CvmConditionCode {
     Always = 0x00,
     UnattendedCash = 0x01,
@@ -302,8 +421,9 @@ impl TryFrom<u8> for CvmConditionCode {
 
 #[derive(Debug, Copy, Clone)]
 pub struct CvmRule {
-    pub amount_x: u32,
-    pub amount_y: u32,
+    // compared with Amount, Authorised (n12), wider than the 4 byte binary amounts of the CVM list
+    pub amount_x: u64,
+    pub amount_y: u64,
     pub fail_if_unsuccessful: bool,
     pub code: CvmCode,
     pub condition: CvmConditionCode,
@@ -318,14 +438,14 @@ impl CvmRule {
             Err(rule) => rule,
         };
 
-        let mut c: u8 = rule_unwrapped.code.try_into().unwrap();
+        let mut c: u8 = rule_unwrapped.code.into();
         if !rule_unwrapped.fail_if_unsuccessful {
             c += 0b0100_0000;
         }
 
         let mut value: Vec<u8> = Vec::new();
         value.push(c);
-        value.push(rule_unwrapped.condition.try_into().unwrap());
+        value.push(rule_unwrapped.condition.into());
 
         let result: u8 = match rule {
             Ok(rule) => {
@@ -356,7 +476,7 @@ pub struct Capabilities {
     pub issuer_authentication: bool,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct UsageControl {
     pub domestic_cash_transactions: bool,
     pub international_cash_transactions: bool,
@@ -372,8 +492,9 @@ pub struct UsageControl {
 
 impl From<Vec<u8>> for UsageControl {
     fn from(data: Vec<u8>) -> Self {
-        let b1: u8 = data[0];
-        let b2: u8 = data[1];
+        // missing bytes are read as zero
+        let b1: u8 = data.first().copied().unwrap_or(0);
+        let b2: u8 = data.get(1).copied().unwrap_or(0);
 
         UsageControl {
             domestic_cash_transactions: get_bit!(b1, 7),
@@ -390,6 +511,34 @@ impl From<Vec<u8>> for UsageControl {
     }
 }
 
+// EMV Book 3, C1 Application Interchange Profile
+#[derive(Serialize, Debug, Copy, Clone)]
+pub struct ApplicationInterchangeProfile {
+    pub sda_supported: bool,
+    pub dda_supported: bool,
+    pub cardholder_verification_supported: bool,
+    pub terminal_risk_management_to_be_performed: bool,
+    pub issuer_authentication_supported: bool,
+    pub cda_supported: bool,
+}
+
+impl From<Vec<u8>> for ApplicationInterchangeProfile {
+    fn from(data: Vec<u8>) -> Self {
+        let b1: u8 = data.first().copied().unwrap_or(0);
+
+        ApplicationInterchangeProfile {
+            //bit 7 = RFU
+            sda_supported: get_bit!(b1, 6),
+            dda_supported: get_bit!(b1, 5),
+            cardholder_verification_supported: get_bit!(b1, 4),
+            terminal_risk_management_to_be_performed: get_bit!(b1, 3),
+            issuer_authentication_supported: get_bit!(b1, 2),
+            //bit 1 = RFU
+            cda_supported: get_bit!(b1, 0),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Icc {
     pub capabilities: Capabilities,
@@ -593,10 +742,93 @@ pub struct Terminal {
     pub capabilities: Capabilities,
     pub tvr: TerminalVerificationResults,
     pub tsi: TransactionStatusInformation,
-    pub cryptogram_type: CryptogramType,
     pub cryptogram_type_arqc: CryptogramType,
     pub terminal_transaction_qualifiers: TerminalTransactionQualifiers,
     pub c4_enhanced_contactless_reader_capabilities: C4EnhancedContactlessReaderCapabilities,
+    // ref. EMV Book 1, 12.3.1 - applications supported by the terminal with their parameters
+    #[serde(default)]
+    pub applications: Vec<TerminalApplication>,
+}
+
+impl Terminal {
+    // An exact match is preferred over a partial one
+    pub fn application(&self, aid: &[u8]) -> Option<&TerminalApplication> {
+        self.applications
+            .iter()
+            .find(|application| hex::decode(&application.aid).ok().as_deref() == Some(aid))
+            .or_else(|| {
+                self.applications
+                    .iter()
+                    .find(|application| application.matches(aid))
+            })
+    }
+}
+
+// ref. EMV Book 4, 6.5 Application Dependent Data - terminal parameters of a supported application,
+// values in hex as in default_tags and amounts in the minor unit of the transaction currency
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TerminalApplication {
+    pub aid: String,
+    // ref. EMV Book 1, 12.3.1 Application Selection Indicator (ASI) - the ICC may have applications
+    // whose DF Name only begins with the terminal AID
+    pub partial_selection: bool,
+    // Application Version Number (9F09)
+    pub application_version_number: Option<String>,
+    pub terminal_action_code_denial: String,
+    pub terminal_action_code_online: String,
+    pub terminal_action_code_default: String,
+    // Terminal Floor Limit (9F1B)
+    pub floor_limit: u32,
+    // ref. EMV Book 3, 10.6.2 Random Transaction Selection
+    pub random_selection_threshold: u32,
+    pub random_selection_target_percentage: u8,
+    pub random_selection_maximum_target_percentage: u8,
+    // used when the ICC does not provide a DDOL (9F49) or a TDOL (97)
+    pub default_ddol: Option<String>,
+    pub default_tdol: Option<String>,
+    // ref. EMV Contactless Book B, 3.3.2 - kernel of the AID in contactless, None when not supported
+    pub kernel_id: Option<u8>,
+    // ref. EMV Contactless Book B, 3.3.2.5 - the Extended Selection (9F29) is appended to the ADF Name
+    pub extended_selection: bool,
+    // ref. EMV Contactless Book B, 3.1.1 Static Entry Point Configuration Data - no limit when not set
+    pub contactless_transaction_limit: Option<u64>,
+    pub contactless_floor_limit: Option<u64>,
+    pub contactless_cvm_required_limit: Option<u64>,
+    // ref. EMV Contactless Book B, 3.1.1.3 - otherwise contactless is not allowed for a zero amount
+    pub zero_amount_allowed: bool,
+}
+
+impl TerminalApplication {
+    // ref. EMV Book 1, 12.3.1 - the same AID, or a longer DF Name when partial selection is allowed
+    pub fn matches(&self, aid: &[u8]) -> bool {
+        match hex::decode(&self.aid) {
+            Ok(terminal_aid) => {
+                aid == &terminal_aid[..]
+                    || (self.partial_selection
+                        && aid.len() > terminal_aid.len()
+                        && aid.starts_with(&terminal_aid))
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Terminal Action Codes - Denial, Online and Default
+    pub fn terminal_action_codes(
+        &self,
+    ) -> Result<
+        (
+            TerminalVerificationResults,
+            TerminalVerificationResults,
+            TerminalVerificationResults,
+        ),
+        hex::FromHexError,
+    > {
+        Ok((
+            hex::decode(&self.terminal_action_code_denial)?.into(),
+            hex::decode(&self.terminal_action_code_online)?.into(),
+            hex::decode(&self.terminal_action_code_default)?.into(),
+        ))
+    }
 }
 
 // EMV Book 3, C5 Terminal Verification Results (TVR)
@@ -788,11 +1020,12 @@ impl TerminalVerificationResults {
 
 impl From<Vec<u8>> for TerminalVerificationResults {
     fn from(data: Vec<u8>) -> Self {
-        let b1: u8 = data[0];
-        let b2: u8 = data[1];
-        let b3: u8 = data[2];
-        let b4: u8 = data[3];
-        let b5: u8 = data[4];
+        // missing bytes are read as zero
+        let b1: u8 = data.first().copied().unwrap_or(0);
+        let b2: u8 = data.get(1).copied().unwrap_or(0);
+        let b3: u8 = data.get(2).copied().unwrap_or(0);
+        let b4: u8 = data.get(3).copied().unwrap_or(0);
+        let b5: u8 = data.get(4).copied().unwrap_or(0);
 
         TerminalVerificationResults {
             offline_data_authentication_was_not_performed: get_bit!(b1, 7),
@@ -926,19 +1159,28 @@ impl From<TransactionStatusInformation> for Vec<u8> {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+// Files not configured are replaced by the bundled EMV tags, kernel tags and constants, scheme CA public keys have no default
+#[derive(Serialize, Deserialize, Default)]
 pub struct ConfigurationFiles {
-    emv_tags: String,
-    scheme_ca_public_keys: String,
-    constants: String,
+    pub emv_tags: Option<String>,
+    pub kernel_tags: Option<String>,
+    pub scheme_ca_public_keys: Option<String>,
+    pub constants: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Settings {
     pub censor_sensitive_fields: bool,
-    configuration_files: ConfigurationFiles,
+    #[serde(default)]
+    pub configuration_files: ConfigurationFiles,
     pub terminal: Terminal,
-    default_tags: HashMap<String, String>,
+    pub default_tags: HashMap<String, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        bundled_yaml!("config/settings.yaml").unwrap()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -948,8 +1190,410 @@ pub struct Constants {
     pub apdu_status_codes: HashMap<String, String>,
 }
 
-pub trait ApduInterface {
-    fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, ()>;
+// EMV Book 3, 10 Functions Used in Transaction Processing
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransactionStep {
+    ApplicationSelection,
+    InitiateApplicationProcessing,
+    ReadApplicationData,
+    OfflineDataAuthentication,
+    ProcessingRestrictions,
+    CardholderVerification,
+    TerminalRiskManagement,
+    TerminalActionAnalysis,
+    CardActionAnalysis,
+    OnlineProcessing,
+    Completion,
+}
+
+impl fmt::Display for TransactionStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let step = match self {
+            TransactionStep::ApplicationSelection => "application selection",
+            TransactionStep::InitiateApplicationProcessing => "initiate application processing",
+            TransactionStep::ReadApplicationData => "read application data",
+            TransactionStep::OfflineDataAuthentication => "offline data authentication",
+            TransactionStep::ProcessingRestrictions => "processing restrictions",
+            TransactionStep::CardholderVerification => "cardholder verification",
+            TransactionStep::TerminalRiskManagement => "terminal risk management",
+            TransactionStep::TerminalActionAnalysis => "terminal action analysis",
+            TransactionStep::CardActionAnalysis => "card action analysis",
+            TransactionStep::OnlineProcessing => "online processing",
+            TransactionStep::Completion => "completion",
+        };
+
+        write!(f, "{}", step)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportError {
+    // Card was removed from the reader (or reset) while communicating
+    CardRemoved,
+    // Card reader was disconnected or can not be used
+    ReaderUnavailable,
+    // Card did not respond in time
+    Timeout,
+    // Any other transmission failure, e.g. a response without a status word
+    Protocol(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::CardRemoved => write!(f, "card removed"),
+            TransportError::ReaderUnavailable => write!(f, "card reader unavailable"),
+            TransportError::Timeout => write!(f, "card response timeout"),
+            TransportError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+        }
+    }
+}
+
+impl error::Error for TransportError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigurationError {
+    // Configuration file does not exist or can not be read
+    Unreadable { file: String, reason: String },
+    // Configuration file content does not match the expected structure
+    Invalid { file: String, reason: String },
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigurationError::Unreadable { file, reason } => {
+                write!(f, "could not read {}: {}", file, reason)
+            }
+            ConfigurationError::Invalid { file, reason } => {
+                write!(f, "invalid configuration {}: {}", file, reason)
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigurationError {}
+
+fn parse_yaml<T: DeserializeOwned>(file: &str, yaml: &str) -> Result<T, ConfigurationError> {
+    serde_yaml::from_str(yaml).map_err(|err| ConfigurationError::Invalid {
+        file: file.to_string(),
+        reason: err.to_string(),
+    })
+}
+
+pub fn load_yaml_file<T: DeserializeOwned>(file: &str) -> Result<T, ConfigurationError> {
+    let yaml = fs::read_to_string(file).map_err(|err| ConfigurationError::Unreadable {
+        file: file.to_string(),
+        reason: err.to_string(),
+    })?;
+
+    parse_yaml(file, &yaml)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmvError {
+    // ICC responded with a status word other than 90 00
+    CardStatus {
+        step: TransactionStep,
+        sw1: u8,
+        sw2: u8,
+    },
+    // ICC response could not be parsed or did not have the expected template
+    MalformedTlv {
+        step: TransactionStep,
+        tag: Option<String>,
+    },
+    // Mandatory data object is not available
+    MissingTag {
+        step: TransactionStep,
+        tag: String,
+    },
+    // Primitive data object returned more than once in the records
+    DuplicateTag {
+        step: TransactionStep,
+        tag: String,
+    },
+    // ICC value does not match the data object format in emv_tags.yaml
+    InvalidTagValue {
+        step: TransactionStep,
+        error: TagValidationError,
+    },
+    // SDA, DDA, fDDA or CDA could not be completed
+    DataAuthentication {
+        step: TransactionStep,
+        tag: Option<String>,
+        reason: &'static str,
+    },
+    // ICC rejected the cardholder verification, e.g. incorrect PIN (63 Cx)
+    CardholderVerification {
+        sw1: u8,
+        sw2: u8,
+    },
+    // ICC, issuer or terminal declined the transaction with an AAC
+    Declined {
+        step: TransactionStep,
+        reason: DeclineReason,
+    },
+    // ICC returned a cryptogram type that the terminal can not continue with
+    UnexpectedCryptogram {
+        step: TransactionStep,
+        cryptogram_type: CryptogramType,
+    },
+    // Communication with the ICC failed
+    Transport {
+        step: TransactionStep,
+        error: TransportError,
+    },
+    // Terminal could not continue, e.g. missing configuration or cardholder cancelled
+    Terminal {
+        step: TransactionStep,
+        reason: &'static str,
+    },
+}
+
+impl EmvError {
+    fn card_status(step: TransactionStep, status: StatusWord) -> EmvError {
+        EmvError::CardStatus {
+            step,
+            sw1: status.sw1,
+            sw2: status.sw2,
+        }
+    }
+
+    fn missing_tag(step: TransactionStep, tag: &str) -> EmvError {
+        EmvError::MissingTag {
+            step,
+            tag: tag.to_string(),
+        }
+    }
+
+    fn malformed_tlv(step: TransactionStep, tag: &str) -> EmvError {
+        EmvError::MalformedTlv {
+            step,
+            tag: Some(tag.to_string()),
+        }
+    }
+
+    fn data_authentication(step: TransactionStep, tag: &str, reason: &'static str) -> EmvError {
+        EmvError::DataAuthentication {
+            step,
+            tag: Some(tag.to_string()),
+            reason,
+        }
+    }
+
+    pub fn step(&self) -> TransactionStep {
+        match self {
+            EmvError::CardStatus { step, .. }
+            | EmvError::MalformedTlv { step, .. }
+            | EmvError::MissingTag { step, .. }
+            | EmvError::DuplicateTag { step, .. }
+            | EmvError::InvalidTagValue { step, .. }
+            | EmvError::DataAuthentication { step, .. }
+            | EmvError::Declined { step, .. }
+            | EmvError::UnexpectedCryptogram { step, .. }
+            | EmvError::Transport { step, .. }
+            | EmvError::Terminal { step, .. } => *step,
+            EmvError::CardholderVerification { .. } => TransactionStep::CardholderVerification,
+        }
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        match self {
+            EmvError::MalformedTlv { tag, .. } | EmvError::DataAuthentication { tag, .. } => {
+                tag.as_deref()
+            }
+            EmvError::MissingTag { tag, .. } | EmvError::DuplicateTag { tag, .. } => Some(tag),
+            EmvError::InvalidTagValue { error, .. } => Some(error.tag()),
+            _ => None,
+        }
+    }
+
+    pub fn decline_reason(&self) -> Option<DeclineReason> {
+        match self {
+            EmvError::Declined { reason, .. } => Some(*reason),
+            _ => None,
+        }
+    }
+
+    pub fn transport_error(&self) -> Option<&TransportError> {
+        match self {
+            EmvError::Transport { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    pub fn status_word(&self) -> Option<(u8, u8)> {
+        match self {
+            EmvError::CardStatus { sw1, sw2, .. }
+            | EmvError::CardholderVerification { sw1, sw2 } => Some((*sw1, *sw2)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EmvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmvError::CardStatus { step, sw1, sw2 } => {
+                write!(f, "ICC status {:02X}{:02X} during {}", sw1, sw2, step)
+            }
+            EmvError::MalformedTlv { step, tag } => match tag {
+                Some(tag) => write!(f, "Malformed data in tag {} during {}", tag, step),
+                None => write!(f, "Malformed ICC response during {}", step),
+            },
+            EmvError::MissingTag { step, tag } => {
+                write!(f, "Mandatory tag {} missing during {}", tag, step)
+            }
+            EmvError::DuplicateTag { step, tag } => {
+                write!(f, "Duplicate tag {} in records during {}", tag, step)
+            }
+            EmvError::InvalidTagValue { step, error } => write!(f, "{} during {}", error, step),
+            EmvError::DataAuthentication { step, tag, reason } => match tag {
+                Some(tag) => write!(f, "{} (tag {}) during {}", reason, tag, step),
+                None => write!(f, "{} during {}", reason, step),
+            },
+            EmvError::CardholderVerification { sw1, sw2 } => {
+                write!(
+                    f,
+                    "Cardholder verification failed with status {:02X}{:02X}",
+                    sw1, sw2
+                )
+            }
+            EmvError::Declined { step, reason } => {
+                write!(f, "Transaction {} during {}", reason, step)
+            }
+            EmvError::UnexpectedCryptogram {
+                step,
+                cryptogram_type,
+            } => write!(f, "Unexpected {:?} during {}", cryptogram_type, step),
+            EmvError::Transport { step, error } => {
+                write!(f, "Transaction aborted, {} during {}", error, step)
+            }
+            EmvError::Terminal { step, reason } => write!(f, "{} during {}", reason, step),
+        }
+    }
+}
+
+impl error::Error for EmvError {}
+
+pub trait ApduInterface: Send + Sync {
+    fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError>;
+
+    // Whether the reader and the card can exchange extended length Lc/Le APDUs
+    fn supports_extended_length(&self) -> bool {
+        false
+    }
+}
+
+// Non-blocking reader, e.g. a contactless reader or a remote card channel driven by an async runtime
+#[cfg(feature = "async")]
+#[async_trait]
+pub trait AsyncApduInterface: Send + Sync {
+    async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError>;
+
+    fn supports_extended_length(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardholderInterfaceError {
+    // Cancelled by the cardholder or the attendant, e.g. PIN entry bypassed
+    Cancelled,
+    // No input from the cardholder in time
+    Timeout,
+}
+
+impl fmt::Display for CardholderInterfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CardholderInterfaceError::Cancelled => write!(f, "cancelled"),
+            CardholderInterfaceError::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+impl error::Error for CardholderInterfaceError {}
+
+// Cardholder and attendant facing terminal functions, ref. EMV Book 4, 11 Cardholder and Attendant Interface
+pub trait CardholderInterface: Send + Sync {
+    fn amount_entry(&self) -> Result<u64, CardholderInterfaceError> {
+        Err(CardholderInterfaceError::Cancelled)
+    }
+
+    fn select_application(
+        &self,
+        applications: &[EmvApplication],
+    ) -> Result<EmvApplication, CardholderInterfaceError> {
+        applications
+            .first()
+            .cloned()
+            .ok_or(CardholderInterfaceError::Cancelled)
+    }
+
+    fn pin_entry(&self) -> Result<String, CardholderInterfaceError> {
+        Err(CardholderInterfaceError::Cancelled)
+    }
+
+    fn confirm_application(&self, _application: &EmvApplication) -> bool {
+        true
+    }
+
+    fn signature_capture(&self) -> bool {
+        true
+    }
+
+    fn display_message(&self, message: &str) {
+        info!("{}", message);
+    }
+
+    fn remove_card(&self) {
+        self.display_message("Remove card");
+    }
+
+    // Called after the terminal defaults have been set up, before GET PROCESSING OPTIONS
+    fn start_transaction(
+        &self,
+        _connection: &mut EmvConnection,
+    ) -> Result<(), CardholderInterfaceError> {
+        Ok(())
+    }
+}
+
+struct DefaultCardholderInterface;
+
+impl CardholderInterface for DefaultCardholderInterface {}
+
+// Non-interactive cardholder, e.g. for unattended terminals and tests
+#[derive(Default)]
+pub struct HeadlessCardholderInterface {
+    pub amount: u64,
+    pub pin: Option<String>,
+    // set as the transaction starts, e.g. fixed date and unpredictable number for reproducible runs
+    pub tags: Vec<(String, Vec<u8>)>,
+}
+
+impl CardholderInterface for HeadlessCardholderInterface {
+    fn amount_entry(&self) -> Result<u64, CardholderInterfaceError> {
+        Ok(self.amount)
+    }
+
+    // the PIN entry is bypassed when there is no PIN
+    fn pin_entry(&self) -> Result<String, CardholderInterfaceError> {
+        self.pin.clone().ok_or(CardholderInterfaceError::Cancelled)
+    }
+
+    fn start_transaction(
+        &self,
+        connection: &mut EmvConnection,
+    ) -> Result<(), CardholderInterfaceError> {
+        for (tag_name, value) in &self.tags {
+            connection.process_tag_as_tlv(tag_name, value.clone());
+        }
+
+        Ok(())
+    }
 }
 
 pub struct DataObject {
@@ -967,10 +1611,53 @@ impl DataObject {
             length: length,
         }
     }
+
+    // ref. EMV Book 3, 5.4 Rules for Using a Data Object List (DOL)
+    pub fn format_value(&self, value: &[u8]) -> Vec<u8> {
+        let length = self.length;
+
+        match self.emv_tag.format {
+            // numeric values are right justified, truncated from the left and padded with leading zeroes
+            Some(FieldFormat::Numeric)
+            | Some(FieldFormat::NumericCountryCode)
+            | Some(FieldFormat::NumericCurrencyCode)
+            | Some(FieldFormat::Date)
+            | Some(FieldFormat::Time) => {
+                if value.len() >= length {
+                    value[value.len() - length..].to_vec()
+                } else {
+                    let mut output = vec![0x00; length - value.len()];
+                    output.extend_from_slice(value);
+                    output
+                }
+            }
+            // compressed numeric values are left justified and padded with trailing 'F's
+            Some(FieldFormat::CompressedNumeric) => {
+                let mut output = value[..value.len().min(length)].to_vec();
+                output.resize(length, 0xFF);
+                output
+            }
+            // any other format is left justified and padded with trailing zeroes
+            _ => {
+                let mut output = value[..value.len().min(length)].to_vec();
+                output.resize(length, 0x00);
+                output
+            }
+        }
+    }
+
+    // ref. ISO/IEC 8825-1 - b6 of the first tag byte marks a constructed data object
+    fn is_constructed(&self) -> bool {
+        hex::decode(&self.emv_tag.tag)
+            .ok()
+            .and_then(|tag| tag.first().copied())
+            .is_some_and(|tag| get_bit!(tag, 5))
+    }
 }
 
 pub struct DataObjectList {
     data_objects: Vec<DataObject>,
+    tag_list: bool,
 }
 
 impl fmt::Display for DataObjectList {
@@ -989,20 +1676,50 @@ impl fmt::Display for DataObjectList {
     }
 }
 
-// EMV Book 3, 5.4 Rules for Using a Data Object List (DOL)
-impl DataObjectList {
-    // EMV has some tags that don't conform to ISO/IEC 7816
-    fn is_non_conforming_one_byte_tag(tag: u8) -> bool {
-        if tag == 0x95 {
-            return true;
-        }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataObjectListError {
+    // Subsequent tag bytes are missing, offset of the tag
+    TruncatedTag(usize),
+    // Tag longer than EMV allows, offset of the tag
+    TagTooLong(usize),
+    // Length field missing or incomplete
+    TruncatedLength { offset: usize, tag: String },
+    // Indefinite or oversized length field
+    InvalidLength { offset: usize, tag: String },
+}
 
-        false
+impl fmt::Display for DataObjectListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataObjectListError::TruncatedTag(offset) => {
+                write!(f, "tag truncated at offset {}", offset)
+            }
+            DataObjectListError::TagTooLong(offset) => {
+                write!(f, "tag too long at offset {}", offset)
+            }
+            DataObjectListError::TruncatedLength { offset, tag } => {
+                write!(f, "length of tag {} truncated at offset {}", tag, offset)
+            }
+            DataObjectListError::InvalidLength { offset, tag } => {
+                write!(f, "invalid length of tag {} at offset {}", tag, offset)
+            }
+        }
     }
+}
+
+impl error::Error for DataObjectListError {}
+
+// EMV Book 3, 5.4 Rules for Using a Data Object List (DOL)
+impl DataObjectList {
+    // EMV tags are at most three bytes, e.g. contactless kernel proprietary DF81XX
+    const MAX_TAG_LENGTH: usize = 3;
+    // DOL lengths are at most 0xFFFF, i.e. '82' followed by two length bytes
+    const MAX_LENGTH_BYTES: usize = 2;
 
-    fn new() -> DataObjectList {
+    fn new(tag_list: bool) -> DataObjectList {
         DataObjectList {
             data_objects: Vec::new(),
+            tag_list,
         }
     }
 
@@ -1010,6 +1727,10 @@ impl DataObjectList {
         self.data_objects.push(data_object);
     }
 
+    pub fn data_objects(&self) -> &[DataObject] {
+        &self.data_objects[..]
+    }
+
     pub fn has_tag(&self, tag_name: &str) -> bool {
         for data_object in &self.data_objects {
             if data_object.emv_tag.tag == tag_name {
@@ -1020,139 +1741,351 @@ impl DataObjectList {
         false
     }
 
-    pub fn process_data_object_list(
-        emv_connection: &EmvConnection,
-        tag_list: &[u8],
-    ) -> Result<DataObjectList, ()> {
-        let mut dol: DataObjectList = DataObjectList::new();
-
-        // FIXME: This parsing is complete BS
-        // - Check if "Tag List" and "Data Object List" are same or different types
-        // - Parse TLV tags appropriately...
-        if tag_list.len() < 2 {
-            let tag_name = hex::encode(&tag_list[0..1]).to_uppercase();
-            dol.push(DataObject::new(emv_connection, &tag_name, 0));
-        } else {
-            let mut i = 0;
-            loop {
-                let tag_value_length: usize;
+    // ref. EMV Book 3, Annex B1 Coding of the Tag Field of BER-TLV Data Objects
+    fn parse_tag(tag_list: &[u8], offset: usize) -> Result<&[u8], DataObjectListError> {
+        let mut tag_length = 1;
 
-                let mut tag_name = hex::encode(&tag_list[i..i + 1]).to_uppercase();
+        // b5-b1 all set, see subsequent bytes
+        if tag_list[offset] & 0x1F == 0x1F {
+            loop {
+                let tag_byte = tag_list
+                    .get(offset + tag_length)
+                    .ok_or(DataObjectListError::TruncatedTag(offset))?;
+                tag_length += 1;
 
-                if Tag::try_from(tag_name.as_str()).is_ok()
-                    || DataObjectList::is_non_conforming_one_byte_tag(tag_list[i])
-                {
-                    tag_value_length = tag_list[i + 1] as usize;
-                    i += 2;
-                } else {
-                    tag_name = hex::encode(&tag_list[i..i + 2]).to_uppercase();
-                    if Tag::try_from(tag_name.as_str()).is_ok() {
-                        tag_value_length = tag_list[i + 2] as usize;
-                        i += 3;
-                    } else {
-                        warn!("Incorrect tag {:?}", tag_name);
-                        return Err(());
-                    }
+                if tag_length > DataObjectList::MAX_TAG_LENGTH {
+                    return Err(DataObjectListError::TagTooLong(offset));
                 }
 
-                dol.push(DataObject::new(emv_connection, &tag_name, tag_value_length));
-
-                if i >= tag_list.len() {
+                // b8 set, another subsequent byte follows
+                if tag_byte & 0x80 == 0 {
                     break;
                 }
             }
         }
 
-        Ok(dol)
+        Ok(&tag_list[offset..offset + tag_length])
     }
 
-    pub fn get_tag_list_tag_values(&self, emv_connection: &EmvConnection) -> Vec<u8> {
-        let mut output: Vec<u8> = Vec::new();
-        for data_object in &self.data_objects {
-            let default_value: Vec<u8> = vec![0; data_object.length];
-            let value = match emv_connection.get_tag_value(&data_object.emv_tag.tag) {
-                Some(value) => value,
-                None => {
-                    debug!(
-                        "tag {:?} has no value, filling with zeros",
-                        data_object.emv_tag.tag
-                    );
+    // ref. EMV Book 3, Annex B2 Coding of the Length Field of BER-TLV Data Objects
+    fn parse_length(
+        tag_list: &[u8],
+        offset: usize,
+        tag_name: &str,
+    ) -> Result<(usize, usize), DataObjectListError> {
+        let first_byte =
+            *tag_list
+                .get(offset)
+                .ok_or_else(|| DataObjectListError::TruncatedLength {
+                    offset,
+                    tag: tag_name.to_string(),
+                })?;
+
+        if first_byte & 0x80 == 0 {
+            return Ok((first_byte as usize, 1));
+        }
+
+        let length_bytes = (first_byte & 0x7F) as usize;
+        if length_bytes == 0 || length_bytes > DataObjectList::MAX_LENGTH_BYTES {
+            return Err(DataObjectListError::InvalidLength {
+                offset,
+                tag: tag_name.to_string(),
+            });
+        }
+
+        let length_field = tag_list
+            .get(offset + 1..offset + 1 + length_bytes)
+            .ok_or_else(|| DataObjectListError::TruncatedLength {
+                offset,
+                tag: tag_name.to_string(),
+            })?;
+        let length = length_field
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
 
-                    &default_value
-                }
+        Ok((length, 1 + length_bytes))
+    }
+
+    fn parse(
+        emv_connection: &EmvConnection,
+        tag_list: &[u8],
+        with_lengths: bool,
+    ) -> Result<DataObjectList, DataObjectListError> {
+        // an empty DOL is valid, e.g. a PDOL of the card asking for no terminal data
+        let mut dol: DataObjectList = DataObjectList::new(!with_lengths);
+
+        let mut offset = 0;
+        while offset < tag_list.len() {
+            let tag = DataObjectList::parse_tag(tag_list, offset)?;
+            let tag_name = hex::encode_upper(tag);
+            offset += tag.len();
+
+            let mut length = 0;
+            if with_lengths {
+                let (tag_value_length, length_field_length) =
+                    DataObjectList::parse_length(tag_list, offset, &tag_name)?;
+                length = tag_value_length;
+                offset += length_field_length;
+            }
+
+            dol.push(DataObject::new(emv_connection, &tag_name, length));
+        }
+
+        Ok(dol)
+    }
+
+    // DOL of tags and lengths, e.g. PDOL, CDOL1, CDOL2, DDOL and TDOL
+    pub fn process_data_object_list(
+        emv_connection: &EmvConnection,
+        tag_list: &[u8],
+    ) -> Result<DataObjectList, DataObjectListError> {
+        DataObjectList::parse(emv_connection, tag_list, true).map_err(|err| {
+            warn!("Invalid data object list {:02X?}: {}", tag_list, err);
+            err
+        })
+    }
+
+    // Tags without lengths, e.g. Static Data Authentication Tag List (9F4A), values are used in full
+    pub fn process_tag_list(
+        emv_connection: &EmvConnection,
+        tag_list: &[u8],
+    ) -> Result<DataObjectList, DataObjectListError> {
+        DataObjectList::parse(emv_connection, tag_list, false).map_err(|err| {
+            warn!("Invalid tag list {:02X?}: {}", tag_list, err);
+            err
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::new();
+        for data_object in &self.data_objects {
+            output.extend_from_slice(&hex::decode(&data_object.emv_tag.tag).unwrap()[..]);
+            if !self.tag_list {
+                output.extend_from_slice(&encode_ber_length(data_object.length)[..]);
+            }
+        }
+
+        output
+    }
+
+    pub fn get_tag_list_tag_values(&self, emv_connection: &EmvConnection) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::new();
+        for data_object in &self.data_objects {
+            let value = match emv_connection.get_tag_value(&data_object.emv_tag.tag) {
+                // constructed data objects are zero filled
+                Some(value) if !data_object.is_constructed() => value,
+                _ => {
+                    debug!(
+                        "tag {:?} has no value, filling with zeros",
+                        data_object.emv_tag.tag
+                    );
+
+                    output.resize(output.len() + data_object.length, 0x00);
+                    continue;
+                }
             };
 
-            // TODO: we need to understand tag metadata (is tag "numeric" etc) in order to properly truncate/pad the tag
-            if data_object.length > 0 && value.len() != data_object.length {
-                warn!(
-                    "tag {:?} value length {:02X} does not match tag list value length {:02X}",
+            if self.tag_list {
+                output.extend_from_slice(&value[..]);
+                continue;
+            }
+
+            if value.len() != data_object.length {
+                debug!(
+                    "tag {:?} value length {:02X} does not match tag list value length {:02X}, format {:?}",
                     data_object.emv_tag.tag,
                     value.len(),
-                    data_object.length
+                    data_object.length,
+                    data_object.emv_tag.format
                 );
-                //return Err(());
-            }
-
-            let mut len = data_object.length;
-            if len == 0 {
-                // at least 9F4A does not provide length information
-                len = value.len();
             }
 
-            output.extend_from_slice(&value[..len]);
+            output.extend_from_slice(&data_object.format_value(&value[..])[..]);
         }
 
         output
     }
 }
 
-pub struct EmvConnection<'a> {
-    pub tags: HashMap<String, Vec<u8>>,
-    pub interface: Option<&'a dyn ApduInterface>,
+pub struct EmvConnection {
+    pub tags: TagStore,
+    pub interface: Option<Arc<dyn ApduInterface>>,
     pub contactless: bool,
+    // Kernel specific tag dictionary in use, set on application selection
+    pub tag_scope: Option<TagScope>,
+    // Terminal parameters of the selected application, None when the terminal has none for it
+    pub terminal_application: Option<TerminalApplication>,
     emv_tags: HashMap<String, EmvTag>,
+    kernel_tags: HashMap<String, KernelTags>,
     constants: Constants,
+    ca_public_keys: HashMap<String, CertificateAuthority>,
     pub settings: Settings,
     pub icc: Icc,
-    pub pin_callback: Option<&'a dyn Fn() -> Result<String, ()>>,
-    pub amount_callback: Option<&'a dyn Fn() -> Result<u64, ()>>,
-    pub pse_application_select_callback:
-        Option<&'a dyn Fn(&Vec<EmvApplication>) -> Result<EmvApplication, ()>>,
-    pub start_transaction_callback: Option<&'a dyn Fn(&mut EmvConnection) -> Result<(), ()>>,
+    pub cardholder_interface: Arc<dyn CardholderInterface>,
+    pub online_authorization_callback: Option<Arc<OnlineAuthorizationCallback>>,
 }
 
-impl EmvConnection<'_> {
-    pub fn new(settings_file: &str) -> Result<EmvConnection<'static>, String> {
-        let settings: Settings = serialize_yaml!(settings_file, "config/settings.yaml");
-        let emv_tags = serialize_yaml!(
-            settings.configuration_files.emv_tags.clone(),
-            "config/emv_tags.yaml"
-        );
-        let constants = serialize_yaml!(
-            settings.configuration_files.constants.clone(),
-            "config/constants.yaml"
-        );
+// In-memory values take precedence over the files referenced by the settings
+#[derive(Default)]
+pub struct EmvConnectionBuilder {
+    settings: Option<Settings>,
+    settings_file: Option<String>,
+    emv_tags: Option<HashMap<String, EmvTag>>,
+    kernel_tags: Option<HashMap<String, KernelTags>>,
+    constants: Option<Constants>,
+    ca_public_keys: Option<HashMap<String, CertificateAuthority>>,
+}
+
+impl EmvConnectionBuilder {
+    pub fn settings(mut self, settings: Settings) -> EmvConnectionBuilder {
+        self.settings = Some(settings);
+        self
+    }
+
+    pub fn settings_file(mut self, settings_file: &str) -> EmvConnectionBuilder {
+        self.settings_file = Some(settings_file.to_string());
+        self
+    }
+
+    pub fn emv_tags(mut self, emv_tags: HashMap<String, EmvTag>) -> EmvConnectionBuilder {
+        self.emv_tags = Some(emv_tags);
+        self
+    }
+
+    pub fn kernel_tags(mut self, kernel_tags: HashMap<String, KernelTags>) -> EmvConnectionBuilder {
+        self.kernel_tags = Some(kernel_tags);
+        self
+    }
+
+    pub fn constants(mut self, constants: Constants) -> EmvConnectionBuilder {
+        self.constants = Some(constants);
+        self
+    }
+
+    pub fn ca_public_keys(
+        mut self,
+        ca_public_keys: HashMap<String, CertificateAuthority>,
+    ) -> EmvConnectionBuilder {
+        self.ca_public_keys = Some(ca_public_keys);
+        self
+    }
+
+    pub fn build(self) -> Result<EmvConnection, ConfigurationError> {
+        let settings: Settings = match (self.settings, self.settings_file) {
+            (Some(settings), _) => settings,
+            (None, Some(settings_file)) => load_yaml_file(&settings_file)?,
+            (None, None) => bundled_yaml!("config/settings.yaml")?,
+        };
+
+        let configuration_files = &settings.configuration_files;
+
+        let emv_tags = match (self.emv_tags, &configuration_files.emv_tags) {
+            (Some(emv_tags), _) => emv_tags,
+            (None, Some(emv_tags_file)) => load_yaml_file(emv_tags_file)?,
+            (None, None) => bundled_yaml!("config/emv_tags.yaml")?,
+        };
+
+        let kernel_tags = match (self.kernel_tags, &configuration_files.kernel_tags) {
+            (Some(kernel_tags), _) => kernel_tags,
+            (None, Some(kernel_tags_file)) => load_yaml_file(kernel_tags_file)?,
+            (None, None) => bundled_yaml!("config/kernel_tags.yaml")?,
+        };
+
+        let constants = match (self.constants, &configuration_files.constants) {
+            (Some(constants), _) => constants,
+            (None, Some(constants_file)) => load_yaml_file(constants_file)?,
+            (None, None) => bundled_yaml!("config/constants.yaml")?,
+        };
+
+        let ca_public_keys = match (
+            self.ca_public_keys,
+            &configuration_files.scheme_ca_public_keys,
+        ) {
+            (Some(ca_public_keys), _) => ca_public_keys,
+            (None, Some(ca_public_keys_file)) => load_yaml_file(ca_public_keys_file)?,
+            (None, None) => {
+                warn!(
+                    "Scheme CA public keys not configured, offline data authentication will fail"
+                );
+                HashMap::new()
+            }
+        };
 
         Ok(EmvConnection {
-            tags: HashMap::new(),
+            tags: TagStore::new(),
             emv_tags: emv_tags,
+            kernel_tags,
             constants: constants,
+            ca_public_keys,
             settings: settings,
             icc: Icc::new(),
             interface: None,
             contactless: false,
-            pin_callback: None,
-            amount_callback: None,
-            pse_application_select_callback: None,
-            start_transaction_callback: None,
+            tag_scope: None,
+            terminal_application: None,
+            cardholder_interface: Arc::new(DefaultCardholderInterface),
+            online_authorization_callback: None,
         })
     }
+}
+
+// EmvConnection driven by an async reader, the card commands and the transaction flow are awaited
+// instead of blocking the thread
+#[cfg(feature = "async")]
+pub struct AsyncEmvConnection {
+    pub connection: EmvConnection,
+    pub interface: Arc<dyn AsyncApduInterface>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncEmvConnection {
+    pub fn new(
+        connection: EmvConnection,
+        interface: Arc<dyn AsyncApduInterface>,
+    ) -> AsyncEmvConnection {
+        AsyncEmvConnection {
+            connection,
+            interface,
+        }
+    }
+
+    fn get_card_interface(&self) -> Result<Arc<dyn AsyncApduInterface>, TransportError> {
+        Ok(self.interface.clone())
+    }
+}
+
+#[cfg(feature = "async")]
+impl Deref for AsyncEmvConnection {
+    type Target = EmvConnection;
+
+    fn deref(&self) -> &EmvConnection {
+        &self.connection
+    }
+}
+
+#[cfg(feature = "async")]
+impl DerefMut for AsyncEmvConnection {
+    fn deref_mut(&mut self) -> &mut EmvConnection {
+        &mut self.connection
+    }
+}
+
+impl EmvConnection {
+    pub fn new(settings_file: &str) -> Result<EmvConnection, ConfigurationError> {
+        EmvConnection::builder()
+            .settings_file(settings_file)
+            .build()
+    }
+
+    pub fn builder() -> EmvConnectionBuilder {
+        EmvConnectionBuilder::default()
+    }
 
     pub fn print_tags(&self) {
         let mut i = 0;
-        for (key, value) in &self.tags {
+        for (key, value) in self.tags.iter() {
             i += 1;
-            let emv_tag = self.emv_tags.get(key);
+            let emv_tag = self.get_emv_tag(key);
             info!(
                 "{:02}. tag: {} - {}",
                 i,
@@ -1163,15 +2096,76 @@ impl EmvConnection<'_> {
         }
     }
 
+    // Definition for the selected application, the generic EMV tag when its kernel does not define the tag
     pub fn get_emv_tag(&self, tag_name: &str) -> Option<&EmvTag> {
-        self.emv_tags.get(tag_name)
+        self.get_emv_tag_in(self.tag_scope.as_ref(), tag_name)
+    }
+
+    pub fn get_emv_tag_in(&self, scope: Option<&TagScope>, tag_name: &str) -> Option<&EmvTag> {
+        scope
+            .and_then(|scope| {
+                self.kernel_tags
+                    .values()
+                    .filter(|kernel_tags| kernel_tags.applies_to(scope))
+                    .find_map(|kernel_tags| kernel_tags.tags.get(tag_name))
+            })
+            .or_else(|| self.emv_tags.get(tag_name))
     }
 
     pub fn get_tag_value(&self, tag_name: &str) -> Option<&Vec<u8>> {
         self.tags.get(tag_name)
     }
 
+    fn get_data_object_list_values(
+        &self,
+        step: TransactionStep,
+        dol_tag_name: &str,
+    ) -> Result<Vec<u8>, EmvError> {
+        let dol = self
+            .get_tag_value(dol_tag_name)
+            .ok_or_else(|| EmvError::missing_tag(step, dol_tag_name))?;
+
+        Ok(DataObjectList::process_data_object_list(self, &dol[..])
+            .map_err(|_| EmvError::malformed_tlv(step, dol_tag_name))?
+            .get_tag_list_tag_values(self))
+    }
+
+    fn get_tag_list_values(
+        &self,
+        step: TransactionStep,
+        tag_list_tag_name: &str,
+    ) -> Result<Vec<u8>, EmvError> {
+        let tag_list = self
+            .get_tag_value(tag_list_tag_name)
+            .ok_or_else(|| EmvError::missing_tag(step, tag_list_tag_name))?;
+
+        Ok(DataObjectList::process_tag_list(self, &tag_list[..])
+            .map_err(|_| EmvError::malformed_tlv(step, tag_list_tag_name))?
+            .get_tag_list_tag_values(self))
+    }
+
+    fn icc_data_missing(&mut self, step: TransactionStep, tag_name: &str) -> EmvError {
+        warn!("Mandatory tag {} missing", tag_name);
+        self.settings.terminal.tvr.icc_data_missing = true;
+
+        EmvError::missing_tag(step, tag_name)
+    }
+
     pub fn add_tag(&mut self, tag_name: &str, value: Vec<u8>) {
+        self.add_tag_from(tag_name, value, TagOrigin::terminal());
+    }
+
+    pub fn add_tag_from(&mut self, tag_name: &str, value: Vec<u8>, origin: TagOrigin) {
+        if origin.source == FieldSource::Icc {
+            if let Some(Err(error)) = self
+                .get_emv_tag(tag_name)
+                .map(|emv_tag| emv_tag.validate_icc_value(&value))
+            {
+                self.reject_tag_value(origin, error);
+                return;
+            }
+        }
+
         let old_tag = self.tags.get(tag_name);
         if old_tag.is_some() {
             if self.settings.censor_sensitive_fields {
@@ -1191,43 +2185,38 @@ impl EmvConnection<'_> {
             }
         }
 
-        self.tags.insert(tag_name.to_string(), value);
+        self.tags.insert(tag_name, value, origin);
     }
 
-    pub fn process_tag_as_tlv(&mut self, tag_name: &str, value: Vec<u8>) {
-        let mut tlv: Vec<u8> = Vec::new();
-        tlv.extend_from_slice(&hex::decode(&tag_name).unwrap()[..]);
-        if value.len() >= 0x80 {
-            tlv.push(0x81 as u8);
+    // An invalid ICC value is not stored, the data object is treated as missing from the card
+    fn reject_tag_value(&mut self, origin: TagOrigin, error: TagValidationError) {
+        warn!("Rejected ICC data: {}", error);
+
+        match error {
+            // the terminal keeps its own value
+            TagValidationError::TerminalData { .. } => {}
+            _ if OFFLINE_DATA_AUTHENTICATION_TAGS.contains(&error.tag()) => {
+                self.set_offline_data_authentication_failed();
+            }
+            _ => self.settings.terminal.tvr.icc_data_missing = true,
         }
-        tlv.push(value.len() as u8);
-        tlv.extend_from_slice(&value[..]);
 
-        self.process_tlv(&tlv[..], 1);
+        self.tags.reject(RejectedValue { error, origin });
     }
 
-    fn send_apdu_select(&mut self, aid: &[u8]) -> (Vec<u8>, Vec<u8>) {
-        //ref. EMV Book 1, 11.3.2 Command message
-        self.tags.clear();
-
-        let apdu_command_select = b"\x00\xA4";
-        let p1_reference_control_parameter: u8 = 0b0000_0100; // "Select by name"
-        let p2_selection_options: u8 = 0b0000_0000; // "First or only occurrence"
-
-        let mut select_command = apdu_command_select.to_vec();
-        select_command.push(p1_reference_control_parameter);
-        select_command.push(p2_selection_options);
-        select_command.push(aid.len() as u8); // lc
-        select_command.extend_from_slice(aid); // data
-        select_command.push(0x00); // le
-
-        self.send_apdu(&select_command)
+    pub fn process_tag_as_tlv(&mut self, tag_name: &str, value: Vec<u8>) {
+        self.process_tag_as_tlv_from(tag_name, value, TagOrigin::terminal());
     }
 
-    fn get_apdu_response_localization(&self, apdu_status: &[u8]) -> String {
-        assert_eq!(apdu_status.len(), 2);
+    pub fn process_tag_as_tlv_from(&mut self, tag_name: &str, value: Vec<u8>, origin: TagOrigin) {
+        match TlvBuilder::new().data_object(tag_name, &value).build() {
+            Ok(tlv) => self.process_tlv_from(&tlv[..], 1, origin),
+            Err(err) => warn!("Could not encode tag {}: {}", tag_name, err),
+        }
+    }
 
-        let response_status_code = hex::encode_upper(apdu_status);
+    fn get_apdu_response_localization(&self, status: StatusWord) -> String {
+        let response_status_code = status.to_string();
 
         let response_localization: String;
         if let Some(response_description) =
@@ -1247,104 +2236,10 @@ impl EmvConnection<'_> {
         response_localization
     }
 
-    pub fn send_apdu<'apdu>(&mut self, apdu: &'apdu [u8]) -> (Vec<u8>, Vec<u8>) {
-        let mut response_data: Vec<u8> = Vec::new();
-        let mut response_trailer: Vec<u8>;
-
-        let mut new_apdu_command;
-        let mut apdu_command = apdu;
-
-        loop {
-            // Send an APDU command.
-            if self.settings.censor_sensitive_fields {
-                debug!(
-                    "Sending APDU: {:02X?}... ({} bytes)",
-                    &apdu_command[0..5],
-                    apdu_command.len()
-                );
-            } else {
-                debug!(
-                    "Sending APDU:\n{}",
-                    HexViewBuilder::new(&apdu_command).finish()
-                );
-            }
-
-            let apdu_response = self.interface.unwrap().send_apdu(apdu_command).unwrap();
-
-            response_data.extend_from_slice(&apdu_response[0..apdu_response.len() - 2]);
-
-            // response codes: https://www.eftlab.com/knowledge-base/complete-list-of-apdu-responses/
-            response_trailer = vec![
-                apdu_response[apdu_response.len() - 2],
-                apdu_response[apdu_response.len() - 1],
-            ];
-
-            debug!(
-                "APDU response status: {}",
-                self.get_apdu_response_localization(&response_trailer[..])
-            );
-
-            // Automatically query more data, if available from the ICC
-            const SW1_BYTES_AVAILABLE: u8 = 0x61;
-            const SW1_WRONG_LENGTH: u8 = 0x6C;
-
-            if response_trailer[0] == SW1_BYTES_AVAILABLE {
-                trace!(
-                    "APDU response({} bytes):\n{}",
-                    response_data.len(),
-                    HexViewBuilder::new(&response_data).finish()
-                );
-
-                let mut available_data_length = response_trailer[1];
-
-                // NOTE: EMV doesn't have a use case where ICC would pass bigger records than what is passable with a single ADPU response
-                if available_data_length == 0x00 {
-                    // there are more than 255 bytes available, query the maximum
-                    available_data_length = 0xFF;
-                }
-
-                let apdu_command_get_response = b"\x00\xC0\x00\x00";
-                new_apdu_command = apdu_command_get_response.to_vec();
-                new_apdu_command.push(available_data_length);
-
-                apdu_command = &new_apdu_command[..];
-            } else if response_trailer[0] == SW1_WRONG_LENGTH {
-                trace!(
-                    "APDU response({} bytes):\n{}",
-                    response_data.len(),
-                    HexViewBuilder::new(&response_data).finish()
-                );
-
-                let available_data_length = response_trailer[1];
-                assert!(available_data_length > 0x00);
-
-                new_apdu_command = apdu.to_vec();
-                let new_apdu_command_length = new_apdu_command.len();
-                new_apdu_command[new_apdu_command_length - 1] = available_data_length;
-
-                apdu_command = &new_apdu_command[..];
-            } else {
-                break;
-            }
-        }
-
-        if self.settings.censor_sensitive_fields {
-            debug!("APDU response({} bytes)", response_data.len());
-        } else {
-            debug!(
-                "APDU response({} bytes):\n{}",
-                response_data.len(),
-                HexViewBuilder::new(&response_data).finish()
-            );
-        }
-
-        if !response_data.is_empty() {
-            debug!("APDU TLV parse:");
-
-            self.process_tlv(&response_data[..], 0);
-        }
-
-        (response_trailer, response_data)
+    fn get_card_interface(&self) -> Result<Arc<dyn ApduInterface>, TransportError> {
+        self.interface
+            .clone()
+            .ok_or(TransportError::ReaderUnavailable)
     }
 
     fn print_tag(&self, emv_tag: &EmvTag, level: u8) {
@@ -1366,23 +2261,28 @@ impl EmvConnection<'_> {
         );
         if let Some(tag) = emv_tag {
             match tag.format {
-                Some(FieldFormat::CompressedNumeric) => {
+                Some(FieldFormat::Numeric) | Some(FieldFormat::CompressedNumeric) => {
                     value = format!("{:02X?}", v)
                         .replace(|c: char| !(c.is_ascii_alphanumeric()), "")
                         .trim_start_matches('0')
                         .to_string();
                 }
-                Some(FieldFormat::Alphanumeric) | Some(FieldFormat::AlphanumericSpecial) => {
+                Some(FieldFormat::Alphabetic)
+                | Some(FieldFormat::Alphanumeric)
+                | Some(FieldFormat::AlphanumericSpecial) => {
                     value = String::from_utf8_lossy(&v).to_string();
                 }
-                Some(FieldFormat::TerminalVerificationResults) => {
+                Some(FieldFormat::IssuerCodeTableText) => {
+                    value = decoder::decode_issuer_code_table_text(v);
+                }
+                Some(FieldFormat::TerminalVerificationResults) if v.len() == 5 => {
                     let tvr: TerminalVerificationResults = v.to_vec().into();
                     value = format!(
                         "{:08b} {:08b} {:08b} {:08b} {:08b} => {:#?}",
                         v[0], v[1], v[2], v[3], v[4], tvr
                     );
                 }
-                Some(FieldFormat::ApplicationUsageControl) => {
+                Some(FieldFormat::ApplicationUsageControl) if v.len() == 2 => {
                     let auc: UsageControl = v.to_vec().into();
                     value = format!("{:08b} {:08b} => {:#?}", v[0], v[1], auc);
                 }
@@ -1390,79 +2290,64 @@ impl EmvConnection<'_> {
                     value = format!("{} bit key", v.len() * 8);
                 }
                 Some(FieldFormat::ServiceCodeIso7813) => {
-                    let position_1_interchange: String = match v[0] {
-                        1 => "International".to_string(),
-                        2 => "International (prefer ICC)".to_string(),
-                        5 => "National".to_string(),
-                        6 => "National (prefer ICC)".to_string(),
-                        7 => "Private".to_string(),
-                        9 => "Test".to_string(),
-                        _ => format!("N/A {}", v[0]),
-                    };
-
-                    let position_2: u8 = v[1] >> 4;
-                    let position_2_authorization_processing: String = match position_2 {
-                        0 => "Normal".to_string(),
-                        2 => "By Issuer".to_string(),
-                        4 => "By Issuer (unless bileteral agreement exists)".to_string(),
-                        _ => format!("N/A {}", position_2),
-                    };
-
-                    let position_3: u8 = v[1] & 0b0000_1111;
-                    let position_3_allowed_services: String = match position_3 {
-                        0 => "No restrictions (PIN required)".to_string(),
-                        1 => "No restrictions".to_string(),
-                        2 => "Goods and services only".to_string(),
-                        3 => "ATM only (PIN required)".to_string(),
-                        4 => "Cash only".to_string(),
-                        5 => "Goods and services only (PIN required)".to_string(),
-                        6 => "No restrictions (PIN prompt if PED)".to_string(),
-                        7 => "Goods and services only (PIN prompt if PED)".to_string(),
-                        _ => format!("N/A {}", position_3),
-                    };
-
-                    value = format!(
-                        "{}{:02X} - {}, {}, {}",
-                        v[0],
-                        v[1],
-                        position_1_interchange,
-                        position_2_authorization_processing,
-                        position_3_allowed_services
-                    );
+                    if let Some(DecodedValue::ServiceCode {
+                        code,
+                        interchange,
+                        authorization_processing,
+                        allowed_services,
+                    }) = decoder::decode_service_code(v)
+                    {
+                        value = format!(
+                            "{} - {}, {}, {}",
+                            code, interchange, authorization_processing, allowed_services
+                        );
+                    }
                 }
-                Some(FieldFormat::NumericCountryCode) => {
+                Some(FieldFormat::NumericCountryCode) if v.len() == 2 => {
                     let numeric_country_code: String = format!("{:02X?}", v)
                         .replace(|c: char| !(c.is_ascii_alphanumeric()), "")[1..]
                         .to_string();
                     value = format!(
                         "{} - {}",
                         numeric_country_code,
-                        self.constants.numeric_country_codes[&numeric_country_code]
+                        self.constants
+                            .numeric_country_codes
+                            .get(&numeric_country_code)
+                            .map_or("N/A", String::as_str)
                     );
                 }
-                Some(FieldFormat::NumericCurrencyCode) => {
+                Some(FieldFormat::NumericCurrencyCode) if v.len() == 2 => {
                     let numeric_currency_code: String = format!("{:02X?}", v)
                         .replace(|c: char| !(c.is_ascii_alphanumeric()), "")[1..]
                         .to_string();
                     value = format!(
                         "{} - {}",
                         numeric_currency_code,
-                        self.constants.numeric_currency_codes[&numeric_currency_code]
+                        self.constants
+                            .numeric_currency_codes
+                            .get(&numeric_currency_code)
+                            .map_or("N/A", String::as_str)
                     );
                 }
                 Some(FieldFormat::DataObjectList) => {
-                    let dol: DataObjectList =
-                        DataObjectList::process_data_object_list(self, &v[..]).unwrap();
-                    value = format!("{}", dol);
+                    if let Ok(dol) = DataObjectList::process_data_object_list(self, &v[..]) {
+                        value = format!("{}", dol);
+                    }
+                }
+                Some(FieldFormat::TagList) => {
+                    if let Ok(tag_list) = DataObjectList::process_tag_list(self, &v[..]) {
+                        value = format!("{}", tag_list);
+                    }
                 }
                 Some(FieldFormat::Track2) => {
                     let track2_raw: String = format!("{:02X?}", v)
                         .replace(|c: char| !(c.is_ascii_alphanumeric()), "")
                         .to_string();
-                    let track2: Track2 = Track2::new(&track2_raw);
-                    value = format!("{}", track2);
+                    if let Some(track2) = Track2::parse(&track2_raw) {
+                        value = format!("{}", track2);
+                    }
                 }
-                Some(FieldFormat::Date) => {
+                Some(FieldFormat::Date) if v.len() == 3 => {
                     value =
                         format!("{:02X?}", v).replace(|c: char| !(c.is_ascii_alphanumeric()), "");
                     let yy = &value[0..2];
@@ -1472,7 +2357,7 @@ impl EmvConnection<'_> {
                     // FIXME: date format does not take into consideration pre 2000s dates
                     value = format!("20{}-{}-{}", yy, mm, dd);
                 }
-                Some(FieldFormat::Time) => {
+                Some(FieldFormat::Time) if v.len() == 3 => {
                     value =
                         format!("{:02X?}", v).replace(|c: char| !(c.is_ascii_alphanumeric()), "");
                     let hh = &value[0..2];
@@ -1486,9 +2371,15 @@ impl EmvConnection<'_> {
 
             // Special rules here
             match tag.tag.as_str() {
+                "9F10" => {
+                    if let Some(iad) = IssuerApplicationData::parse(v, self.tag_scope.as_ref()) {
+                        value = format!("{} => {:#?}", hex::encode_upper(v), iad);
+                    }
+                }
                 "9F27" => {
-                    let icc_cryptogram_type = CryptogramType::try_from(v[0] as u8).unwrap();
-                    value = format!("{:?}", icc_cryptogram_type);
+                    if let Some(cid) = v.first() {
+                        value = format!("{:?}", CryptogramInformationData::from(*cid));
+                    }
                 }
                 _ => { /* NOP */ }
             }
@@ -1501,14 +2392,17 @@ impl EmvConnection<'_> {
                         let truncated_pan = get_truncated_pan(&value);
                         debug!("{}-data: {}", padding, truncated_pan);
                     }
-                    Some(FieldSensitivity::Track2) => {
-                        let mut track2: Track2 =
-                            Track2::new(&String::from_utf8_lossy(&v).to_string());
-                        track2.censor();
-                        value = format!("{}", track2);
+                    Some(FieldSensitivity::Track2) => match Track2::parse(&value) {
+                        Some(mut track2) => {
+                            track2.censor();
+                            value = format!("{}", track2);
 
-                        debug!("{}-data: {}", padding, value);
-                    }
+                            debug!("{}-data: {}", padding, value);
+                        }
+                        None => {
+                            debug!("{}-data: censored {} bytes", padding, v.len());
+                        }
+                    },
                     Some(
                         FieldSensitivity::SensitiveAuthenticationData | FieldSensitivity::Sensitive,
                     ) => {
@@ -1538,6 +2432,10 @@ impl EmvConnection<'_> {
     }
 
     pub fn process_tlv(&mut self, buf: &[u8], level: u8) {
+        self.process_tlv_from(buf, level, TagOrigin::terminal());
+    }
+
+    pub fn process_tlv_from(&mut self, buf: &[u8], level: u8, origin: TagOrigin) {
         let mut read_buffer = buf;
 
         loop {
@@ -1562,7 +2460,7 @@ impl EmvConnection<'_> {
 
             let tag_name = hex::encode_upper(tlv_data.tag().to_bytes());
 
-            let emv_tag: Option<&EmvTag> = match self.emv_tags.get(tag_name.as_str()) {
+            let emv_tag: Option<&EmvTag> = match self.get_emv_tag(tag_name.as_str()) {
                 Some(emv_tag) => {
                     self.print_tag(&emv_tag, level);
                     Some(emv_tag)
@@ -1577,12 +2475,12 @@ impl EmvConnection<'_> {
             match tlv_data.value() {
                 Value::Constructed(v) => {
                     for tlv_tag in v {
-                        self.process_tlv(&tlv_tag.to_vec(), level + 1);
+                        self.process_tlv_from(&tlv_tag.to_vec(), level + 1, origin);
                     }
                 }
                 Value::Primitive(v) => {
                     self.print_tag_value(&emv_tag, v, level);
-                    self.add_tag(&tag_name, v.to_vec());
+                    self.add_tag_from(&tag_name, v.to_vec(), origin);
                 }
             };
 
@@ -1592,280 +2490,61 @@ impl EmvConnection<'_> {
         }
     }
 
-    pub fn handle_get_processing_options(&mut self) -> Result<(), ()> {
-        //ref. EMV Book 3, 6.5.8 GET PROCESSING OPTIONS Command-Response APDUs
-
-        debug!("GET PROCESSING OPTIONS:");
+    fn fill_random(&self, data: &mut [u8]) {
+        if self.settings.terminal.use_random {
+            let mut rng = ChaCha20Rng::from_entropy();
+            rng.try_fill(data).unwrap();
+        }
+    }
 
-        let apdu_command_get_processing_options = b"\x80\xA8\x00\x00";
-        let mut get_processing_options_command = apdu_command_get_processing_options.to_vec();
+    fn handle_application_cryptogram_card_authentication(
+        &mut self,
+        response_data: &[u8],
+        cdol_tag: &str,
+    ) -> Result<(), EmvError> {
+        //ref. EMV Book 2, 6.6.2 Dynamic Signature Verification
 
-        match self.get_tag_value("9F38") {
-            Some(tag_9f38_pdol) => {
-                let pdol_data = DataObjectList::process_data_object_list(self, &tag_9f38_pdol[..])
-                    .unwrap()
-                    .get_tag_list_tag_values(self);
+        debug!("Perform Application Cryptogram Data Authentication (CDA):");
 
-                get_processing_options_command.push((pdol_data.len() + 2) as u8); // lc
-                                                                                  // data
-                get_processing_options_command.push(0x83); // tag 83
-                get_processing_options_command.push(pdol_data.len() as u8); // tag 83 length
-                get_processing_options_command.extend_from_slice(&pdol_data[..]); // pdol list
-                get_processing_options_command.push(0x00); // le
-            }
-            None => {
-                get_processing_options_command.push(0x02); // lc
-                get_processing_options_command.push(0x83); // data
-                get_processing_options_command.push(0x00); // le
-            }
-        }
+        let tag_9f37_unpredictable_number = self
+            .get_tag_value("9F37")
+            .ok_or_else(|| EmvError::missing_tag(TransactionStep::CardActionAnalysis, "9F37"))?;
 
-        let (response_trailer, response_data) = self.send_apdu(&get_processing_options_command);
-        if !is_success_response(&response_trailer) {
-            warn!("Could not get processing options");
-            return Err(());
-        }
+        let tag_9f4b_signed_data_decrypted_dynamic_data = self
+            .validate_signed_dynamic_application_data(
+                TransactionStep::CardActionAnalysis,
+                &tag_9f37_unpredictable_number[..],
+            )?;
 
-        if response_data[0] == 0x80 {
-            self.process_tag_as_tlv("82", response_data[2..4].to_vec());
-            self.process_tag_as_tlv("94", response_data[4..].to_vec());
-        } else if response_data[0] != 0x77 {
-            warn!("Unrecognized response");
-            return Err(());
+        let icc_dynamic_number_length = tag_9f4b_signed_data_decrypted_dynamic_data
+            .first()
+            .copied()
+            .unwrap_or(0) as usize;
+        if tag_9f4b_signed_data_decrypted_dynamic_data.len()
+            < 1 + icc_dynamic_number_length + 1 + 8 + 20
+        {
+            warn!("ICC dynamic data too short for CDA");
+            return Err(EmvError::DataAuthentication {
+                step: TransactionStep::CardActionAnalysis,
+                tag: Some("9F4B".to_string()),
+                reason: "ICC dynamic data too short",
+            });
         }
 
-        let tag_94_afl = self.get_tag_value("94").unwrap().clone();
+        let mut i = 1;
+        let _icc_dynamic_number =
+            &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + icc_dynamic_number_length];
+        i += icc_dynamic_number_length;
+        let cryptogram_information_data = &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + 1];
+        i += 1;
+        let tag_9f26_application_cryptogram =
+            &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + 8];
+        i += 8;
+        let transaction_data_hash_code = &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + 20];
 
-        debug!("Read card Application File Locator (AFL) information:");
-
-        let mut data_authentication: Vec<u8> = Vec::new();
-        assert_eq!(tag_94_afl.len() % 4, 0);
-        let mut records: Vec<u8> = Vec::new();
-        for i in (0..tag_94_afl.len()).step_by(4) {
-            let short_file_identifier: u8 = tag_94_afl[i] >> 3;
-            let record_index_start: u8 = tag_94_afl[i + 1];
-            let record_index_end: u8 = tag_94_afl[i + 2];
-            let mut data_authentication_records: u8 = tag_94_afl[i + 3];
-
-            for record_index in record_index_start..record_index_end + 1 {
-                if let Some(data) = self.read_record(short_file_identifier, record_index) {
-                    assert_eq!(data[0], 0x70);
-                    records.extend(&data);
-
-                    // Add data authentication input
-                    // ref EMV Book 3, 10.3 Offline Data Authentication
-                    if data_authentication_records > 0 {
-                        data_authentication_records -= 1;
-
-                        if short_file_identifier <= 10 {
-                            if let Value::Constructed(tag_70_tags) =
-                                parse_tlv(&data[..]).unwrap().value()
-                            {
-                                for tag in tag_70_tags {
-                                    data_authentication.extend(tag.to_vec());
-                                }
-                            }
-                        } else {
-                            data_authentication.extend_from_slice(&data[..]);
-                        }
-
-                        if self.settings.censor_sensitive_fields {
-                            trace!("Data authentication building: short_file_identifier:{}, data_authentication_records:{}, record_index:{}/{}, data:{} bytes", short_file_identifier, data_authentication_records, record_index, record_index_end, data_authentication.len());
-                        } else {
-                            trace!("Data authentication building: short_file_identifier:{}, data_authentication_records:{}, record_index:{}/{}, data:{:02X?}", short_file_identifier, data_authentication_records, record_index, record_index_end, data_authentication);
-                        }
-                    }
-                }
-            }
-        }
-
-        if self.settings.censor_sensitive_fields {
-            debug!(
-                "AFL data authentication: {} bytes",
-                data_authentication.len()
-            );
-        } else {
-            debug!(
-                "AFL data authentication:\n{}",
-                HexViewBuilder::new(&data_authentication).finish()
-            );
-        }
-
-        let tag_82_aip = self.get_tag_value("82").unwrap();
-
-        let auc_b1: u8 = tag_82_aip[0];
-        // bit 7 = RFU
-        self.icc.capabilities.sda = get_bit!(auc_b1, 6);
-        self.icc.capabilities.dda = get_bit!(auc_b1, 5);
-        if get_bit!(auc_b1, 4) {
-            // Cardholder verification is supported
-
-            let tag_8e_cvm_list = self.get_tag_value("8E").unwrap().clone();
-            let amount1 = &tag_8e_cvm_list[0..4];
-            let amount2 = &tag_8e_cvm_list[4..8];
-            let amount_x = str::from_utf8(&bcdutil::bcd_to_ascii(&amount1[..]).unwrap()[..])
-                .unwrap()
-                .parse::<u32>()
-                .unwrap();
-            let amount_y = str::from_utf8(&bcdutil::bcd_to_ascii(&amount2[..]).unwrap()[..])
-                .unwrap()
-                .parse::<u32>()
-                .unwrap();
-
-            let tag_84_cvm_rules = &tag_8e_cvm_list[8..];
-            assert_eq!(tag_84_cvm_rules.len() % 2, 0);
-            for i in (0..tag_84_cvm_rules.len()).step_by(2) {
-                let cvm_rule = &tag_84_cvm_rules[i..i + 2];
-                let cvm_code = cvm_rule[0];
-                let cvm_condition_code = cvm_rule[1];
-
-                // bit 7 = RFU
-                let fail_if_unsuccessful = !get_bit!(cvm_code, 6);
-                let cvm_code = (cvm_code << 2) >> 2;
-                let code: CvmCode = cvm_code.try_into().unwrap();
-                let condition: CvmConditionCode = cvm_condition_code.try_into().unwrap();
-
-                let rule = CvmRule {
-                    amount_x: amount_x,
-                    amount_y: amount_y,
-                    fail_if_unsuccessful: fail_if_unsuccessful,
-                    code: code,
-                    condition: condition,
-                };
-                self.icc.cvm_rules.push(rule);
-            }
-        }
-        self.icc.capabilities.terminal_risk_management = get_bit!(auc_b1, 3);
-        // Issuer Authentication using the EXTERNAL AUTHENTICATE command is supported
-        self.icc.capabilities.issuer_authentication = get_bit!(auc_b1, 2);
-        // bit 1 = RFU
-        self.icc.capabilities.cda = get_bit!(auc_b1, 0);
-
-        if let Some(tag_9f07_application_usage_control) = self.get_tag_value("9F07") {
-            self.icc.usage = tag_9f07_application_usage_control.to_vec().into();
-        }
-
-        debug!("{:?}", self.icc);
-
-        // 5 - 0 bits are RFU
-
-        self.icc.data_authentication = Some(data_authentication);
-
-        Ok(())
-    }
-
-    pub fn handle_verify_plaintext_pin(&mut self, ascii_pin: &[u8]) -> Result<(), ()> {
-        debug!("Verify plaintext PIN:");
-
-        let pin_bcd_cn = bcdutil::ascii_to_bcd_cn(ascii_pin, 6).unwrap();
-
-        let apdu_command_verify = b"\x00\x20\x00";
-        let mut verify_command = apdu_command_verify.to_vec();
-        let p2_pin_type_qualifier = 0b1000_0000;
-        verify_command.push(p2_pin_type_qualifier);
-        verify_command.push(0x08); // data length
-        verify_command.push(0b0010_0000 + ascii_pin.len() as u8); // control + PIN length
-        verify_command.extend_from_slice(&pin_bcd_cn[..]);
-        verify_command.push(0xFF); // filler
-
-        let (response_trailer, _) = self.send_apdu(&verify_command);
-        if !is_success_response(&response_trailer) {
-            warn!("Could not verify PIN");
-            //Incorrect PIN = 63, C4
-            return Err(());
-        }
-
-        info!("Pin OK");
-        Ok(())
-    }
-
-    fn fill_random(&self, data: &mut [u8]) {
-        if self.settings.terminal.use_random {
-            let mut rng = ChaCha20Rng::from_entropy();
-            rng.try_fill(data).unwrap();
-        }
-    }
-
-    pub fn handle_verify_enciphered_pin(&mut self, ascii_pin: &[u8]) -> Result<(), ()> {
-        debug!("Verify enciphered PIN:");
-
-        let pin_bcd_cn = bcdutil::ascii_to_bcd_cn(ascii_pin, 6).unwrap();
-
-        const PK_MAX_SIZE: usize = 248; // ref. EMV Book 2, B2.1 RSA Algorithm
-        let mut random_padding = [0u8; PK_MAX_SIZE];
-        self.fill_random(&mut random_padding[..]);
-
-        let icc_unpredictable_number = self.handle_get_challenge().unwrap();
-
-        // EMV Book 2, 7.1 Keys and Certificates, 7.2 PIN Encipherment and Verification
-
-        let mut plaintext_data = Vec::new();
-        plaintext_data.push(0x7F);
-        // PIN block
-        plaintext_data.push(0b0010_0000 + ascii_pin.len() as u8); // control + PIN length
-        plaintext_data.extend_from_slice(&pin_bcd_cn[..]);
-        plaintext_data.push(0xFF);
-        // ICC Unpredictable Number
-        plaintext_data.extend_from_slice(&icc_unpredictable_number[..]);
-        // Random padding
-        plaintext_data.extend_from_slice(
-            &random_padding[0..self.icc.icc_pin_pk.as_ref().unwrap().get_key_byte_size() - 17],
-        );
-
-        let ciphered_pin_data = self
-            .icc
-            .icc_pin_pk
-            .as_ref()
-            .unwrap()
-            .public_encrypt(&plaintext_data[..])
-            .unwrap();
-
-        let apdu_command_verify = b"\x00\x20\x00";
-        let mut verify_command = apdu_command_verify.to_vec();
-        let p2_pin_type_qualifier = 0b1000_1000;
-        verify_command.push(p2_pin_type_qualifier);
-        verify_command.push(ciphered_pin_data.len() as u8);
-        verify_command.extend_from_slice(&ciphered_pin_data[..]);
-
-        let (response_trailer, _) = self.send_apdu(&verify_command);
-        if !is_success_response(&response_trailer) {
-            warn!("Could not verify PIN");
-            //Incorrect PIN = 63, C4
-            return Err(());
-        }
-
-        info!("Pin OK");
-        Ok(())
-    }
-
-    fn handle_application_cryptogram_card_authentication(
-        &mut self,
-        tag_77_data: &[u8],
-        cdol_tag: &str,
-    ) -> Result<(), ()> {
-        //ref. EMV Book 2, 6.6.2 Dynamic Signature Verification
-
-        debug!("Perform Application Cryptogram Data Authentication (CDA):");
-
-        let tag_9f37_unpredictable_number = self.get_tag_value("9F37").unwrap();
-
-        let tag_9f4b_signed_data_decrypted_dynamic_data = self
-            .validate_signed_dynamic_application_data(&tag_9f37_unpredictable_number[..])
-            .unwrap();
-        let mut i = 0;
-        let icc_dynamic_number_length = tag_9f4b_signed_data_decrypted_dynamic_data[i] as usize;
-        i += 1;
-        let _icc_dynamic_number =
-            &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + icc_dynamic_number_length];
-        i += icc_dynamic_number_length;
-        let cryptogram_information_data = &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + 1];
-        i += 1;
-        let tag_9f26_application_cryptogram =
-            &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + 8];
-        i += 8;
-        let transaction_data_hash_code = &tag_9f4b_signed_data_decrypted_dynamic_data[i..i + 20];
-
-        let tag_9f27_cryptogram_information_data = self.get_tag_value("9F27").unwrap();
+        let tag_9f27_cryptogram_information_data = self
+            .get_tag_value("9F27")
+            .ok_or_else(|| EmvError::missing_tag(TransactionStep::CardActionAnalysis, "9F27"))?;
 
         if &tag_9f27_cryptogram_information_data[..] != cryptogram_information_data {
             warn!(
@@ -1873,7 +2552,11 @@ impl EmvConnection<'_> {
                 &tag_9f27_cryptogram_information_data[..],
                 cryptogram_information_data
             );
-            return Err(());
+            return Err(EmvError::DataAuthentication {
+                step: TransactionStep::CardActionAnalysis,
+                tag: Some("9F27".to_string()),
+                reason: "Cryptogram information data mismatch",
+            });
         }
 
         let mut checksum_data: Vec<u8> = Vec::new();
@@ -1882,52 +2565,32 @@ impl EmvConnection<'_> {
         if tag_9f38_pdol.is_some() {
             let pdol_data =
                 DataObjectList::process_data_object_list(self, &tag_9f38_pdol.unwrap()[..])
-                    .unwrap()
+                    .map_err(|_| {
+                        EmvError::malformed_tlv(TransactionStep::CardActionAnalysis, "9F38")
+                    })?
                     .get_tag_list_tag_values(self);
-            assert!(pdol_data.len() <= 0xFF);
             checksum_data.extend_from_slice(&pdol_data);
         }
 
         let cdol1_data =
-            DataObjectList::process_data_object_list(self, &self.get_tag_value("8C").unwrap()[..])
-                .unwrap()
-                .get_tag_list_tag_values(self);
-        assert!(cdol1_data.len() <= 0xFF);
+            self.get_data_object_list_values(TransactionStep::CardActionAnalysis, "8C")?;
         checksum_data.extend_from_slice(&cdol1_data);
 
         if cdol_tag == "8D" {
-            let cdol2_data = DataObjectList::process_data_object_list(
-                self,
-                &self.get_tag_value("8D").unwrap()[..],
-            )
-            .unwrap()
-            .get_tag_list_tag_values(self);
-            assert!(cdol2_data.len() <= 0xFF);
+            let cdol2_data =
+                self.get_data_object_list_values(TransactionStep::CardActionAnalysis, "8D")?;
             checksum_data.extend_from_slice(&cdol2_data);
         }
 
-        let mut tag_77_hex_encoded = hex::encode_upper(tag_77_data);
-
-        let tag_9f4b_signed_dynamic_application_data_hex_encoded =
-            hex::encode_upper(self.get_tag_value("9F4B").unwrap());
-
-        let tag_9f4b_tlv_header_length = 4 /* tag */ + 2 /* tag length */;
-        let tag_77_part1: String = tag_77_hex_encoded
-            .drain(
-                ..tag_77_hex_encoded
-                    .find(&tag_9f4b_signed_dynamic_application_data_hex_encoded)
-                    .unwrap()
-                    - tag_9f4b_tlv_header_length,
-            )
-            .collect();
-        checksum_data.extend_from_slice(&hex::decode(&tag_77_part1).unwrap()[..]);
-
-        let tag_9f4b_whole_size = tag_9f4b_signed_dynamic_application_data_hex_encoded.len()
-            + tag_9f4b_tlv_header_length
-            + 2;
-        if tag_77_hex_encoded.len() > tag_9f4b_whole_size {
-            let tag_77_part2: String = tag_77_hex_encoded.drain(tag_9f4b_whole_size..).collect();
-            checksum_data.extend_from_slice(&hex::decode(&tag_77_part2).unwrap()[..]);
+        // All TLV data objects of the response template, except Signed Dynamic Application Data
+        let tag_77_response = parse_tlv(response_data)
+            .ok_or_else(|| EmvError::malformed_tlv(TransactionStep::CardActionAnalysis, "77"))?;
+        if let Value::Constructed(tag_77_tags) = tag_77_response.value() {
+            for tag in tag_77_tags {
+                if hex::encode_upper(tag.tag().to_bytes()) != "9F4B" {
+                    checksum_data.extend(tag.to_vec());
+                }
+            }
         }
 
         let transaction_data_hash_code_checksum = sha::sha1(&checksum_data[..]);
@@ -1947,355 +2610,134 @@ impl EmvConnection<'_> {
                 HexViewBuilder::new(transaction_data_hash_code).finish()
             );
 
-            return Err(());
+            return Err(EmvError::DataAuthentication {
+                step: TransactionStep::CardActionAnalysis,
+                tag: Some("9F4B".to_string()),
+                reason: "Transaction data hash code mismatch",
+            });
         }
 
-        self.process_tag_as_tlv("9F26", tag_9f26_application_cryptogram.to_vec());
+        self.process_tag_as_tlv_from(
+            "9F26",
+            tag_9f26_application_cryptogram.to_vec(),
+            TagOrigin::icc(None),
+        );
 
         Ok(())
     }
 
-    fn validate_ac(&self, requested_cryptogram_type: CryptogramType) -> Result<CryptogramType, ()> {
+    fn get_cryptogram_information_data(
+        &self,
+        step: TransactionStep,
+    ) -> Result<CryptogramInformationData, EmvError> {
+        self.get_tag_value("9F27")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F27"))?
+            .first()
+            .map(|cid| CryptogramInformationData::from(*cid))
+            .ok_or_else(|| EmvError::malformed_tlv(step, "9F27"))
+    }
+
+    fn validate_ac(
+        &self,
+        step: TransactionStep,
+        requested_cryptogram_type: CryptogramType,
+    ) -> Result<CryptogramType, EmvError> {
         if let CryptogramType::ApplicationAuthenticationCryptogram = requested_cryptogram_type {
-            warn!("Transaction declined by terminal (AAC)");
-            return Err(());
+            // ref. EMV Book 3, 10.11 Completion - AAC requested for a declined authorisation, Z3 is
+            // declined offline by the terminal when unable to go online
+            let reason = match self.get_tag_value("8A") {
+                Some(tag_8a_authorisation_response_code)
+                    if step == TransactionStep::Completion
+                        && !is_authorisation_approved(tag_8a_authorisation_response_code)
+                        && tag_8a_authorisation_response_code != b"Z3" =>
+                {
+                    DeclineReason::Issuer
+                }
+                _ => DeclineReason::Terminal,
+            };
+            warn!("Transaction {} (AAC)", reason);
+            return Err(EmvError::Declined { step, reason });
         }
 
-        let tag_9f27_cryptogram_information_data = self.get_tag_value("9F27").unwrap();
-        let icc_cryptogram_type =
-            CryptogramType::try_from(tag_9f27_cryptogram_information_data[0] as u8).unwrap();
+        let cryptogram_information_data = self.get_cryptogram_information_data(step)?;
+        let icc_cryptogram_type = cryptogram_information_data
+            .cryptogram_type
+            .ok_or_else(|| EmvError::malformed_tlv(step, "9F27"))?;
+
+        if cryptogram_information_data.advice_required {
+            info!(
+                "Advice requested by ICC, reason: {:?}",
+                cryptogram_information_data.reason_code
+            );
+        }
 
         if let CryptogramType::ApplicationAuthenticationCryptogram = icc_cryptogram_type {
-            warn!("Transaction declined by ICC (AAC)");
-            return Err(());
+            let reason = DeclineReason::Card(cryptogram_information_data.reason_code);
+            warn!("Transaction {} (AAC)", reason);
+            return Err(EmvError::Declined { step, reason });
         }
 
-        let _tag_9f36_application_transaction_counter = self.get_tag_value("9F36").unwrap();
+        let _tag_9f36_application_transaction_counter = self
+            .get_tag_value("9F36")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F36"))?;
         let _tag_9f26_application_cryptogram = self.get_tag_value("9F26");
         let _tag_9f10_issuer_application_data = self.get_tag_value("9F10");
 
         Ok(icc_cryptogram_type)
     }
 
-    // ref. EMV Book 3, 6.5.5 GENERATE APPLICATION CRYPTOGRAM
-    // ref. EMV Contactless Book C-2, 7.6 Procedure – Prepare Generate AC Command
-    fn send_generate_ac(
-        &mut self,
-        requested_cryptogram_type: CryptogramType,
-        cdol_tag: &str,
-    ) -> Result<CryptogramType, ()> {
-        let mut p1_reference_control_parameter: u8 = requested_cryptogram_type.into();
-        if self.icc.capabilities.cda {
-            set_bit!(
-                p1_reference_control_parameter,
-                4,
-                self.settings.terminal.capabilities.cda
-            );
-        }
-
-        let cdol_list = DataObjectList::process_data_object_list(
-            self,
-            &self.get_tag_value(cdol_tag).unwrap()[..],
-        )
-        .unwrap();
+    // ref. EMV Book 3, 9.2.2 Transaction Certificate Data Object List (TDOL)
+    fn handle_tc_hash_value(&mut self, step: TransactionStep) -> Result<(), EmvError> {
+        let default_tdol = self
+            .terminal_application
+            .as_ref()
+            .and_then(|terminal_application| terminal_application.default_tdol.clone());
 
-        if cdol_list.has_tag("9F4C") {
-            // GET CHALLENGE might be needed to the 9F4C value
-            if let None = self.get_tag_value("9F4C") {
-                let tag_9f4c_icc_dynamic_number = self.handle_get_challenge().unwrap();
-                self.process_tag_as_tlv("9F4C", tag_9f4c_icc_dynamic_number);
-            }
-        }
+        let tdol = match (self.get_tag_value("97").cloned(), default_tdol) {
+            (Some(tag_97_tdol), _) => tag_97_tdol,
+            (None, Some(default_tdol)) => {
+                let default_tdol = hex::decode(&default_tdol).map_err(|_| EmvError::Terminal {
+                    step,
+                    reason: "Invalid default TDOL in settings",
+                })?;
 
-        let cdol_data = cdol_list.get_tag_list_tag_values(self);
-        assert!(cdol_data.len() <= 0xFF);
-
-        let apdu_command_generate_ac = b"\x80\xAE";
-        let mut generate_ac_command = apdu_command_generate_ac.to_vec();
-        generate_ac_command.push(p1_reference_control_parameter);
-        generate_ac_command.push(0x00);
-        generate_ac_command.push(cdol_data.len() as u8);
-        generate_ac_command.extend_from_slice(&cdol_data);
-        generate_ac_command.push(0x00);
-
-        let (response_trailer, response_data) = self.send_apdu(&generate_ac_command);
-        if !is_success_response(&response_trailer) {
-            // 67 00 = wrong length (i.e. CDOL data incorrect)
-            warn!("Could not process generate ac");
-            return Err(());
-        }
+                self.settings.terminal.tvr.default_tdol_used = true;
+                let tag_95_tvr: Vec<u8> = self.settings.terminal.tvr.into();
+                self.process_tag_as_tlv("95", tag_95_tvr);
 
-        if response_data[0] == 0x80 {
-            self.process_tag_as_tlv("9F27", response_data[2..3].to_vec());
-            self.process_tag_as_tlv("9F36", response_data[3..5].to_vec());
-            self.process_tag_as_tlv("9F26", response_data[5..13].to_vec());
-            if response_data.len() > 13 {
-                self.process_tag_as_tlv("9F10", response_data[13..].to_vec());
+                default_tdol
             }
-        } else if response_data[0] != 0x77 {
-            warn!("Unrecognized response");
-            return Err(());
-        }
+            // a TDOL without data objects
+            (None, None) => Vec::new(),
+        };
 
-        if get_bit!(p1_reference_control_parameter, 4) {
-            let tag_9f27_cryptogram_information_data = self.get_tag_value("9F27").unwrap();
-            let icc_cryptogram_type =
-                CryptogramType::try_from(tag_9f27_cryptogram_information_data[0] as u8).unwrap();
+        let tdol_data = if tdol.is_empty() {
+            Vec::new()
+        } else {
+            DataObjectList::process_data_object_list(self, &tdol[..])
+                .map_err(|_| EmvError::malformed_tlv(step, "97"))?
+                .get_tag_list_tag_values(self)
+        };
 
-            match icc_cryptogram_type {
-                CryptogramType::TransactionCertificate
-                | CryptogramType::AuthorisationRequestCryptogram => {
-                    self.handle_application_cryptogram_card_authentication(
-                        &response_data[3..],
-                        cdol_tag,
-                    )?;
-                }
-                _ => {}
-            }
-        }
+        // TC Hash Value
+        self.process_tag_as_tlv("98", sha::sha1(&tdol_data[..]).to_vec());
 
-        self.validate_ac(requested_cryptogram_type)
+        Ok(())
     }
 
-    pub fn handle_1st_generate_ac(&mut self) -> Result<CryptogramType, ()> {
-        debug!("Generate Application Cryptogram (GENERATE AC) - first issuance:");
+    // new card session, nothing of a previous transaction applies
+    fn reset_card_session(&mut self) {
+        self.tags.clear();
+        self.tag_scope = None;
+        self.terminal_application = None;
+    }
 
-        let icc_cryptogram_type;
-        if self.contactless && self.get_tag_value("9F26").is_some() {
-            debug!("Application Cryptogram returned in GET PROCESSING OPTIONS");
-            // ref. EMV Contactless Book C-3, A.2 Data Elements by Name - cryptogram returned in GET PROCESSING OPTIONS (Kernel 3, Visa)
-            icc_cryptogram_type = self.validate_ac(self.settings.terminal.cryptogram_type)?;
-        } else {
-            icc_cryptogram_type =
-                self.send_generate_ac(self.settings.terminal.cryptogram_type, "8C")?;
-
-            if let CryptogramType::AuthorisationRequestCryptogram = icc_cryptogram_type {
-                // handle_2nd_generate_ac needed
-            } else if let CryptogramType::AuthorisationRequestCryptogram =
-                self.settings.terminal.cryptogram_type
-            {
-                warn!("Transaction terminated by terminal - ARQC requested but got unexpected return type from ICC");
-                return Err(());
-            }
-        }
-
-        Ok(icc_cryptogram_type)
-    }
-
-    pub fn handle_2nd_generate_ac(&mut self) -> Result<CryptogramType, ()> {
-        debug!("Generate Application Cryptogram (GENERATE AC) - second issuance:");
-
-        let icc_cryptogram_type =
-            self.send_generate_ac(self.settings.terminal.cryptogram_type_arqc, "8D")?;
-
-        if let CryptogramType::TransactionCertificate = icc_cryptogram_type {
-            return Ok(icc_cryptogram_type);
-        }
-
-        warn!("Transaction has unexpected return type from ICC");
-        Err(())
-    }
-
-    fn read_record(&mut self, short_file_identifier: u8, record_index: u8) -> Option<Vec<u8>> {
-        let mut records: Vec<u8> = Vec::new();
-
-        let apdu_command_read = b"\x00\xB2";
-
-        let mut read_record = apdu_command_read.to_vec();
-        read_record.push(record_index);
-        read_record.push((short_file_identifier << 3) | 0x04);
-
-        const RECORD_LENGTH_DEFAULT: u8 = 0x00;
-        read_record.push(RECORD_LENGTH_DEFAULT);
-
-        let (response_trailer, response_data) = self.send_apdu(&read_record);
-
-        if is_success_response(&response_trailer) {
-            records.extend_from_slice(&response_data);
-        }
-
-        if !records.is_empty() {
-            return Some(records);
-        }
-
-        None
-    }
-
-    pub fn handle_select_payment_system_environment(&mut self) -> Result<Vec<EmvApplication>, ()> {
-        debug!("Selecting Payment System Environment (PSE):");
-        let contact_pse_name = "1PAY.SYS.DDF01";
-        let contactless_pse_name = "2PAY.SYS.DDF01";
-
-        let mut pse_name = contact_pse_name;
-
-        if self.contactless {
-            self.contactless = true;
-            pse_name = contactless_pse_name;
-        }
-
-        let (response_trailer, response_data) = self.send_apdu_select(&pse_name.as_bytes());
-        if !is_success_response(&response_trailer) {
-            warn!("Could not select {:?}", pse_name);
-            return Err(());
-        }
-
-        let mut all_applications: Vec<EmvApplication> = Vec::new();
-
-        if self.contactless {
-            //EMV Contactless Book B, Entry Point Specification v2.6, Table3-2: SELECT Response Message Data Field (FCI) of the PPSE
-            match find_tlv_tag(&response_data, "BF0C") {
-                Some(tag_bf0c) => {
-                    if let Value::Constructed(application_templates) = tag_bf0c.value() {
-                        for tag_61_application_template in application_templates {
-                            if let Value::Constructed(application_template) =
-                                tag_61_application_template.value()
-                            {
-                                self.tags.clear();
-
-                                for application_template_child_tag in application_template {
-                                    if let Value::Primitive(value) =
-                                        application_template_child_tag.value()
-                                    {
-                                        let tag_name = hex::encode(
-                                            application_template_child_tag.tag().to_bytes(),
-                                        )
-                                        .to_uppercase();
-                                        self.add_tag(&tag_name, value.to_vec());
-                                    }
-                                }
-
-                                let tag_4f_aid = self.get_tag_value("4F").unwrap();
-                                let tag_50_label = match self.get_tag_value("50") {
-                                    Some(v) => v,
-                                    None => "UNKNOWN".as_bytes(),
-                                };
-
-                                //EMV Contactless Book B, Entry Point Specification v2.6, Table3-3: Format of Application Priority Indicator
-                                let tag_87_priority = match self.get_tag_value("87") {
-                                    Some(v) => v,
-                                    None => "01".as_bytes(),
-                                };
-
-                                all_applications.push(EmvApplication {
-                                    aid: tag_4f_aid.clone(),
-                                    label: tag_50_label.to_vec(),
-                                    priority: tag_87_priority.to_vec(),
-                                });
-
-                                // TODO: Since in NFC we're interested only of a single application
-                                //       However we should select the one with the best priority
-
-                                break;
-                            }
-                        }
-                    }
-                }
-                None => {
-                    warn!("Expected tag BF0C not found! pse:{}", pse_name);
-                    return Err(());
-                }
-            }
-        } else {
-            let sfi_data = self.get_tag_value("88").unwrap().clone();
-            assert_eq!(sfi_data.len(), 1);
-            let short_file_identifier = sfi_data[0];
-
-            debug!("Read available AIDs:");
-
-            for record_index in 0x01..0xFF {
-                match self.read_record(short_file_identifier, record_index) {
-                    Some(data) => {
-                        if data[0] != 0x70 {
-                            warn!("Expected template data");
-                            return Err(());
-                        }
-
-                        if let Value::Constructed(application_templates) =
-                            parse_tlv(&data).unwrap().value()
-                        {
-                            for tag_61_application_template in application_templates {
-                                if let Value::Constructed(application_template) =
-                                    tag_61_application_template.value()
-                                {
-                                    self.tags.clear();
-
-                                    for application_template_child_tag in application_template {
-                                        if let Value::Primitive(value) =
-                                            application_template_child_tag.value()
-                                        {
-                                            let tag_name = hex::encode(
-                                                application_template_child_tag.tag().to_bytes(),
-                                            )
-                                            .to_uppercase();
-                                            self.add_tag(&tag_name, value.to_vec());
-                                        }
-                                    }
-
-                                    let tag_4f_aid = self.get_tag_value("4F").unwrap();
-                                    let default_label = "UNKNOWN".as_bytes().to_vec();
-                                    let tag_50_label =
-                                        self.get_tag_value("50").unwrap_or(&default_label);
-
-                                    if let Some(tag_87_priority) = self.get_tag_value("87") {
-                                        all_applications.push(EmvApplication {
-                                            aid: tag_4f_aid.clone(),
-                                            label: tag_50_label.clone(),
-                                            priority: tag_87_priority.clone(),
-                                        });
-                                    } else {
-                                        debug!(
-                                            "Skipping application. AID:{:02X?}, label:{:?}",
-                                            tag_4f_aid,
-                                            str::from_utf8(&tag_50_label).unwrap()
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    None => break,
-                };
-            }
-        }
-
-        if all_applications.is_empty() {
-            warn!("No application records found!");
-            return Err(());
-        }
-
-        Ok(all_applications)
-    }
-
-    pub fn handle_select_payment_application(
-        &mut self,
-        application: &EmvApplication,
-    ) -> Result<(), ()> {
-        info!(
-            "Selecting application. AID:{:02X?}, label:{:?}, priority:{:02X?}",
-            application.aid,
-            str::from_utf8(&application.label).unwrap(),
-            application.priority
-        );
-        let (response_trailer, _) = self.send_apdu_select(&application.aid);
-        if !is_success_response(&response_trailer) {
-            warn!(
-                "Could not select payment application! {:02X?}, {:?}",
-                application.aid, application.label
-            );
-            return Err(());
-        }
-
-        Ok(())
-    }
-
-    pub fn select_payment_application(&mut self) -> Result<EmvApplication, ()> {
-        let applications = self.handle_select_payment_system_environment()?;
-
-        let application = self.pse_application_select_callback.unwrap()(&applications)?;
-        self.handle_select_payment_application(&application)?;
-
-        Ok(application)
-    }
+    // ref. EMV Contactless Book B, 3.2 Protocol Activation - polling and activation of the card are
+    // done by the reader, the card session starts over
+    pub fn handle_protocol_activation(&mut self) {
+        debug!("Protocol activation");
+        self.reset_card_session();
+    }
 
     pub fn process_settings(&mut self) -> Result<(), Box<dyn error::Error>> {
         let default_tags = self.settings.default_tags.clone();
@@ -2303,6 +2745,18 @@ impl EmvConnection<'_> {
             self.process_tag_as_tlv(&tag_name, hex::decode(&tag_value.clone())?);
         }
 
+        if let Some(terminal_application) = self.terminal_application.clone() {
+            if let Some(application_version_number) =
+                terminal_application.application_version_number
+            {
+                self.process_tag_as_tlv("9F09", hex::decode(&application_version_number)?);
+            }
+            self.process_tag_as_tlv(
+                "9F1B",
+                terminal_application.floor_limit.to_be_bytes().to_vec(),
+            );
+        }
+
         let now = Utc::now().naive_utc();
         if !self.get_tag_value("9A").is_some() {
             let today = now.date();
@@ -2361,51 +2815,7 @@ impl EmvConnection<'_> {
         Ok(())
     }
 
-    pub fn handle_get_data(&mut self, tag: &[u8]) -> Result<Vec<u8>, ()> {
-        debug!("GET DATA:");
-
-        assert_eq!(tag.len(), 2);
-        assert_eq!(tag[0], 0x9F);
-        //allowed tags: 9F36, 9F13, 9F17 or 9F4F
-
-        let apdu_command_get_data = b"\x80\xCA";
-
-        let mut get_data_command = apdu_command_get_data.to_vec();
-        get_data_command.extend_from_slice(tag);
-        get_data_command.push(0x05);
-
-        let (response_trailer, response_data) = self.send_apdu(&get_data_command[..]);
-        if !is_success_response(&response_trailer) {
-            // 67 00 = wrong length (i.e. CDOL data incorrect)
-            warn!("Could not process get data");
-            return Err(());
-        }
-
-        let mut output: Vec<u8> = Vec::new();
-        output.extend_from_slice(&response_data);
-
-        Ok(output)
-    }
-
-    pub fn handle_get_challenge(&mut self) -> Result<Vec<u8>, ()> {
-        debug!("GET CHALLENGE:");
-
-        let apdu_command_get_challenge = b"\x00\x84\x00\x00\x00";
-
-        let (response_trailer, response_data) = self.send_apdu(&apdu_command_get_challenge[..]);
-        if !is_success_response(&response_trailer) {
-            // 67 00 = wrong length (i.e. CDOL data incorrect)
-            warn!("Could not process get challenge");
-            return Err(());
-        }
-
-        let mut output: Vec<u8> = Vec::new();
-        output.extend_from_slice(&response_data);
-
-        Ok(output)
-    }
-
-    pub fn handle_public_keys(&mut self, application: &EmvApplication) -> Result<(), ()> {
+    pub fn handle_public_keys(&mut self, application: &EmvApplication) -> Result<(), EmvError> {
         if self.get_tag_value("8F").is_none() {
             debug!("Card does not support offline data authentication");
             return Ok(());
@@ -2418,7 +2828,7 @@ impl EmvConnection<'_> {
             self.settings.censor_sensitive_fields,
         ));
 
-        let data_authentication = &self.icc.data_authentication.as_ref().unwrap()[..];
+        let data_authentication = self.icc.data_authentication.as_deref().unwrap_or_default();
 
         let tag_9f46_icc_pk_certificate = self.get_tag_value("9F46");
         let tag_9f47_icc_pk_exponent = self.get_tag_value("9F47");
@@ -2464,33 +2874,59 @@ impl EmvConnection<'_> {
     pub fn get_issuer_public_key(
         &self,
         application: &EmvApplication,
-    ) -> Result<(Vec<u8>, Vec<u8>), ()> {
+    ) -> Result<(Vec<u8>, Vec<u8>), EmvError> {
         // ref. https://www.emvco.com/wp-content/uploads/2017/05/EMV_v4.3_Book_2_Security_and_Key_Management_20120607061923900.pdf - 6.3 Retrieval of Issuer Public Key
-        let ca_data: HashMap<String, CertificateAuthority> = serialize_yaml!(
-            &self.settings.configuration_files.scheme_ca_public_keys,
-            "config/scheme_ca_public_keys_test.yaml"
-        );
+        let step = TransactionStep::OfflineDataAuthentication;
 
         let tag_92_issuer_pk_remainder = self.get_tag_value("92");
-        let tag_9f32_issuer_pk_exponent = self.get_tag_value("9F32").unwrap();
-        let tag_90_issuer_public_key_certificate = self.get_tag_value("90").unwrap();
+        let tag_9f32_issuer_pk_exponent = self
+            .get_tag_value("9F32")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F32"))?;
+        let tag_90_issuer_public_key_certificate = self
+            .get_tag_value("90")
+            .ok_or_else(|| EmvError::missing_tag(step, "90"))?;
 
+        if application.aid.len() < 5 {
+            warn!("AID too short to contain RID: {:02X?}", application.aid);
+            return Err(EmvError::malformed_tlv(step, "4F"));
+        }
         let rid = &application.aid[0..5];
-        let tag_8f_ca_pk_index = self.get_tag_value("8F").unwrap();
+        let tag_8f_ca_pk_index = self
+            .get_tag_value("8F")
+            .ok_or_else(|| EmvError::missing_tag(step, "8F"))?;
 
-        let ca_pk = get_ca_public_key(&ca_data, &rid[..], &tag_8f_ca_pk_index[..]).unwrap();
+        let ca_pk = get_ca_public_key(&self.ca_public_keys, rid, &tag_8f_ca_pk_index[..])
+            .ok_or_else(|| {
+                EmvError::data_authentication(step, "8F", "Certification authority key not found")
+            })?;
 
         let issuer_certificate = ca_pk
             .public_decrypt(&tag_90_issuer_public_key_certificate[..])
-            .unwrap();
+            .map_err(|_| {
+                EmvError::data_authentication(step, "90", "Issuer certificate recovery failed")
+            })?;
         let issuer_certificate_length = issuer_certificate.len();
 
+        // header, format, fixed fields, hash and trailer, ref. EMV Book 2, Table 6
+        if issuer_certificate_length < 36 {
+            warn!("Issuer certificate too short");
+            return Err(EmvError::data_authentication(
+                step,
+                "90",
+                "Issuer certificate too short",
+            ));
+        }
+
         if issuer_certificate[1] != 0x02 {
             warn!(
                 "Incorrect issuer certificate type {:02X?}",
                 issuer_certificate[1]
             );
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "90",
+                "Incorrect issuer certificate type",
+            ));
         }
 
         let checksum_position = 15 + issuer_certificate_length - 36;
@@ -2518,8 +2954,15 @@ impl EmvConnection<'_> {
             issuer_pk_leftmost_digits
         );
 
-        assert_eq!(issuer_certificate_hash_algorithm[0], 0x01); // SHA-1
-        assert_eq!(issuer_pk_algorithm[0], 0x01); // RSA as defined in EMV Book 2, B2.1 RSA Algorihm
+        // SHA-1 and RSA as defined in EMV Book 2, B2.1 RSA Algorihm
+        if issuer_certificate_hash_algorithm[0] != 0x01 || issuer_pk_algorithm[0] != 0x01 {
+            warn!("Unsupported issuer certificate algorithms");
+            return Err(EmvError::data_authentication(
+                step,
+                "90",
+                "Unsupported issuer certificate algorithm",
+            ));
+        }
 
         let issuer_certificate_checksum =
             &issuer_certificate[checksum_position..checksum_position + 20];
@@ -2544,20 +2987,32 @@ impl EmvConnection<'_> {
                 HexViewBuilder::new(&issuer_certificate_checksum[..]).finish()
             );
 
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "90",
+                "Issuer certificate checksum mismatch",
+            ));
         }
 
-        let tag_5a_pan = self.get_tag_value("5A").unwrap();
-        let ascii_pan = bcdutil::bcd_to_ascii(&tag_5a_pan[..]).unwrap();
-        let ascii_iin = bcdutil::bcd_to_ascii(&issuer_certificate_iin).unwrap();
-        if ascii_iin != &ascii_pan[0..ascii_iin.len()] {
+        let tag_5a_pan = self
+            .get_tag_value("5A")
+            .ok_or_else(|| EmvError::missing_tag(step, "5A"))?;
+        let ascii_pan = bcdutil::bcd_to_ascii(&tag_5a_pan[..])
+            .map_err(|_| EmvError::malformed_tlv(step, "5A"))?;
+        let ascii_iin = bcdutil::bcd_to_ascii(issuer_certificate_iin)
+            .map_err(|_| EmvError::data_authentication(step, "90", "Invalid issuer identifier"))?;
+        if !ascii_pan.starts_with(&ascii_iin) {
             warn!(
                 "IIN mismatch! Cert IIN: {:02X?}, PAN IIN: {:02X?}",
                 ascii_iin,
-                &ascii_pan[0..ascii_iin.len()]
+                &ascii_pan[0..ascii_iin.len().min(ascii_pan.len())]
             );
 
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "90",
+                "Issuer identifier does not match PAN",
+            ));
         }
 
         is_certificate_expired(&issuer_certificate_expiry[..]);
@@ -2566,8 +3021,7 @@ impl EmvConnection<'_> {
             .iter()
             .rev()
             .position(|c| -> bool { *c != 0xBB })
-            .map(|i| issuer_pk_leftmost_digits.len() - i)
-            .unwrap();
+            .map_or(0, |i| issuer_pk_leftmost_digits.len() - i);
 
         let mut issuer_pk_modulus: Vec<u8> = Vec::new();
         issuer_pk_modulus
@@ -2589,11 +3043,13 @@ impl EmvConnection<'_> {
         icc_pk_exponent: &Vec<u8>,
         icc_pk_remainder: Option<&Vec<u8>>,
         data_authentication: &[u8],
-    ) -> Result<(Vec<u8>, Vec<u8>), ()> {
+    ) -> Result<(Vec<u8>, Vec<u8>), EmvError> {
         // ICC public key retrieval: EMV Book 2, 6.4 Retrieval of ICC Public Key
+        let step = TransactionStep::OfflineDataAuthentication;
+
         debug!(
             "Retrieving ICC public key {:02X?}",
-            &icc_pk_certificate[0..2]
+            &icc_pk_certificate[0..icc_pk_certificate.len().min(2)]
         );
 
         let tag_9f46_icc_pk_certificate = icc_pk_certificate;
@@ -2602,13 +3058,34 @@ impl EmvConnection<'_> {
             .icc
             .issuer_pk
             .as_ref()
-            .unwrap()
+            .ok_or(EmvError::DataAuthentication {
+                step,
+                tag: None,
+                reason: "Issuer public key missing",
+            })?
             .public_decrypt(&tag_9f46_icc_pk_certificate[..])
-            .unwrap();
+            .map_err(|_| {
+                EmvError::data_authentication(step, "9F46", "ICC certificate recovery failed")
+            })?;
         let icc_certificate_length = icc_certificate.len();
+
+        // header, format, fixed fields, hash and trailer, ref. EMV Book 2, Table 14
+        if icc_certificate_length < 42 {
+            warn!("ICC certificate too short");
+            return Err(EmvError::data_authentication(
+                step,
+                "9F46",
+                "ICC certificate too short",
+            ));
+        }
+
         if icc_certificate[1] != 0x04 {
             warn!("Incorrect ICC certificate type {:02X?}", icc_certificate[1]);
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "9F46",
+                "Incorrect ICC certificate type",
+            ));
         }
 
         let checksum_position = 21 + icc_certificate_length - 42;
@@ -2623,9 +3100,10 @@ impl EmvConnection<'_> {
         let icc_certificate_pk_leftmost_digits = &icc_certificate[21..checksum_position];
 
         if self.settings.censor_sensitive_fields {
-            let pan: String =
-                String::from_utf8_lossy(&bcdutil::bcd_to_ascii(&icc_certificate_pan).unwrap())
-                    .to_string();
+            let pan: String = String::from_utf8_lossy(
+                &bcdutil::bcd_to_ascii(icc_certificate_pan).unwrap_or_default(),
+            )
+            .to_string();
             let truncated_pan = get_truncated_pan(&pan);
             debug!("ICC PAN:{}", truncated_pan);
         } else {
@@ -2642,8 +3120,15 @@ impl EmvConnection<'_> {
             icc_certificate_pk_leftmost_digits
         );
 
-        assert_eq!(icc_certificate_hash_algo[0], 0x01); // SHA-1
-        assert_eq!(icc_certificate_pk_algo[0], 0x01); // RSA as defined in EMV Book 2, B2.1 RSA Algorihm
+        // SHA-1 and RSA as defined in EMV Book 2, B2.1 RSA Algorihm
+        if icc_certificate_hash_algo[0] != 0x01 || icc_certificate_pk_algo[0] != 0x01 {
+            warn!("Unsupported ICC certificate algorithms");
+            return Err(EmvError::data_authentication(
+                step,
+                "9F46",
+                "Unsupported ICC certificate algorithm",
+            ));
+        }
 
         let tag_9f47_icc_pk_exponent = icc_pk_exponent;
 
@@ -2659,14 +3144,9 @@ impl EmvConnection<'_> {
 
         checksum_data.extend_from_slice(data_authentication);
 
-        if let Some(tag_9f4a_static_data_authentication_tag_list) = self.get_tag_value("9F4A") {
+        if self.get_tag_value("9F4A").is_some() {
             let static_data_authentication_tag_list_tag_values =
-                DataObjectList::process_data_object_list(
-                    self,
-                    &tag_9f4a_static_data_authentication_tag_list[..],
-                )
-                .unwrap()
-                .get_tag_list_tag_values(self);
+                self.get_tag_list_values(step, "9F4A")?;
             checksum_data.extend_from_slice(&static_data_authentication_tag_list_tag_values[..]);
         }
 
@@ -2679,18 +3159,34 @@ impl EmvConnection<'_> {
         }
         trace!("Calculated checksum: {:02X?}", cert_checksum);
         trace!("Stored ICC checksum: {:02X?}", icc_certificate_checksum);
-        assert_eq!(cert_checksum, icc_certificate_checksum);
+        if &cert_checksum[..] != icc_certificate_checksum {
+            warn!("ICC cert checksum mismatch!");
+            return Err(EmvError::data_authentication(
+                step,
+                "9F46",
+                "ICC certificate checksum mismatch",
+            ));
+        }
 
-        let tag_5a_pan = self.get_tag_value("5A").unwrap();
-        let ascii_pan = bcdutil::bcd_to_ascii(&tag_5a_pan[..]).unwrap();
-        let icc_ascii_pan = bcdutil::bcd_to_ascii(&icc_certificate_pan).unwrap();
+        let tag_5a_pan = self
+            .get_tag_value("5A")
+            .ok_or_else(|| EmvError::missing_tag(step, "5A"))?;
+        let ascii_pan = bcdutil::bcd_to_ascii(&tag_5a_pan[..])
+            .map_err(|_| EmvError::malformed_tlv(step, "5A"))?;
+        let icc_ascii_pan = bcdutil::bcd_to_ascii(icc_certificate_pan).map_err(|_| {
+            EmvError::data_authentication(step, "9F46", "Invalid ICC certificate PAN")
+        })?;
         if icc_ascii_pan != ascii_pan {
             warn!(
                 "PAN mismatch! Cert PAN: {:02X?}, PAN: {:02X?}",
                 icc_ascii_pan, ascii_pan
             );
 
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "9F46",
+                "ICC certificate PAN mismatch",
+            ));
         }
 
         is_certificate_expired(&icc_certificate_expiry[..]);
@@ -2701,8 +3197,7 @@ impl EmvConnection<'_> {
             .iter()
             .rev()
             .position(|c| -> bool { *c != 0xBB })
-            .map(|i| icc_certificate_pk_leftmost_digits.len() - i)
-            .unwrap();
+            .map_or(0, |i| icc_certificate_pk_leftmost_digits.len() - i);
 
         icc_pk_modulus.extend_from_slice(
             &icc_certificate_pk_leftmost_digits[..icc_certificate_pk_leftmost_digits_length],
@@ -2723,9 +3218,12 @@ impl EmvConnection<'_> {
 
     pub fn validate_signed_dynamic_application_data(
         &self,
+        step: TransactionStep,
         auth_data: &[u8],
-    ) -> Result<Vec<u8>, ()> {
-        let tag_9f4b_signed_data = self.get_tag_value("9F4B").unwrap();
+    ) -> Result<Vec<u8>, EmvError> {
+        let tag_9f4b_signed_data = self
+            .get_tag_value("9F4B")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F4B"))?;
         trace!(
             "9F4B signed data result moduluslength: ({} bytes):\n{}",
             tag_9f4b_signed_data.len(),
@@ -2734,7 +3232,11 @@ impl EmvConnection<'_> {
 
         if self.icc.icc_pk.is_none() {
             warn!("ICC PK missing, can't validate");
-            return Err(());
+            return Err(EmvError::DataAuthentication {
+                step,
+                tag: None,
+                reason: "ICC public key missing",
+            });
         }
 
         let tag_9f4b_signed_data_decrypted = self
@@ -2743,18 +3245,52 @@ impl EmvConnection<'_> {
             .as_ref()
             .unwrap()
             .public_decrypt(&tag_9f4b_signed_data[..])
-            .unwrap();
+            .map_err(|_| {
+                EmvError::data_authentication(step, "9F4B", "Signed dynamic data recovery failed")
+            })?;
         let tag_9f4b_signed_data_decrypted_length = tag_9f4b_signed_data_decrypted.len();
+
+        // header, format, hash algorithm, data length, hash and trailer, ref. EMV Book 2, Table 17
+        if tag_9f4b_signed_data_decrypted_length < 25 {
+            warn!("Signed dynamic data too short");
+            return Err(EmvError::data_authentication(
+                step,
+                "9F4B",
+                "Signed dynamic data too short",
+            ));
+        }
+
         if tag_9f4b_signed_data_decrypted[1] != 0x05 {
             warn!("Unrecognized format");
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "9F4B",
+                "Unrecognized signed data format",
+            ));
         }
 
         let tag_9f4b_signed_data_decrypted_hash_algo = tag_9f4b_signed_data_decrypted[2];
-        assert_eq!(tag_9f4b_signed_data_decrypted_hash_algo, 0x01);
+        if tag_9f4b_signed_data_decrypted_hash_algo != 0x01 {
+            warn!("Unsupported hash algorithm");
+            return Err(EmvError::data_authentication(
+                step,
+                "9F4B",
+                "Unsupported signed dynamic data hash algorithm",
+            ));
+        }
 
         let tag_9f4b_signed_data_decrypted_dynamic_data_length =
             tag_9f4b_signed_data_decrypted[3] as usize;
+        if 4 + tag_9f4b_signed_data_decrypted_dynamic_data_length
+            > tag_9f4b_signed_data_decrypted_length - 21
+        {
+            warn!("ICC dynamic data length exceeds signed data");
+            return Err(EmvError::data_authentication(
+                step,
+                "9F4B",
+                "Invalid ICC dynamic data length",
+            ));
+        }
 
         let tag_9f4b_signed_data_decrypted_dynamic_data = &tag_9f4b_signed_data_decrypted
             [4..4 + tag_9f4b_signed_data_decrypted_dynamic_data_length];
@@ -2780,7 +3316,11 @@ impl EmvConnection<'_> {
                 HexViewBuilder::new(&tag_9f4b_signed_data_decrypted_checksum[..]).finish()
             );
 
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "9F4B",
+                "Signed dynamic data checksum mismatch",
+            ));
         }
 
         Ok(tag_9f4b_signed_data_decrypted_dynamic_data.to_vec())
@@ -2789,19 +3329,31 @@ impl EmvConnection<'_> {
     pub fn handle_signed_static_application_data(
         &mut self,
         data_authentication: &[u8],
-    ) -> Result<(), ()> {
+    ) -> Result<(), EmvError> {
         debug!("Validate Signed Static Application Data (SDA):");
 
+        let step = TransactionStep::OfflineDataAuthentication;
+
         if self.icc.issuer_pk.is_none() {
             warn!("Issuer PK missing, can't perform SDA");
-            return Err(());
+            return Err(EmvError::DataAuthentication {
+                step,
+                tag: None,
+                reason: "Issuer public key missing",
+            });
         }
 
-        let tag_93_ssad = self.get_tag_value("93").unwrap();
+        let tag_93_ssad = self
+            .get_tag_value("93")
+            .ok_or_else(|| EmvError::missing_tag(step, "93"))?;
 
         if tag_93_ssad.len() != self.icc.issuer_pk.as_ref().unwrap().get_key_byte_size() {
             warn!("SDA and issuer key mismatch");
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "93",
+                "Signed static data and issuer key length mismatch",
+            ));
         }
 
         let tag_93_ssad_decrypted = self
@@ -2810,21 +3362,26 @@ impl EmvConnection<'_> {
             .as_ref()
             .unwrap()
             .public_decrypt(&tag_93_ssad[..])
-            .unwrap();
-
-        assert_eq!(tag_93_ssad_decrypted[1], 0x03);
+            .map_err(|_| {
+                EmvError::data_authentication(step, "93", "Signed static data recovery failed")
+            })?;
+
+        // header, format, fixed fields, hash and trailer, ref. EMV Book 2, Table 7
+        if tag_93_ssad_decrypted.len() < 26 || tag_93_ssad_decrypted[1] != 0x03 {
+            warn!("Unrecognized signed static data format");
+            return Err(EmvError::data_authentication(
+                step,
+                "93",
+                "Unrecognized signed static data format",
+            ));
+        }
 
         let mut checksum_data: Vec<u8> = Vec::new();
         checksum_data
             .extend_from_slice(&tag_93_ssad_decrypted[1..tag_93_ssad_decrypted.len() - 22]);
         checksum_data.extend_from_slice(data_authentication);
         let static_data_authentication_tag_list_tag_values =
-            DataObjectList::process_data_object_list(
-                self,
-                &self.get_tag_value("9F4A").unwrap()[..],
-            )
-            .unwrap()
-            .get_tag_list_tag_values(self);
+            self.get_tag_list_values(step, "9F4A")?;
         checksum_data.extend_from_slice(&static_data_authentication_tag_list_tag_values[..]);
 
         let ssad_checksum_calculated = sha::sha1(&checksum_data[..]);
@@ -2847,261 +3404,181 @@ impl EmvConnection<'_> {
                 HexViewBuilder::new(&ssad_checksum[..]).finish()
             );
 
-            return Err(());
+            return Err(EmvError::data_authentication(
+                step,
+                "93",
+                "Signed static data checksum mismatch",
+            ));
         }
 
-        self.process_tag_as_tlv("9F45", tag_93_ssad_decrypted[3..5].to_vec());
+        self.process_tag_as_tlv_from(
+            "9F45",
+            tag_93_ssad_decrypted[3..5].to_vec(),
+            TagOrigin::icc(None),
+        );
 
         Ok(())
     }
 
-    pub fn handle_dynamic_data_authentication(&mut self) -> Result<(), ()> {
-        let mut auth_data: Vec<u8> = Vec::new();
-
-        if let Some(tag_9f69_card_authentication_related_data) = self.get_tag_value("9F69") {
-            // ref. EMV Contactless Book C-3, Annex C Fast Dynamic Data Authentication (fDDA)
-            // ref. EMV Contactless Book C-7, Annex B Fast Dynamic Data Authentication (fDDA)
-
-            debug!("Perform Fast Dynamic Data Authentication (fDDA):");
+    pub fn handle_terminal_risk_management(&mut self) -> Result<(), EmvError> {
+        //ref. EMV 4.3 Book 3 - 10.6 Terminal Risk Management
+        //risk management for online transaction:
+        //- check terminal floor limit
+        //- random transaction selection; select a transaction randomly for online authorization
+        //- velocity checking; check offline transaction counter / limits from the card
+        let step = TransactionStep::TerminalRiskManagement;
 
-            if !self.contactless {
-                warn!("fDDA expected only for contactless");
-                return Err(());
-            }
-
-            if tag_9f69_card_authentication_related_data[0] != 0x01 {
-                warn!(
-                    "fDDA version not recognized:{}",
-                    tag_9f69_card_authentication_related_data[0]
-                );
-                return Err(());
-            }
-
-            auth_data.extend_from_slice(&self.get_tag_value("9F37").unwrap()[..]);
-            auth_data.extend_from_slice(&self.get_tag_value("9F02").unwrap()[..]);
-            auth_data.extend_from_slice(&self.get_tag_value("5F2A").unwrap()[..]);
-            auth_data.extend_from_slice(&tag_9f69_card_authentication_related_data[..]);
-        } else {
-            debug!("Perform Dynamic Data Authentication (DDA):");
-
-            let ddol_default_value = b"\x9f\x37\x04".to_vec();
-            let tag_9f49_ddol = match self.get_tag_value("9F49") {
-                Some(ddol) => ddol,
-                // fall-back to a default DDOL
-                None => &ddol_default_value,
-            };
-
-            let ddol_data = DataObjectList::process_data_object_list(self, &tag_9f49_ddol[..])
-                .unwrap()
-                .get_tag_list_tag_values(self);
-
-            auth_data.extend_from_slice(&ddol_data[..]);
-
-            let apdu_command_internal_authenticate = b"\x00\x88\x00\x00";
-            let mut internal_authenticate_command = apdu_command_internal_authenticate.to_vec();
-            internal_authenticate_command.push(auth_data.len() as u8);
-            internal_authenticate_command.extend_from_slice(&auth_data[..]);
-            internal_authenticate_command.push(0x00);
-
-            let (response_trailer, response_data) = self.send_apdu(&internal_authenticate_command);
-            if !is_success_response(&response_trailer) {
-                warn!("Could not process internal authenticate");
-                return Err(());
+        let terminal_application = match &self.terminal_application {
+            Some(terminal_application) => terminal_application.clone(),
+            None => {
+                debug!("No terminal parameters for the application, risk management skipped");
+                return Ok(());
             }
+        };
 
-            if response_data[0] == 0x80 {
-                self.process_tag_as_tlv("9F4B", response_data[3..].to_vec());
-            } else if response_data[0] != 0x77 {
-                warn!("Unrecognized response");
-                return Err(());
-            }
+        let tag_9f02_amount_authorised = self
+            .get_tag_value("9F02")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F02"))?;
+        let amount = bcdutil::bcd_to_ascii(&tag_9f02_amount_authorised[..])
+            .ok()
+            .and_then(|ascii_amount| str::from_utf8(&ascii_amount[..]).ok()?.parse::<u64>().ok())
+            .ok_or_else(|| EmvError::malformed_tlv(step, "9F02"))?;
+
+        // ref. EMV Book 3, 10.6.1 Floor Limits
+        let floor_limit = terminal_application.floor_limit as u64;
+        if amount >= floor_limit {
+            debug!("Transaction exceeds floor limit {}", floor_limit);
+            self.settings.terminal.tvr.transaction_exceeds_floor_limit = true;
+        } else if self.is_selected_randomly(amount, &terminal_application) {
+            // ref. EMV Book 3, 10.6.2 Random Transaction Selection
+            debug!("Transaction selected randomly for online processing");
+            self.settings
+                .terminal
+                .tvr
+                .transaction_selected_randomly_for_online_processing = true;
         }
 
-        let tag_9f4b_signed_data_decrypted_dynamic_data = self
-            .validate_signed_dynamic_application_data(&auth_data[..])
-            .unwrap();
-
-        let tag_9f4c_icc_dynamic_number = &tag_9f4b_signed_data_decrypted_dynamic_data[1..];
-        self.process_tag_as_tlv("9F4C", tag_9f4c_icc_dynamic_number.to_vec());
+        self.settings
+            .terminal
+            .tsi
+            .terminal_risk_management_was_performed = true;
 
         Ok(())
     }
 
-    pub fn start_transaction(&mut self, application: &EmvApplication) -> Result<(), ()> {
-        self.process_settings().unwrap();
-
-        self.start_transaction_callback.unwrap()(self)?;
-
-        self.handle_get_processing_options()?;
+    // ref. EMV Book 3, 10.6.2 Random Transaction Selection - the probability increases from the
+    // target percentage at the threshold up to the maximum target percentage at the floor limit
+    fn is_selected_randomly(
+        &self,
+        amount: u64,
+        terminal_application: &TerminalApplication,
+    ) -> bool {
+        let threshold = terminal_application.random_selection_threshold as u64;
+        let floor_limit = terminal_application.floor_limit as u64;
+        let target_percentage = terminal_application.random_selection_target_percentage as u64;
+        let maximum_target_percentage =
+            terminal_application.random_selection_maximum_target_percentage as u64;
+
+        let transaction_target_percentage = if amount < threshold || floor_limit <= threshold {
+            target_percentage
+        } else {
+            target_percentage
+                + maximum_target_percentage.saturating_sub(target_percentage) * (amount - threshold)
+                    / (floor_limit - threshold)
+        };
 
-        self.handle_public_keys(application).unwrap();
+        // random number in the range 1 to 99
+        let mut random_bytes = [0u8; 4];
+        self.fill_random(&mut random_bytes[..]);
+        let random_number = (u32::from_be_bytes(random_bytes) % 99) as u64 + 1;
 
-        Ok(())
+        random_number <= transaction_target_percentage
     }
 
-    pub fn handle_card_verification_methods(&mut self) -> Result<(), ()> {
-        let purchase_amount = str::from_utf8(
-            &bcdutil::bcd_to_ascii(&self.get_tag_value("9F02").unwrap()[..]).unwrap()[..],
-        )
-        .unwrap()
-        .parse::<u32>()
-        .unwrap();
-
-        let cvm_rules = self.icc.cvm_rules.clone();
-        for rule in cvm_rules {
-            let mut skip_if_not_supported = false;
-            let mut success = false;
-
-            match rule.condition {
-                CvmConditionCode::UnattendedCash
-                | CvmConditionCode::ManualCash
-                | CvmConditionCode::PurchaseWithCashback => {
-                    // TODO: conditions currently never supported, maybe should implement it more flexible
-                    continue;
-                }
-                CvmConditionCode::CvmSupported => {
-                    skip_if_not_supported = true;
-                }
-                // TODO: verify that ICC and terminal currencies are the same or provide conversion
-                CvmConditionCode::IccCurrencyUnderX => {
-                    if purchase_amount >= rule.amount_x {
-                        continue;
-                    }
-                }
-                CvmConditionCode::IccCurrencyOverX => {
-                    if purchase_amount <= rule.amount_x {
-                        continue;
-                    }
-                }
-                CvmConditionCode::IccCurrencyUnderY => {
-                    if purchase_amount >= rule.amount_y {
-                        continue;
-                    }
-                }
-                CvmConditionCode::IccCurrencyOverY => {
-                    if purchase_amount <= rule.amount_y {
-                        continue;
-                    }
-                }
-                _ => (),
-            }
-
-            match rule.code {
-                CvmCode::FailCvmProcessing => success = false,
-                CvmCode::EncipheredPinOnline => {
-                    debug!("Enciphered PIN online is not supported");
-
-                    if skip_if_not_supported {
-                        continue;
-                    }
-
-                    success = false;
-                }
-                CvmCode::PlaintextPin
-                | CvmCode::PlaintextPinAndSignature
-                | CvmCode::EncipheredPinOffline
-                | CvmCode::EncipheredPinOfflineAndSignature => {
-                    let enciphered_pin = match rule.code {
-                        CvmCode::EncipheredPinOffline
-                        | CvmCode::EncipheredPinOfflineAndSignature => true,
-                        _ => false,
-                    };
+    fn set_offline_data_authentication_failed(&mut self) {
+        let terminal_capabilities = &self.settings.terminal.capabilities;
+        let icc_capabilities = &self.icc.capabilities;
 
-                    let ascii_pin = self.pin_callback.unwrap()()?;
-
-                    if enciphered_pin && self.settings.terminal.capabilities.enciphered_pin {
-                        success = match self.handle_verify_enciphered_pin(ascii_pin.as_bytes()) {
-                            Ok(_) => true,
-                            Err(_) => false,
-                        };
-                    } else if self.settings.terminal.capabilities.plaintext_pin {
-                        success = match self.handle_verify_plaintext_pin(ascii_pin.as_bytes()) {
-                            Ok(_) => true,
-                            Err(_) => false,
-                        };
-                    } else if skip_if_not_supported {
-                        continue;
-                    }
-                }
-                CvmCode::Signature | CvmCode::NoCvm => {
-                    success = true;
-                }
-            }
+        if terminal_capabilities.cda && icc_capabilities.cda {
+            self.settings.terminal.tvr.cda_failed = true;
+        } else if terminal_capabilities.dda && icc_capabilities.dda {
+            self.settings.terminal.tvr.dda_failed = true;
+        } else if terminal_capabilities.sda && icc_capabilities.sda {
+            self.settings.terminal.tvr.sda_failed = true;
+        }
+    }
 
-            if success {
-                self.settings
-                    .terminal
-                    .tvr
-                    .cardholder_verification_was_not_successful = false;
-                self.process_tag_as_tlv("9F34", CvmRule::into_9f34_value(Ok(rule)));
-                break;
-            } else {
-                self.settings
+    // ref. EMV Book 4, A1 Terminal Type - online only or offline with online capability
+    fn is_online_capable(&self) -> bool {
+        match self
+            .get_tag_value("9F35")
+            .and_then(|tag_9f35_terminal_type| tag_9f35_terminal_type.first())
+        {
+            Some(terminal_type) => matches!(terminal_type & 0x0F, 0x01 | 0x02 | 0x04 | 0x05),
+            None => {
+                !self
+                    .settings
                     .terminal
-                    .tvr
-                    .cardholder_verification_was_not_successful = true;
-                self.process_tag_as_tlv("9F34", CvmRule::into_9f34_value(Err(rule)));
-
-                if !skip_if_not_supported {
-                    break;
-                }
+                    .terminal_transaction_qualifiers
+                    .offline_only_reader
             }
         }
-
-        self.settings
-            .terminal
-            .tsi
-            .cardholder_verification_was_performed = true;
-
-        if !self.get_tag_value("9F34").is_some() {
-            self.settings
-                .terminal
-                .tvr
-                .cardholder_verification_was_not_successful = true;
-
-            // "no CVM performed"
-            self.process_tag_as_tlv("9F34", b"\x3F\x00\x01".to_vec());
-        }
-
-        Ok(())
-    }
-
-    pub fn handle_terminal_risk_management(&mut self) -> Result<(), ()> {
-        //ref. EMV 4.3 Book 3 - 10.6 Terminal Risk Management
-        //risk management for online transaction:
-        //- check terminal floor limit
-        //- random transaction selection; select a transaction randomly for online authorization
-        //- velocity checking; check offline transaction counter / limits from the card
-
-        Ok(())
     }
 
-    pub fn handle_offline_data_authentication(&mut self) -> Result<(), ()> {
-        //ref. EMV 4.3 Book 3 - 10.3 Offline Data Authentication
+    // Issuer Action Code and Terminal Action Code pairs - Denial, Online and Default
+    fn action_codes(
+        &self,
+        step: TransactionStep,
+    ) -> Result<[(TerminalVerificationResults, TerminalVerificationResults); 3], EmvError> {
+        let tvr_len = Vec::<u8>::from(self.settings.terminal.tvr).len();
+        let action_zero: TerminalVerificationResults = vec![0; tvr_len].into();
+        let action_one: TerminalVerificationResults = vec![0xFF; tvr_len].into();
 
-        if !(self.settings.terminal.capabilities.cda && self.icc.capabilities.cda) {
-            if self.settings.terminal.capabilities.dda && self.icc.capabilities.dda {
-                if let Err(_) = self.handle_dynamic_data_authentication() {
-                    self.settings.terminal.tvr.dda_failed = true;
-                }
-            } else if self.settings.terminal.capabilities.sda && self.icc.capabilities.sda {
-                if let Err(_) = self.handle_signed_static_application_data(
-                    &self.icc.data_authentication.as_ref().unwrap().clone()[..],
-                ) {
-                    self.settings.terminal.tvr.sda_failed = true;
+        let issuer_action_code =
+            |tag_name: &str, name: &str, default| match self.get_tag_value(tag_name) {
+                Some(iac) => {
+                    let ac: TerminalVerificationResults = iac.to_vec().into();
+                    debug!("Action Code - {}: {:?}", name, ac);
+                    ac
                 }
-            }
-        }
-
-        self.settings
-            .terminal
-            .tsi
-            .offline_data_authentication_was_performed = true;
+                None => default,
+            };
+        let tag_9f0e_issuer_action_code_denial = issuer_action_code("9F0E", "Denial", action_zero);
+        let tag_9f0f_issuer_action_code_online = issuer_action_code("9F0F", "Online", action_one);
+        let tag_9f0d_issuer_action_code_default = issuer_action_code("9F0D", "Default", action_one);
+
+        let (
+            terminal_action_code_denial,
+            terminal_action_code_online,
+            terminal_action_code_default,
+        ) =
+            match &self.terminal_application {
+                Some(terminal_application) => terminal_application
+                    .terminal_action_codes()
+                    .map_err(|_| EmvError::Terminal {
+                        step,
+                        reason: "Invalid terminal action code in settings",
+                    })?,
+                None => (action_zero, action_zero, action_zero),
+            };
 
-        Ok(())
+        Ok([
+            (
+                tag_9f0e_issuer_action_code_denial,
+                terminal_action_code_denial,
+            ),
+            (
+                tag_9f0f_issuer_action_code_online,
+                terminal_action_code_online,
+            ),
+            (
+                tag_9f0d_issuer_action_code_default,
+                terminal_action_code_default,
+            ),
+        ])
     }
 
-    pub fn handle_terminal_action_analysis(&mut self) -> Result<(), ()> {
+    pub fn handle_terminal_action_analysis(&mut self) -> Result<CryptogramType, EmvError> {
         // ref. EMV 4.3 Book 3 - 10.7 Terminal Action Analysis
         // Terminal & Issuer Action Code - Denial => default bits 0
         // For each bit in the TVR that has a value of 1, the terminal shall check the corresponding bits in
@@ -3118,668 +3595,5356 @@ impl EmvConnection<'_> {
         //used (for example, in case of an offline-only terminal) or indicated a desire on the part of the issuer or the acquirer
         //to process the transaction online but the terminal was unable to go online.
 
+        let step = TransactionStep::TerminalActionAnalysis;
+
         let tag_95_tvr: Vec<u8> = self.settings.terminal.tvr.into();
-        let tvr_len = tag_95_tvr.len();
         self.process_tag_as_tlv("95", tag_95_tvr);
         debug!("{:?}", self.settings.terminal.tvr);
 
-        let action_zero: TerminalVerificationResults = vec![0; tvr_len].into();
-        let action_one: TerminalVerificationResults = vec![1; tvr_len].into();
-
-        let tag_9f0e_issuer_action_code_denial: TerminalVerificationResults =
-            match self.get_tag_value("9F0E") {
-                Some(iac) => {
-                    let ac: TerminalVerificationResults = iac.to_vec().into();
-                    debug!("Action Code - Denial: {:?}", ac);
-                    ac
-                }
-                None => action_zero.clone(),
-            };
-        let tag_9f0f_issuer_action_code_online: TerminalVerificationResults =
-            match self.get_tag_value("9F0F") {
-                Some(iac) => {
-                    let ac: TerminalVerificationResults = iac.to_vec().into();
-                    debug!("Action Code - Online: {:?}", ac);
-                    ac
-                }
-                None => action_one.clone(),
+        let [denial, online, default] = self.action_codes(step)?;
+        let tvr = &self.settings.terminal.tvr;
+        let action_code_matches =
+            |(iac, tac): &(TerminalVerificationResults, TerminalVerificationResults)| {
+                TerminalVerificationResults::action_code_matches(tvr, iac, tac)
             };
-        let tag_9f0d_issuer_action_code_default = match self.get_tag_value("9F0D") {
-            Some(iac) => {
-                let ac: TerminalVerificationResults = iac.to_vec().into();
-                debug!("Action Code - Default: {:?}", ac);
-                ac
-            }
-            None => action_one.clone(),
-        };
 
-        let terminal_action_code_denial: TerminalVerificationResults = action_zero.clone();
-        let terminal_action_code_online: TerminalVerificationResults = action_zero.clone();
-        let terminal_action_code_default: TerminalVerificationResults = action_zero.clone();
-
-        // TODO: actually make the GENERATE AC happen
-
-        if TerminalVerificationResults::action_code_matches(
-            &self.settings.terminal.tvr,
-            &tag_9f0e_issuer_action_code_denial,
-            &terminal_action_code_denial,
-        ) {
+        let cryptogram_type = if action_code_matches(&denial) {
             debug!("Action Code - Denial matches => GENERATE AC AAC needed");
-        } else if TerminalVerificationResults::action_code_matches(
-            &self.settings.terminal.tvr,
-            &tag_9f0f_issuer_action_code_online,
-            &terminal_action_code_online,
-        ) {
+            CryptogramType::ApplicationAuthenticationCryptogram
+        } else if self.is_online_capable() {
             // online action codes for online capable terminals
-            debug!("Action Code - Online matches => GENERATE AC ARQC needed");
-        } else if TerminalVerificationResults::action_code_matches(
-            &self.settings.terminal.tvr,
-            &tag_9f0d_issuer_action_code_default,
-            &terminal_action_code_default,
-        ) {
-            // TODO: offline-only terminals or if online authorization is not possible this is to be done
+            if action_code_matches(&online) {
+                debug!("Action Code - Online matches => GENERATE AC ARQC needed");
+                CryptogramType::AuthorisationRequestCryptogram
+            } else {
+                debug!("Action Codes vs. TVR are OK => GENERATE AC TC needed");
+                CryptogramType::TransactionCertificate
+            }
+        } else if action_code_matches(&default) {
+            // default action codes for offline-only terminals
             debug!("Action Code - Default matches => GENERATE AC AAC needed");
+            CryptogramType::ApplicationAuthenticationCryptogram
         } else {
             debug!("Action Codes vs. TVR are OK => GENERATE AC TC needed");
-        }
+            CryptogramType::TransactionCertificate
+        };
 
-        Ok(())
+        Ok(cryptogram_type)
+    }
+}
+
+// Card commands and the transaction flow, blocking methods of EmvConnection and the same flow as
+// async methods of AsyncEmvConnection
+#[maybe_async_cfg::maybe(
+    idents(EmvConnection(sync, async = "AsyncEmvConnection")),
+    sync(keep_self),
+    async(feature = "async")
+)]
+impl EmvConnection {
+    async fn send_apdu_select(
+        &mut self,
+        aid: &[u8],
+        p2_selection_options: u8,
+    ) -> Result<ResponseApdu, TransportError> {
+        //ref. EMV Book 1, 11.3.2 Command message
+        // ICC data of a previously selected file does not apply to the new selection
+        self.tags.clear_source(FieldSource::Icc);
+
+        self.send_apdu(&CommandApdu::select(aid, p2_selection_options))
+            .await
     }
 
-    pub fn handle_issuer_authentication_data(&mut self) -> Result<(), ()> {
-        // ref. EMV 4.3 Book 3 - 10.9 Online Processing
-        // ref. EMV 4.3 Book 3 - 6.5.4 EXTERNAL AUTHENTICATE Command-Response APDUs
+    pub async fn send_apdu(
+        &mut self,
+        command: &CommandApdu,
+    ) -> Result<ResponseApdu, TransportError> {
+        let mut response_data: Vec<u8> = Vec::new();
+        let mut response_status: StatusWord;
 
-        let tag_91_issuer_authentication_data = self.get_tag_value("91");
-        if !self.icc.capabilities.issuer_authentication
-            && tag_91_issuer_authentication_data.is_none()
-        {
-            return Ok(());
+        let mut apdu_command = command.clone();
+
+        // Upper bound for data accumulated over GET RESPONSE rounds, protects against an ICC
+        // which keeps on answering 61XX
+        const MAX_RESPONSE_DATA_LENGTH: usize = 0x10000;
+        let mut wrong_length_retried = false;
+
+        let interface = self.get_card_interface()?;
+
+        if command.is_extended() && !interface.supports_extended_length() {
+            warn!(
+                "Extended length APDU not supported by the reader, command data {} bytes",
+                command.lc()
+            );
+            return Err(TransportError::Protocol(
+                "extended length not supported by the reader".to_string(),
+            ));
         }
 
-        debug!("Validating issuer authentication data");
-        // TODO: call external authenticate
-        let apdu_command_external_authenticate = b"\x00\x82\x00\x00"; // EXTERNAL AUTHENTICATE
-        let mut external_authenticate_command = apdu_command_external_authenticate.to_vec();
-        external_authenticate_command.push(tag_91_issuer_authentication_data.unwrap().len() as u8);
-        external_authenticate_command
-            .extend_from_slice(&tag_91_issuer_authentication_data.unwrap()[..]);
-
-        let (response_trailer, _response_data) = self.send_apdu(&external_authenticate_command);
-        if !is_success_response(&response_trailer) {
-            self.settings.terminal.tvr.issuer_authentication_failed = true;
+        // GET RESPONSE is the T=0 chaining command with a single byte P3, only the command of the
+        // caller asks for all the response data with the extended Le
+        if interface.supports_extended_length() {
+            apdu_command = apdu_command.with_extended_le();
         }
 
-        Ok(())
-    }
-}
+        loop {
+            let apdu_command_bytes = apdu_command
+                .to_bytes()
+                .map_err(|err| TransportError::Protocol(err.to_string()))?;
 
-#[derive(Clone, Debug)]
-pub struct EmvApplication {
-    pub aid: Vec<u8>,
-    pub label: Vec<u8>,
-    pub priority: Vec<u8>,
-}
+            // Send an APDU command.
+            if self.settings.censor_sensitive_fields {
+                debug!(
+                    "Sending APDU: {:02X?}... ({} bytes)",
+                    &apdu_command_bytes[0..apdu_command_bytes.len().min(5)],
+                    apdu_command_bytes.len()
+                );
+            } else {
+                debug!(
+                    "Sending APDU:\n{}",
+                    HexViewBuilder::new(&apdu_command_bytes).finish()
+                );
+            }
 
-#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
-pub enum FieldSensitivity {
-    Public,
-    SensitiveAuthenticationData,
-    Sensitive,
-    Track2,
-    PrimaryAccountNumber,
-    PersonallyIdentifiableInformation,
-}
-
-#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
-pub enum FieldFormat {
-    CompressedNumeric,
-    Binary,
-    Alphanumeric,
-    AlphanumericSpecial,
-    TerminalVerificationResults,
-    ApplicationUsageControl,
-    KeyCertificate,
-    ServiceCodeIso7813,
-    NumericCountryCode,
-    NumericCurrencyCode,
-    DataObjectList,
-    Track2,
-    Date,
-    Time,
-}
-
-#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
-pub enum FieldSource {
-    Icc,
-    Terminal,
-    Issuer,
-    IssuerOrTerminal,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct EmvTag {
-    pub tag: String,
-    pub name: String,
-    pub sensitivity: Option<FieldSensitivity>,
-    pub format: Option<FieldFormat>,
-    pub min: Option<u8>,
-    pub max: Option<u8>,
-    pub source: Option<FieldSource>,
-}
-
-impl EmvTag {
-    pub fn new(tag_name: &str) -> EmvTag {
-        EmvTag {
-            tag: tag_name.to_string(),
-            name: "Unknown tag".to_string(),
-            sensitivity: None,
-            format: None,
-            min: None,
-            max: None,
-            source: None,
-        }
-    }
-}
+            let apdu_response = interface.send_apdu(&apdu_command_bytes).await?;
+            let apdu_response = ResponseApdu::parse(&apdu_response[..]).map_err(|err| {
+                warn!(
+                    "APDU response too short to contain status: {:02X?}",
+                    apdu_response
+                );
+                TransportError::Protocol(err.to_string())
+            })?;
+            response_data.extend_from_slice(&apdu_response.data[..]);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct RsaPublicKey {
-    pub modulus: String,
-    pub exponent: String,
-    sensitive: Option<bool>,
-}
+            // response codes: https://www.eftlab.com/knowledge-base/complete-list-of-apdu-responses/
+            response_status = apdu_response.status;
 
-impl RsaPublicKey {
-    pub fn new(modulus: &[u8], exponent: &[u8], sensitive: bool) -> RsaPublicKey {
-        RsaPublicKey {
-            modulus: hex::encode_upper(modulus),
-            exponent: hex::encode_upper(exponent),
-            sensitive: Some(sensitive),
-        }
-    }
+            debug!(
+                "APDU response status: {}",
+                self.get_apdu_response_localization(response_status)
+            );
 
-    pub fn get_key_byte_size(&self) -> usize {
-        return self.modulus.len() / 2;
-    }
+            if response_data.len() > MAX_RESPONSE_DATA_LENGTH {
+                warn!("APDU response exceeds {} bytes", MAX_RESPONSE_DATA_LENGTH);
+                return Err(TransportError::Protocol(
+                    "response data too long".to_string(),
+                ));
+            }
 
-    pub fn public_encrypt(&self, plaintext_data: &[u8]) -> Result<Vec<u8>, ()> {
-        let pk_modulus_raw = hex::decode(&self.modulus).unwrap();
-        let pk_modulus = BigNum::from_slice(&pk_modulus_raw[..]).unwrap();
-        let pk_exponent = BigNum::from_slice(&(hex::decode(&self.exponent).unwrap())[..]).unwrap();
+            // Automatically query more data, if available from the ICC
+            if response_status.is_bytes_available() {
+                trace!(
+                    "APDU response({} bytes):\n{}",
+                    response_data.len(),
+                    HexViewBuilder::new(&response_data).finish()
+                );
 
-        let rsa = Rsa::from_public_components(pk_modulus, pk_exponent).unwrap();
+                // ref. ISO/IEC 7816-4, 5.3.4 61XX, XX = 00 when 256 or more bytes are still available
+                let available_data_length = match response_status.sw2 {
+                    0x00 => 0x100,
+                    sw2 => sw2 as usize,
+                };
 
-        let mut encrypt_output = [0u8; 4096];
+                // Le 00 queries up to 256 bytes, the ICC keeps on answering 61XX until all is returned
+                apdu_command = CommandApdu::get_response(available_data_length);
+            } else if response_status.is_wrong_length() {
+                trace!(
+                    "APDU response({} bytes):\n{}",
+                    response_data.len(),
+                    HexViewBuilder::new(&response_data).finish()
+                );
 
-        let length =
-            match rsa.public_encrypt(plaintext_data, &mut encrypt_output[..], Padding::NONE) {
-                Ok(length) => length,
-                Err(_) => {
-                    warn!("Could not encrypt data");
-                    return Err(());
+                let available_data_length = response_status.sw2;
+                if available_data_length == 0x00 || wrong_length_retried {
+                    // the command is re-issued only once with the corrected Le
+                    warn!("Unexpected wrong length response");
+                    break;
                 }
-            };
+                wrong_length_retried = true;
 
-        let mut data = Vec::new();
-        data.extend_from_slice(&encrypt_output[..length]);
+                // re-issues the last command, e.g. a GET RESPONSE, the data of the earlier rounds
+                // is kept in the response data
+                apdu_command = apdu_command.clone().with_le(available_data_length as usize);
+            } else {
+                break;
+            }
+        }
 
-        if let Some(true) = self.sensitive {
-            trace!("Encrypt result ({} bytes)", data.len());
+        if self.settings.censor_sensitive_fields {
+            debug!("APDU response({} bytes)", response_data.len());
         } else {
-            trace!(
-                "Encrypt result ({} bytes):\n{}",
-                data.len(),
-                HexViewBuilder::new(&data[..]).finish()
+            debug!(
+                "APDU response({} bytes):\n{}",
+                response_data.len(),
+                HexViewBuilder::new(&response_data).finish()
             );
         }
 
-        if data.len() != pk_modulus_raw.len() {
-            warn!("Data length discrepancy");
-            return Err(());
+        if !response_data.is_empty() {
+            debug!("APDU TLV parse:");
+
+            self.process_tlv_from(
+                &response_data[..],
+                0,
+                TagOrigin::icc(Some(CardCommand::from(command))),
+            );
         }
 
-        Ok(data)
+        Ok(ResponseApdu::new(response_data, response_status))
     }
 
-    pub fn public_decrypt(&self, cipher_data: &[u8]) -> Result<Vec<u8>, ()> {
-        let pk_modulus_raw = hex::decode(&self.modulus).unwrap();
-        let pk_modulus = BigNum::from_slice(&pk_modulus_raw[..]).unwrap();
-        let pk_exponent = BigNum::from_slice(&(hex::decode(&self.exponent).unwrap())[..]).unwrap();
+    pub async fn handle_get_processing_options(&mut self) -> Result<(), EmvError> {
+        //ref. EMV Book 3, 6.5.8 GET PROCESSING OPTIONS Command-Response APDUs
 
-        let rsa = Rsa::from_public_components(pk_modulus, pk_exponent).unwrap();
+        debug!("GET PROCESSING OPTIONS:");
 
-        let mut decrypt_output = [0u8; 4096];
+        let step = TransactionStep::InitiateApplicationProcessing;
 
-        let length = match rsa.public_decrypt(cipher_data, &mut decrypt_output[..], Padding::NONE) {
-            Ok(length) => length,
-            Err(_) => {
-                warn!("Could not decrypt data");
-                return Err(());
+        let pdol_data = match self.get_tag_value("9F38") {
+            Some(tag_9f38_pdol) => {
+                DataObjectList::process_data_object_list(self, &tag_9f38_pdol[..])
+                    .map_err(|_| EmvError::malformed_tlv(step, "9F38"))?
+                    .get_tag_list_tag_values(self)
             }
+            None => Vec::new(),
         };
 
-        let mut data = Vec::new();
-        data.extend_from_slice(&decrypt_output[..length]);
+        // Command Template, ref. EMV Book 3, 6.5.8.3 Data Field Sent in the Command Message
+        let pdol_data_template = TlvBuilder::new()
+            .primitive("83", &pdol_data)
+            .build()
+            .unwrap();
 
-        if let Some(true) = self.sensitive {
-            trace!("Decrypt result ({} bytes)", data.len());
-        } else {
-            trace!(
-                "Decrypt result ({} bytes):\n{}",
-                data.len(),
-                HexViewBuilder::new(&data[..]).finish()
-            );
+        let response = self
+            .send_apdu(&CommandApdu::get_processing_options(&pdol_data_template))
+            .await
+            .map_err(|error| EmvError::Transport { step, error })?;
+        if !response.is_success() {
+            warn!("Could not get processing options");
+            return Err(EmvError::card_status(step, response.status));
         }
+        let response_data = response.data;
 
-        if data.len() != pk_modulus_raw.len() {
-            warn!("Data length discrepancy");
-            return Err(());
+        match response_data.first() {
+            Some(0x80) => {
+                // Format 1: AIP followed by AFL, ref. EMV Book 3, 6.5.8.4 Data Field Returned in the Response Message
+                if response_data.len() < 4 {
+                    warn!("Response format 1 too short");
+                    return Err(EmvError::malformed_tlv(step, "80"));
+                }
+                let origin = TagOrigin::icc(Some(CardCommand::GetProcessingOptions));
+                self.process_tag_as_tlv_from("82", response_data[2..4].to_vec(), origin);
+                self.process_tag_as_tlv_from("94", response_data[4..].to_vec(), origin);
+            }
+            Some(0x77) => {}
+            _ => {
+                warn!("Unrecognized response");
+                return Err(EmvError::MalformedTlv { step, tag: None });
+            }
         }
-        if data[0] != 0x6A {
-            warn!("Data header incorrect");
-            return Err(());
+
+        let tag_94_afl = match self.get_tag_value("94") {
+            Some(tag_94_afl) => tag_94_afl.clone(),
+            None => return Err(self.icc_data_missing(step, "94")),
+        };
+
+        debug!("Read card Application File Locator (AFL) information:");
+
+        let step = TransactionStep::ReadApplicationData;
+
+        if tag_94_afl.is_empty() || tag_94_afl.len() % 4 != 0 {
+            warn!("Invalid AFL length {}", tag_94_afl.len());
+            return Err(EmvError::malformed_tlv(step, "94"));
         }
-        if data[data.len() - 1] != 0xBC {
-            warn!("Data trailer incorrect");
-            return Err(());
+
+        let mut data_authentication: Vec<u8> = Vec::new();
+        let mut records: Vec<u8> = Vec::new();
+        for afl_entry in tag_94_afl.chunks(4) {
+            let short_file_identifier: u8 = afl_entry[0] >> 3;
+            let record_index_start: u8 = afl_entry[1];
+            let record_index_end: u8 = afl_entry[2];
+            let mut data_authentication_records: u8 = afl_entry[3];
+
+            // ref. EMV Book 3, 10.2 Read Application Data
+            if short_file_identifier == 0
+                || short_file_identifier == 31
+                || record_index_start == 0
+                || record_index_end < record_index_start
+                || data_authentication_records > record_index_end - record_index_start + 1
+            {
+                warn!("Invalid AFL entry {:02X?}", afl_entry);
+                return Err(EmvError::malformed_tlv(step, "94"));
+            }
+
+            for record_index in record_index_start..=record_index_end {
+                if let Some(data) = self
+                    .read_record(short_file_identifier, record_index)
+                    .await
+                    .map_err(|error| EmvError::Transport { step, error })?
+                {
+                    if data[0] != 0x70 {
+                        warn!("Expected record template, got {:02X?}", data[0]);
+                        return Err(EmvError::malformed_tlv(step, "70"));
+                    }
+                    if let Some(tag_name) = self.tags.duplicates().first() {
+                        warn!("Duplicate tag {} in records", tag_name);
+                        return Err(EmvError::DuplicateTag {
+                            step,
+                            tag: tag_name.clone(),
+                        });
+                    }
+                    // ref. EMV Book 3, 10.2 Read Application Data - format errors terminate the transaction
+                    if let Some(rejected_value) = self.tags.rejected().iter().find(|rejected| {
+                        rejected.origin.is_record()
+                            && !matches!(rejected.error, TagValidationError::TerminalData { .. })
+                    }) {
+                        return Err(EmvError::InvalidTagValue {
+                            step,
+                            error: rejected_value.error.clone(),
+                        });
+                    }
+                    records.extend(&data);
+
+                    // Add data authentication input
+                    // ref EMV Book 3, 10.3 Offline Data Authentication
+                    if data_authentication_records > 0 {
+                        data_authentication_records -= 1;
+
+                        if short_file_identifier <= 10 {
+                            let tag_70_record = parse_tlv(&data[..])
+                                .ok_or_else(|| EmvError::malformed_tlv(step, "70"))?;
+                            if let Value::Constructed(tag_70_tags) = tag_70_record.value() {
+                                for tag in tag_70_tags {
+                                    data_authentication.extend(tag.to_vec());
+                                }
+                            }
+                        } else {
+                            data_authentication.extend_from_slice(&data[..]);
+                        }
+
+                        if self.settings.censor_sensitive_fields {
+                            trace!("Data authentication building: short_file_identifier:{}, data_authentication_records:{}, record_index:{}/{}, data:{} bytes", short_file_identifier, data_authentication_records, record_index, record_index_end, data_authentication.len());
+                        } else {
+                            trace!("Data authentication building: short_file_identifier:{}, data_authentication_records:{}, record_index:{}/{}, data:{:02X?}", short_file_identifier, data_authentication_records, record_index, record_index_end, data_authentication);
+                        }
+                    }
+                }
+            }
         }
 
-        Ok(data)
-    }
-}
+        if self.settings.censor_sensitive_fields {
+            debug!(
+                "AFL data authentication: {} bytes",
+                data_authentication.len()
+            );
+        } else {
+            debug!(
+                "AFL data authentication:\n{}",
+                HexViewBuilder::new(&data_authentication).finish()
+            );
+        }
 
-#[derive(Serialize, Deserialize)]
-pub struct CertificateAuthority {
-    issuer: String,
-    certificates: HashMap<String, RsaPublicKey>,
-}
+        let tag_82_aip = match self.get_tag_value("82") {
+            Some(tag_82_aip) => tag_82_aip.clone(),
+            None => return Err(self.icc_data_missing(step, "82")),
+        };
+        if tag_82_aip.len() != 2 {
+            warn!("Invalid AIP length {}", tag_82_aip.len());
+            return Err(EmvError::malformed_tlv(step, "82"));
+        }
 
-pub fn is_success_response(response_trailer: &Vec<u8>) -> bool {
-    let mut success = false;
+        let auc_b1: u8 = tag_82_aip[0];
+        // bit 7 = RFU
+        self.icc.capabilities.sda = get_bit!(auc_b1, 6);
+        self.icc.capabilities.dda = get_bit!(auc_b1, 5);
+        if get_bit!(auc_b1, 4) {
+            // Cardholder verification is supported
 
-    if response_trailer.len() >= 2 && response_trailer[0] == 0x90 && response_trailer[1] == 0x00 {
-        success = true;
-    }
+            let tag_8e_cvm_list = match self.get_tag_value("8E") {
+                Some(tag_8e_cvm_list) => tag_8e_cvm_list.clone(),
+                None => return Err(self.icc_data_missing(step, "8E")),
+            };
+            if tag_8e_cvm_list.len() < 8 || tag_8e_cvm_list.len() % 2 != 0 {
+                warn!("Invalid CVM list length {}", tag_8e_cvm_list.len());
+                return Err(EmvError::malformed_tlv(step, "8E"));
+            }
 
-    success
-}
+            // amounts X and Y are binary, ref. EMV Book 3, 10.5 Cardholder Verification
+            let amount_x = u64::from(u32::from_be_bytes([
+                tag_8e_cvm_list[0],
+                tag_8e_cvm_list[1],
+                tag_8e_cvm_list[2],
+                tag_8e_cvm_list[3],
+            ]));
+            let amount_y = u64::from(u32::from_be_bytes([
+                tag_8e_cvm_list[4],
+                tag_8e_cvm_list[5],
+                tag_8e_cvm_list[6],
+                tag_8e_cvm_list[7],
+            ]));
 
-fn parse_tlv(raw_data: &[u8]) -> Option<Tlv> {
-    let (tlv_data, leftover_buffer) = Tlv::parse(raw_data);
-    if leftover_buffer.len() > 0 {
-        trace!("Could not parse as TLV: {:02X?}", leftover_buffer);
-    }
+            let tag_84_cvm_rules = &tag_8e_cvm_list[8..];
+            for cvm_rule in tag_84_cvm_rules.chunks(2) {
+                let cvm_code = cvm_rule[0];
+                let cvm_condition_code = cvm_rule[1];
 
-    let tlv_data: Option<Tlv> = match tlv_data {
-        Ok(tlv) => Some(tlv),
-        Err(_) => None,
-    };
+                // bit 7 = RFU
+                let fail_if_unsuccessful = !get_bit!(cvm_code, 6);
+                let cvm_code = (cvm_code << 2) >> 2;
+                let code: CvmCode = cvm_code
+                    .try_into()
+                    .unwrap_or(CvmCode::Unrecognised(cvm_code));
+                let condition: CvmConditionCode = match cvm_condition_code.try_into() {
+                    Ok(condition) => condition,
+                    Err(_) => {
+                        // conditions not understood are bypassed
+                        debug!("Unrecognised CVM condition {:02X}", cvm_condition_code);
+                        continue;
+                    }
+                };
 
-    return tlv_data;
-}
+                let rule = CvmRule {
+                    amount_x: amount_x,
+                    amount_y: amount_y,
+                    fail_if_unsuccessful: fail_if_unsuccessful,
+                    code: code,
+                    condition: condition,
+                };
+                self.icc.cvm_rules.push(rule);
+            }
+        }
+        self.icc.capabilities.terminal_risk_management = get_bit!(auc_b1, 3);
+        // Issuer Authentication using the EXTERNAL AUTHENTICATE command is supported
+        self.icc.capabilities.issuer_authentication = get_bit!(auc_b1, 2);
+        // bit 1 = RFU
+        self.icc.capabilities.cda = get_bit!(auc_b1, 0);
+
+        if let Some(tag_9f07_application_usage_control) = self.get_tag_value("9F07") {
+            self.icc.usage = tag_9f07_application_usage_control.to_vec().into();
+        }
+
+        debug!("{:?}", self.icc);
+
+        // 5 - 0 bits are RFU
+
+        self.icc.data_authentication = Some(data_authentication);
+
+        Ok(())
+    }
+
+    pub async fn handle_verify_plaintext_pin(&mut self, ascii_pin: &[u8]) -> Result<(), EmvError> {
+        debug!("Verify plaintext PIN:");
+
+        let pin_bcd_cn =
+            bcdutil::ascii_to_bcd_cn(ascii_pin, 6).map_err(|_| EmvError::Terminal {
+                step: TransactionStep::CardholderVerification,
+                reason: "PIN is not numeric",
+            })?;
+
+        let p2_pin_type_qualifier = 0b1000_0000;
+        let mut pin_block: Vec<u8> = Vec::with_capacity(8);
+        pin_block.push(0b0010_0000 + ascii_pin.len() as u8); // control + PIN length
+        pin_block.extend_from_slice(&pin_bcd_cn[..]);
+        pin_block.push(0xFF); // filler
+
+        self.send_verify(CommandApdu::verify(p2_pin_type_qualifier, &pin_block))
+            .await
+    }
+
+    async fn send_verify(&mut self, verify_command: CommandApdu) -> Result<(), EmvError> {
+        let response =
+            self.send_apdu(&verify_command)
+                .await
+                .map_err(|error| EmvError::Transport {
+                    step: TransactionStep::CardholderVerification,
+                    error,
+                })?;
+        if !response.is_success() {
+            warn!("Could not verify PIN");
+            //Incorrect PIN = 63, C4
+            if let Some(retries_left) = response.status.retries_left() {
+                warn!("PIN tries left: {}", retries_left);
+            }
+            return Err(EmvError::CardholderVerification {
+                sw1: response.status.sw1,
+                sw2: response.status.sw2,
+            });
+        }
+
+        info!("Pin OK");
+        Ok(())
+    }
+
+    pub async fn handle_verify_enciphered_pin(&mut self, ascii_pin: &[u8]) -> Result<(), EmvError> {
+        debug!("Verify enciphered PIN:");
+
+        let pin_bcd_cn =
+            bcdutil::ascii_to_bcd_cn(ascii_pin, 6).map_err(|_| EmvError::Terminal {
+                step: TransactionStep::CardholderVerification,
+                reason: "PIN is not numeric",
+            })?;
+
+        if self.icc.icc_pin_pk.is_none() {
+            warn!("ICC PIN encipherment key missing");
+            return Err(EmvError::Terminal {
+                step: TransactionStep::CardholderVerification,
+                reason: "ICC PIN encipherment key missing",
+            });
+        }
+
+        const PK_MAX_SIZE: usize = 248; // ref. EMV Book 2, B2.1 RSA Algorithm
+        let mut random_padding = [0u8; PK_MAX_SIZE];
+        self.fill_random(&mut random_padding[..]);
+
+        let icc_unpredictable_number = self
+            .handle_get_challenge(TransactionStep::CardholderVerification)
+            .await?;
+
+        // EMV Book 2, 7.1 Keys and Certificates, 7.2 PIN Encipherment and Verification
+
+        let mut plaintext_data = Vec::new();
+        plaintext_data.push(0x7F);
+        // PIN block
+        plaintext_data.push(0b0010_0000 + ascii_pin.len() as u8); // control + PIN length
+        plaintext_data.extend_from_slice(&pin_bcd_cn[..]);
+        plaintext_data.push(0xFF);
+        // ICC Unpredictable Number
+        plaintext_data.extend_from_slice(&icc_unpredictable_number[..]);
+        // Random padding
+        plaintext_data.extend_from_slice(
+            &random_padding[0..self.icc.icc_pin_pk.as_ref().unwrap().get_key_byte_size() - 17],
+        );
+
+        let ciphered_pin_data = self
+            .icc
+            .icc_pin_pk
+            .as_ref()
+            .unwrap()
+            .public_encrypt(&plaintext_data[..])
+            .map_err(|_| EmvError::Terminal {
+                step: TransactionStep::CardholderVerification,
+                reason: "PIN encipherment failed",
+            })?;
+
+        let p2_pin_type_qualifier = 0b1000_1000;
+
+        self.send_verify(CommandApdu::verify(
+            p2_pin_type_qualifier,
+            &ciphered_pin_data,
+        ))
+        .await
+    }
+
+    // ref. EMV Book 3, 6.5.5 GENERATE APPLICATION CRYPTOGRAM
+    // ref. EMV Contactless Book C-2, 7.6 Procedure – Prepare Generate AC Command
+    async fn send_generate_ac(
+        &mut self,
+        step: TransactionStep,
+        requested_cryptogram_type: CryptogramType,
+        cdol_tag: &str,
+    ) -> Result<CryptogramType, EmvError> {
+        let mut p1_reference_control_parameter: u8 = requested_cryptogram_type.into();
+        if self.icc.capabilities.cda {
+            set_bit!(
+                p1_reference_control_parameter,
+                4,
+                self.settings.terminal.capabilities.cda
+            );
+        }
+
+        let cdol_list = DataObjectList::process_data_object_list(
+            self,
+            &self
+                .get_tag_value(cdol_tag)
+                .ok_or_else(|| EmvError::missing_tag(step, cdol_tag))?[..],
+        )
+        .map_err(|_| EmvError::malformed_tlv(step, cdol_tag))?;
+
+        if cdol_list.has_tag("9F4C") {
+            // GET CHALLENGE might be needed to the 9F4C value
+            if let None = self.get_tag_value("9F4C") {
+                let tag_9f4c_icc_dynamic_number = self.handle_get_challenge(step).await?;
+                self.process_tag_as_tlv_from(
+                    "9F4C",
+                    tag_9f4c_icc_dynamic_number,
+                    TagOrigin::icc(Some(CardCommand::GetChallenge)),
+                );
+            }
+        }
+
+        if cdol_list.has_tag("98") {
+            self.handle_tc_hash_value(step)?;
+        }
+
+        let cdol_data = cdol_list.get_tag_list_tag_values(self);
+
+        let response = self
+            .send_apdu(&CommandApdu::generate_ac(
+                p1_reference_control_parameter,
+                &cdol_data,
+            ))
+            .await
+            .map_err(|error| EmvError::Transport { step, error })?;
+        if !response.is_success() {
+            // 67 00 = wrong length (i.e. CDOL data incorrect)
+            warn!("Could not process generate ac");
+            return Err(EmvError::card_status(step, response.status));
+        }
+        let response_data = response.data;
+
+        match response_data.first() {
+            Some(0x80) => {
+                // Format 1: CID, ATC, AC and optional IAD, ref. EMV Book 3, 6.5.5.4 Data Field Returned in the Response Message
+                if response_data.len() < 13 {
+                    warn!("Response format 1 too short");
+                    return Err(EmvError::malformed_tlv(step, "80"));
+                }
+                let origin = TagOrigin::icc(Some(CardCommand::GenerateAc));
+                self.process_tag_as_tlv_from("9F27", response_data[2..3].to_vec(), origin);
+                self.process_tag_as_tlv_from("9F36", response_data[3..5].to_vec(), origin);
+                self.process_tag_as_tlv_from("9F26", response_data[5..13].to_vec(), origin);
+                if response_data.len() > 13 {
+                    self.process_tag_as_tlv_from("9F10", response_data[13..].to_vec(), origin);
+                }
+            }
+            Some(0x77) => {}
+            _ => {
+                warn!("Unrecognized response");
+                return Err(EmvError::MalformedTlv { step, tag: None });
+            }
+        }
+
+        if get_bit!(p1_reference_control_parameter, 4) {
+            let icc_cryptogram_type = self
+                .get_cryptogram_information_data(step)?
+                .cryptogram_type
+                .ok_or_else(|| EmvError::malformed_tlv(step, "9F27"))?;
+
+            match icc_cryptogram_type {
+                CryptogramType::TransactionCertificate
+                | CryptogramType::AuthorisationRequestCryptogram => {
+                    self.handle_application_cryptogram_card_authentication(
+                        &response_data[..],
+                        cdol_tag,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        self.validate_ac(step, requested_cryptogram_type)
+    }
+
+    pub async fn handle_1st_generate_ac(
+        &mut self,
+        requested_cryptogram_type: CryptogramType,
+    ) -> Result<CryptogramType, EmvError> {
+        debug!("Generate Application Cryptogram (GENERATE AC) - first issuance:");
+
+        let step = TransactionStep::CardActionAnalysis;
+
+        let icc_cryptogram_type;
+        if self.contactless && self.get_tag_value("9F26").is_some() {
+            debug!("Application Cryptogram returned in GET PROCESSING OPTIONS");
+            // ref. EMV Contactless Book C-3, A.2 Data Elements by Name - cryptogram returned in GET PROCESSING OPTIONS (Kernel 3, Visa)
+            icc_cryptogram_type = self.validate_ac(step, requested_cryptogram_type)?;
+        } else {
+            icc_cryptogram_type = self
+                .send_generate_ac(step, requested_cryptogram_type, "8C")
+                .await?;
+            self.settings
+                .terminal
+                .tsi
+                .card_risk_management_was_performed = true;
+
+            if let CryptogramType::AuthorisationRequestCryptogram = icc_cryptogram_type {
+                // handle_2nd_generate_ac needed
+            } else if let CryptogramType::AuthorisationRequestCryptogram = requested_cryptogram_type
+            {
+                warn!("Transaction terminated by terminal - ARQC requested but got unexpected return type from ICC");
+                return Err(EmvError::UnexpectedCryptogram {
+                    step,
+                    cryptogram_type: icc_cryptogram_type,
+                });
+            }
+        }
+
+        Ok(icc_cryptogram_type)
+    }
+
+    pub async fn handle_2nd_generate_ac(&mut self) -> Result<CryptogramType, EmvError> {
+        debug!("Generate Application Cryptogram (GENERATE AC) - second issuance:");
+
+        let step = TransactionStep::Completion;
+
+        // ref. EMV Book 3, 10.11 Completion - AAC is requested for a declined authorisation
+        let requested_cryptogram_type = match self.get_tag_value("8A") {
+            Some(tag_8a_authorisation_response_code)
+                if !is_authorisation_approved(tag_8a_authorisation_response_code) =>
+            {
+                CryptogramType::ApplicationAuthenticationCryptogram
+            }
+            _ => self.settings.terminal.cryptogram_type_arqc,
+        };
+
+        let icc_cryptogram_type = self
+            .send_generate_ac(step, requested_cryptogram_type, "8D")
+            .await?;
+
+        if let CryptogramType::TransactionCertificate = icc_cryptogram_type {
+            return Ok(icc_cryptogram_type);
+        }
+
+        warn!("Transaction has unexpected return type from ICC");
+        Err(EmvError::UnexpectedCryptogram {
+            step,
+            cryptogram_type: icc_cryptogram_type,
+        })
+    }
+
+    async fn read_record(
+        &mut self,
+        short_file_identifier: u8,
+        record_index: u8,
+    ) -> Result<Option<Vec<u8>>, TransportError> {
+        let mut records: Vec<u8> = Vec::new();
+
+        let response = self
+            .send_apdu(&CommandApdu::read_record(
+                short_file_identifier,
+                record_index,
+            ))
+            .await?;
+
+        if response.is_success() {
+            records.extend_from_slice(&response.data);
+        }
+
+        if !records.is_empty() {
+            return Ok(Some(records));
+        }
+
+        Ok(None)
+    }
+
+    // ref. EMV Contactless Book B, 3.3.2 - the candidate list is empty when the PPSE is not available
+    pub async fn handle_combination_selection(
+        &mut self,
+        combinations: &[Combination],
+    ) -> Result<Vec<Candidate>, EmvError> {
+        let step = TransactionStep::ApplicationSelection;
+
+        debug!("Selecting Proximity Payment System Environment (PPSE):");
+        let ppse_name = "2PAY.SYS.DDF01";
+
+        let response = self
+            .send_apdu_select(ppse_name.as_bytes(), SELECT_FIRST_OCCURRENCE)
+            .await
+            .map_err(|error| EmvError::Transport { step, error })?;
+        if !response.is_success() {
+            warn!("Could not select {:?}", ppse_name);
+            return Ok(Vec::new());
+        }
+
+        Ok(entry_point::build_candidate_list(
+            combinations,
+            &response.data,
+        ))
+    }
+
+    // ref. EMV Contactless Book B, 3.3.3 Final Combination Selection and 3.4 Kernel Activation - the
+    // selected candidate is removed from the list, None when no candidate could be selected
+    pub async fn handle_final_combination_selection(
+        &mut self,
+        candidates: &mut Vec<Candidate>,
+    ) -> Result<Option<KernelActivation>, EmvError> {
+        let step = TransactionStep::ApplicationSelection;
+
+        while let Some(index) = entry_point::highest_priority_candidate(candidates) {
+            let candidate = candidates.remove(index);
+            let kernel_id = candidate.combination.kernel_id;
+
+            let mut adf_name = candidate.application.aid.clone();
+            if let Some(tag_9f29_extended_selection) = &candidate.extended_selection {
+                adf_name.extend_from_slice(tag_9f29_extended_selection);
+            }
+
+            info!(
+                "Selecting application. ADF Name:{:02X?}, label:{:?}, kernel:{}",
+                adf_name,
+                String::from_utf8_lossy(&candidate.application.label),
+                kernel_id
+            );
+            // nothing of a previously selected combination applies to this one
+            self.tags.remove("9F66");
+            self.terminal_application = None;
+            self.tag_scope = Some(TagScope::KernelId(kernel_id));
+            let response = self
+                .send_apdu_select(&adf_name, SELECT_FIRST_OCCURRENCE)
+                .await
+                .map_err(|error| EmvError::Transport { step, error })?;
+            if !response.is_success() {
+                warn!("Could not select ADF Name {:02X?}", adf_name);
+                continue;
+            }
+
+            self.terminal_application = Some(candidate.combination.terminal_application);
+            if let Some(tag_9f66_ttq) = &candidate
+                .combination
+                .indicators
+                .terminal_transaction_qualifiers
+            {
+                self.process_tag_as_tlv("9F66", tag_9f66_ttq.clone());
+            }
+
+            return Ok(Some(KernelActivation {
+                kernel_id,
+                application: candidate.application,
+                indicators: candidate.combination.indicators,
+                fci: response.data,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    pub async fn handle_select_payment_system_environment(
+        &mut self,
+    ) -> Result<Vec<EmvApplication>, EmvError> {
+        let step = TransactionStep::ApplicationSelection;
+
+        debug!("Selecting Payment System Environment (PSE):");
+        let contact_pse_name = "1PAY.SYS.DDF01";
+        let contactless_pse_name = "2PAY.SYS.DDF01";
+
+        let mut pse_name = contact_pse_name;
+
+        self.reset_card_session();
+
+        if self.contactless {
+            self.contactless = true;
+            pse_name = contactless_pse_name;
+        }
+
+        let response = self
+            .send_apdu_select(&pse_name.as_bytes(), SELECT_FIRST_OCCURRENCE)
+            .await
+            .map_err(|error| EmvError::Transport { step, error })?;
+        if !response.is_success() {
+            warn!("Could not select {:?}", pse_name);
+            return Err(EmvError::card_status(step, response.status));
+        }
+        let response_data = response.data;
+
+        let mut all_applications: Vec<EmvApplication> = Vec::new();
+
+        if self.contactless {
+            //EMV Contactless Book B, Entry Point Specification v2.6, Table3-2: SELECT Response Message Data Field (FCI) of the PPSE
+            match find_tlv_tag(&response_data, "BF0C") {
+                Some(tag_bf0c) => {
+                    if let Value::Constructed(application_templates) = tag_bf0c.value() {
+                        for tag_61_application_template in application_templates {
+                            if let Value::Constructed(application_template) =
+                                tag_61_application_template.value()
+                            {
+                                let candidate_tags = parse_application_template(
+                                    application_template,
+                                    TagOrigin::icc(Some(CardCommand::Select)),
+                                );
+
+                                let tag_4f_aid = candidate_tags
+                                    .get("4F")
+                                    .ok_or_else(|| EmvError::missing_tag(step, "4F"))?;
+                                let tag_50_label = match candidate_tags.get("50") {
+                                    Some(v) => v,
+                                    None => "UNKNOWN".as_bytes(),
+                                };
+
+                                //EMV Contactless Book B, Entry Point Specification v2.6, Table3-3: Format of Application Priority Indicator
+                                let tag_87_priority =
+                                    candidate_tags.get("87").cloned().unwrap_or_default();
+
+                                all_applications.push(EmvApplication {
+                                    aid: tag_4f_aid.clone(),
+                                    label: tag_50_label.to_vec(),
+                                    priority: tag_87_priority,
+                                    tags: candidate_tags,
+                                });
+                            }
+                        }
+                    }
+                }
+                None => {
+                    warn!("Expected tag BF0C not found! pse:{}", pse_name);
+                    return Err(EmvError::missing_tag(step, "BF0C"));
+                }
+            }
+        } else {
+            let sfi_data = self
+                .get_tag_value("88")
+                .ok_or_else(|| EmvError::missing_tag(step, "88"))?
+                .clone();
+            if sfi_data.len() != 1 {
+                warn!("Invalid SFI {:02X?}", sfi_data);
+                return Err(EmvError::malformed_tlv(step, "88"));
+            }
+            let short_file_identifier = sfi_data[0];
+
+            debug!("Read available AIDs:");
+
+            for record_index in 0x01..0xFF {
+                match self
+                    .read_record(short_file_identifier, record_index)
+                    .await
+                    .map_err(|error| EmvError::Transport { step, error })?
+                {
+                    Some(data) => {
+                        if data[0] != 0x70 {
+                            warn!("Expected template data");
+                            return Err(EmvError::MalformedTlv { step, tag: None });
+                        }
+
+                        let tag_70_record =
+                            parse_tlv(&data).ok_or_else(|| EmvError::malformed_tlv(step, "70"))?;
+                        if let Value::Constructed(application_templates) = tag_70_record.value() {
+                            for tag_61_application_template in application_templates {
+                                if let Value::Constructed(application_template) =
+                                    tag_61_application_template.value()
+                                {
+                                    let candidate_tags = parse_application_template(
+                                        application_template,
+                                        TagOrigin::icc(Some(CardCommand::ReadRecord {
+                                            short_file_identifier,
+                                            record_index,
+                                        })),
+                                    );
+
+                                    let tag_4f_aid = candidate_tags
+                                        .get("4F")
+                                        .ok_or_else(|| EmvError::missing_tag(step, "4F"))?;
+                                    let default_label = "UNKNOWN".as_bytes().to_vec();
+                                    let tag_50_label =
+                                        candidate_tags.get("50").unwrap_or(&default_label);
+
+                                    // ref. EMV Book 1, 12.2.3 - the Application Priority Indicator is optional
+                                    let tag_87_priority =
+                                        candidate_tags.get("87").cloned().unwrap_or_default();
+
+                                    all_applications.push(EmvApplication {
+                                        aid: tag_4f_aid.clone(),
+                                        label: tag_50_label.clone(),
+                                        priority: tag_87_priority,
+                                        tags: candidate_tags,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                    None => break,
+                };
+            }
+        }
+
+        if all_applications.is_empty() {
+            warn!("No application records found!");
+            return Err(EmvError::Terminal {
+                step,
+                reason: "No applications found",
+            });
+        }
+
+        Ok(all_applications)
+    }
+
+    // ref. EMV Book 1, 12.3.3 Using a List of AIDs
+    pub async fn handle_select_list_of_aids(&mut self) -> Result<Vec<EmvApplication>, EmvError> {
+        let step = TransactionStep::ApplicationSelection;
+
+        debug!("Selecting applications by the list of AIDs:");
+
+        let mut all_applications: Vec<EmvApplication> = Vec::new();
+
+        for terminal_application in self.settings.terminal.applications.clone() {
+            let terminal_aid = match hex::decode(&terminal_application.aid) {
+                Ok(aid) => aid,
+                Err(_) => {
+                    warn!("Invalid terminal AID {:?}", terminal_application.aid);
+                    continue;
+                }
+            };
+
+            let mut p2_selection_options = SELECT_FIRST_OCCURRENCE;
+            let mut selected_df_names: Vec<Vec<u8>> = Vec::new();
+
+            loop {
+                let response = self
+                    .send_apdu_select(&terminal_aid, p2_selection_options)
+                    .await
+                    .map_err(|error| EmvError::Transport { step, error })?;
+
+                // card blocked or SELECT not supported, the terminal terminates the session
+                if response.status == StatusWord::new(0x6A, 0x81) {
+                    warn!("SELECT not supported by the card");
+                    return Err(EmvError::card_status(step, response.status));
+                }
+
+                let application_blocked = response.status == StatusWord::new(0x62, 0x83);
+                if !response.is_success() && !application_blocked {
+                    break;
+                }
+
+                let candidate_tags = match parse_adf_fci(
+                    &response.data,
+                    TagOrigin::icc(Some(CardCommand::Select)),
+                ) {
+                    Some(tags) => tags,
+                    None => {
+                        warn!("Invalid FCI for AID {:02X?}", terminal_aid);
+                        break;
+                    }
+                };
+                let tag_84_df_name = match candidate_tags.get("84") {
+                    Some(df_name) => df_name.clone(),
+                    None => {
+                        warn!("DF Name missing for AID {:02X?}", terminal_aid);
+                        break;
+                    }
+                };
+
+                // the same ADF returned again, the card does not support next occurrence
+                if selected_df_names.contains(&tag_84_df_name) {
+                    break;
+                }
+                selected_df_names.push(tag_84_df_name.clone());
+
+                let exact_match = tag_84_df_name == terminal_aid;
+
+                if application_blocked {
+                    debug!("Application blocked. DF Name:{:02X?}", tag_84_df_name);
+                } else if terminal_application.matches(&tag_84_df_name) {
+                    let default_label = "UNKNOWN".as_bytes().to_vec();
+                    let tag_50_label = candidate_tags.get("50").unwrap_or(&default_label).clone();
+                    let tag_87_priority = candidate_tags.get("87").cloned().unwrap_or_default();
+
+                    all_applications.push(EmvApplication {
+                        aid: tag_84_df_name,
+                        label: tag_50_label,
+                        priority: tag_87_priority,
+                        tags: candidate_tags,
+                    });
+                }
+
+                // other applications only have a longer DF Name
+                if exact_match {
+                    break;
+                }
+
+                p2_selection_options = SELECT_NEXT_OCCURRENCE;
+            }
+        }
+
+        Ok(all_applications)
+    }
+
+    pub async fn handle_select_payment_application(
+        &mut self,
+        application: &EmvApplication,
+    ) -> Result<(), EmvError> {
+        info!(
+            "Selecting application. AID:{:02X?}, label:{:?}, priority:{:02X?}",
+            application.aid,
+            String::from_utf8_lossy(&application.label),
+            application.priority
+        );
+        self.tag_scope = Some(TagScope::of_application(application));
+        self.terminal_application = self
+            .settings
+            .terminal
+            .application(&application.aid)
+            .cloned();
+        let response = self
+            .send_apdu_select(&application.aid, SELECT_FIRST_OCCURRENCE)
+            .await
+            .map_err(|error| EmvError::Transport {
+                step: TransactionStep::ApplicationSelection,
+                error,
+            })?;
+        if !response.is_success() {
+            warn!(
+                "Could not select payment application! {:02X?}, {:?}",
+                application.aid, application.label
+            );
+            return Err(EmvError::card_status(
+                TransactionStep::ApplicationSelection,
+                response.status,
+            ));
+        }
+
+        Ok(())
+    }
+
+    // ref. EMV Book 1, 12.3 Building the Candidate List
+    pub async fn build_candidate_list(&mut self) -> Result<Vec<EmvApplication>, EmvError> {
+        let pse_error = match self.handle_select_payment_system_environment().await {
+            Ok(applications) => return Ok(applications),
+            Err(error) => error,
+        };
+
+        // the list of AIDs is used when the PSE is not found or has no applications, 6A81 ends the session
+        let blocked = matches!(
+            pse_error,
+            EmvError::CardStatus {
+                sw1: 0x6A,
+                sw2: 0x81,
+                ..
+            }
+        );
+        if self.contactless || blocked || matches!(pse_error, EmvError::Transport { .. }) {
+            return Err(pse_error);
+        }
+
+        let applications = self.handle_select_list_of_aids().await?;
+        if applications.is_empty() {
+            warn!("No applications found by the list of AIDs");
+            return Err(pse_error);
+        }
+
+        Ok(applications)
+    }
+
+    // ref. EMV Book 1, 12.4 Final Selection
+    pub async fn select_payment_application(&mut self) -> Result<EmvApplication, EmvError> {
+        let step = TransactionStep::ApplicationSelection;
+
+        let mut applications = self.build_candidate_list().await?;
+
+        // stable sort, equal priorities stay in the order of the card
+        applications.sort_by_key(|application| application.priority_order());
+
+        loop {
+            // ref. EMV Book 1, 12.4 Final Selection - the cardholder selects from a list of more than
+            // one application, otherwise the terminal selects the one of the highest priority
+            // ref. EMV Contactless Book B, 3.3.3 Final Combination Selection - no cardholder interaction
+            let cardholder_selection = !self.contactless && applications.len() > 1;
+            let application = if cardholder_selection {
+                self.cardholder_interface
+                    .select_application(&applications)
+                    .map_err(|_| EmvError::Terminal {
+                        step,
+                        reason: "Application selection cancelled",
+                    })?
+            } else {
+                applications[0].clone()
+            };
+
+            // b8 of the Application Priority Indicator, only for an application not selected by
+            // the cardholder
+            let result = if !self.contactless
+                && !cardholder_selection
+                && application.confirmation_required()
+                && !self.cardholder_interface.confirm_application(&application)
+            {
+                Err(EmvError::Terminal {
+                    step,
+                    reason: "Application not confirmed",
+                })
+            } else {
+                self.handle_select_payment_application(&application).await
+            };
+
+            let error = match result {
+                Ok(_) => return Ok(application),
+                // ref. EMV Book 1, 12.3.2 - blocked card, no other application can be selected
+                Err(error @ EmvError::Transport { .. })
+                | Err(
+                    error @ EmvError::CardStatus {
+                        sw1: 0x6A,
+                        sw2: 0x81,
+                        ..
+                    },
+                ) => return Err(error),
+                Err(error) => error,
+            };
+
+            // the application is removed from the candidate list, e.g. blocked (6283) or not confirmed
+            warn!("Application not selected: {}", error);
+            let candidates = applications.len();
+            applications.retain(|candidate| candidate.aid != application.aid);
+            if applications.is_empty() || applications.len() == candidates {
+                return Err(error);
+            }
+
+            self.cardholder_interface.display_message("Try again");
+        }
+    }
+
+    pub async fn handle_get_data(
+        &mut self,
+        step: TransactionStep,
+        tag: &[u8],
+    ) -> Result<Vec<u8>, EmvError> {
+        debug!("GET DATA:");
+
+        //allowed tags: 9F36, 9F13, 9F17 or 9F4F
+        if tag.len() != 2 || tag[0] != 0x9F {
+            warn!("Invalid GET DATA tag {:02X?}", tag);
+            return Err(EmvError::Terminal {
+                step,
+                reason: "Invalid GET DATA tag",
+            });
+        }
+
+        let response = self
+            .send_apdu(&CommandApdu::get_data([tag[0], tag[1]], 0x05))
+            .await
+            .map_err(|error| EmvError::Transport { step, error })?;
+        if !response.is_success() {
+            // 67 00 = wrong length (i.e. CDOL data incorrect)
+            warn!("Could not process get data");
+            return Err(EmvError::card_status(step, response.status));
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        output.extend_from_slice(&response.data);
+
+        Ok(output)
+    }
+
+    pub async fn handle_get_challenge(
+        &mut self,
+        step: TransactionStep,
+    ) -> Result<Vec<u8>, EmvError> {
+        debug!("GET CHALLENGE:");
+
+        let response = self
+            .send_apdu(&CommandApdu::get_challenge())
+            .await
+            .map_err(|error| EmvError::Transport { step, error })?;
+        if !response.is_success() {
+            // 67 00 = wrong length (i.e. CDOL data incorrect)
+            warn!("Could not process get challenge");
+            return Err(EmvError::card_status(step, response.status));
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        output.extend_from_slice(&response.data);
+
+        Ok(output)
+    }
+
+    pub async fn handle_dynamic_data_authentication(&mut self) -> Result<(), EmvError> {
+        let step = TransactionStep::OfflineDataAuthentication;
+
+        let mut auth_data: Vec<u8> = Vec::new();
+
+        if let Some(tag_9f69_card_authentication_related_data) = self.get_tag_value("9F69") {
+            // ref. EMV Contactless Book C-3, Annex C Fast Dynamic Data Authentication (fDDA)
+            // ref. EMV Contactless Book C-7, Annex B Fast Dynamic Data Authentication (fDDA)
+
+            debug!("Perform Fast Dynamic Data Authentication (fDDA):");
+
+            if !self.contactless {
+                warn!("fDDA expected only for contactless");
+                return Err(EmvError::data_authentication(
+                    step,
+                    "9F69",
+                    "fDDA is supported only for contactless",
+                ));
+            }
+
+            if tag_9f69_card_authentication_related_data.first() != Some(&0x01) {
+                warn!(
+                    "fDDA version not recognized:{:02X?}",
+                    tag_9f69_card_authentication_related_data.first()
+                );
+                return Err(EmvError::data_authentication(
+                    step,
+                    "9F69",
+                    "fDDA version not recognized",
+                ));
+            }
+
+            for tag_name in ["9F37", "9F02", "5F2A"] {
+                auth_data.extend_from_slice(
+                    &self
+                        .get_tag_value(tag_name)
+                        .ok_or_else(|| EmvError::missing_tag(step, tag_name))?[..],
+                );
+            }
+            auth_data.extend_from_slice(&tag_9f69_card_authentication_related_data[..]);
+        } else {
+            debug!("Perform Dynamic Data Authentication (DDA):");
+
+            let ddol_default_value = match self
+                .terminal_application
+                .as_ref()
+                .and_then(|terminal_application| terminal_application.default_ddol.as_ref())
+            {
+                Some(default_ddol) => {
+                    hex::decode(default_ddol).map_err(|_| EmvError::Terminal {
+                        step,
+                        reason: "Invalid default DDOL in settings",
+                    })?
+                }
+                None => b"\x9f\x37\x04".to_vec(),
+            };
+            let tag_9f49_ddol = match self.get_tag_value("9F49") {
+                Some(ddol) => ddol,
+                // fall-back to a default DDOL
+                None => &ddol_default_value,
+            };
+
+            let ddol_data = DataObjectList::process_data_object_list(self, &tag_9f49_ddol[..])
+                .map_err(|_| EmvError::malformed_tlv(step, "9F49"))?
+                .get_tag_list_tag_values(self);
+
+            auth_data.extend_from_slice(&ddol_data[..]);
+
+            let response = self
+                .send_apdu(&CommandApdu::internal_authenticate(&auth_data))
+                .await
+                .map_err(|error| EmvError::Transport { step, error })?;
+            if !response.is_success() {
+                warn!("Could not process internal authenticate");
+                return Err(EmvError::card_status(step, response.status));
+            }
+            let response_data = response.data;
+
+            match response_data.first() {
+                Some(0x80) => {
+                    // Format 1: Signed Dynamic Application Data only
+                    let tag_80_response = parse_tlv(&response_data[..])
+                        .ok_or_else(|| EmvError::malformed_tlv(step, "80"))?;
+                    if let Value::Primitive(tag_9f4b_signed_data) = tag_80_response.value() {
+                        self.process_tag_as_tlv_from(
+                            "9F4B",
+                            tag_9f4b_signed_data.to_vec(),
+                            TagOrigin::icc(Some(CardCommand::InternalAuthenticate)),
+                        );
+                    }
+                }
+                Some(0x77) => {}
+                _ => {
+                    warn!("Unrecognized response");
+                    return Err(EmvError::MalformedTlv { step, tag: None });
+                }
+            }
+        }
+
+        let tag_9f4b_signed_data_decrypted_dynamic_data =
+            self.validate_signed_dynamic_application_data(step, &auth_data[..])?;
+
+        let tag_9f4c_icc_dynamic_number = tag_9f4b_signed_data_decrypted_dynamic_data
+            .get(1..)
+            .unwrap_or_default();
+        self.process_tag_as_tlv_from(
+            "9F4C",
+            tag_9f4c_icc_dynamic_number.to_vec(),
+            TagOrigin::icc(None),
+        );
+
+        Ok(())
+    }
+
+    pub async fn start_transaction(
+        &mut self,
+        application: &EmvApplication,
+    ) -> Result<(), EmvError> {
+        let step = TransactionStep::InitiateApplicationProcessing;
+
+        self.process_settings().map_err(|_| EmvError::Terminal {
+            step,
+            reason: "Invalid default tag value in settings",
+        })?;
+
+        let cardholder_interface = self.cardholder_interface.clone();
+        cardholder_interface
+            .start_transaction(self)
+            .map_err(|_| EmvError::Terminal {
+                step,
+                reason: "Transaction cancelled",
+            })?;
+
+        self.handle_get_processing_options().await?;
+
+        // ref. EMV Book 3, 10.3 Offline Data Authentication - key retrieval failure fails the ODA, not the transaction
+        if let Err(err) = self.handle_public_keys(application) {
+            warn!("Public key retrieval failed: {}", err);
+
+            if let EmvError::MissingTag { .. } = err {
+                self.settings.terminal.tvr.icc_data_missing = true;
+            }
+            self.set_offline_data_authentication_failed();
+        }
+
+        Ok(())
+    }
+
+    pub async fn handle_card_verification_methods(&mut self) -> Result<(), EmvError> {
+        let step = TransactionStep::CardholderVerification;
+
+        let tag_9f02_amount_authorised = self
+            .get_tag_value("9F02")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F02"))?;
+        let purchase_amount = bcdutil::bcd_to_ascii(&tag_9f02_amount_authorised[..])
+            .ok()
+            .and_then(|ascii_amount| str::from_utf8(&ascii_amount[..]).ok()?.parse::<u64>().ok())
+            .ok_or_else(|| EmvError::malformed_tlv(step, "9F02"))?;
+
+        let cvm_rules = self.icc.cvm_rules.clone();
+        for rule in cvm_rules {
+            let mut skip_if_not_supported = false;
+            let mut success = false;
+
+            match rule.condition {
+                CvmConditionCode::UnattendedCash
+                | CvmConditionCode::ManualCash
+                | CvmConditionCode::PurchaseWithCashback => {
+                    // TODO: conditions currently never supported, maybe should implement it more flexible
+                    continue;
+                }
+                CvmConditionCode::CvmSupported => {
+                    skip_if_not_supported = true;
+                }
+                // TODO: verify that ICC and terminal currencies are the same or provide conversion
+                CvmConditionCode::IccCurrencyUnderX => {
+                    if purchase_amount >= rule.amount_x {
+                        continue;
+                    }
+                }
+                CvmConditionCode::IccCurrencyOverX => {
+                    if purchase_amount <= rule.amount_x {
+                        continue;
+                    }
+                }
+                CvmConditionCode::IccCurrencyUnderY => {
+                    if purchase_amount >= rule.amount_y {
+                        continue;
+                    }
+                }
+                CvmConditionCode::IccCurrencyOverY => {
+                    if purchase_amount <= rule.amount_y {
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+
+            match rule.code {
+                CvmCode::FailCvmProcessing => success = false,
+                CvmCode::EncipheredPinOnline => {
+                    debug!("Enciphered PIN online is not supported");
+
+                    if skip_if_not_supported {
+                        continue;
+                    }
+
+                    success = false;
+                }
+                CvmCode::PlaintextPin
+                | CvmCode::PlaintextPinAndSignature
+                | CvmCode::EncipheredPinOffline
+                | CvmCode::EncipheredPinOfflineAndSignature => {
+                    let enciphered_pin = match rule.code {
+                        CvmCode::EncipheredPinOffline
+                        | CvmCode::EncipheredPinOfflineAndSignature => true,
+                        _ => false,
+                    };
+
+                    let pin_supported = if enciphered_pin {
+                        self.settings.terminal.capabilities.enciphered_pin
+                    } else {
+                        self.settings.terminal.capabilities.plaintext_pin
+                    };
+
+                    if !pin_supported {
+                        debug!("Offline PIN not supported by the terminal");
+
+                        if skip_if_not_supported {
+                            continue;
+                        }
+
+                        // ref. EMV Book 3, 10.5.1 Offline PIN Processing - no PIN pad for the CVM,
+                        // the cardholder is not asked for the PIN
+                        self.settings
+                            .terminal
+                            .tvr
+                            .pin_entry_required_and_pin_pad_not_present_or_not_working = true;
+                    } else {
+                        match self.cardholder_interface.pin_entry() {
+                            Ok(ascii_pin) => {
+                                success = if enciphered_pin {
+                                    self.handle_verify_enciphered_pin(ascii_pin.as_bytes())
+                                        .await
+                                        .is_ok()
+                                } else {
+                                    self.handle_verify_plaintext_pin(ascii_pin.as_bytes())
+                                        .await
+                                        .is_ok()
+                                };
+                            }
+                            // ref. EMV Book 3, 10.5.1 Offline PIN Processing - PIN entry bypass fails the CVM
+                            Err(err) => {
+                                warn!("PIN not entered: {}", err);
+                                self.settings
+                                    .terminal
+                                    .tvr
+                                    .pin_entry_required_pin_pad_present_but_pin_was_not_entered =
+                                    true;
+                            }
+                        }
+                    }
+
+                    if success {
+                        if let CvmCode::PlaintextPinAndSignature
+                        | CvmCode::EncipheredPinOfflineAndSignature = rule.code
+                        {
+                            success = self.cardholder_interface.signature_capture();
+                        }
+                    }
+                }
+                CvmCode::Signature => {
+                    success = self.cardholder_interface.signature_capture();
+                }
+                CvmCode::NoCvm => {
+                    success = true;
+                }
+                // ref. EMV Book 3, 10.5 Cardholder Verification - a CVM not recognised fails
+                CvmCode::Unrecognised(code) => {
+                    debug!("Unrecognised CVM code {:02X}", code);
+
+                    if skip_if_not_supported {
+                        continue;
+                    }
+
+                    self.settings.terminal.tvr.unrecognised_cvm = true;
+                    success = false;
+                }
+            }
+
+            if success {
+                self.settings
+                    .terminal
+                    .tvr
+                    .cardholder_verification_was_not_successful = false;
+                self.process_tag_as_tlv("9F34", CvmRule::into_9f34_value(Ok(rule)));
+                break;
+            } else {
+                self.settings
+                    .terminal
+                    .tvr
+                    .cardholder_verification_was_not_successful = true;
+                self.process_tag_as_tlv("9F34", CvmRule::into_9f34_value(Err(rule)));
+
+                // ref. EMV Book 3, 10.5 Cardholder Verification - b7 of the CVM code, apply the
+                // succeeding CV Rule if this CVM is unsuccessful. A CVM not supported by the
+                // terminal was skipped already when the condition requires the support.
+                if rule.fail_if_unsuccessful {
+                    break;
+                }
+            }
+        }
+
+        self.settings
+            .terminal
+            .tsi
+            .cardholder_verification_was_performed = true;
+
+        if !self.get_tag_value("9F34").is_some() {
+            self.settings
+                .terminal
+                .tvr
+                .cardholder_verification_was_not_successful = true;
+
+            // "no CVM performed"
+            self.process_tag_as_tlv("9F34", b"\x3F\x00\x01".to_vec());
+        }
+
+        Ok(())
+    }
+
+    pub async fn handle_offline_data_authentication(&mut self) -> Result<(), EmvError> {
+        //ref. EMV 4.3 Book 3 - 10.3 Offline Data Authentication
+
+        if !(self.settings.terminal.capabilities.cda && self.icc.capabilities.cda) {
+            if self.settings.terminal.capabilities.dda && self.icc.capabilities.dda {
+                if self.handle_dynamic_data_authentication().await.is_err() {
+                    self.settings.terminal.tvr.dda_failed = true;
+                }
+            } else if self.settings.terminal.capabilities.sda && self.icc.capabilities.sda {
+                let data_authentication = self.icc.data_authentication.clone().unwrap_or_default();
+                if self
+                    .handle_signed_static_application_data(&data_authentication[..])
+                    .is_err()
+                {
+                    self.settings.terminal.tvr.sda_failed = true;
+                }
+            }
+        }
+
+        self.settings
+            .terminal
+            .tsi
+            .offline_data_authentication_was_performed = true;
+
+        Ok(())
+    }
+
+    pub fn handle_processing_restrictions(&mut self) -> Result<(), EmvError> {
+        // ref. EMV Book 3, 10.4 Processing Restrictions
+        let step = TransactionStep::ProcessingRestrictions;
+
+        // ref. EMV Book 3, 10.4.1 Application Version Number - compatible when the ICC has none
+        if let (Some(tag_9f08_icc_version), Some(tag_9f09_terminal_version)) =
+            (self.get_tag_value("9F08"), self.get_tag_value("9F09"))
+        {
+            if tag_9f08_icc_version != tag_9f09_terminal_version {
+                debug!(
+                    "Application version of the ICC {:02X?} differs from the terminal {:02X?}",
+                    tag_9f08_icc_version, tag_9f09_terminal_version
+                );
+                self.settings
+                    .terminal
+                    .tvr
+                    .icc_and_terminal_have_different_application_versions = true;
+            }
+        }
+
+        // ref. EMV Book 3, 10.4.2 Application Usage Control
+        if let Some(tag_9f07_application_usage_control) = self.get_tag_value("9F07") {
+            let auc: UsageControl = tag_9f07_application_usage_control.to_vec().into();
+            if !self.is_service_allowed(&auc) {
+                debug!("Requested service not allowed by the AUC: {:?}", auc);
+                self.settings
+                    .terminal
+                    .tvr
+                    .requested_service_not_allowed_for_card_product = true;
+            }
+        }
+
+        // ref. EMV Book 3, 10.4.3 Application Effective/Expiration Dates Checking
+        let transaction_date = self
+            .get_tag_value("9A")
+            .ok_or_else(|| EmvError::missing_tag(step, "9A"))
+            .and_then(|v| {
+                decoder::decode_date(v).ok_or_else(|| EmvError::malformed_tlv(step, "9A"))
+            })?;
+
+        if let Some(tag_5f25_application_effective_date) = self.get_tag_value("5F25") {
+            let effective_date = decoder::decode_date(tag_5f25_application_effective_date)
+                .ok_or_else(|| EmvError::malformed_tlv(step, "5F25"))?;
+            if transaction_date < effective_date {
+                debug!(
+                    "Application not yet effective, effective date {}",
+                    effective_date
+                );
+                self.settings.terminal.tvr.application_not_yet_effective = true;
+            }
+        }
+
+        if let Some(tag_5f24_application_expiration_date) = self.get_tag_value("5F24") {
+            let expiration_date = decoder::decode_date(tag_5f24_application_expiration_date)
+                .ok_or_else(|| EmvError::malformed_tlv(step, "5F24"))?;
+            if transaction_date > expiration_date {
+                debug!("Application expired, expiration date {}", expiration_date);
+                self.settings.terminal.tvr.expired_application = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    // ref. EMV Book 3, 10.4.2 Application Usage Control - the transaction is domestic when the
+    // Issuer Country Code matches the Terminal Country Code
+    fn is_service_allowed(&self, auc: &UsageControl) -> bool {
+        // ATM: terminal type 14, 15 or 16 with cash in the Additional Terminal Capabilities
+        let atm = self
+            .get_tag_value("9F35")
+            .is_some_and(|terminal_type| matches!(terminal_type.first(), Some(0x14..=0x16)))
+            && self
+                .get_tag_value("9F40")
+                .is_some_and(|capabilities| capabilities.first().is_some_and(|&b| get_bit!(b, 7)));
+        if (atm && !auc.atms) || (!atm && !auc.terminals_other_than_atms) {
+            return false;
+        }
+
+        let tag_5f28_issuer_country_code = match self.get_tag_value("5F28") {
+            Some(issuer_country_code) => issuer_country_code,
+            None => return true,
+        };
+        let domestic = self.get_tag_value("9F1A") == Some(tag_5f28_issuer_country_code);
+
+        let goods_and_services = if domestic {
+            auc.domestic_goods || auc.domestic_services
+        } else {
+            auc.international_goods || auc.international_services
+        };
+
+        match self.get_tag_value("9C").and_then(|v| v.first().copied()) {
+            // cash
+            Some(0x01) => {
+                if domestic {
+                    auc.domestic_cash_transactions
+                } else {
+                    auc.international_cash_transactions
+                }
+            }
+            // goods and services
+            Some(0x00) => goods_and_services,
+            // goods and services with cashback
+            Some(0x09) => {
+                goods_and_services
+                    && if domestic {
+                        auc.domestic_cashback
+                    } else {
+                        auc.international_cashback
+                    }
+            }
+            _ => true,
+        }
+    }
+
+    pub async fn handle_issuer_authentication_data(&mut self) -> Result<(), EmvError> {
+        // ref. EMV 4.3 Book 3 - 10.9 Online Processing
+        // ref. EMV 4.3 Book 3 - 6.5.4 EXTERNAL AUTHENTICATE Command-Response APDUs
+
+        let tag_91_issuer_authentication_data = match self.get_tag_value("91") {
+            Some(tag_91_issuer_authentication_data) => tag_91_issuer_authentication_data.clone(),
+            None => return Ok(()),
+        };
+
+        // ref. EMV Book 3, 10.9 Online Processing - without issuer authentication in the AIP the
+        // Issuer Authentication Data is only passed to the ICC in the CDOL2 data
+        if !self.icc.capabilities.issuer_authentication {
+            debug!("Issuer authentication not supported by the ICC, EXTERNAL AUTHENTICATE skipped");
+            return Ok(());
+        }
+
+        debug!("Validating issuer authentication data");
+        let response = self
+            .send_apdu(&CommandApdu::external_authenticate(
+                &tag_91_issuer_authentication_data,
+            ))
+            .await
+            .map_err(|error| EmvError::Transport {
+                step: TransactionStep::OnlineProcessing,
+                error,
+            })?;
+        if !response.is_success() {
+            self.settings.terminal.tvr.issuer_authentication_failed = true;
+        }
+
+        self.settings
+            .terminal
+            .tsi
+            .issuer_authentication_was_performed = true;
+
+        Ok(())
+    }
+}
+
+// ref. EMV Book 4, A6 Authorisation Response Code - codes which lead to TC request
+pub fn is_authorisation_approved(tag_8a_authorisation_response_code: &[u8]) -> bool {
+    matches!(
+        tag_8a_authorisation_response_code,
+        b"00" | b"08" | b"10" | b"11" | b"Y1" | b"Y3"
+    )
+}
+
+// Data needed by the acquirer for the online authorisation of an ARQC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArqcData {
+    pub application_cryptogram: Vec<u8>,
+    pub cryptogram_information_data: u8,
+    pub application_transaction_counter: Vec<u8>,
+    pub issuer_application_data: Option<Vec<u8>>,
+    pub unpredictable_number: Option<Vec<u8>>,
+    pub terminal_verification_results: Vec<u8>,
+    // ICC system related data, i.e. ISO 8583 field 55 of the authorisation request
+    pub icc_data: Vec<u8>,
+}
+
+// Data of the advice the ICC requested with the Cryptogram Information Data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdviceData {
+    pub cryptogram_information_data: CryptogramInformationData,
+    pub application_cryptogram: Option<Vec<u8>>,
+    pub terminal_verification_results: Vec<u8>,
+    // ICC system related data, i.e. ISO 8583 field 55 of the advice
+    pub icc_data: Vec<u8>,
+}
+
+// ref. EMV Book 4, 12.2.2 Authorisation Request - ICC data elements available for the authorisation
+const AUTHORISATION_REQUEST_TAGS: [&str; 21] = [
+    "9F26", "9F27", "9F10", "9F37", "9F36", "95", "9A", "9C", "9F02", "5F2A", "82", "9F1A", "9F03",
+    "9F33", "9F34", "9F35", "84", "9F09", "9F1E", "9F41", "5F34",
+];
+
+// Acquirer host connection, the ARQC is sent for an authorisation and the issuer response returned
+pub type OnlineAuthorizationCallback =
+    dyn Fn(&ArqcData) -> Result<OnlineAuthorizationResponse, ()> + Send + Sync;
+
+// Issuer response relayed by the acquirer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnlineAuthorizationResponse {
+    pub authorisation_response_code: Vec<u8>,
+    pub issuer_authentication_data: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransactionResult {
+    ApprovedOffline,
+    ApprovedOnline,
+    DeclinedOffline,
+    DeclinedOnline,
+    TryAnotherInterface,
+    EndApplication,
+    Fallback,
+}
+
+impl fmt::Display for TransactionResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let result = match self {
+            TransactionResult::ApprovedOffline => "Approved (offline)",
+            TransactionResult::ApprovedOnline => "Approved (online)",
+            TransactionResult::DeclinedOffline => "Declined (offline)",
+            TransactionResult::DeclinedOnline => "Declined (online)",
+            TransactionResult::TryAnotherInterface => "Try another interface",
+            TransactionResult::EndApplication => "End application",
+            TransactionResult::Fallback => "Fallback",
+        };
+
+        write!(f, "{}", result)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransactionOutcome {
+    pub result: TransactionResult,
+    pub tvr: TerminalVerificationResults,
+    pub tsi: TransactionStatusInformation,
+    pub cryptogram_information_data: Option<CryptogramInformationData>,
+    pub decline_reason: Option<DeclineReason>,
+    // generated when requested by the ICC, to be sent to the acquirer
+    pub advice: Option<AdviceData>,
+    pub arqc: Option<ArqcData>,
+    pub cvm_result: Option<Vec<u8>>,
+    // reason for terminating the transaction before completion
+    pub error: Option<EmvError>,
+}
+
+// ref. EMV Book 3, 10 Functions Used in Transaction Processing
+#[maybe_async_cfg::maybe(
+    idents(
+        EmvConnection(sync, async = "AsyncEmvConnection"),
+        EmvTransaction(sync, async = "AsyncEmvTransaction")
+    ),
+    sync(keep_self),
+    async(feature = "async")
+)]
+pub struct EmvTransaction<'a> {
+    connection: &'a mut EmvConnection,
+    amount: u64,
+    arqc: Option<ArqcData>,
+    decline_reason: Option<DeclineReason>,
+}
+
+#[maybe_async_cfg::maybe(
+    idents(
+        EmvConnection(sync, async = "AsyncEmvConnection"),
+        EmvTransaction(sync, async = "AsyncEmvTransaction")
+    ),
+    sync(keep_self),
+    async(feature = "async")
+)]
+impl<'a> EmvTransaction<'a> {
+    pub fn new(connection: &'a mut EmvConnection, amount: u64) -> EmvTransaction<'a> {
+        EmvTransaction {
+            connection,
+            amount,
+            arqc: None,
+            decline_reason: None,
+        }
+    }
+
+    // Runs the transaction from application selection to completion, only transport failures are
+    // returned as errors as the ICC can't be trusted to be present anymore
+    pub async fn run(&mut self) -> Result<TransactionOutcome, EmvError> {
+        let result = match self.process().await {
+            Ok(result) => (result, None),
+            Err(err) => {
+                if let EmvError::Transport { .. } = err {
+                    return Err(err);
+                }
+
+                warn!("Transaction terminated: {}", err);
+                (self.get_error_result(&err), Some(err))
+            }
+        };
+
+        let outcome = self.get_outcome(result.0, result.1);
+
+        let cardholder_interface = &self.connection.cardholder_interface;
+        cardholder_interface.display_message(&outcome.result.to_string());
+        cardholder_interface.remove_card();
+
+        Ok(outcome)
+    }
+
+    async fn process(&mut self) -> Result<TransactionResult, EmvError> {
+        if self.connection.contactless {
+            return self.process_entry_point().await;
+        }
+
+        let application = self.connection.select_payment_application().await?;
+
+        self.process_application(&application).await
+    }
+
+    // ref. EMV Contactless Book B, 3 Entry Point
+    async fn process_entry_point(&mut self) -> Result<TransactionResult, EmvError> {
+        // Start A
+        let combinations =
+            entry_point::pre_processing(&self.connection.settings.terminal, self.amount);
+        if !entry_point::is_contactless_allowed(&combinations) {
+            warn!("Contactless not allowed for the amount {}", self.amount);
+            return Ok(TransactionResult::TryAnotherInterface);
+        }
+
+        // Start B
+        self.connection.handle_protocol_activation();
+        let mut candidates = self
+            .connection
+            .handle_combination_selection(&combinations)
+            .await?;
+
+        loop {
+            // Start C
+            let kernel_activation = match self
+                .connection
+                .handle_final_combination_selection(&mut candidates)
+                .await?
+            {
+                Some(kernel_activation) => kernel_activation,
+                None => {
+                    warn!("No contactless application supported by the reader");
+                    return Ok(TransactionResult::TryAnotherInterface);
+                }
+            };
+
+            // ref. EMV Contactless Book B, 3.5 Outcome Processing - Select Next when the kernel
+            // rejects the application and there are other candidates
+            match self.process_kernel(&kernel_activation).await {
+                Err(EmvError::CardStatus {
+                    step: TransactionStep::InitiateApplicationProcessing,
+                    sw1,
+                    sw2,
+                }) if !candidates.is_empty() => {
+                    warn!(
+                        "Kernel {} ended with {:02X}{:02X}, selecting next",
+                        kernel_activation.kernel_id, sw1, sw2
+                    );
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    // ref. EMV Contactless Book B, 3.4 Kernel Activation - kernels without their own processing run the
+    // generic flow of GET PROCESSING OPTIONS to GENERATE AC
+    async fn process_kernel(
+        &mut self,
+        kernel_activation: &KernelActivation,
+    ) -> Result<TransactionResult, EmvError> {
+        debug!("Activating kernel {}", kernel_activation.kernel_id);
+
+        self.process_application(&kernel_activation.application)
+            .await
+    }
+
+    async fn process_application(
+        &mut self,
+        application: &EmvApplication,
+    ) -> Result<TransactionResult, EmvError> {
+        let tag_9f02_amount_authorised =
+            bcdutil::ascii_to_bcd_n(format!("{}", self.amount).as_bytes(), 6).map_err(|_| {
+                EmvError::Terminal {
+                    step: TransactionStep::InitiateApplicationProcessing,
+                    reason: "Invalid amount",
+                }
+            })?;
+        self.connection
+            .process_tag_as_tlv("9F02", tag_9f02_amount_authorised);
+
+        self.connection.start_transaction(application).await?;
+        self.connection.handle_offline_data_authentication().await?;
+        self.connection.handle_processing_restrictions()?;
+        self.connection.handle_card_verification_methods().await?;
+        self.connection.handle_terminal_risk_management()?;
+        let cryptogram_type = self.connection.handle_terminal_action_analysis()?;
+
+        match self
+            .connection
+            .handle_1st_generate_ac(cryptogram_type)
+            .await?
+        {
+            CryptogramType::TransactionCertificate => Ok(TransactionResult::ApprovedOffline),
+            CryptogramType::AuthorisationRequestCryptogram => self.process_online().await,
+            // an AAC is returned as EmvError::Declined by validate_ac, never as a cryptogram type
+            cryptogram_type => Err(EmvError::UnexpectedCryptogram {
+                step: TransactionStep::CardActionAnalysis,
+                cryptogram_type,
+            }),
+        }
+    }
+
+    async fn process_online(&mut self) -> Result<TransactionResult, EmvError> {
+        // ref. EMV Book 3, 10.9 Online Processing
+        let step = TransactionStep::OnlineProcessing;
+
+        let arqc = self.get_arqc_data(step)?;
+        self.arqc = Some(arqc.clone());
+
+        let online_response = match &self.connection.online_authorization_callback {
+            Some(online_authorization_callback) => online_authorization_callback(&arqc).ok(),
+            None => None,
+        };
+
+        let online = match online_response {
+            Some(response) => {
+                self.connection.process_tag_as_tlv_from(
+                    "8A",
+                    response.authorisation_response_code,
+                    TagOrigin::issuer(),
+                );
+
+                if let Some(tag_91_issuer_authentication_data) = response.issuer_authentication_data
+                {
+                    self.connection.process_tag_as_tlv_from(
+                        "91",
+                        tag_91_issuer_authentication_data,
+                        TagOrigin::issuer(),
+                    );
+                    self.connection.handle_issuer_authentication_data().await?;
+                }
+
+                true
+            }
+            None => {
+                // ref. EMV Book 3, 10.7 and 10.9 - unable to go online, the Issuer Action Code -
+                // Default and the Terminal Action Code - Default decide between AAC and TC
+                let [_, _, (iac, tac)] = self.connection.action_codes(step)?;
+                let tag_8a_authorisation_response_code =
+                    if TerminalVerificationResults::action_code_matches(
+                        &self.connection.settings.terminal.tvr,
+                        &iac,
+                        &tac,
+                    ) {
+                        debug!("Unable to go online, Action Code - Default matches");
+                        b"Z3" // Unable to go online, offline declined
+                    } else {
+                        debug!("Unable to go online");
+                        b"Y3" // Unable to go online, offline approved
+                    };
+                self.connection
+                    .process_tag_as_tlv("8A", tag_8a_authorisation_response_code.to_vec());
+
+                false
+            }
+        };
+
+        match self.connection.handle_2nd_generate_ac().await {
+            Ok(_) if online => Ok(TransactionResult::ApprovedOnline),
+            Ok(_) => Ok(TransactionResult::ApprovedOffline),
+            Err(EmvError::Declined { reason, .. }) => {
+                self.decline_reason = Some(reason);
+                if online {
+                    Ok(TransactionResult::DeclinedOnline)
+                } else {
+                    Ok(TransactionResult::DeclinedOffline)
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn get_arqc_data(&self, step: TransactionStep) -> Result<ArqcData, EmvError> {
+        let connection = &self.connection;
+
+        let application_cryptogram = connection
+            .get_tag_value("9F26")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F26"))?
+            .clone();
+        let cryptogram_information_data = connection
+            .get_tag_value("9F27")
+            .and_then(|cid| cid.first().copied())
+            .ok_or_else(|| EmvError::missing_tag(step, "9F27"))?;
+        let application_transaction_counter = connection
+            .get_tag_value("9F36")
+            .ok_or_else(|| EmvError::missing_tag(step, "9F36"))?
+            .clone();
+
+        Ok(ArqcData {
+            application_cryptogram,
+            cryptogram_information_data,
+            application_transaction_counter,
+            issuer_application_data: connection.get_tag_value("9F10").cloned(),
+            unpredictable_number: connection.get_tag_value("9F37").cloned(),
+            terminal_verification_results: connection.settings.terminal.tvr.into(),
+            icc_data: self
+                .get_icc_data()
+                .map_err(|_| EmvError::malformed_tlv(step, "55"))?,
+        })
+    }
+
+    fn get_icc_data(&self) -> Result<Vec<u8>, tlv::TlvError> {
+        let mut icc_data = TlvBuilder::new();
+        for tag_name in AUTHORISATION_REQUEST_TAGS.iter() {
+            if let Some(value) = self.connection.get_tag_value(tag_name) {
+                icc_data = icc_data.primitive(tag_name, value);
+            }
+        }
+
+        icc_data.build()
+    }
+
+    // ref. EMV Book 3, 6.5.5.4 Data Field Returned in the Response Message - advice required
+    fn get_advice_data(
+        &self,
+        cryptogram_information_data: CryptogramInformationData,
+    ) -> Option<AdviceData> {
+        if !cryptogram_information_data.advice_required {
+            return None;
+        }
+
+        let connection = &self.connection;
+        info!(
+            "Generating advice, reason: {:?}",
+            cryptogram_information_data.reason_code
+        );
+
+        Some(AdviceData {
+            cryptogram_information_data,
+            application_cryptogram: connection.get_tag_value("9F26").cloned(),
+            terminal_verification_results: connection.settings.terminal.tvr.into(),
+            icc_data: self.get_icc_data().ok()?,
+        })
+    }
+
+    fn get_error_result(&self, err: &EmvError) -> TransactionResult {
+        match err {
+            EmvError::Declined { step, .. } => match step {
+                TransactionStep::Completion if self.arqc.is_some() => {
+                    TransactionResult::DeclinedOnline
+                }
+                _ => TransactionResult::DeclinedOffline,
+            },
+            // ref. EMV Book 1, 12.3.2 - blocked card, the terminal shall terminate the transaction
+            EmvError::CardStatus {
+                step: TransactionStep::ApplicationSelection,
+                sw1: 0x6A,
+                sw2: 0x81,
+            } => TransactionResult::EndApplication,
+            // chip could not be read, magnetic stripe may be used instead
+            EmvError::CardStatus {
+                step: TransactionStep::ApplicationSelection,
+                ..
+            }
+            | EmvError::MalformedTlv {
+                step: TransactionStep::ApplicationSelection,
+                ..
+            } => TransactionResult::Fallback,
+            // ref. EMV Book 3, 10.1 - conditions of use not satisfied
+            EmvError::CardStatus {
+                step: TransactionStep::InitiateApplicationProcessing,
+                sw1: 0x69,
+                sw2: 0x85,
+            } if self.connection.contactless => TransactionResult::TryAnotherInterface,
+            _ => TransactionResult::EndApplication,
+        }
+    }
+
+    fn get_outcome(
+        &self,
+        result: TransactionResult,
+        error: Option<EmvError>,
+    ) -> TransactionOutcome {
+        let connection = &self.connection;
+
+        let cryptogram_information_data = connection
+            .get_tag_value("9F27")
+            .and_then(|cid| cid.first())
+            .map(|cid| CryptogramInformationData::from(*cid));
+        let decline_reason = match result {
+            TransactionResult::DeclinedOffline | TransactionResult::DeclinedOnline => error
+                .as_ref()
+                .and_then(EmvError::decline_reason)
+                .or(self.decline_reason),
+            _ => None,
+        };
+
+        TransactionOutcome {
+            result,
+            tvr: connection.settings.terminal.tvr,
+            tsi: connection.settings.terminal.tsi,
+            cryptogram_information_data,
+            decline_reason,
+            advice: cryptogram_information_data.and_then(|cid| self.get_advice_data(cid)),
+            arqc: self.arqc.clone(),
+            cvm_result: connection.get_tag_value("9F34").cloned(),
+            error,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmvApplication {
+    pub aid: Vec<u8>,
+    pub label: Vec<u8>,
+    // Application Priority Indicator (87), empty when the card has none
+    pub priority: Vec<u8>,
+    // Data of the directory entry, kept apart from the data of the selected application
+    pub tags: TagStore,
+}
+
+impl EmvApplication {
+    // ref. EMV Book 1, Table 48 Format of Application Priority Indicator - b4-b1, 1 is the highest
+    // priority and 0 no priority, applications without priority come last
+    pub fn priority_order(&self) -> u8 {
+        match self.priority.first().map(|priority| priority & 0x0F) {
+            Some(priority) if priority != 0 => priority,
+            _ => 0x10,
+        }
+    }
+
+    // b8 of the Application Priority Indicator
+    pub fn confirmation_required(&self) -> bool {
+        matches!(self.priority.first(), Some(priority) if get_bit!(priority, 7))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+pub enum FieldSensitivity {
+    Public,
+    SensitiveAuthenticationData,
+    Sensitive,
+    Track2,
+    PrimaryAccountNumber,
+    PersonallyIdentifiableInformation,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FieldFormat {
+    Numeric,
+    CompressedNumeric,
+    Binary,
+    Alphabetic,
+    Alphanumeric,
+    AlphanumericSpecial,
+    IssuerCodeTableText,
+    TerminalVerificationResults,
+    ApplicationUsageControl,
+    KeyCertificate,
+    ServiceCodeIso7813,
+    NumericCountryCode,
+    NumericCurrencyCode,
+    DataObjectList,
+    TagList,
+    Track2,
+    Date,
+    Time,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FieldSource {
+    Icc,
+    Terminal,
+    Issuer,
+    IssuerOrTerminal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmvTag {
+    pub tag: String,
+    pub name: String,
+    pub sensitivity: Option<FieldSensitivity>,
+    pub format: Option<FieldFormat>,
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+    pub source: Option<FieldSource>,
+    // templates the data object is found in, ref. EMV Book 3, Annex A1 Data Elements Dictionary
+    pub templates: Option<Vec<String>>,
+    // contactless kernels defining the tag, e.g. C-2, None for tags of the EMV contact specifications
+    pub kernels: Option<Vec<String>>,
+}
+
+impl EmvTag {
+    pub fn new(tag_name: &str) -> EmvTag {
+        EmvTag {
+            tag: tag_name.to_string(),
+            name: "Unknown tag".to_string(),
+            sensitivity: None,
+            format: None,
+            min: None,
+            max: None,
+            source: None,
+            templates: None,
+            kernels: None,
+        }
+    }
+
+    // ref. EMV Book 3, 4.3 Data Element Format Conventions
+    pub fn validate(&self, value: &[u8]) -> Result<(), TagValidationError> {
+        let length = value.len();
+        if self.min.is_some_and(|min| length < min as usize)
+            || self.max.is_some_and(|max| length > max as usize)
+        {
+            return Err(TagValidationError::InvalidLength {
+                tag: self.tag.clone(),
+                length,
+                min: self.min,
+                max: self.max,
+            });
+        }
+
+        let nibbles = || value.iter().flat_map(|b| [b >> 4, b & 0x0F]);
+
+        let valid = match self.format {
+            Some(FieldFormat::Numeric)
+            | Some(FieldFormat::NumericCountryCode)
+            | Some(FieldFormat::NumericCurrencyCode)
+            | Some(FieldFormat::ServiceCodeIso7813)
+            | Some(FieldFormat::Date)
+            | Some(FieldFormat::Time) => nibbles().all(|digit| digit <= 9),
+            // digits, right padded with F
+            Some(FieldFormat::CompressedNumeric) => nibbles()
+                .skip_while(|&digit| digit <= 9)
+                .all(|padding| padding == 0x0F),
+            Some(FieldFormat::Alphabetic) => value.iter().all(|c| c.is_ascii_alphabetic()),
+            Some(FieldFormat::Alphanumeric) => value.iter().all(|c| c.is_ascii_alphanumeric()),
+            // printable characters of the common character set, ref. EMV Book 4, Annex B
+            Some(FieldFormat::AlphanumericSpecial) => {
+                value.iter().all(|&c| (0x20..=0x7E).contains(&c))
+            }
+            // ISO/IEC 8859 part of the Issuer Code Table Index, the common character set and the
+            // national characters above 0xA0
+            Some(FieldFormat::IssuerCodeTableText) => value
+                .iter()
+                .all(|&c| (0x20..=0x7E).contains(&c) || c >= 0xA0),
+            _ => true,
+        };
+        if !valid {
+            return Err(TagValidationError::InvalidFormat {
+                tag: self.tag.clone(),
+                format: self.format.unwrap(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn validate_icc_value(&self, value: &[u8]) -> Result<(), TagValidationError> {
+        match self.source {
+            Some(FieldSource::Icc) | None => self.validate(value),
+            Some(_) => Err(TagValidationError::TerminalData {
+                tag: self.tag.clone(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagValidationError {
+    InvalidLength {
+        tag: String,
+        length: usize,
+        min: Option<u8>,
+        max: Option<u8>,
+    },
+    // not BCD for n and cn formats, or characters outside the an/ans character set
+    InvalidFormat {
+        tag: String,
+        format: FieldFormat,
+    },
+    // ICC returned a data object only the terminal or the issuer may provide
+    TerminalData {
+        tag: String,
+    },
+}
+
+impl TagValidationError {
+    pub fn tag(&self) -> &str {
+        match self {
+            TagValidationError::InvalidLength { tag, .. }
+            | TagValidationError::InvalidFormat { tag, .. }
+            | TagValidationError::TerminalData { tag } => tag,
+        }
+    }
+}
+
+impl fmt::Display for TagValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagValidationError::InvalidLength {
+                tag,
+                length,
+                min,
+                max,
+            } => write!(
+                f,
+                "Invalid length {} of tag {}, expected {}..{}",
+                length,
+                tag,
+                min.unwrap_or(0),
+                max.map(|max| max.to_string()).unwrap_or_default()
+            ),
+            TagValidationError::InvalidFormat { tag, format } => {
+                write!(f, "Tag {} is not valid {:?} data", tag, format)
+            }
+            TagValidationError::TerminalData { tag } => {
+                write!(f, "Tag {} can not be provided by the ICC", tag)
+            }
+        }
+    }
+}
+
+impl error::Error for TagValidationError {}
+
+// Input of the offline data authentication, an invalid value fails the authentication
+const OFFLINE_DATA_AUTHENTICATION_TAGS: [&str; 13] = [
+    "8F", "90", "92", "93", "9F2D", "9F2E", "9F2F", "9F32", "9F46", "9F47", "9F48", "9F49", "9F4B",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RsaPublicKey {
+    pub modulus: String,
+    pub exponent: String,
+    sensitive: Option<bool>,
+}
+
+impl RsaPublicKey {
+    pub fn new(modulus: &[u8], exponent: &[u8], sensitive: bool) -> RsaPublicKey {
+        RsaPublicKey {
+            modulus: hex::encode_upper(modulus),
+            exponent: hex::encode_upper(exponent),
+            sensitive: Some(sensitive),
+        }
+    }
+
+    pub fn get_key_byte_size(&self) -> usize {
+        return self.modulus.len() / 2;
+    }
+
+    pub fn public_encrypt(&self, plaintext_data: &[u8]) -> Result<Vec<u8>, ()> {
+        let pk_modulus_raw = hex::decode(&self.modulus).unwrap();
+        let pk_modulus = BigNum::from_slice(&pk_modulus_raw[..]).unwrap();
+        let pk_exponent = BigNum::from_slice(&(hex::decode(&self.exponent).unwrap())[..]).unwrap();
+
+        let rsa = match Rsa::from_public_components(pk_modulus, pk_exponent) {
+            Ok(rsa) => rsa,
+            Err(_) => {
+                warn!("Invalid public key");
+                return Err(());
+            }
+        };
+
+        let mut encrypt_output = [0u8; 4096];
+
+        let length =
+            match rsa.public_encrypt(plaintext_data, &mut encrypt_output[..], Padding::NONE) {
+                Ok(length) => length,
+                Err(_) => {
+                    warn!("Could not encrypt data");
+                    return Err(());
+                }
+            };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&encrypt_output[..length]);
+
+        if let Some(true) = self.sensitive {
+            trace!("Encrypt result ({} bytes)", data.len());
+        } else {
+            trace!(
+                "Encrypt result ({} bytes):\n{}",
+                data.len(),
+                HexViewBuilder::new(&data[..]).finish()
+            );
+        }
+
+        if data.len() != pk_modulus_raw.len() {
+            warn!("Data length discrepancy");
+            return Err(());
+        }
+
+        Ok(data)
+    }
+
+    pub fn public_decrypt(&self, cipher_data: &[u8]) -> Result<Vec<u8>, ()> {
+        let pk_modulus_raw = hex::decode(&self.modulus).unwrap();
+        let pk_modulus = BigNum::from_slice(&pk_modulus_raw[..]).unwrap();
+        let pk_exponent = BigNum::from_slice(&(hex::decode(&self.exponent).unwrap())[..]).unwrap();
+
+        let rsa = match Rsa::from_public_components(pk_modulus, pk_exponent) {
+            Ok(rsa) => rsa,
+            Err(_) => {
+                warn!("Invalid public key");
+                return Err(());
+            }
+        };
+
+        let mut decrypt_output = [0u8; 4096];
+
+        let length = match rsa.public_decrypt(cipher_data, &mut decrypt_output[..], Padding::NONE) {
+            Ok(length) => length,
+            Err(_) => {
+                warn!("Could not decrypt data");
+                return Err(());
+            }
+        };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&decrypt_output[..length]);
+
+        if let Some(true) = self.sensitive {
+            trace!("Decrypt result ({} bytes)", data.len());
+        } else {
+            trace!(
+                "Decrypt result ({} bytes):\n{}",
+                data.len(),
+                HexViewBuilder::new(&data[..]).finish()
+            );
+        }
+
+        if data.len() != pk_modulus_raw.len() {
+            warn!("Data length discrepancy");
+            return Err(());
+        }
+        if data.first() != Some(&0x6A) {
+            warn!("Data header incorrect");
+            return Err(());
+        }
+        if data.last() != Some(&0xBC) {
+            warn!("Data trailer incorrect");
+            return Err(());
+        }
+
+        Ok(data)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CertificateAuthority {
+    pub issuer: String,
+    pub certificates: HashMap<String, RsaPublicKey>,
+}
+
+impl CertificateAuthority {
+    // Bundled test keys by RID, must not be used for production transactions
+    pub fn test_keys() -> HashMap<String, CertificateAuthority> {
+        bundled_yaml!("config/scheme_ca_public_keys_test.yaml").unwrap()
+    }
+}
+
+// Application template (61) of a directory entry, ref. EMV Book 1, 12.2.3 Coding of a Payment System Directory
+fn parse_application_template(application_template: &[Tlv], origin: TagOrigin) -> TagStore {
+    let mut tags = TagStore::new();
+
+    for data_object in application_template {
+        if let Value::Primitive(value) = data_object.value() {
+            let tag_name = hex::encode_upper(data_object.tag().to_bytes());
+            tags.insert(&tag_name, value.to_vec(), origin);
+        }
+    }
+
+    tags
+}
+
+// ref. EMV Book 1, Table 45 SELECT Response Message Data Field (FCI) of an ADF
+fn parse_adf_fci(response_data: &[u8], origin: TagOrigin) -> Option<TagStore> {
+    let tag_6f_fci = parse_tlv(response_data)?;
+    let fci_template = match tag_6f_fci.value() {
+        Value::Constructed(fci_template) if tag_6f_fci.tag().to_bytes() == [0x6F] => fci_template,
+        _ => return None,
+    };
+
+    let mut tags = parse_application_template(fci_template, origin);
+
+    let tag_a5_proprietary_template = fci_template
+        .iter()
+        .find(|data_object| data_object.tag().to_bytes() == [0xA5]);
+    if let Some(tag_a5_proprietary_template) = tag_a5_proprietary_template {
+        if let Value::Constructed(proprietary_template) = tag_a5_proprietary_template.value() {
+            for (tag_name, value) in parse_application_template(proprietary_template, origin).iter()
+            {
+                tags.insert(tag_name, value.clone(), origin);
+            }
+        }
+    }
+
+    Some(tags)
+}
+
+fn parse_tlv(raw_data: &[u8]) -> Option<Tlv> {
+    let (tlv_data, leftover_buffer) = Tlv::parse(raw_data);
+    if leftover_buffer.len() > 0 {
+        trace!("Could not parse as TLV: {:02X?}", leftover_buffer);
+    }
+
+    let tlv_data: Option<Tlv> = match tlv_data {
+        Ok(tlv) => Some(tlv),
+        Err(_) => None,
+    };
+
+    return tlv_data;
+}
+
+fn find_tlv_tag(buf: &[u8], tag: &str) -> Option<Tlv> {
+    let mut read_buffer = buf;
+
+    loop {
+        let (tlv_data, leftover_buffer) = Tlv::parse(read_buffer);
+
+        let tlv_data: Tlv = match tlv_data {
+            Ok(tlv) => tlv,
+            Err(err) => {
+                if leftover_buffer.len() > 0 {
+                    trace!(
+                        "Could not parse as TLV! error:{:?}, data: {:02X?}",
+                        err,
+                        read_buffer
+                    );
+                }
+
+                break;
+            }
+        };
+
+        read_buffer = leftover_buffer;
+
+        let tag_name = hex::encode_upper(tlv_data.tag().to_bytes());
+
+        if tag_name.eq(tag) {
+            return Some(tlv_data);
+        }
+
+        if let Value::Constructed(v) = tlv_data.value() {
+            for tlv_tag in v {
+                let child_tlv: Option<Tlv> = find_tlv_tag(&tlv_tag.to_vec(), tag);
+                if child_tlv.is_some() {
+                    return child_tlv;
+                }
+            }
+        }
+
+        if leftover_buffer.len() == 0 {
+            break;
+        }
+    }
+
+    None
+}
+
+pub fn get_ca_public_key<'a>(
+    ca_data: &'a HashMap<String, CertificateAuthority>,
+    rid: &[u8],
+    index: &[u8],
+) -> Option<&'a RsaPublicKey> {
+    match ca_data.get(&hex::encode_upper(&rid)) {
+        Some(ca) => match ca.certificates.get(&hex::encode_upper(&index)) {
+            Some(pk) => Some(pk),
+            _ => {
+                warn!("No CA key defined! rid:{:02X?}, index:{:02X?}", rid, index);
+                return None;
+            }
+        },
+        _ => None,
+    }
+}
+
+pub fn is_certificate_expired(date_bcd: &[u8]) -> bool {
+    let today = Utc::now().date_naive();
+    let expiry_date = match NaiveDate::parse_from_str(&format!("01{:02X?}", date_bcd), "%d[%m, %y]")
+    {
+        Ok(expiry_date) => expiry_date,
+        Err(_) => {
+            warn!("Invalid certificate expiry date (MMYY) {:02X?}", date_bcd);
+            return true;
+        }
+    };
+    let duration = today.signed_duration_since(expiry_date).num_days();
+
+    if duration > 30 {
+        warn!(
+            "Certificate expiry date (MMYY) {:02X?} is {} days in the past",
+            date_bcd,
+            duration.to_string()
+        );
+
+        return true;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bcdutil::*;
+    use super::*;
+    use hex;
+    use hexplay::HexViewBuilder;
+    use log::{debug, LevelFilter};
+    use log4rs;
+    use log4rs::{
+        append::console::ConsoleAppender,
+        config::{Appender, Root},
+    };
+    use openssl::rsa::{Padding, Rsa};
+    use serde::{Deserialize, Serialize};
+    use std::fs::{self};
+    use std::str;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, Once};
+
+    static LOGGING: Once = Once::new();
+
+    fn new_connection() -> EmvConnection {
+        EmvConnection::builder()
+            .ca_public_keys(CertificateAuthority::test_keys())
+            .build()
+            .unwrap()
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    struct ApduRequestResponse {
+        req: String,
+        res: String,
+    }
+
+    impl ApduRequestResponse {
+        fn to_raw_vec(s: &String) -> Vec<u8> {
+            hex::decode(s.replace(" ", "")).unwrap()
+        }
+    }
+
+    struct DummySmartCardConnection {
+        test_data_file: String,
+    }
+
+    impl DummySmartCardConnection {
+        fn find_dummy_apdu<'a>(
+            test_data: &'a Vec<ApduRequestResponse>,
+            apdu: &[u8],
+        ) -> Option<&'a ApduRequestResponse> {
+            for data in test_data {
+                if &apdu[..] == &ApduRequestResponse::to_raw_vec(&data.req)[..] {
+                    return Some(data);
+                }
+            }
+
+            None
+        }
+    }
+
+    impl ApduInterface for DummySmartCardConnection {
+        fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError> {
+            let mut output: Vec<u8> = Vec::new();
+
+            let mut response = b"\x6A\x82".to_vec(); // file not found error
+
+            let test_data: Vec<ApduRequestResponse> =
+                serde_yaml::from_str(&fs::read_to_string(&self.test_data_file).unwrap()).unwrap();
+
+            if let Some(req) = DummySmartCardConnection::find_dummy_apdu(&test_data, &apdu[..]) {
+                response = ApduRequestResponse::to_raw_vec(&req.res);
+            }
+
+            output.extend_from_slice(&response[..]);
+            Ok(output)
+        }
+    }
+
+    fn init_logging() {
+        LOGGING.call_once(|| {
+            let stdout: ConsoleAppender = ConsoleAppender::builder().build();
+            let config = log4rs::config::Config::builder()
+                .appender(Appender::builder().build("stdout", Box::new(stdout)))
+                .build(Root::builder().appender("stdout").build(LevelFilter::Trace))
+                .unwrap();
+            log4rs::init_config(config).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_rsa_key() -> Result<(), String> {
+        init_logging();
+
+        const KEY_SIZE: u32 = 1408;
+        const KEY_BYTE_SIZE: usize = KEY_SIZE as usize / 8;
+
+        // openssl key generation:
+        // openssl genrsa -out icc_1234560012345608_e_3_private_key.pem -3 1024
+        // openssl genrsa -out iin_313233343536_e_3_private_key.pem -3 1408
+        // openssl genrsa -out AFFFFFFFFF_92_ca_private_key.pem -3 1408
+        // openssl rsa -in AFFFFFFFFF_92_ca_private_key.pem -outform PEM -pubout -out AFFFFFFFFF_92_ca_key.pem
+
+        let rsa = Rsa::private_key_from_pem(
+            &fs::read_to_string("config/AFFFFFFFFF_92_ca_private_key.pem")
+                .unwrap()
+                .as_bytes(),
+        )
+        .unwrap();
+        //let rsa = Rsa::private_key_from_pem(&fs::read_to_string("config/iin_313233343536_e_3_private_key.pem").unwrap().as_bytes()).unwrap();
+        //let rsa = Rsa::private_key_from_pem(&fs::read_to_string("config/icc_1234560012345608_e_3_private_key.pem").unwrap().as_bytes()).unwrap();
+
+        let public_key_modulus = &rsa.n().to_vec()[..];
+        let public_key_exponent = &rsa.e().to_vec()[..];
+        let private_key_exponent = &rsa.d().to_vec()[..];
+
+        let pk = RsaPublicKey::new(public_key_modulus, public_key_exponent, false);
+        debug!(
+            "modulus: {:02X?}, exponent: {:02X?}, private_exponent: {:02X?}",
+            public_key_modulus, public_key_exponent, private_key_exponent
+        );
+
+        let mut encrypt_output = [0u8; KEY_BYTE_SIZE];
+        let mut plaintext_data = [0u8; KEY_BYTE_SIZE];
+        plaintext_data[0] = 0x6A;
+        plaintext_data[1] = 0xFF;
+        plaintext_data[KEY_BYTE_SIZE - 1] = 0xBC;
+
+        let encrypt_size = rsa
+            .private_encrypt(&plaintext_data[..], &mut encrypt_output[..], Padding::NONE)
+            .unwrap();
+
+        debug!(
+            "Encrypt result ({} bytes):\n{}",
+            encrypt_output.len(),
+            HexViewBuilder::new(&encrypt_output[..]).finish()
+        );
+
+        let decrypted_data = pk.public_decrypt(&encrypt_output[0..encrypt_size]).unwrap();
+
+        assert_eq!(&plaintext_data[..], &decrypted_data[..]);
+
+        Ok(())
+    }
+
+    fn cardholder_interface() -> Arc<HeadlessCardholderInterface> {
+        Arc::new(HeadlessCardholderInterface {
+            amount: 1,
+            pin: Some("1234".to_string()),
+            tags: vec![
+                // force transaction date as 24.07.2018, before the test card expires
+                ("9A".to_string(), b"\x18\x07\x24".to_vec()),
+                // force unpreditable number
+                ("9F37".to_string(), b"\x01\x23\x45\x67".to_vec()),
+                // force issuer authentication data
+                (
+                    "91".to_string(),
+                    b"\x12\x34\x56\x78\x12\x34\x56\x78".to_vec(),
+                ),
+            ],
+        })
+    }
+
+    fn setup_connection(connection: &mut EmvConnection) -> Result<(), EmvError> {
+        connection.contactless = false;
+        connection.settings.terminal.use_random = false;
+        connection.cardholder_interface = cardholder_interface();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_data() -> Result<(), EmvError> {
+        init_logging();
+
+        let mut connection = new_connection();
+        let smart_card_connection = Arc::new(DummySmartCardConnection {
+            test_data_file: "test_data.yaml".to_string(),
+        });
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+
+        connection.select_payment_application()?;
+
+        let search_tag = b"\x9f\x36";
+        connection.handle_get_data(TransactionStep::TerminalRiskManagement, &search_tag[..])?;
+
+        // only the 9FXX data objects of the ICC are retrieved with GET DATA
+        for invalid_tag in [&b"\x9f"[..], &b"\x5f\x36"[..], &b"\x9f\x36\x00"[..]] {
+            assert!(matches!(
+                connection.handle_get_data(TransactionStep::TerminalRiskManagement, invalid_tag),
+                Err(EmvError::Terminal {
+                    step: TransactionStep::TerminalRiskManagement,
+                    reason: "Invalid GET DATA tag",
+                })
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_data_card_status_error() -> Result<(), EmvError> {
+        init_logging();
+
+        let mut connection = new_connection();
+        let smart_card_connection = Arc::new(DummySmartCardConnection {
+            test_data_file: "test_data.yaml".to_string(),
+        });
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+
+        connection.select_payment_application()?;
+
+        let search_tag = b"\x9f\x17";
+        let error = connection
+            .handle_get_data(TransactionStep::TerminalRiskManagement, &search_tag[..])
+            .unwrap_err();
+        assert_eq!(error.step(), TransactionStep::TerminalRiskManagement);
+        assert_eq!(error.status_word(), Some((0x6A, 0x82)));
+
+        // the error is reported for the step the command was sent in
+        let error = connection
+            .handle_get_data(TransactionStep::CardholderVerification, &search_tag[..])
+            .unwrap_err();
+        assert_eq!(error.step(), TransactionStep::CardholderVerification);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_verification_methods() -> Result<(), EmvError> {
+        init_logging();
+
+        let mut connection = new_connection();
+        let smart_card_connection = Arc::new(DummySmartCardConnection {
+            test_data_file: "test_data.yaml".to_string(),
+        });
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+
+        let application = connection.select_payment_application()?;
+
+        connection.start_transaction(&application).unwrap();
+
+        let ascii_pin = connection.cardholder_interface.pin_entry().unwrap();
+
+        connection.handle_verify_plaintext_pin(ascii_pin.as_bytes())?;
+        connection.handle_verify_enciphered_pin(ascii_pin.as_bytes())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrecognised_cvm() -> Result<(), EmvError> {
+        let cvm_rule = |code: CvmCode, fail_if_unsuccessful: bool| CvmRule {
+            amount_x: 0,
+            amount_y: 0,
+            fail_if_unsuccessful,
+            code,
+            condition: CvmConditionCode::Always,
+        };
+
+        // ref. EMV Book 3, 10.5 - the unrecognised CVM fails, the succeeding CV Rule is applied
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x01\x00".to_vec());
+        connection.icc.cvm_rules = vec![
+            cvm_rule(CvmCode::Unrecognised(0x08), false),
+            cvm_rule(CvmCode::NoCvm, true),
+        ];
+        connection.handle_card_verification_methods()?;
+        assert!(connection.settings.terminal.tvr.unrecognised_cvm);
+        assert!(
+            !connection
+                .settings
+                .terminal
+                .tvr
+                .cardholder_verification_was_not_successful
+        );
+        assert_eq!(
+            connection.get_tag_value("9F34"),
+            Some(&b"\x1F\x00\x02".to_vec())
+        );
+
+        // not reached, the TVR bit is not set
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x01\x00".to_vec());
+        connection.icc.cvm_rules = vec![
+            cvm_rule(CvmCode::NoCvm, true),
+            cvm_rule(CvmCode::Unrecognised(0x08), true),
+        ];
+        connection.handle_card_verification_methods()?;
+        assert!(!connection.settings.terminal.tvr.unrecognised_cvm);
+
+        // cardholder verification fails
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x01\x00".to_vec());
+        connection.icc.cvm_rules = vec![
+            cvm_rule(CvmCode::Unrecognised(0x08), true),
+            cvm_rule(CvmCode::NoCvm, true),
+        ];
+        connection.handle_card_verification_methods()?;
+        assert!(connection.settings.terminal.tvr.unrecognised_cvm);
+        assert!(
+            connection
+                .settings
+                .terminal
+                .tvr
+                .cardholder_verification_was_not_successful
+        );
+        assert_eq!(
+            connection.get_tag_value("9F34"),
+            Some(&b"\x08\x00\x01".to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cvm_amount_conditions() -> Result<(), EmvError> {
+        // Amount, Authorised is n12, e.g. 50 000 000.00 does not fit in 32 bits
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.process_tag_as_tlv("9F02", b"\x00\x50\x00\x00\x00\x00".to_vec());
+        connection.icc.cvm_rules = vec![
+            CvmRule {
+                amount_x: 0,
+                amount_y: u64::from(u32::MAX),
+                fail_if_unsuccessful: true,
+                code: CvmCode::FailCvmProcessing,
+                condition: CvmConditionCode::IccCurrencyUnderY,
+            },
+            CvmRule {
+                amount_x: 0,
+                amount_y: u64::from(u32::MAX),
+                fail_if_unsuccessful: true,
+                code: CvmCode::NoCvm,
+                condition: CvmConditionCode::IccCurrencyOverY,
+            },
+        ];
+        connection.handle_card_verification_methods()?;
+        assert!(
+            !connection
+                .settings
+                .terminal
+                .tvr
+                .cardholder_verification_was_not_successful
+        );
+        assert_eq!(
+            connection.get_tag_value("9F34"),
+            Some(&b"\x1F\x09\x02".to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_cvm_supported_by_terminal() -> Result<(), EmvError> {
+        // ref. EMV Book 3, 10.5 - the terminal supports the plaintext PIN, the condition is
+        // satisfied and the failed CVM ends the cardholder verification
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![b"\x63\xC2".to_vec()],
+            false,
+        ));
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.interface = Some(smart_card_connection.clone());
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x01\x00".to_vec());
+        connection.icc.cvm_rules = vec![
+            CvmRule {
+                amount_x: 0,
+                amount_y: 0,
+                fail_if_unsuccessful: true,
+                code: CvmCode::PlaintextPin,
+                condition: CvmConditionCode::CvmSupported,
+            },
+            CvmRule {
+                amount_x: 0,
+                amount_y: 0,
+                fail_if_unsuccessful: true,
+                code: CvmCode::NoCvm,
+                condition: CvmConditionCode::Always,
+            },
+        ];
+        connection.handle_card_verification_methods()?;
+        assert!(
+            connection
+                .settings
+                .terminal
+                .tvr
+                .cardholder_verification_was_not_successful
+        );
+        assert_eq!(
+            connection.get_tag_value("9F34"),
+            Some(&b"\x01\x03\x01".to_vec())
+        );
+
+        let commands = smart_card_connection.commands.lock().unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(&commands[0][..4], b"\x00\x20\x00\x80");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_not_supported_by_terminal() -> Result<(), EmvError> {
+        struct NoPinCardholderInterface;
+        impl CardholderInterface for NoPinCardholderInterface {
+            fn pin_entry(&self) -> Result<String, CardholderInterfaceError> {
+                panic!("PIN entry without a PIN pad");
+            }
+        }
+
+        let pin_connection = |condition: CvmConditionCode| {
+            let mut connection = new_connection();
+            connection.cardholder_interface = Arc::new(NoPinCardholderInterface);
+            connection.settings.terminal.capabilities.plaintext_pin = false;
+            connection.settings.terminal.capabilities.enciphered_pin = false;
+            connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x01\x00".to_vec());
+            connection.icc.cvm_rules = vec![
+                CvmRule {
+                    amount_x: 0,
+                    amount_y: 0,
+                    fail_if_unsuccessful: true,
+                    code: CvmCode::PlaintextPin,
+                    condition,
+                },
+                CvmRule {
+                    amount_x: 0,
+                    amount_y: 0,
+                    fail_if_unsuccessful: true,
+                    code: CvmCode::NoCvm,
+                    condition: CvmConditionCode::Always,
+                },
+            ];
+            connection
+        };
+
+        // the rule applies only if the terminal supports the CVM, skipped
+        let mut connection = pin_connection(CvmConditionCode::CvmSupported);
+        connection.handle_card_verification_methods()?;
+        let tvr = connection.settings.terminal.tvr;
+        assert!(!tvr.pin_entry_required_and_pin_pad_not_present_or_not_working);
+        assert!(!tvr.cardholder_verification_was_not_successful);
+        assert_eq!(
+            connection.get_tag_value("9F34"),
+            Some(&b"\x1F\x00\x02".to_vec())
+        );
+
+        // ref. EMV Book 3, 10.5.1 - PIN required but no PIN pad, the CVM fails
+        let mut connection = pin_connection(CvmConditionCode::Always);
+        connection.handle_card_verification_methods()?;
+        let tvr = connection.settings.terminal.tvr;
+        assert!(tvr.pin_entry_required_and_pin_pad_not_present_or_not_working);
+        assert!(!tvr.pin_entry_required_pin_pad_present_but_pin_was_not_entered);
+        assert!(tvr.cardholder_verification_was_not_successful);
+        assert_eq!(
+            connection.get_tag_value("9F34"),
+            Some(&b"\x01\x00\x01".to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_purchase_transaction() -> Result<(), EmvError> {
+        init_logging();
+
+        let mut connection = new_connection();
+        let smart_card_connection = Arc::new(DummySmartCardConnection {
+            test_data_file: "test_data.yaml".to_string(),
+        });
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+        // the TVR matches the Issuer Action Code - Online of the card
+        connection
+            .settings
+            .terminal
+            .tvr
+            .merchant_forced_transaction_online = true;
+
+        let amount = connection.cardholder_interface.amount_entry().unwrap();
+
+        let application = connection.select_payment_application()?;
+
+        connection.start_transaction(&application)?;
+
+        connection.process_tag_as_tlv(
+            "9F02",
+            ascii_to_bcd_n(format!("{}", amount).as_bytes(), 6).unwrap(),
+        );
+
+        connection.handle_card_verification_methods()?;
+
+        connection.handle_terminal_risk_management()?;
+
+        connection.handle_offline_data_authentication()?;
+
+        let cryptogram_type = connection.handle_terminal_action_analysis()?;
+        assert_eq!(
+            cryptogram_type,
+            CryptogramType::AuthorisationRequestCryptogram
+        );
+
+        match connection.handle_1st_generate_ac(cryptogram_type)? {
+            CryptogramType::AuthorisationRequestCryptogram => {
+                connection.handle_issuer_authentication_data()?;
+                assert!(
+                    !connection
+                        .settings
+                        .terminal
+                        .tvr
+                        .issuer_authentication_failed
+                );
+
+                assert_eq!(
+                    connection.handle_2nd_generate_ac()?,
+                    CryptogramType::TransactionCertificate
+                );
+            }
+            cryptogram_type => {
+                /* For test case 2ND GEN AC TC is expected */
+                panic!("Unexpected 1st GENERATE AC result {:?}", cryptogram_type);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_store_provenance() -> Result<(), EmvError> {
+        let mut connection = new_connection();
+        let smart_card_connection = Arc::new(DummySmartCardConnection {
+            test_data_file: "test_data.yaml".to_string(),
+        });
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+
+        let application = connection.select_payment_application()?;
+        assert_eq!(application.tags.get("4F"), Some(&application.aid));
+        assert_eq!(
+            application.tags.get_entry("87").map(|entry| entry.origin),
+            Some(TagOrigin::icc(Some(CardCommand::ReadRecord {
+                short_file_identifier: 1,
+                record_index: 1
+            })))
+        );
+        // PSE directory data does not leak into the selected application
+        assert!(connection.get_tag_value("4F").is_none());
+        assert!(connection.get_tag_value("88").is_none());
+        assert_eq!(
+            connection.tags.get_entry("84").map(|entry| entry.origin),
+            Some(TagOrigin::icc(Some(CardCommand::Select)))
+        );
+
+        connection.start_transaction(&application)?;
+        assert_eq!(
+            connection.tags.get_entry("82").map(|entry| entry.origin),
+            Some(TagOrigin::icc(Some(CardCommand::GetProcessingOptions)))
+        );
+        assert!(matches!(
+            connection
+                .tags
+                .get_entry("5A")
+                .and_then(|entry| entry.origin.command),
+            Some(CardCommand::ReadRecord { .. })
+        ));
+        assert!(connection.tags.duplicates().is_empty());
+
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x01\x00".to_vec());
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x02\x00".to_vec());
+        let entry = connection.tags.get_entry("9F02").unwrap();
+        assert_eq!(entry.value, b"\x00\x00\x00\x00\x02\x00".to_vec());
+        assert_eq!(entry.origin, TagOrigin::terminal());
+        assert_eq!(
+            entry.history,
+            vec![tags::TagValue {
+                value: b"\x00\x00\x00\x00\x01\x00".to_vec(),
+                origin: TagOrigin::terminal()
+            }]
+        );
+
+        // a new selection drops the ICC data but keeps the terminal data
+        connection.handle_select_payment_application(&application)?;
+        assert!(connection.get_tag_value("5A").is_none());
+        assert!(connection.get_tag_value("9F02").is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_of_aids_selection() -> Result<(), EmvError> {
+        let fci = |df_name: &[u8], label: &str, priority: u8| {
+            TlvBuilder::new().constructed(
+                "6F",
+                TlvBuilder::new().primitive("84", df_name).constructed(
+                    "A5",
+                    TlvBuilder::new()
+                        .primitive("50", label.as_bytes())
+                        .primitive("87", &[priority]),
+                ),
+            )
+        };
+        let mut blocked_application_response =
+            fci(b"\xA0\x00\x00\x00\x03\x10\x10\x03", "VISA", 0x03)
+                .build()
+                .unwrap();
+        blocked_application_response.extend_from_slice(b"\x62\x83");
+
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                // no PSE
+                b"\x6A\x82".to_vec(),
+                // several ADFs under the first AID
+                card_response(fci(
+                    b"\xA0\x00\x00\x00\x03\x10\x10\x01",
+                    "VISA CREDIT",
+                    0x01,
+                )),
+                card_response(fci(b"\xA0\x00\x00\x00\x03\x10\x10\x02", "VISA DEBIT", 0x02)),
+                blocked_application_response,
+                b"\x6A\x82".to_vec(),
+                // partial selection not allowed for the second AID
+                card_response(fci(b"\xA0\x00\x00\x00\x04\x10\x10\x01", "MASTERCARD", 0x01)),
+                card_response(fci(b"\xA0\x00\x00\x00\x04\x10\x10", "MASTERCARD", 0x02)),
+            ],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        connection.settings.terminal.applications = vec![
+            TerminalApplication {
+                aid: "A0000000031010".to_string(),
+                partial_selection: true,
+                ..TerminalApplication::default()
+            },
+            TerminalApplication {
+                aid: "A0000000041010".to_string(),
+                partial_selection: false,
+                ..TerminalApplication::default()
+            },
+        ];
+
+        let applications = connection.build_candidate_list()?;
+        let aids: Vec<String> = applications
+            .iter()
+            .map(|application| hex::encode_upper(&application.aid))
+            .collect();
+        assert_eq!(
+            aids,
+            vec!["A000000003101001", "A000000003101002", "A0000000041010"]
+        );
+        assert_eq!(applications[1].label, b"VISA DEBIT".to_vec());
+        assert_eq!(applications[1].priority, vec![0x02]);
+
+        // SELECT next occurrence until the card has no more ADFs, none after an exact match
+        let p2_selection_options: Vec<u8> = smart_card_connection
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|command| command[3])
+            .collect();
+        assert_eq!(
+            p2_selection_options,
+            vec![0x00, 0x00, 0x02, 0x02, 0x02, 0x00, 0x02]
+        );
+
+        // no applications by either method
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(vec![], false));
+        connection.interface = Some(smart_card_connection.clone());
+        let error = connection.build_candidate_list().unwrap_err();
+        assert_eq!(error.status_word(), Some((0x6A, 0x82)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_application_priority_selection() -> Result<(), EmvError> {
+        let pse_fci = card_response(
+            TlvBuilder::new().constructed(
+                "6F",
+                TlvBuilder::new()
+                    .primitive("84", b"1PAY.SYS.DDF01")
+                    .constructed("A5", TlvBuilder::new().primitive("88", b"\x01")),
+            ),
+        );
+        let directory_entry = |aid: &[u8], label: &str, priority: Option<u8>| {
+            let mut entry = TlvBuilder::new()
+                .primitive("4F", aid)
+                .primitive("50", label.as_bytes());
+            if let Some(priority) = priority {
+                entry = entry.primitive("87", &[priority]);
+            }
+            TlvBuilder::new().constructed("61", entry)
+        };
+        let aid_a = b"\xA0\x00\x00\x00\x03\x10\x10";
+        let aid_b = b"\xA0\x00\x00\x00\x04\x10\x10";
+        let aid_c = b"\xA0\x00\x00\x00\x04\x30\x60";
+
+        let directory_record = card_response(
+            TlvBuilder::new().constructed(
+                "70",
+                directory_entry(aid_a, "A", Some(0x02))
+                    .raw(&directory_entry(aid_b, "B", None).build().unwrap())
+                    .raw(&directory_entry(aid_c, "C", Some(0x81)).build().unwrap()),
+            ),
+        );
+
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                pse_fci.clone(),
+                directory_record.clone(),
+                b"\x6A\x83".to_vec(),
+            ],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+
+        let applications = connection.build_candidate_list()?;
+        let priority_orders: Vec<u8> = applications
+            .iter()
+            .map(|application| application.priority_order())
+            .collect();
+        assert_eq!(priority_orders, vec![0x02, 0x10, 0x01]);
+        assert!(applications[2].confirmation_required());
+
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                pse_fci.clone(),
+                directory_record,
+                b"\x6A\x83".to_vec(),
+                // the application of the highest priority is blocked
+                b"\x62\x83".to_vec(),
+                card_response(
+                    TlvBuilder::new().constructed("6F", TlvBuilder::new().primitive("84", aid_a)),
+                ),
+            ],
+            false,
+        ));
+        connection.interface = Some(smart_card_connection.clone());
+
+        let application = connection.select_payment_application()?;
+        assert_eq!(application.aid, aid_a.to_vec());
+
+        // the blocked application is removed and the next one in priority order selected
+        let selected_aids: Vec<Vec<u8>> = smart_card_connection
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|command| command[1] == 0xA4)
+            .map(|command| command[5..5 + command[4] as usize].to_vec())
+            .collect();
+        assert_eq!(
+            selected_aids,
+            vec![b"1PAY.SYS.DDF01".to_vec(), aid_c.to_vec(), aid_a.to_vec()]
+        );
+
+        // the only application is not confirmed by the cardholder
+        struct UnconfirmingCardholderInterface;
+        impl CardholderInterface for UnconfirmingCardholderInterface {
+            fn confirm_application(&self, _application: &EmvApplication) -> bool {
+                false
+            }
+        }
+
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                pse_fci.clone(),
+                card_response(
+                    TlvBuilder::new().constructed("70", directory_entry(aid_c, "C", Some(0x81))),
+                ),
+            ],
+            false,
+        ));
+        connection.interface = Some(smart_card_connection.clone());
+        connection.cardholder_interface = Arc::new(UnconfirmingCardholderInterface);
+
+        let error = connection.select_payment_application().unwrap_err();
+        assert!(matches!(
+            error,
+            EmvError::Terminal {
+                reason: "Application not confirmed",
+                ..
+            }
+        ));
+
+        // the application chosen by the cardholder from the list is not confirmed again
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                pse_fci,
+                card_response(
+                    TlvBuilder::new().constructed(
+                        "70",
+                        directory_entry(aid_a, "A", Some(0x02))
+                            .raw(&directory_entry(aid_c, "C", Some(0x81)).build().unwrap()),
+                    ),
+                ),
+                b"\x6A\x83".to_vec(),
+                card_response(
+                    TlvBuilder::new().constructed("6F", TlvBuilder::new().primitive("84", aid_c)),
+                ),
+            ],
+            false,
+        ));
+        connection.interface = Some(smart_card_connection.clone());
+
+        let application = connection.select_payment_application()?;
+        assert_eq!(application.aid, aid_c.to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_point() -> Result<(), EmvError> {
+        let visa_aid = b"\xA0\x00\x00\x00\x03\x10\x10";
+        let mastercard_aid = b"\xA0\x00\x00\x00\x04\x10\x10";
+        let ppse_fci = card_response(
+            TlvBuilder::new().constructed(
+                "6F",
+                TlvBuilder::new()
+                    .primitive("84", b"2PAY.SYS.DDF01")
+                    .constructed(
+                        "A5",
+                        TlvBuilder::new().constructed(
+                            "BF0C",
+                            TlvBuilder::new()
+                                .constructed(
+                                    "61",
+                                    TlvBuilder::new()
+                                        .primitive("4F", b"\xA0\x00\x00\x09\x99\x99\x99")
+                                        .primitive("87", b"\x01"),
+                                )
+                                // a kernel not supported for the AID
+                                .constructed(
+                                    "61",
+                                    TlvBuilder::new()
+                                        .primitive("4F", visa_aid)
+                                        .primitive("87", b"\x01")
+                                        .primitive("9F2A", b"\x05"),
+                                )
+                                // the default kernel of the RID
+                                .constructed(
+                                    "61",
+                                    TlvBuilder::new()
+                                        .primitive("4F", visa_aid)
+                                        .primitive("50", b"VISA")
+                                        .primitive("87", b"\x02"),
+                                )
+                                .constructed(
+                                    "61",
+                                    TlvBuilder::new()
+                                        .primitive("4F", mastercard_aid)
+                                        .primitive("50", b"MASTERCARD")
+                                        .primitive("87", b"\x01")
+                                        .primitive("9F2A", b"\x02")
+                                        .primitive("9F29", b"\x01"),
+                                ),
+                        ),
+                    ),
+            ),
+        );
+
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.contactless = true;
+
+        // ref. EMV Contactless Book B, 3.1 Pre-Processing
+        let combinations = entry_point::pre_processing(&connection.settings.terminal, 100);
+        let visa = combinations
+            .iter()
+            .find(|combination| combination.kernel_id == 3)
+            .unwrap();
+        assert!(visa.indicators.reader_contactless_floor_limit_exceeded);
+        assert!(!visa.indicators.reader_cvm_required_limit_exceeded);
+        assert!(visa.indicators.terminal_transaction_qualifiers.is_some());
+        let mastercard = combinations
+            .iter()
+            .find(|combination| combination.kernel_id == 2)
+            .unwrap();
+        assert!(mastercard
+            .indicators
+            .terminal_transaction_qualifiers
+            .is_none());
+
+        // ref. EMV Contactless Book B, 3.3.2 Combination Selection
+        connection.interface = Some(Arc::new(ScriptedSmartCardConnection::new(
+            vec![ppse_fci.clone()],
+            false,
+        )));
+        let candidates = connection.handle_combination_selection(&combinations)?;
+        let candidate_kernels: Vec<(String, u8, Option<Vec<u8>>)> = candidates
+            .iter()
+            .map(|candidate| {
+                (
+                    hex::encode_upper(&candidate.application.aid),
+                    candidate.combination.kernel_id,
+                    candidate.extended_selection.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            candidate_kernels,
+            vec![
+                ("A0000000031010".to_string(), 3, None),
+                ("A0000000041010".to_string(), 2, Some(vec![0x01])),
+            ]
+        );
+
+        // the highest priority is selected with the extended selection, the kernel rejects the
+        // application and the next one can't be selected
+        let adf_fci = |adf_name: &[u8]| {
+            card_response(
+                TlvBuilder::new().constructed("6F", TlvBuilder::new().primitive("84", adf_name)),
+            )
+        };
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                ppse_fci,
+                adf_fci(b"\xA0\x00\x00\x00\x04\x10\x10\x01"),
+                b"\x69\x86".to_vec(),
+                b"\x6A\x82".to_vec(),
+            ],
+            false,
+        ));
+        connection.interface = Some(smart_card_connection.clone());
+
+        let outcome = EmvTransaction::new(&mut connection, 100).run()?;
+        assert_eq!(outcome.result, TransactionResult::TryAnotherInterface);
+
+        let commands: Vec<(u8, Vec<u8>)> = smart_card_connection
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|command| (command[1], command[5..5 + command[4] as usize].to_vec()))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                (0xA4, b"2PAY.SYS.DDF01".to_vec()),
+                (0xA4, b"\xA0\x00\x00\x00\x04\x10\x10\x01".to_vec()),
+                (0xA8, b"\x83\x00".to_vec()),
+                (0xA4, visa_aid.to_vec()),
+            ]
+        );
+
+        // the TTQ copy of the kernel is used
+        connection.interface = Some(Arc::new(ScriptedSmartCardConnection::new(
+            vec![adf_fci(visa_aid)],
+            false,
+        )));
+        let mut visa_candidates = vec![candidates[0].clone()];
+        let kernel_activation = connection
+            .handle_final_combination_selection(&mut visa_candidates)?
+            .unwrap();
+        assert_eq!(kernel_activation.kernel_id, 3);
+        assert!(visa_candidates.is_empty());
+        assert_eq!(connection.tag_scope, Some(TagScope::KernelId(3)));
+        assert_eq!(
+            connection.get_tag_value("9F66"),
+            kernel_activation
+                .indicators
+                .terminal_transaction_qualifiers
+                .as_ref()
+        );
+
+        // the TTQ of the previous combination is not kept for a kernel which doesn't use it
+        connection.interface = Some(Arc::new(ScriptedSmartCardConnection::new(
+            vec![adf_fci(b"\xA0\x00\x00\x00\x04\x10\x10\x01")],
+            false,
+        )));
+        let mut mastercard_candidates = vec![candidates[1].clone()];
+        let kernel_activation = connection
+            .handle_final_combination_selection(&mut mastercard_candidates)?
+            .unwrap();
+        assert_eq!(kernel_activation.kernel_id, 2);
+        assert_eq!(connection.get_tag_value("9F66"), None);
+        assert_eq!(
+            connection
+                .terminal_application
+                .as_ref()
+                .map(|terminal_application| terminal_application.aid.clone()),
+            Some("A0000000041010".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_point_pre_processing() -> Result<(), EmvError> {
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.contactless = true;
+
+        let visa_indicators = |terminal: &Terminal, amount: u64| {
+            entry_point::pre_processing(terminal, amount)
+                .into_iter()
+                .find(|combination| combination.terminal_application.aid == "A0000000031010")
+                .unwrap()
+                .indicators
+        };
+
+        // ref. EMV Contactless Book B, 3.1.1.8 and 3.1.1.11 - online cryptogram and CVM required in the TTQ copy
+        let indicators = visa_indicators(&connection.settings.terminal, 10);
+        assert!(!indicators.contactless_application_not_allowed);
+        assert!(indicators.reader_contactless_floor_limit_exceeded);
+        assert!(!indicators.reader_cvm_required_limit_exceeded);
+        assert_eq!(
+            indicators.terminal_transaction_qualifiers.unwrap()[1] & 0xC0,
+            0x80
+        );
+        let indicators = visa_indicators(&connection.settings.terminal, 5000);
+        assert!(indicators.reader_cvm_required_limit_exceeded);
+        assert_eq!(
+            indicators.terminal_transaction_qualifiers.unwrap()[1] & 0xC0,
+            0xC0
+        );
+
+        // 3.1.1.3 and 3.1.1.10 - zero amount
+        let indicators = visa_indicators(&connection.settings.terminal, 0);
+        assert!(indicators.zero_amount);
+        assert!(!indicators.reader_contactless_floor_limit_exceeded);
+        assert_eq!(
+            indicators.terminal_transaction_qualifiers.unwrap()[1] & 0xC0,
+            0x80
+        );
+        let terminal = &mut connection.settings.terminal;
+        terminal.terminal_transaction_qualifiers.offline_only_reader = true;
+        assert!(visa_indicators(terminal, 0).contactless_application_not_allowed);
+        for terminal_application in terminal.applications.iter_mut() {
+            terminal_application.zero_amount_allowed = false;
+        }
+        terminal.terminal_transaction_qualifiers.offline_only_reader = false;
+        assert!(visa_indicators(terminal, 0).contactless_application_not_allowed);
+
+        // 3.3.2.5 - a combination over the contactless transaction limit is not a candidate
+        for terminal_application in connection.settings.terminal.applications.iter_mut() {
+            terminal_application.zero_amount_allowed = true;
+            if terminal_application.aid == "A0000000041010" {
+                terminal_application.contactless_transaction_limit = Some(100);
+            }
+        }
+        let ppse_fci = TlvBuilder::new()
+            .constructed(
+                "6F",
+                TlvBuilder::new().constructed(
+                    "A5",
+                    TlvBuilder::new().constructed(
+                        "BF0C",
+                        TlvBuilder::new()
+                            .constructed(
+                                "61",
+                                TlvBuilder::new().primitive("4F", b"\xA0\x00\x00\x00\x04\x10\x10"),
+                            )
+                            .constructed(
+                                "61",
+                                TlvBuilder::new().primitive("4F", b"\xA0\x00\x00\x00\x03\x10\x10"),
+                            ),
+                    ),
+                ),
+            )
+            .build()
+            .unwrap();
+        let combinations = entry_point::pre_processing(&connection.settings.terminal, 100);
+        let candidates = entry_point::build_candidate_list(&combinations, &ppse_fci);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].combination.kernel_id, 3);
+
+        // 3.1.1.13 - no combination allowed, the card is not accessed
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(Vec::new(), false));
+        connection.interface = Some(smart_card_connection.clone());
+        let outcome = EmvTransaction::new(&mut connection, 100000).run()?;
+        assert_eq!(outcome.result, TransactionResult::TryAnotherInterface);
+        assert!(smart_card_connection.commands.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_terminal_application_parameters() -> Result<(), EmvError> {
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+
+        // the bundled table, the exact match is preferred over the partial one
+        let terminal = &connection.settings.terminal;
+        let application = |aid: &[u8]| terminal.application(aid).map(|a| a.aid.clone());
+        assert_eq!(
+            application(b"\xA0\x00\x00\x00\x03\x10\x10\x01"),
+            Some("A0000000031010".to_string())
+        );
+        assert_eq!(
+            application(b"\xA0\x00\x00\x00\x04\x30\x60"),
+            Some("A0000000043060".to_string())
+        );
+        assert_eq!(application(b"\xA0\x00\x00\x00\x04"), None);
+        assert!(terminal
+            .application(b"\xA0\x00\x00\x00\x03\x10\x10")
+            .unwrap()
+            .terminal_action_codes()
+            .is_ok());
+
+        let terminal_application = TerminalApplication {
+            aid: "A0000000031010".to_string(),
+            application_version_number: Some("008C".to_string()),
+            floor_limit: 1000,
+            random_selection_threshold: 500,
+            random_selection_target_percentage: 0,
+            random_selection_maximum_target_percentage: 50,
+            default_tdol: Some("9F0206".to_string()),
+            ..TerminalApplication::default()
+        };
+        connection.terminal_application = Some(terminal_application.clone());
+        connection.process_settings().unwrap();
+        assert_eq!(connection.get_tag_value("9F09"), Some(&vec![0x00, 0x8C]));
+        assert_eq!(
+            connection.get_tag_value("9F1B"),
+            Some(&vec![0x00, 0x00, 0x03, 0xE8])
+        );
+
+        // ref. EMV Book 3, 10.6.1 Floor Limits
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x10\x00".to_vec());
+        connection.handle_terminal_risk_management()?;
+        assert!(
+            connection
+                .settings
+                .terminal
+                .tvr
+                .transaction_exceeds_floor_limit
+        );
+        assert!(
+            connection
+                .settings
+                .terminal
+                .tsi
+                .terminal_risk_management_was_performed
+        );
+
+        // random numbers are 1 without use_random, selected when the target percentage is reached
+        connection.settings.terminal.tvr = vec![0; 5].into();
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x05\x00".to_vec());
+        connection.handle_terminal_risk_management()?;
+        assert!(
+            !connection
+                .settings
+                .terminal
+                .tvr
+                .transaction_exceeds_floor_limit
+        );
+        assert!(
+            !connection
+                .settings
+                .terminal
+                .tvr
+                .transaction_selected_randomly_for_online_processing
+        );
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x05\x20".to_vec());
+        connection.handle_terminal_risk_management()?;
+        assert!(
+            connection
+                .settings
+                .terminal
+                .tvr
+                .transaction_selected_randomly_for_online_processing
+        );
+
+        // ref. EMV Book 3, 9.2.2 - the default TDOL is used when the ICC has none
+        connection.handle_tc_hash_value(TransactionStep::CardActionAnalysis)?;
+        assert_eq!(
+            connection.get_tag_value("98"),
+            Some(&sha::sha1(b"\x00\x00\x00\x00\x05\x20").to_vec())
+        );
+        assert!(connection.settings.terminal.tvr.default_tdol_used);
+
+        // no terminal parameters for the application
+        connection.terminal_application = None;
+        connection.settings.terminal.tvr = vec![0; 5].into();
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x20\x00".to_vec());
+        connection.handle_terminal_risk_management()?;
+        assert!(
+            !connection
+                .settings
+                .terminal
+                .tvr
+                .transaction_exceeds_floor_limit
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_terminal_action_analysis() -> Result<(), EmvError> {
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        connection.terminal_application = Some(TerminalApplication {
+            aid: "A0000000031010".to_string(),
+            terminal_action_code_denial: "0040000000".to_string(),
+            terminal_action_code_online: "0000000000".to_string(),
+            terminal_action_code_default: "0000000000".to_string(),
+            ..TerminalApplication::default()
+        });
+        connection.process_settings().unwrap();
+
+        // ref. EMV Book 3, 10.7 - no TVR bit set, TC
+        assert_eq!(
+            connection.handle_terminal_action_analysis()?,
+            CryptogramType::TransactionCertificate
+        );
+
+        // the Issuer Action Code - Online is all bits 1 when the ICC has none
+        connection.settings.terminal.tvr.new_card = true;
+        assert_eq!(
+            connection.handle_terminal_action_analysis()?,
+            CryptogramType::AuthorisationRequestCryptogram
+        );
+
+        // offline-only terminal, the Issuer Action Code - Default is all bits 1 when the ICC has none
+        connection.process_tag_as_tlv("9F35", b"\x23".to_vec());
+        assert_eq!(
+            connection.handle_terminal_action_analysis()?,
+            CryptogramType::ApplicationAuthenticationCryptogram
+        );
+        connection.process_tag_as_tlv("9F0D", b"\x00\x00\x00\x00\x00".to_vec());
+        assert_eq!(
+            connection.handle_terminal_action_analysis()?,
+            CryptogramType::TransactionCertificate
+        );
+
+        // Terminal Action Code - Denial match, the first GENERATE AC requests an AAC
+        connection.settings.terminal.tvr.expired_application = true;
+        let cryptogram_type = connection.handle_terminal_action_analysis()?;
+        assert_eq!(
+            cryptogram_type,
+            CryptogramType::ApplicationAuthenticationCryptogram
+        );
+
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![b"\x80\x12\x00\x00\xF3\xB0\x18\x91\x01\xD1\x14\x16\xC1\x06\x01\x0A\x03\xA4\xA0\x02\x90\x00".to_vec()],
+            false,
+        ));
+        connection.interface = Some(smart_card_connection.clone());
+        connection.process_tag_as_tlv("8C", b"\x95\x05".to_vec());
+        let error = connection
+            .handle_1st_generate_ac(cryptogram_type)
+            .unwrap_err();
+        assert_eq!(error.decline_reason(), Some(DeclineReason::Terminal));
+
+        let commands = smart_card_connection.commands.lock().unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(&commands[0][..4], b"\x80\xAE\x00\x00");
+        assert_eq!(&commands[0][5..10], b"\x00\x48\x00\x00\x00");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_processing_restrictions() -> Result<(), EmvError> {
+        let restricted_connection = |tags: &[(&str, &[u8])]| {
+            let mut connection = new_connection();
+            connection.terminal_application = Some(TerminalApplication {
+                aid: "A0000000031010".to_string(),
+                application_version_number: Some("008C".to_string()),
+                ..TerminalApplication::default()
+            });
+            connection.process_settings().unwrap();
+            connection.process_tag_as_tlv("9A", b"\x20\x07\x24".to_vec());
+            connection.process_tag_as_tlv("9C", b"\x00".to_vec());
+            for (tag_name, value) in tags {
+                connection.process_tag_as_tlv(tag_name, value.to_vec());
+            }
+            connection
+        };
+
+        // expired card of the test data, domestic purchase allowed by the AUC
+        let mut connection = restricted_connection(&[
+            ("5F24", b"\x18\x11\x30"),
+            ("9F07", b"\xFF\x80"),
+            ("5F28", b"\x02\x46"),
+            ("9F08", b"\x00\x8C"),
+        ]);
+        connection.handle_processing_restrictions()?;
+        let tvr = connection.settings.terminal.tvr;
+        assert!(tvr.expired_application);
+        assert!(!tvr.application_not_yet_effective);
+        assert!(!tvr.icc_and_terminal_have_different_application_versions);
+        assert!(!tvr.requested_service_not_allowed_for_card_product);
+
+        // international cash withdrawal with a card valid only for domestic cash
+        let mut connection = restricted_connection(&[
+            ("5F25", b"\x21\x01\x01"),
+            ("5F24", b"\x29\x12\x31"),
+            ("9F07", b"\x81\x00"),
+            ("5F28", b"\x08\x40"),
+            ("9F08", b"\x00\x96"),
+            ("9C", b"\x01"),
+        ]);
+        connection.handle_processing_restrictions()?;
+        let tvr = connection.settings.terminal.tvr;
+        assert!(!tvr.expired_application);
+        assert!(tvr.application_not_yet_effective);
+        assert!(tvr.icc_and_terminal_have_different_application_versions);
+        assert!(tvr.requested_service_not_allowed_for_card_product);
+
+        // the expired application is declined by the Terminal Action Code - Denial
+        let mut connection = restricted_connection(&[("5F24", b"\x20\x07\x23")]);
+        connection.terminal_application = Some(TerminalApplication {
+            aid: "A0000000031010".to_string(),
+            terminal_action_code_denial: "0040000000".to_string(),
+            terminal_action_code_online: "0000000000".to_string(),
+            terminal_action_code_default: "0000000000".to_string(),
+            ..TerminalApplication::default()
+        });
+        connection.handle_processing_restrictions()?;
+        assert_eq!(
+            connection.handle_terminal_action_analysis()?,
+            CryptogramType::ApplicationAuthenticationCryptogram
+        );
+
+        let mut connection = restricted_connection(&[("5F24", b"\x20\x07\x24")]);
+        connection.handle_processing_restrictions()?;
+        assert!(!connection.settings.terminal.tvr.expired_application);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_record_tag() -> Result<(), EmvError> {
+        let track2 = b"\x12\x34\x56\x00\x12\x34\x56\x08\xD1\x81\x12\x21";
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                card_response(
+                    TlvBuilder::new().constructed(
+                        "77",
+                        TlvBuilder::new()
+                            .primitive("82", b"\x3C\x00")
+                            .primitive("94", b"\x08\x01\x02\x00"),
+                    ),
+                ),
+                card_response(
+                    TlvBuilder::new().constructed("70", TlvBuilder::new().primitive("57", track2)),
+                ),
+                card_response(
+                    TlvBuilder::new().constructed(
+                        "70",
+                        TlvBuilder::new()
+                            .primitive("5F24", b"\x29\x12\x31")
+                            .primitive("57", track2),
+                    ),
+                ),
+            ],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+
+        let error = connection.handle_get_processing_options().unwrap_err();
+        assert_eq!(error.step(), TransactionStep::ReadApplicationData);
+        assert!(matches!(error, EmvError::DuplicateTag { .. }));
+        assert_eq!(error.tag(), Some("57"));
+
+        // a record repeating the AIP of a format 2 GPO response
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                card_response(
+                    TlvBuilder::new().constructed(
+                        "77",
+                        TlvBuilder::new()
+                            .primitive("82", b"\x3C\x00")
+                            .primitive("94", b"\x08\x01\x01\x00"),
+                    ),
+                ),
+                card_response(
+                    TlvBuilder::new().constructed(
+                        "70",
+                        TlvBuilder::new()
+                            .primitive("57", track2)
+                            .primitive("82", b"\x3C\x00"),
+                    ),
+                ),
+            ],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+
+        let error = connection.handle_get_processing_options().unwrap_err();
+        assert!(matches!(error, EmvError::DuplicateTag { .. }));
+        assert_eq!(error.tag(), Some("82"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_validation() -> Result<(), EmvError> {
+        let connection = new_connection();
+        let validate = |tag_name: &str, value: &[u8]| {
+            connection
+                .get_emv_tag(tag_name)
+                .unwrap()
+                .validate_icc_value(value)
+        };
+
+        assert!(validate("5A", b"\x54\x13\x33\x00\x89\x00\x00\x3F").is_ok());
+        assert!(validate("5A", b"\x54\x13\x3F\x00").is_err());
+        assert!(validate("5F24", b"\x29\x12\x31").is_ok());
+        assert!(validate("5F24", b"\x29\x1A\x31").is_err());
+        assert!(validate("5F28", b"\x02\x46").is_ok());
+        assert!(validate("5F28", b"\x02\x46\x00").is_err());
+        assert!(validate("5F2D", b"enfi").is_ok());
+        assert!(validate("5F2D", b"e-").is_err());
+        assert!(validate("50", b"VESA ELECTRON").is_ok());
+        assert!(validate("50", b"VESA\x0AELECTRON").is_err());
+        assert!(validate("5F20", b"DOE/JOHN").is_ok());
+        // ISO/IEC 8859-1 preferred name
+        assert!(validate("9F12", b"K\xE4ytt\xF6tili").is_ok());
+        assert!(validate("9F12", b"K\x84ytt\x94tili").is_err());
+        assert!(validate("50", b"K\xE4ytt\xF6tili").is_err());
+        assert!(matches!(
+            connection.decode_tag("9F12", b"K\xE4ytt\xF6tili").value,
+            Some(DecodedValue::Text(name)) if name == "Käyttötili"
+        ));
+        assert_eq!(
+            validate("82", b"\x3C"),
+            Err(TagValidationError::InvalidLength {
+                tag: "82".to_string(),
+                length: 1,
+                min: Some(2),
+                max: Some(2)
+            })
+        );
+        assert_eq!(
+            validate("9F02", b"\x00\x00\x00\x00\x01\x00"),
+            Err(TagValidationError::TerminalData {
+                tag: "9F02".to_string()
+            })
+        );
+
+        // the terminal value is kept when the card returns a terminal data object
+        let mut connection = new_connection();
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x01\x00".to_vec());
+        connection.process_tag_as_tlv_from(
+            "9F02",
+            b"\x00\x00\x00\x00\x00\x01".to_vec(),
+            TagOrigin::icc(Some(CardCommand::GetData)),
+        );
+        assert_eq!(
+            connection.get_tag_value("9F02"),
+            Some(&b"\x00\x00\x00\x00\x01\x00".to_vec())
+        );
+        assert!(!connection.settings.terminal.tvr.icc_data_missing);
+
+        connection.process_tag_as_tlv_from(
+            "9F12",
+            b"K\xE4ytt\xF6tili".to_vec(),
+            TagOrigin::icc(Some(CardCommand::Select)),
+        );
+        assert!(connection.get_tag_value("9F12").is_some());
+        assert!(!connection.settings.terminal.tvr.icc_data_missing);
+
+        // an invalid value is treated as missing
+        connection.process_tag_as_tlv_from(
+            "9F36",
+            b"\x00".to_vec(),
+            TagOrigin::icc(Some(CardCommand::GetData)),
+        );
+        assert!(connection.get_tag_value("9F36").is_none());
+        assert!(connection.settings.terminal.tvr.icc_data_missing);
+        assert_eq!(connection.tags.rejected().len(), 2);
+
+        // format errors in the records terminate the transaction
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                card_response(
+                    TlvBuilder::new().constructed(
+                        "77",
+                        TlvBuilder::new()
+                            .primitive("82", b"\x3C\x00")
+                            .primitive("94", b"\x08\x01\x01\x00"),
+                    ),
+                ),
+                card_response(
+                    TlvBuilder::new()
+                        .constructed("70", TlvBuilder::new().primitive("5F24", b"\x29\x1A\x31")),
+                ),
+            ],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+
+        let error = connection.handle_get_processing_options().unwrap_err();
+        assert_eq!(error.step(), TransactionStep::ReadApplicationData);
+        assert!(matches!(
+            error,
+            EmvError::InvalidTagValue {
+                error: TagValidationError::InvalidFormat { .. },
+                ..
+            }
+        ));
+        assert_eq!(error.tag(), Some("5F24"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_dictionary() {
+        let connection = new_connection();
+
+        let pan = connection.get_emv_tag("5A").unwrap();
+        assert_eq!(
+            pan.templates,
+            Some(vec!["70".to_string(), "77".to_string()])
+        );
+        assert!(pan.kernels.is_none());
+
+        let issuer_country_code = connection.get_emv_tag("5F55").unwrap();
+        assert!(issuer_country_code.validate_icc_value(b"FI").is_ok());
+        assert!(issuer_country_code.validate_icc_value(b"F1").is_err());
+
+        let error_indication = connection.get_emv_tag("DF8115").unwrap();
+        assert_eq!(error_indication.kernels, Some(vec!["C-2".to_string()]));
+        assert!(matches!(
+            error_indication.validate_icc_value(&[0x00; 6]),
+            Err(TagValidationError::TerminalData { .. })
+        ));
+
+        // tags defined differently by the kernels accept any ICC value
+        let form_factor_indicator = connection.get_emv_tag("9F6E").unwrap();
+        assert_eq!(form_factor_indicator.kernels.as_ref().unwrap().len(), 3);
+        assert!(form_factor_indicator.validate_icc_value(&[0x00; 4]).is_ok());
 
-fn find_tlv_tag(buf: &[u8], tag: &str) -> Option<Tlv> {
-    let mut read_buffer = buf;
+        for (tag_name, emv_tag) in connection.emv_tags.iter() {
+            assert_eq!(tag_name, &emv_tag.tag);
+        }
+    }
 
-    loop {
-        let (tlv_data, leftover_buffer) = Tlv::parse(read_buffer);
+    #[test]
+    fn test_kernel_tag_dictionary() {
+        let mut connection = new_connection();
+        let name = |connection: &EmvConnection, tag_name: &str| {
+            connection.get_emv_tag(tag_name).unwrap().name.clone()
+        };
 
-        let tlv_data: Tlv = match tlv_data {
-            Ok(tlv) => tlv,
-            Err(err) => {
-                if leftover_buffer.len() > 0 {
-                    trace!(
-                        "Could not parse as TLV! error:{:?}, data: {:02X?}",
-                        err,
-                        read_buffer
-                    );
-                }
+        let generic_name = name(&connection, "9F6E");
+        assert!(generic_name.contains("Form Factor Indicator"));
+        assert!(generic_name.contains("Third Party Data"));
 
-                break;
-            }
+        let mut application = EmvApplication {
+            aid: b"\xA0\x00\x00\x00\x03\x10\x10".to_vec(),
+            label: b"VISA".to_vec(),
+            priority: b"\x01".to_vec(),
+            tags: TagStore::new(),
         };
+        let visa = TagScope::of_application(&application);
+        assert_eq!(visa, TagScope::Rid(b"\xA0\x00\x00\x00\x03".to_vec()));
+        assert_eq!(
+            connection.get_emv_tag_in(Some(&visa), "9F6E").unwrap().name,
+            "Form Factor Indicator (FFI)"
+        );
 
-        read_buffer = leftover_buffer;
+        // the kernel announced in the directory entry takes precedence over the RID
+        application
+            .tags
+            .insert("9F2A", b"\x02".to_vec(), TagOrigin::icc(None));
+        connection.tag_scope = Some(TagScope::of_application(&application));
+        assert_eq!(connection.tag_scope, Some(TagScope::KernelId(2)));
+
+        // b8-b7 '01' is an international kernel like in the combination selection
+        application
+            .tags
+            .insert("9F2A", b"\x42\x00".to_vec(), TagOrigin::icc(None));
+        assert_eq!(
+            TagScope::of_application(&application),
+            TagScope::KernelId(2)
+        );
+        assert_eq!(tags::international_kernel_id(b"\x42\x00"), Some(2));
+        assert_eq!(tags::international_kernel_id(b"\x82"), None);
+        assert_eq!(tags::international_kernel_id(b"\x00"), None);
+        application
+            .tags
+            .insert("9F2A", b"\x02".to_vec(), TagOrigin::icc(None));
+        assert_eq!(name(&connection, "9F6E"), "Third Party Data");
+        assert_eq!(name(&connection, "DF8115"), "Error Indication");
+        assert_eq!(
+            name(&connection, "5A"),
+            "Application Primary Account Number (PAN)"
+        );
 
-        let tag_name = hex::encode_upper(tlv_data.tag().to_bytes());
+        // ICC validation uses the kernel definition, 9F66 is terminal data for other kernels
+        let punatc = connection.get_emv_tag("9F66").unwrap();
+        assert!(punatc.validate_icc_value(b"\x00\xFF").is_ok());
+        assert!(connection
+            .get_emv_tag_in(None, "9F66")
+            .unwrap()
+            .validate_icc_value(b"\x00\xFF")
+            .is_err());
+
+        // DOL formatting and decoding
+        connection.tag_scope = Some(TagScope::KernelId(3));
+        let data_object = DataObject::new(&connection, "9F5D", 6);
+        assert_eq!(data_object.emv_tag.format, Some(FieldFormat::Numeric));
+        assert!(matches!(
+            connection.decode_tag("9F5D", b"\x00\x00\x00\x01\x00\x00").value,
+            Some(DecodedValue::Numeric(amount)) if amount == "10000"
+        ));
+    }
 
-        if tag_name.eq(tag) {
-            return Some(tlv_data);
-        }
+    #[test]
+    fn test_issuer_application_data() {
+        let parse = |iad: &str| IssuerApplicationData::parse(&hex::decode(iad).unwrap(), None);
+
+        // Visa CVN 10, ARQC requested, velocity checking counters exceeded
+        let visa = parse("06010A03A02000").unwrap();
+        assert_eq!(visa.format, iad::IssuerApplicationDataFormat::Visa);
+        assert_eq!(visa.cryptogram_version_number, 0x0A);
+        assert_eq!(visa.derivation_key_index, 0x01);
+        let cvr = &visa.card_verification_results;
+        assert_eq!(
+            cvr.first_generate_ac,
+            Some(CryptogramType::AuthorisationRequestCryptogram)
+        );
+        assert_eq!(cvr.second_generate_ac, None);
+        assert!(cvr.offline_limits_exceeded);
+        assert!(!cvr.pin_try_limit_exceeded);
+        assert!(visa.issuer_discretionary_data.is_none());
+
+        // Visa CVN 18 with issuer discretionary data, application blocked by the PIN Try Limit
+        let visa = parse(&format!("060112038042100F{}", "00".repeat(15))).unwrap();
+        assert!(visa.card_verification_results.pin_try_limit_exceeded);
+        assert_eq!(visa.card_verification_results.issuer_script_commands, 1);
+        assert_eq!(visa.issuer_discretionary_data, Some("00".repeat(15)));
+
+        // M/Chip CVN 10, 2 scripts processed, 3 PIN tries left, upper consecutive offline limit exceeded
+        let mchip = parse("0110A0002308400000000000000000000000").unwrap();
+        assert_eq!(mchip.format, iad::IssuerApplicationDataFormat::MChip);
+        let cvr = &mchip.card_verification_results;
+        assert_eq!(cvr.issuer_script_commands, 2);
+        assert_eq!(cvr.pin_try_counter, Some(3));
+        assert!(cvr.pin_try_limit_exceeded);
+        assert!(cvr.upper_consecutive_offline_limit_exceeded);
+        assert!(!cvr.lower_consecutive_offline_limit_exceeded);
+
+        // Common Core Definitions, offline PIN tried until the limit, previous ODA failed
+        let ccd = parse(&format!(
+            "0FA501A00A20240000000000000000000F{}",
+            "00".repeat(15)
+        ))
+        .unwrap();
+        assert_eq!(
+            ccd.format,
+            iad::IssuerApplicationDataFormat::CommonCoreDefinitions
+        );
+        let cvr = &ccd.card_verification_results;
+        assert!(cvr.offline_pin_verification_performed);
+        assert!(cvr.pin_try_limit_exceeded);
+        assert!(cvr.lower_cumulative_offline_limit_exceeded);
+        assert_eq!(cvr.issuer_script_commands, 2);
+        assert!(cvr.offline_data_authentication_failed_on_previous_transaction);
+
+        // the scheme of the selected application rules out the other layouts
+        let visa_scope = TagScope::Rid(b"\xA0\x00\x00\x00\x03".to_vec());
+        let mchip_iad = hex::decode("0110A00023084000").unwrap();
+        assert!(IssuerApplicationData::parse(&mchip_iad, Some(&visa_scope)).is_none());
+        assert!(parse("0102030405").is_none());
+
+        let mut connection = new_connection();
+        connection.tag_scope = Some(TagScope::KernelId(2));
+        assert!(matches!(
+            connection.decode_tag("9F10", &mchip_iad).value,
+            Some(DecodedValue::IssuerApplicationData(iad)) if iad.card_verification_results.pin_try_limit_exceeded
+        ));
+    }
 
-        if let Value::Constructed(v) = tlv_data.value() {
-            for tlv_tag in v {
-                let child_tlv: Option<Tlv> = find_tlv_tag(&tlv_tag.to_vec(), tag);
-                if child_tlv.is_some() {
-                    return child_tlv;
+    #[test]
+    fn test_tag_decoder() -> Result<(), tlv::TlvError> {
+        let mut connection = new_connection();
+
+        let field_55 = TlvBuilder::new()
+            .primitive("9F27", b"\x80")
+            .primitive("95", b"\x80\x00\x08\x80\x00")
+            .primitive("82", b"\x3C\x00")
+            .primitive(
+                "8E",
+                b"\x00\x00\x00\x00\x00\x00\x00\x00\x42\x03\x1F\x00\x2A\x00",
+            )
+            .primitive("9A", b"\x26\x10\x16")
+            .primitive("5F2A", b"\x09\x78")
+            .primitive("9F02", b"\x00\x00\x00\x01\x23\x45")
+            .primitive("5A", b"\x54\x13\x33\x00\x89\x00\x00\x3F")
+            .constructed(
+                "71",
+                TlvBuilder::new().primitive("9F18", b"\x00\x00\x00\x01"),
+            )
+            .build()?;
+
+        let decoded_tags = connection.decode_tlv(&field_55);
+        assert_eq!(decoded_tags.len(), 9);
+
+        assert!(matches!(
+            decoded_tags[0].value,
+            Some(DecodedValue::CryptogramInformationData(
+                CryptogramInformationData {
+                    cryptogram_type: Some(CryptogramType::AuthorisationRequestCryptogram),
+                    advice_required: false,
+                    reason_code: Some(CidReasonCode::NoInformationGiven),
+                    ..
                 }
+            ))
+        ));
+        match &decoded_tags[1].value {
+            Some(DecodedValue::TerminalVerificationResults(tvr)) => {
+                assert!(tvr.offline_data_authentication_was_not_performed);
+                assert!(tvr.pin_entry_required_pin_pad_present_but_pin_was_not_entered);
+                assert!(tvr.transaction_exceeds_floor_limit);
+                assert!(!tvr.sda_failed);
+            }
+            value => panic!("Unexpected TVR value {:?}", value),
+        }
+        match &decoded_tags[2].value {
+            Some(DecodedValue::ApplicationInterchangeProfile(aip)) => {
+                assert!(!aip.sda_supported);
+                assert!(aip.dda_supported);
+                assert!(aip.cardholder_verification_supported);
+                assert!(!aip.cda_supported);
             }
+            value => panic!("Unexpected AIP value {:?}", value),
+        }
+        match &decoded_tags[3].value {
+            Some(DecodedValue::CvmList(cvm_list)) => {
+                assert_eq!(cvm_list.rules.len(), 3);
+                assert_eq!(cvm_list.rules[0].code, Some(CvmCode::EncipheredPinOnline));
+                assert_eq!(
+                    cvm_list.rules[0].condition,
+                    Some(CvmConditionCode::CvmSupported)
+                );
+                assert!(!cvm_list.rules[0].fail_if_unsuccessful);
+                assert_eq!(cvm_list.rules[1].code, Some(CvmCode::NoCvm));
+                assert_eq!(cvm_list.rules[2].code, None);
+            }
+            value => panic!("Unexpected CVM list value {:?}", value),
         }
+        assert!(
+            matches!(&decoded_tags[4].value, Some(DecodedValue::Date(date)) if date == "2026-10-16")
+        );
+        assert!(matches!(
+            &decoded_tags[5].value,
+            Some(DecodedValue::CurrencyCode { code, currency: Some(currency) }) if code == "978" && currency == "EUR"
+        ));
+        assert!(
+            matches!(&decoded_tags[6].value, Some(DecodedValue::Numeric(amount)) if amount == "12345")
+        );
+        assert!(
+            matches!(&decoded_tags[7].value, Some(DecodedValue::Numeric(pan)) if pan == "541333008900003")
+        );
+        assert_eq!(decoded_tags[7].raw.as_deref(), Some("541333008900003F"));
+        assert_eq!(decoded_tags[8].children.len(), 1);
+        assert_eq!(decoded_tags[8].children[0].tag, "9F18");
+
+        let serialized = serde_yaml::to_string(&decoded_tags).unwrap();
+        assert!(serialized.contains("type: TerminalVerificationResults"));
+        assert!(serialized.contains("transaction_exceeds_floor_limit: true"));
+
+        // sensitive values are censored like in the logs
+        connection.settings.censor_sensitive_fields = true;
+        let decoded_pan = connection.decode_tag("5A", b"\x54\x13\x33\x00\x89\x00\x00\x3F");
+        assert_eq!(decoded_pan.raw, None);
+        assert!(
+            matches!(&decoded_pan.value, Some(DecodedValue::Numeric(pan)) if !pan.contains("30089"))
+        );
 
-        if leftover_buffer.len() == 0 {
-            break;
-        }
+        let record = TlvBuilder::new()
+            .constructed(
+                "70",
+                TlvBuilder::new()
+                    .primitive("5A", b"\x54\x13\x33\x00\x89\x00\x00\x3F")
+                    .primitive(
+                        "57",
+                        b"\x54\x13\x33\x00\x89\x00\x00\x3F\xD2\x51\x22\x01\x12\x34\x5F",
+                    ),
+            )
+            .build()?;
+        let decoded_record = connection.decode_tlv(&record);
+        assert_eq!(decoded_record.len(), 1);
+        assert_eq!(decoded_record[0].raw, None);
+        assert_eq!(decoded_record[0].children.len(), 2);
+        assert!(decoded_record[0]
+            .children
+            .iter()
+            .all(|child| child.raw.is_none()));
+        let serialized = serde_yaml::to_string(&decoded_record).unwrap();
+        assert!(!serialized.contains("5413330089"));
+        assert!(!serialized.contains("541333008900003"));
+
+        Ok(())
     }
 
-    None
-}
+    fn online_approval(arqc: &ArqcData) -> Result<OnlineAuthorizationResponse, ()> {
+        assert_eq!(arqc.cryptogram_information_data, 0x80);
+        assert!(arqc.icc_data.starts_with(b"\x9F\x26\x08"));
 
-pub fn get_ca_public_key<'a>(
-    ca_data: &'a HashMap<String, CertificateAuthority>,
-    rid: &[u8],
-    index: &[u8],
-) -> Option<&'a RsaPublicKey> {
-    match ca_data.get(&hex::encode_upper(&rid)) {
-        Some(ca) => match ca.certificates.get(&hex::encode_upper(&index)) {
-            Some(pk) => Some(pk),
-            _ => {
-                warn!("No CA key defined! rid:{:02X?}, index:{:02X?}", rid, index);
-                return None;
-            }
-        },
-        _ => None,
+        Ok(OnlineAuthorizationResponse {
+            authorisation_response_code: b"00".to_vec(),
+            issuer_authentication_data: Some(b"\x12\x34\x56\x78\x12\x34\x56\x78".to_vec()),
+        })
     }
-}
 
-pub fn is_certificate_expired(date_bcd: &[u8]) -> bool {
-    let today = Utc::now().date_naive();
-    let expiry_date =
-        NaiveDate::parse_from_str(&format!("01{:02X?}", date_bcd), "%d[%m, %y]").unwrap();
-    let duration = today.signed_duration_since(expiry_date).num_days();
+    fn online_decline(_arqc: &ArqcData) -> Result<OnlineAuthorizationResponse, ()> {
+        Ok(OnlineAuthorizationResponse {
+            authorisation_response_code: b"05".to_vec(),
+            issuer_authentication_data: None,
+        })
+    }
 
-    if duration > 30 {
-        warn!(
-            "Certificate expiry date (MMYY) {:02X?} is {} days in the past",
-            date_bcd,
-            duration.to_string()
+    #[test]
+    fn test_cryptogram_information_data() {
+        // AAC, advice required, PIN try limit exceeded
+        let cid = CryptogramInformationData::from(0x0A);
+        assert_eq!(
+            cid.cryptogram_type,
+            Some(CryptogramType::ApplicationAuthenticationCryptogram)
+        );
+        assert!(cid.advice_required);
+        assert_eq!(cid.reason_code, Some(CidReasonCode::PinTryLimitExceeded));
+        assert_eq!(CryptogramInformationData::from(0x07).reason_code, None);
+        assert_eq!(CryptogramInformationData::from(0xC0).cryptogram_type, None);
+        assert_eq!(
+            CryptogramInformationData::from(0x90).payment_system_specific,
+            1
         );
 
-        return true;
+        let mut connection = new_connection();
+        connection.process_tag_as_tlv("9F36", b"\x00\x01".to_vec());
+        connection.process_tag_as_tlv("9F27", b"\x0A".to_vec());
+        let error = connection
+            .validate_ac(
+                TransactionStep::CardActionAnalysis,
+                CryptogramType::TransactionCertificate,
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.decline_reason(),
+            Some(DeclineReason::Card(Some(
+                CidReasonCode::PinTryLimitExceeded
+            )))
+        );
+        assert_eq!(
+            error.to_string(),
+            "Transaction declined by ICC (PIN try limit exceeded) during card action analysis"
+        );
+
+        // the AAC of the first GENERATE AC is declined offline through the error
+        let transaction = EmvTransaction::new(&mut connection, 1);
+        assert_eq!(
+            transaction.get_error_result(&error),
+            TransactionResult::DeclinedOffline
+        );
+        let outcome = transaction.get_outcome(TransactionResult::DeclinedOffline, Some(error));
+        assert_eq!(
+            outcome.decline_reason,
+            Some(DeclineReason::Card(Some(
+                CidReasonCode::PinTryLimitExceeded
+            )))
+        );
+        let advice = outcome.advice.unwrap();
+        assert_eq!(
+            advice.cryptogram_information_data.reason_code,
+            Some(CidReasonCode::PinTryLimitExceeded)
+        );
+        assert!(advice.icc_data.starts_with(b"\x9F\x27\x01\x0A"));
+
+        // the terminal declining does not depend on the card
+        let error = connection
+            .validate_ac(
+                TransactionStep::CardActionAnalysis,
+                CryptogramType::ApplicationAuthenticationCryptogram,
+            )
+            .unwrap_err();
+        assert_eq!(error.decline_reason(), Some(DeclineReason::Terminal));
     }
 
-    false
-}
+    #[test]
+    fn test_transaction_outcome() -> Result<(), EmvError> {
+        init_logging();
 
-#[cfg(test)]
-mod tests {
-    use super::bcdutil::*;
-    use super::*;
-    use hex;
-    use hexplay::HexViewBuilder;
-    use log::{debug, LevelFilter};
-    use log4rs;
-    use log4rs::{
-        append::console::ConsoleAppender,
-        config::{Appender, Root},
-    };
-    use openssl::rsa::{Padding, Rsa};
-    use serde::{Deserialize, Serialize};
-    use std::fs::{self};
-    use std::str;
-    use std::sync::Once;
+        let smart_card_connection = Arc::new(DummySmartCardConnection {
+            test_data_file: "test_data.yaml".to_string(),
+        });
 
-    static LOGGING: Once = Once::new();
+        // no Action Code matches the TVR => TC requested in the first GENERATE AC, approved offline
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
 
-    static SETTINGS_FILE: &str = "config/settings.yaml";
+        let outcome = EmvTransaction::new(&mut connection, 1).run()?;
+        assert_eq!(outcome.result, TransactionResult::ApprovedOffline);
+        assert!(outcome.error.is_none());
+        assert_eq!(
+            outcome.cryptogram_information_data,
+            Some(CryptogramInformationData::from(0x40))
+        );
+        assert!(outcome.decline_reason.is_none());
+        assert!(outcome.advice.is_none());
+        assert!(outcome.arqc.is_none());
+        assert!(outcome.cvm_result.is_some());
+        assert!(outcome.tsi.card_risk_management_was_performed);
+        assert!(!outcome.tsi.issuer_authentication_was_performed);
+
+        // the Issuer Action Code - Online matches the TVR => ARQC
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+        connection
+            .settings
+            .terminal
+            .tvr
+            .merchant_forced_transaction_online = true;
+        connection.online_authorization_callback = Some(Arc::new(online_approval));
 
-    #[derive(Serialize, Deserialize, Clone)]
-    struct ApduRequestResponse {
-        req: String,
-        res: String,
+        let outcome = EmvTransaction::new(&mut connection, 1).run()?;
+        assert_eq!(outcome.result, TransactionResult::ApprovedOnline);
+        assert_eq!(
+            outcome
+                .arqc
+                .as_ref()
+                .map(|arqc| arqc.application_cryptogram.clone()),
+            Some(b"\xB0\x18\x91\x01\xD1\x14\x16\xC1".to_vec())
+        );
+        assert!(outcome.tsi.issuer_authentication_was_performed);
+        assert!(!outcome.tvr.issuer_authentication_failed);
+
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+        connection
+            .settings
+            .terminal
+            .tvr
+            .merchant_forced_transaction_online = true;
+        connection.online_authorization_callback = Some(Arc::new(online_decline));
+
+        let outcome = EmvTransaction::new(&mut connection, 1).run()?;
+        assert_eq!(outcome.result, TransactionResult::DeclinedOnline);
+        assert_eq!(
+            outcome.cryptogram_information_data,
+            Some(CryptogramInformationData::from(0x00))
+        );
+        assert_eq!(outcome.decline_reason, Some(DeclineReason::Issuer));
+
+        // no acquirer connection => unable to go online, the Issuer Action Code - Default of the
+        // card matches the TVR, declined offline (Z3)
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+        connection
+            .settings
+            .terminal
+            .tvr
+            .merchant_forced_transaction_online = true;
+
+        let outcome = EmvTransaction::new(&mut connection, 1).run()?;
+        assert_eq!(outcome.result, TransactionResult::DeclinedOffline);
+        assert_eq!(connection.get_tag_value("8A"), Some(&b"Z3".to_vec()));
+        assert_eq!(outcome.decline_reason, Some(DeclineReason::Terminal));
+        assert!(outcome.arqc.is_some());
+
+        Ok(())
     }
 
-    impl ApduRequestResponse {
-        fn to_raw_vec(s: &String) -> Vec<u8> {
-            hex::decode(s.replace(" ", "")).unwrap()
+    #[test]
+    fn test_concurrent_transactions() -> Result<(), EmvError> {
+        let lanes: Vec<std::thread::JoinHandle<Result<TransactionOutcome, EmvError>>> = (0..4)
+            .map(|_| {
+                let mut connection = new_connection();
+                connection.interface = Some(Arc::new(DummySmartCardConnection {
+                    test_data_file: "test_data.yaml".to_string(),
+                }));
+                setup_connection(&mut connection).unwrap();
+
+                std::thread::spawn(move || EmvTransaction::new(&mut connection, 1).run())
+            })
+            .collect();
+
+        for lane in lanes {
+            let outcome = lane.join().unwrap()?;
+            assert_eq!(outcome.result, TransactionResult::ApprovedOffline);
         }
+
+        Ok(())
     }
 
-    struct DummySmartCardConnection {
-        test_data_file: String,
+    // Reader which answers only after yielding once to the executor
+    #[cfg(feature = "async")]
+    struct AsyncSmartCardConnection {
+        connection: DummySmartCardConnection,
     }
 
-    impl DummySmartCardConnection {
-        fn find_dummy_apdu<'a>(
-            test_data: &'a Vec<ApduRequestResponse>,
-            apdu: &[u8],
-        ) -> Option<&'a ApduRequestResponse> {
-            for data in test_data {
-                if &apdu[..] == &ApduRequestResponse::to_raw_vec(&data.req)[..] {
-                    return Some(data);
-                }
-            }
+    #[cfg(feature = "async")]
+    #[async_trait]
+    impl AsyncApduInterface for AsyncSmartCardConnection {
+        async fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError> {
+            tokio::task::yield_now().await;
 
-            None
+            self.connection.send_apdu(apdu)
         }
     }
 
-    impl ApduInterface for DummySmartCardConnection {
-        fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, ()> {
-            let mut output: Vec<u8> = Vec::new();
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_transaction() -> Result<(), EmvError> {
+        // no blocking reader, all commands go through the async one
+        let mut connection = new_connection();
+        setup_connection(&mut connection)?;
+        let mut connection = AsyncEmvConnection::new(
+            connection,
+            Arc::new(AsyncSmartCardConnection {
+                connection: DummySmartCardConnection {
+                    test_data_file: "test_data.yaml".to_string(),
+                },
+            }),
+        );
 
-            let mut response = b"\x6A\x82".to_vec(); // file not found error
+        // spawned as a task, i.e. the transaction future is Send
+        let outcome =
+            tokio::spawn(async move { AsyncEmvTransaction::new(&mut connection, 1).run().await })
+                .await
+                .unwrap()?;
+        assert_eq!(outcome.result, TransactionResult::ApprovedOffline);
 
-            let test_data: Vec<ApduRequestResponse> =
-                serde_yaml::from_str(&fs::read_to_string(&self.test_data_file).unwrap()).unwrap();
+        Ok(())
+    }
 
-            if let Some(req) = DummySmartCardConnection::find_dummy_apdu(&test_data, &apdu[..]) {
-                response = ApduRequestResponse::to_raw_vec(&req.res);
-            }
+    #[test]
+    fn test_connection_builder() -> Result<(), ConfigurationError> {
+        let mut settings = Settings {
+            censor_sensitive_fields: true,
+            ..Default::default()
+        };
+        settings.terminal.use_random = false;
 
-            output.extend_from_slice(&response[..]);
-            Ok(output)
-        }
-    }
+        let connection = EmvConnection::builder()
+            .settings(settings)
+            .ca_public_keys(CertificateAuthority::test_keys())
+            .build()?;
+        assert!(connection.settings.censor_sensitive_fields);
+        assert!(!connection.settings.terminal.use_random);
+        assert!(connection.get_emv_tag("9F02").is_some());
+        assert!(connection.ca_public_keys.contains_key("A000000003"));
 
-    fn init_logging() {
-        LOGGING.call_once(|| {
-            let stdout: ConsoleAppender = ConsoleAppender::builder().build();
-            let config = log4rs::config::Config::builder()
-                .appender(Appender::builder().build("stdout", Box::new(stdout)))
-                .build(Root::builder().appender("stdout").build(LevelFilter::Trace))
-                .unwrap();
-            log4rs::init_config(config).unwrap();
-        });
+        // the bundled settings don't reference any CA keys
+        let connection = EmvConnection::builder().build()?;
+        assert!(connection.ca_public_keys.is_empty());
+
+        Ok(())
     }
 
     #[test]
-    fn test_rsa_key() -> Result<(), String> {
-        init_logging();
+    fn test_connection_builder_errors() {
+        match EmvConnection::builder()
+            .settings_file("missing_settings.yaml")
+            .build()
+        {
+            Err(ConfigurationError::Unreadable { file, .. }) => {
+                assert_eq!(file, "missing_settings.yaml")
+            }
+            _ => panic!("missing settings file accepted"),
+        }
 
-        const KEY_SIZE: u32 = 1408;
-        const KEY_BYTE_SIZE: usize = KEY_SIZE as usize / 8;
+        let mut settings = Settings::default();
+        settings.configuration_files.scheme_ca_public_keys =
+            Some("missing_ca_public_keys.yaml".to_string());
+        assert!(matches!(
+            EmvConnection::builder().settings(settings).build(),
+            Err(ConfigurationError::Unreadable { .. })
+        ));
 
-        // openssl key generation:
-        // openssl genrsa -out icc_1234560012345608_e_3_private_key.pem -3 1024
-        // openssl genrsa -out iin_313233343536_e_3_private_key.pem -3 1408
-        // openssl genrsa -out AFFFFFFFFF_92_ca_private_key.pem -3 1408
-        // openssl rsa -in AFFFFFFFFF_92_ca_private_key.pem -outform PEM -pubout -out AFFFFFFFFF_92_ca_key.pem
+        let invalid_settings_file = std::env::temp_dir().join("emvpt_invalid_settings.yaml");
+        fs::write(&invalid_settings_file, "censor_sensitive_fields: maybe").unwrap();
+        assert!(matches!(
+            EmvConnection::new(invalid_settings_file.to_str().unwrap()),
+            Err(ConfigurationError::Invalid { file, .. }) if file == invalid_settings_file.to_str().unwrap()
+        ));
+        assert!(matches!(
+            EmvConnection::new("missing_settings.yaml"),
+            Err(ConfigurationError::Unreadable { .. })
+        ));
+        fs::remove_file(&invalid_settings_file).unwrap();
+    }
 
-        let rsa = Rsa::private_key_from_pem(
-            &fs::read_to_string("config/AFFFFFFFFF_92_ca_private_key.pem")
-                .unwrap()
-                .as_bytes(),
-        )
-        .unwrap();
-        //let rsa = Rsa::private_key_from_pem(&fs::read_to_string("config/iin_313233343536_e_3_private_key.pem").unwrap().as_bytes()).unwrap();
-        //let rsa = Rsa::private_key_from_pem(&fs::read_to_string("config/icc_1234560012345608_e_3_private_key.pem").unwrap().as_bytes()).unwrap();
+    #[test]
+    fn test_cardholder_interface_defaults() -> Result<(), EmvError> {
+        let smart_card_connection = Arc::new(DummySmartCardConnection {
+            test_data_file: "test_data.yaml".to_string(),
+        });
 
-        let public_key_modulus = &rsa.n().to_vec()[..];
-        let public_key_exponent = &rsa.e().to_vec()[..];
-        let private_key_exponent = &rsa.d().to_vec()[..];
+        // no PIN, the cardholder bypasses the PIN entry required by the card
+        let cardholder_interface = Arc::new(HeadlessCardholderInterface::default());
+        assert!(cardholder_interface.amount_entry().is_ok());
+        assert_eq!(
+            cardholder_interface.pin_entry(),
+            Err(CardholderInterfaceError::Cancelled)
+        );
+        assert!(cardholder_interface.select_application(&[]).is_err());
 
-        let pk = RsaPublicKey::new(public_key_modulus, public_key_exponent, false);
-        debug!(
-            "modulus: {:02X?}, exponent: {:02X?}, private_exponent: {:02X?}",
-            public_key_modulus, public_key_exponent, private_key_exponent
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        connection.cardholder_interface = cardholder_interface;
+
+        // the PIN CV Rules fail and the succeeding signature is applied, the transaction goes on
+        // until the card has no GENERATE AC response for the TVR
+        let outcome = EmvTransaction::new(&mut connection, 1).run()?;
+        assert!(
+            outcome
+                .tvr
+                .pin_entry_required_pin_pad_present_but_pin_was_not_entered
+        );
+        assert!(!outcome.tvr.cardholder_verification_was_not_successful);
+        assert_eq!(outcome.cvm_result, Some(b"\x1E\x03\x00".to_vec()));
+        assert_eq!(
+            outcome.error.map(|err| err.step()),
+            Some(TransactionStep::CardActionAnalysis)
         );
 
-        let mut encrypt_output = [0u8; KEY_BYTE_SIZE];
-        let mut plaintext_data = [0u8; KEY_BYTE_SIZE];
-        plaintext_data[0] = 0x6A;
-        plaintext_data[1] = 0xFF;
-        plaintext_data[KEY_BYTE_SIZE - 1] = 0xBC;
+        Ok(())
+    }
 
-        let encrypt_size = rsa
-            .private_encrypt(&plaintext_data[..], &mut encrypt_output[..], Padding::NONE)
-            .unwrap();
+    #[test]
+    fn test_transaction_outcome_card_errors() -> Result<(), EmvError> {
+        // PSE not found => chip can't be used, fall back to magnetic stripe
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![b"\x6A\x82".to_vec()],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
 
-        debug!(
-            "Encrypt result ({} bytes):\n{}",
-            encrypt_output.len(),
-            HexViewBuilder::new(&encrypt_output[..]).finish()
+        let outcome = EmvTransaction::new(&mut connection, 1).run()?;
+        assert_eq!(outcome.result, TransactionResult::Fallback);
+        assert_eq!(
+            outcome.error.and_then(|err| err.status_word()),
+            Some((0x6A, 0x82))
         );
 
-        let decrypted_data = pk.public_decrypt(&encrypt_output[0..encrypt_size]).unwrap();
+        // blocked card
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![b"\x6A\x81".to_vec()],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
 
-        assert_eq!(&plaintext_data[..], &decrypted_data[..]);
+        let outcome = EmvTransaction::new(&mut connection, 1).run()?;
+        assert_eq!(outcome.result, TransactionResult::EndApplication);
+
+        // card removed
+        let smart_card_connection = Arc::new(FuzzSmartCardConnection {
+            mutation: FuzzSmartCardConnection::CARD_REMOVED,
+            mutated_response_index: 8,
+            ..FuzzSmartCardConnection::new(0)
+        });
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
+
+        let error = EmvTransaction::new(&mut connection, 1).run().unwrap_err();
+        assert_eq!(error.transport_error(), Some(&TransportError::CardRemoved));
 
         Ok(())
     }
 
-    fn pse_application_select(applications: &Vec<EmvApplication>) -> Result<EmvApplication, ()> {
-        Ok(applications[0].clone())
+    // Replays test_data.yaml but corrupts the nth ICC response, simulating a broken or hostile card
+    struct FuzzSmartCardConnection {
+        test_data: Vec<ApduRequestResponse>,
+        mutation: usize,
+        mutated_response_index: usize,
+        response_index: AtomicUsize,
+        rng: Mutex<ChaCha20Rng>,
     }
 
-    fn pin_entry() -> Result<String, ()> {
-        Ok("1234".to_string())
+    impl FuzzSmartCardConnection {
+        // the card is gone from the mutated response onwards
+        const CARD_REMOVED: usize = 7;
+
+        fn new(seed: u64) -> FuzzSmartCardConnection {
+            let mut rng = ChaCha20Rng::seed_from_u64(seed);
+
+            FuzzSmartCardConnection {
+                test_data: serde_yaml::from_str(&fs::read_to_string("test_data.yaml").unwrap())
+                    .unwrap(),
+                mutation: rng.gen_range(0..FuzzSmartCardConnection::CARD_REMOVED + 1),
+                mutated_response_index: rng.gen_range(0..16),
+                response_index: AtomicUsize::new(0),
+                rng: Mutex::new(rng),
+            }
+        }
+
+        fn mutate(&self, response: Vec<u8>) -> Vec<u8> {
+            let mut rng = self.rng.lock().unwrap();
+            let (data, trailer) = response.split_at(response.len() - 2);
+            let mut data = data.to_vec();
+
+            match self.mutation {
+                0 => return Vec::new(),
+                1 => return trailer[..1].to_vec(),
+                2 => data.truncate(rng.gen_range(0..data.len() + 1)),
+                3 => {
+                    if !data.is_empty() {
+                        let i = rng.gen_range(0..data.len());
+                        data[i] = rng.gen();
+                    }
+                }
+                4 => {
+                    data = vec![0; rng.gen_range(0..300)];
+                    rng.fill(&mut data[..]);
+                }
+                5 => {
+                    // keep the template and its length, scramble the content
+                    for byte in data.iter_mut().skip(3) {
+                        if rng.gen_bool(0.1) {
+                            *byte = rng.gen();
+                        }
+                    }
+                }
+                _ => {
+                    let sw1 = [0x61, 0x6C, 0x62, 0x63, 0x69, 0x6A][rng.gen_range(0..6)];
+                    return vec![sw1, rng.gen()];
+                }
+            }
+
+            data.extend_from_slice(trailer);
+            data
+        }
     }
 
-    fn amount_entry() -> Result<u64, ()> {
-        Ok(1)
+    impl ApduInterface for FuzzSmartCardConnection {
+        fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError> {
+            let response = match DummySmartCardConnection::find_dummy_apdu(&self.test_data, apdu) {
+                Some(req) => ApduRequestResponse::to_raw_vec(&req.res),
+                None => b"\x6A\x82".to_vec(),
+            };
+
+            let response_index = self.response_index.fetch_add(1, Ordering::SeqCst);
+
+            if self.mutation == FuzzSmartCardConnection::CARD_REMOVED
+                && response_index >= self.mutated_response_index
+            {
+                return Err(TransportError::CardRemoved);
+            }
+
+            if response_index == self.mutated_response_index {
+                return Ok(self.mutate(response));
+            }
+
+            Ok(response)
+        }
     }
 
-    fn start_transaction(connection: &mut EmvConnection) -> Result<(), ()> {
-        // force transaction date as 24.07.2020
-        connection.process_tag_as_tlv("9A", b"\x20\x07\x24".to_vec());
+    fn run_purchase_transaction(connection: &mut EmvConnection) -> Result<(), EmvError> {
+        connection
+            .settings
+            .terminal
+            .tvr
+            .merchant_forced_transaction_online = true;
 
-        // force unpreditable number
-        connection.process_tag_as_tlv("9F37", b"\x01\x23\x45\x67".to_vec());
-        connection.settings.terminal.use_random = false;
+        let application = connection.select_payment_application()?;
 
-        // force issuer authentication data
-        connection.process_tag_as_tlv("91", b"\x12\x34\x56\x78\x12\x34\x56\x78".to_vec());
+        connection.start_transaction(&application)?;
+        connection.process_tag_as_tlv("9F02", b"\x00\x00\x00\x00\x00\x01".to_vec());
+        connection.handle_card_verification_methods()?;
+        connection.handle_terminal_risk_management()?;
+        connection.handle_offline_data_authentication()?;
+        let cryptogram_type = connection.handle_terminal_action_analysis()?;
+
+        if let CryptogramType::AuthorisationRequestCryptogram =
+            connection.handle_1st_generate_ac(cryptogram_type)?
+        {
+            connection.handle_issuer_authentication_data()?;
+            connection.handle_2nd_generate_ac()?;
+        }
 
         Ok(())
     }
 
-    fn setup_connection(connection: &mut EmvConnection) -> Result<(), ()> {
-        connection.contactless = false;
-        connection.pse_application_select_callback = Some(&pse_application_select);
-        connection.pin_callback = Some(&pin_entry);
-        connection.amount_callback = Some(&amount_entry);
-        connection.start_transaction_callback = Some(&start_transaction);
+    #[test]
+    fn test_issuer_authentication_not_supported() -> Result<(), EmvError> {
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![b"\x69\x85".to_vec()],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
+        connection.process_tag_as_tlv("91", b"\x12\x34\x56\x78\x12\x34\x56\x78".to_vec());
+
+        // AIP without issuer authentication, no EXTERNAL AUTHENTICATE
+        connection.icc.capabilities.issuer_authentication = false;
+        connection.handle_issuer_authentication_data()?;
+        assert!(smart_card_connection.commands.lock().unwrap().is_empty());
+        assert!(
+            !connection
+                .settings
+                .terminal
+                .tvr
+                .issuer_authentication_failed
+        );
+        assert!(
+            !connection
+                .settings
+                .terminal
+                .tsi
+                .issuer_authentication_was_performed
+        );
+
+        connection.icc.capabilities.issuer_authentication = true;
+        connection.handle_issuer_authentication_data()?;
+        assert_eq!(smart_card_connection.commands.lock().unwrap().len(), 1);
+        assert!(
+            connection
+                .settings
+                .terminal
+                .tvr
+                .issuer_authentication_failed
+        );
+        assert!(
+            connection
+                .settings
+                .terminal
+                .tsi
+                .issuer_authentication_was_performed
+        );
 
         Ok(())
     }
 
     #[test]
-    fn test_get_data() -> Result<(), ()> {
-        init_logging();
+    fn test_malformed_card_responses() -> Result<(), EmvError> {
+        for seed in 0..300 {
+            let mut connection = new_connection();
+            let smart_card_connection = Arc::new(FuzzSmartCardConnection::new(seed));
+            connection.interface = Some(smart_card_connection.clone());
+            setup_connection(&mut connection)?;
+
+            // any outcome is fine, as long as there is no panic
+            if let Err(err) = run_purchase_transaction(&mut connection) {
+                debug!("seed {}: {}", seed, err);
+            }
+        }
 
-        let mut connection = EmvConnection::new(SETTINGS_FILE).unwrap();
-        let smart_card_connection = DummySmartCardConnection {
-            test_data_file: "test_data.yaml".to_string(),
-        };
-        connection.interface = Some(&smart_card_connection);
-        setup_connection(&mut connection)?;
+        Ok(())
+    }
 
-        connection.select_payment_application()?;
+    #[test]
+    fn test_card_removed_during_transaction() -> Result<(), EmvError> {
+        let mut connection = new_connection();
+        let smart_card_connection = Arc::new(FuzzSmartCardConnection {
+            mutation: FuzzSmartCardConnection::CARD_REMOVED,
+            mutated_response_index: 8,
+            ..FuzzSmartCardConnection::new(0)
+        });
+        connection.interface = Some(smart_card_connection.clone());
+        setup_connection(&mut connection)?;
 
-        let search_tag = b"\x9f\x36";
-        connection.handle_get_data(&search_tag[..])?;
+        let error = run_purchase_transaction(&mut connection).unwrap_err();
+        assert_eq!(error.transport_error(), Some(&TransportError::CardRemoved));
 
         Ok(())
     }
 
+    // Replays canned responses in order and records the commands sent
+    struct ScriptedSmartCardConnection {
+        responses: Mutex<Vec<Vec<u8>>>,
+        commands: Mutex<Vec<Vec<u8>>>,
+        extended_length: bool,
+    }
+
+    impl ScriptedSmartCardConnection {
+        fn new(responses: Vec<Vec<u8>>, extended_length: bool) -> ScriptedSmartCardConnection {
+            ScriptedSmartCardConnection {
+                responses: Mutex::new(responses),
+                commands: Mutex::new(Vec::new()),
+                extended_length,
+            }
+        }
+    }
+
+    impl ApduInterface for ScriptedSmartCardConnection {
+        fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError> {
+            self.commands.lock().unwrap().push(apdu.to_vec());
+
+            let mut responses = self.responses.lock().unwrap();
+            if responses.is_empty() {
+                return Ok(b"\x6A\x82".to_vec());
+            }
+
+            Ok(responses.remove(0))
+        }
+
+        fn supports_extended_length(&self) -> bool {
+            self.extended_length
+        }
+    }
+
     #[test]
-    fn test_pin_verification_methods() -> Result<(), ()> {
-        init_logging();
+    fn test_ber_length_encoding() {
+        assert_eq!(encode_ber_length(0x00), vec![0x00]);
+        assert_eq!(encode_ber_length(0x7F), vec![0x7F]);
+        assert_eq!(encode_ber_length(0x80), vec![0x81, 0x80]);
+        assert_eq!(encode_ber_length(0xFF), vec![0x81, 0xFF]);
+        assert_eq!(encode_ber_length(0x100), vec![0x82, 0x01, 0x00]);
+        assert_eq!(encode_ber_length(0xFFFF), vec![0x82, 0xFF, 0xFF]);
+        assert_eq!(encode_ber_length(0x10000), vec![0x83, 0x01, 0x00, 0x00]);
+
+        let mut connection = new_connection();
+        let issuer_public_key_certificate = vec![0xAB; 0x100];
+        connection.process_tag_as_tlv("90", issuer_public_key_certificate.clone());
+        assert_eq!(
+            connection.get_tag_value("90"),
+            Some(&issuer_public_key_certificate)
+        );
+    }
 
-        let mut connection = EmvConnection::new(SETTINGS_FILE).unwrap();
-        let smart_card_connection = DummySmartCardConnection {
-            test_data_file: "test_data.yaml".to_string(),
-        };
-        connection.interface = Some(&smart_card_connection);
-        setup_connection(&mut connection)?;
+    #[test]
+    fn test_get_response_chaining() -> Result<(), TransportError> {
+        // record template with a 296 byte data object, 305 bytes in total
+        let record = TlvBuilder::new()
+            .constructed("70", TlvBuilder::new().primitive("9F4B", &[0x5A; 0x128]))
+            .build()
+            .unwrap();
+        assert_eq!(record.len(), 0x131);
 
-        let application = connection.select_payment_application()?;
+        let mut first_chunk = record[..0x100].to_vec();
+        first_chunk.extend_from_slice(b"\x61\x31");
+        let mut second_chunk = record[0x100..].to_vec();
+        second_chunk.extend_from_slice(b"\x90\x00");
 
-        connection.start_transaction(&application).unwrap();
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![b"\x61\x00".to_vec(), first_chunk, second_chunk],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
 
-        let ascii_pin = connection.pin_callback.unwrap()()?;
+        let response = connection.send_apdu(&CommandApdu::read_record(1, 1))?;
+        assert!(response.is_success());
+        assert_eq!(response.data, record);
+        assert_eq!(
+            connection.get_tag_value("9F4B").map(|v| v.len()),
+            Some(0x128)
+        );
 
-        connection.handle_verify_plaintext_pin(ascii_pin.as_bytes())?;
-        connection.handle_verify_enciphered_pin(ascii_pin.as_bytes())?;
+        let commands = smart_card_connection.commands.lock().unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[1], b"\x00\xC0\x00\x00\x00".to_vec());
+        assert_eq!(commands[2], b"\x00\xC0\x00\x00\x31".to_vec());
 
         Ok(())
     }
 
     #[test]
-    fn test_purchase_transaction() -> Result<(), ()> {
-        init_logging();
+    fn test_get_response_wrong_length() -> Result<(), TransportError> {
+        let response_data = TlvBuilder::new()
+            .constructed(
+                "77",
+                TlvBuilder::new()
+                    .primitive("9F27", b"\x80")
+                    .primitive("9F36", b"\x00\x01")
+                    .primitive("9F26", &[0x12; 8])
+                    .primitive("9F4B", &[0x34; 0x80]),
+            )
+            .build()
+            .unwrap();
+        let (first_part, second_part) = response_data.split_at(response_data.len() - 0x80);
 
-        let mut connection = EmvConnection::new(SETTINGS_FILE).unwrap();
-        let smart_card_connection = DummySmartCardConnection {
-            test_data_file: "test_data.yaml".to_string(),
-        };
-        connection.interface = Some(&smart_card_connection);
-        setup_connection(&mut connection)?;
+        let mut first_chunk = first_part.to_vec();
+        first_chunk.extend_from_slice(b"\x61\x00");
+        let mut second_chunk = second_part.to_vec();
+        second_chunk.extend_from_slice(b"\x90\x00");
 
-        let amount = connection.amount_callback.unwrap()()?;
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![first_chunk, b"\x6C\x80".to_vec(), second_chunk],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
 
-        let application = connection.select_payment_application()?;
+        let response = connection.send_apdu(&CommandApdu::generate_ac(0x80, &[0x00; 0x10]))?;
+        assert!(response.is_success());
+        assert_eq!(response.data, response_data);
 
-        connection.start_transaction(&application)?;
+        // the GET RESPONSE is re-issued with the corrected Le, not the GENERATE AC
+        let commands = smart_card_connection.commands.lock().unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(&commands[0][..2], b"\x80\xAE");
+        assert_eq!(commands[1], b"\x00\xC0\x00\x00\x00".to_vec());
+        assert_eq!(commands[2], b"\x00\xC0\x00\x00\x80".to_vec());
 
-        connection.process_tag_as_tlv(
-            "9F02",
-            ascii_to_bcd_n(format!("{}", amount).as_bytes(), 6).unwrap(),
+        Ok(())
+    }
+
+    fn card_response(data: TlvBuilder) -> Vec<u8> {
+        let mut response = data.build().unwrap();
+        response.extend_from_slice(b"\x90\x00");
+        response
+    }
+
+    #[test]
+    fn test_tlv_builder() -> Result<(), tlv::TlvError> {
+        // FCI of a PSE with a directory entry in the FCI issuer discretionary data
+        let fci = TlvBuilder::new()
+            .constructed(
+                "6F",
+                TlvBuilder::new()
+                    .primitive("84", b"1PAY.SYS.DDF01")
+                    .constructed(
+                        "A5",
+                        TlvBuilder::new().primitive("88", b"\x01").constructed(
+                            "BF0C",
+                            TlvBuilder::new().constructed(
+                                "61",
+                                TlvBuilder::new()
+                                    .primitive("4F", b"\xA0\x00\x00\x00\x03\x10\x10")
+                                    .primitive("87", b"\x01"),
+                            ),
+                        ),
+                    ),
+            )
+            .build()?;
+        assert_eq!(
+            fci,
+            b"\x6F\x26\x84\x0E1PAY.SYS.DDF01\xA5\x14\x88\x01\x01\xBF\x0C\x0E\x61\x0C\x4F\x07\xA0\x00\x00\x00\x03\x10\x10\x87\x01\x01"
+                .to_vec()
         );
 
-        connection.handle_card_verification_methods()?;
+        // issuer scripts of an authorisation response, field 55
+        let issuer_scripts = TlvBuilder::new()
+            .constructed(
+                "71",
+                TlvBuilder::new()
+                    .primitive("9F18", b"\x00\x00\x00\x01")
+                    .primitive("86", b"\x84\x24\x00\x00\x00"),
+            )
+            .constructed("72", TlvBuilder::new().primitive("86", &[0xAB; 0x80]))
+            .build()?;
+        assert_eq!(
+            issuer_scripts[..13],
+            b"\x71\x0E\x9F\x18\x04\x00\x00\x00\x01\x86\x05\x84\x24"[..]
+        );
+        assert_eq!(issuer_scripts[16..22], b"\x72\x81\x83\x86\x81\x80"[..]);
+        assert_eq!(issuer_scripts.len(), 16 + 6 + 0x80);
 
-        connection.handle_terminal_risk_management()?;
+        // long lengths are encoded with the minimum number of subsequent bytes
+        let record = TlvBuilder::new()
+            .constructed("70", TlvBuilder::new().primitive("90", &[0x00; 0x100]))
+            .build()?;
+        assert_eq!(record[..8], b"\x70\x82\x01\x04\x90\x82\x01\x00"[..]);
 
-        connection.handle_offline_data_authentication()?;
+        assert!(TlvBuilder::new().is_empty());
+        assert_eq!(TlvBuilder::new().build()?, Vec::<u8>::new());
+        assert_eq!(
+            TlvBuilder::new().primitive("9F", b"\x00").build(),
+            Err(tlv::TlvError::InvalidTag("9F".to_string()))
+        );
+        assert_eq!(
+            TlvBuilder::new().primitive("9F81", b"\x00").build(),
+            Err(tlv::TlvError::InvalidTag("9F81".to_string()))
+        );
+        assert_eq!(
+            TlvBuilder::new().primitive("XY", b"\x00").build(),
+            Err(tlv::TlvError::InvalidTag("XY".to_string()))
+        );
+        assert_eq!(
+            TlvBuilder::new()
+                .constructed("77", TlvBuilder::new().primitive("70", b"\x00"))
+                .build(),
+            Err(tlv::TlvError::WrongTagType("70".to_string()))
+        );
+        assert_eq!(
+            TlvBuilder::new()
+                .constructed("5A", TlvBuilder::new())
+                .build(),
+            Err(tlv::TlvError::WrongTagType("5A".to_string()))
+        );
 
-        connection.handle_terminal_action_analysis()?;
+        // response message template format 2 replayed by a scripted card
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![card_response(
+                TlvBuilder::new().constructed(
+                    "77",
+                    TlvBuilder::new()
+                        .primitive("82", b"\x39\x00")
+                        .primitive("94", b"\x08\x01\x01\x00"),
+                ),
+            )],
+            false,
+        ));
+        let mut connection = new_connection();
+        connection.interface = Some(smart_card_connection.clone());
 
-        match connection.handle_1st_generate_ac()? {
-            CryptogramType::AuthorisationRequestCryptogram => {
-                connection.handle_issuer_authentication_data()?;
-                assert!(
-                    !connection
-                        .settings
-                        .terminal
-                        .tvr
-                        .issuer_authentication_failed
-                );
+        let response = connection
+            .send_apdu(&CommandApdu::get_processing_options(b"\x83\x00"))
+            .unwrap();
+        assert!(response.is_success());
+        assert_eq!(connection.get_tag_value("82"), Some(&b"\x39\x00".to_vec()));
+        assert_eq!(
+            connection.get_tag_value("94"),
+            Some(&b"\x08\x01\x01\x00".to_vec())
+        );
 
-                match connection.handle_2nd_generate_ac()? {
-                    CryptogramType::AuthorisationRequestCryptogram => {
-                        return Err(());
-                    }
-                    CryptogramType::TransactionCertificate => { /* Expected */ }
-                    CryptogramType::ApplicationAuthenticationCryptogram => {
-                        return Err(());
-                    }
-                }
-            }
-            CryptogramType::TransactionCertificate => {
-                return Err(()); /* For test case 2ND GEN AC TC is expected */
-            }
-            CryptogramType::ApplicationAuthenticationCryptogram => {
-                return Err(());
-            }
-        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extended_length_command() -> Result<(), TransportError> {
+        let cdol_data = vec![0x00; 0x12C];
+        let generate_ac_command = CommandApdu::generate_ac(0x80, &cdol_data);
+
+        let short_reader = Arc::new(ScriptedSmartCardConnection::new(Vec::new(), false));
+        let mut connection = new_connection();
+        connection.interface = Some(short_reader.clone());
+        assert!(matches!(
+            connection.send_apdu(&generate_ac_command),
+            Err(TransportError::Protocol(_))
+        ));
+        assert!(short_reader.commands.lock().unwrap().is_empty());
+
+        let extended_reader = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                b"\x90\x00".to_vec(),
+                b"\x61\x00".to_vec(),
+                b"\x90\x00".to_vec(),
+            ],
+            true,
+        ));
+        connection.interface = Some(extended_reader.clone());
+        assert!(connection.send_apdu(&generate_ac_command)?.is_success());
+
+        // all the response data is asked for with the extended Le, a T=0 card chains the
+        // response with short GET RESPONSE commands
+        assert!(connection
+            .send_apdu(&CommandApdu::read_record(1, 1))?
+            .is_success());
+
+        let commands = extended_reader.commands.lock().unwrap();
+        assert_eq!(&commands[0][..7], b"\x80\xAE\x80\x00\x00\x01\x2C");
+        assert_eq!(&commands[0][commands[0].len() - 2..], b"\x00\x00");
+        assert_eq!(commands[0].len(), 7 + 0x12C + 2);
+        assert_eq!(commands[1], b"\x00\xB2\x01\x0C\x00\x00\x00".to_vec());
+        assert_eq!(commands[2], b"\x00\xC0\x00\x00\x00".to_vec());
 
         Ok(())
     }
 
     #[test]
     fn test_data_object_list_processing() -> Result<(), ()> {
-        let mut connection = EmvConnection::new(SETTINGS_FILE).unwrap();
+        let mut connection = new_connection();
 
         let cdol1: [u8; 39] = [
             //tag       length
@@ -3819,7 +8984,7 @@ mod tests {
         // Curious case of static data authentication list (9F4A) which slightly differs from regular DOL by not providing tag length
         let static_data_authentication_list: [u8; 1] = [0x82];
         let static_dol1: DataObjectList =
-            DataObjectList::process_data_object_list(&connection, &static_data_authentication_list)
+            DataObjectList::process_tag_list(&connection, &static_data_authentication_list)
                 .unwrap();
         let static_data_authentication_list_output: Vec<u8> =
             static_dol1.get_tag_list_tag_values(&connection);
@@ -3828,6 +8993,169 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_data_object_list_parsing() -> Result<(), DataObjectListError> {
+        let connection = new_connection();
+
+        let card_data_object_lists: [&str; 5] = [
+            // qVSDC PDOL
+            "9F66049F02069F03069F1A0295055F2A029A039C019F3704",
+            // M/Chip CDOL1
+            "9F02069F03069F1A0295055F2A029A039C019F37049F35019F45029F4C089F34039F21039F7C14",
+            // M/Chip CDOL2
+            "910A8A0295059F37049F4C08",
+            // DDOL
+            "9F3704",
+            // PayPass PDOL with a three byte kernel tag
+            "9F660482029F0206DF810201",
+        ];
+
+        for card_data_object_list in card_data_object_lists.iter() {
+            let tag_list = hex::decode(card_data_object_list).unwrap();
+            let dol = DataObjectList::process_data_object_list(&connection, &tag_list)?;
+            assert_eq!(dol.to_bytes(), tag_list);
+        }
+
+        let dol = DataObjectList::process_data_object_list(
+            &connection,
+            &hex::decode("9F0206DF81020195059F7C8180").unwrap(),
+        )?;
+        let tags: Vec<(&str, usize)> = dol
+            .data_objects()
+            .iter()
+            .map(|data_object| (data_object.emv_tag.tag.as_str(), data_object.length))
+            .collect();
+        assert_eq!(
+            tags,
+            [("9F02", 6), ("DF8102", 1), ("95", 5), ("9F7C", 0x80)]
+        );
+        assert_eq!(
+            dol.get_tag_list_tag_values(&connection).len(),
+            6 + 1 + 5 + 0x80
+        );
+
+        let tag_list = DataObjectList::process_tag_list(&connection, &[0x82, 0x9F, 0x36])?;
+        assert!(tag_list.has_tag("82") && tag_list.has_tag("9F36"));
+        assert_eq!(tag_list.to_bytes(), [0x82, 0x9F, 0x36]);
+
+        let empty_dol = DataObjectList::process_data_object_list(&connection, &[])?;
+        assert!(empty_dol.data_objects().is_empty());
+        assert!(empty_dol.get_tag_list_tag_values(&connection).is_empty());
+
+        // ref. EMV Book 3, 6.5.8.3 - an empty PDOL is sent as the empty command template 83 00
+        let smart_card_connection = Arc::new(ScriptedSmartCardConnection::new(
+            vec![
+                card_response(
+                    TlvBuilder::new().constructed(
+                        "77",
+                        TlvBuilder::new()
+                            .primitive("82", b"\x39\x00")
+                            .primitive("94", b"\x08\x01\x01\x00"),
+                    ),
+                ),
+                card_response(
+                    TlvBuilder::new()
+                        .constructed("70", TlvBuilder::new().primitive("5F24", b"\x29\x12\x31")),
+                ),
+            ],
+            false,
+        ));
+        let mut gpo_connection = new_connection();
+        gpo_connection.interface = Some(smart_card_connection.clone());
+        gpo_connection.process_tag_as_tlv("9F38", Vec::new());
+        // GET PROCESSING OPTIONS passes, the minimal record lacks the mandatory data
+        let error = gpo_connection.handle_get_processing_options().unwrap_err();
+        assert_eq!(error.step(), TransactionStep::ReadApplicationData);
+        assert_eq!(
+            smart_card_connection.commands.lock().unwrap()[0],
+            b"\x80\xA8\x00\x00\x02\x83\x00\x00".to_vec()
+        );
+
+        let invalid_data_object_lists: [(&str, DataObjectListError); 5] = [
+            ("9F0206DF", DataObjectListError::TruncatedTag(3)),
+            ("9F0206DF818102", DataObjectListError::TagTooLong(3)),
+            (
+                "9F02",
+                DataObjectListError::TruncatedLength {
+                    offset: 2,
+                    tag: "9F02".to_string(),
+                },
+            ),
+            (
+                "9F7C82FF",
+                DataObjectListError::TruncatedLength {
+                    offset: 2,
+                    tag: "9F7C".to_string(),
+                },
+            ),
+            (
+                "9F7C80",
+                DataObjectListError::InvalidLength {
+                    offset: 2,
+                    tag: "9F7C".to_string(),
+                },
+            ),
+        ];
+
+        for (tag_list, error) in invalid_data_object_lists.iter() {
+            assert_eq!(
+                DataObjectList::process_data_object_list(
+                    &connection,
+                    &hex::decode(tag_list).unwrap()
+                )
+                .err()
+                .as_ref(),
+                Some(error)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_object_list_padding() -> Result<(), DataObjectListError> {
+        let mut connection = new_connection();
+
+        connection.process_tag_as_tlv("9F02", vec![0x00, 0x00, 0x00, 0x12, 0x34, 0x56]);
+        connection.process_tag_as_tlv("5A", vec![0x54, 0x13, 0x33, 0x00, 0x89, 0x00, 0x00, 0x3F]);
+        connection.process_tag_as_tlv("50", b"VISA".to_vec());
+        connection.process_tag_as_tlv("9F37", vec![0x01, 0x23, 0x45, 0x67]);
+        connection.add_tag("BF0C", vec![0x01, 0x02]);
+
+        let expected_values: [(&str, &str); 11] = [
+            // n: truncated from the left, padded with leading zeroes
+            ("9F0206", "000000123456"),
+            ("9F0204", "00123456"),
+            ("9F0208", "0000000000123456"),
+            // cn: truncated from the right, padded with trailing 'F's
+            ("5A04", "54133300"),
+            ("5A0A", "541333008900003FFFFF"),
+            // an, b: truncated from the right, padded with trailing zeroes
+            ("5002", "5649"),
+            ("5006", "564953410000"),
+            ("9F3702", "0123"),
+            ("9F3706", "012345670000"),
+            // constructed and missing data objects are zero filled
+            ("BF0C02", "0000"),
+            ("9F4C03", "000000"),
+        ];
+
+        for (tag_list, expected_value) in expected_values.iter() {
+            let dol = DataObjectList::process_data_object_list(
+                &connection,
+                &hex::decode(tag_list).unwrap(),
+            )?;
+            assert_eq!(
+                hex::encode_upper(dol.get_tag_list_tag_values(&connection)),
+                *expected_value,
+                "{}",
+                tag_list
+            );
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_track2_human_readable() -> Result<(), ()> {
         let track2_data = ";4321432143214321=2612101123456789123?";
@@ -3953,4 +9281,119 @@ mod tests {
         assert_eq!(get_truncated_pan("000000000000000"), "000000*****0000");
         assert_eq!(get_truncated_pan("00000000000000"), "000000****0000");
     }
+
+    #[test]
+    fn test_command_apdu_cases() -> Result<(), apdu::ApduError> {
+        let case1 = CommandApdu::new(0x00, 0xA4, 0x04, 0x00);
+        assert_eq!(case1.case(), apdu::CommandCase::Case1);
+        assert_eq!(case1.to_bytes()?, b"\x00\xA4\x04\x00".to_vec());
+
+        let case2 = CommandApdu::get_challenge();
+        assert_eq!(case2.case(), apdu::CommandCase::Case2);
+        assert_eq!(case2.to_bytes()?, b"\x00\x84\x00\x00\x00".to_vec());
+
+        let case3 = CommandApdu::external_authenticate(b"\x01\x02");
+        assert_eq!(case3.case(), apdu::CommandCase::Case3);
+        assert_eq!(case3.lc(), 2);
+        assert_eq!(case3.to_bytes()?, b"\x00\x82\x00\x00\x02\x01\x02".to_vec());
+
+        let case4 = CommandApdu::select(b"1PAY.SYS.DDF01", 0x00);
+        assert_eq!(case4.case(), apdu::CommandCase::Case4);
+        assert_eq!(
+            hex::encode_upper(case4.to_bytes()?),
+            "00A404000E315041592E5359532E444446303100"
+        );
+
+        assert_eq!(
+            CommandApdu::read_record(1, 2).to_bytes()?,
+            b"\x00\xB2\x02\x0C\x00".to_vec()
+        );
+        assert_eq!(
+            CommandApdu::get_data([0x9F, 0x17], 5).to_bytes()?,
+            b"\x80\xCA\x9F\x17\x05".to_vec()
+        );
+        assert_eq!(
+            CommandApdu::get_processing_options(b"\x83\x00").to_bytes()?,
+            b"\x80\xA8\x00\x00\x02\x83\x00\x00".to_vec()
+        );
+        assert_eq!(
+            CommandApdu::verify(0x80, b"\x24\x12\x34\xFF\xFF\xFF\xFF\xFF").to_bytes()?,
+            b"\x00\x20\x00\x80\x08\x24\x12\x34\xFF\xFF\xFF\xFF\xFF".to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_apdu_extended_length() -> Result<(), apdu::ApduError> {
+        let case2 = CommandApdu::new(0x00, 0xB0, 0x00, 0x00).with_le(0x10000);
+        assert!(case2.is_extended());
+        assert_eq!(case2.to_bytes()?, b"\x00\xB0\x00\x00\x00\x00\x00".to_vec());
+
+        let data = vec![0xAB; 0x100];
+        let case3 = CommandApdu::new(0x00, 0xD6, 0x00, 0x00).with_data(&data);
+        assert!(case3.is_extended());
+        let case3_bytes = case3.to_bytes()?;
+        assert_eq!(&case3_bytes[..7], b"\x00\xD6\x00\x00\x00\x01\x00");
+        assert_eq!(case3_bytes.len(), 7 + 0x100);
+
+        let case4 = CommandApdu::new(0x80, 0xAE, 0x80, 0x00)
+            .with_data(&data)
+            .with_le(0x200);
+        let case4_bytes = case4.to_bytes()?;
+        assert_eq!(&case4_bytes[case4_bytes.len() - 2..], b"\x02\x00");
+        assert_eq!(case4_bytes.len(), 7 + 0x100 + 2);
+
+        // maximum short Le is still encoded as a short command
+        let short = CommandApdu::new(0x00, 0xC0, 0x00, 0x00).with_le(0x100);
+        assert!(!short.is_extended());
+        assert_eq!(short.to_bytes()?, b"\x00\xC0\x00\x00\x00".to_vec());
+
+        assert_eq!(
+            CommandApdu::new(0x00, 0xC0, 0x00, 0x00)
+                .with_le(0)
+                .to_bytes(),
+            Err(apdu::ApduError::InvalidLe(0))
+        );
+        assert_eq!(
+            CommandApdu::new(0x00, 0xD6, 0x00, 0x00)
+                .with_data(&vec![0x00; 0x10000])
+                .to_bytes(),
+            Err(apdu::ApduError::DataTooLong(0x10000))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_apdu_status_word() -> Result<(), apdu::ApduError> {
+        let response = ResponseApdu::parse(b"\x01\x02\x90\x00")?;
+        assert_eq!(response.data, b"\x01\x02".to_vec());
+        assert!(response.is_success());
+        assert_eq!(response.status.to_string(), "9000");
+
+        let wrong_pin = ResponseApdu::parse(b"\x63\xC2")?;
+        assert!(wrong_pin.data.is_empty());
+        assert!(wrong_pin.status.is_warning());
+        assert!(!wrong_pin.status.is_error());
+        assert_eq!(wrong_pin.status.retries_left(), Some(2));
+
+        let selected_file_invalidated = StatusWord::new(0x62, 0x83);
+        assert!(selected_file_invalidated.is_warning());
+        assert_eq!(selected_file_invalidated.retries_left(), None);
+
+        let file_not_found = StatusWord::new(0x6A, 0x82);
+        assert!(file_not_found.is_error());
+        assert!(!file_not_found.is_success());
+
+        assert!(StatusWord::new(0x61, 0x10).is_bytes_available());
+        assert!(StatusWord::new(0x6C, 0x10).is_wrong_length());
+
+        assert_eq!(
+            ResponseApdu::parse(b"\x90"),
+            Err(apdu::ApduError::MissingStatusWord)
+        );
+
+        Ok(())
+    }
 }
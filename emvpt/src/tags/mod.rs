@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::apdu::CommandApdu;
+use crate::{EmvApplication, EmvTag, FieldSource, TagValidationError};
+
+// ICC command whose response carried a data object, ref. EMV Book 3, 6.5 Commands for Financial Transaction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardCommand {
+    Select,
+    ReadRecord {
+        short_file_identifier: u8,
+        record_index: u8,
+    },
+    GetProcessingOptions,
+    GenerateAc,
+    GetData,
+    GetChallenge,
+    InternalAuthenticate,
+    Other {
+        ins: u8,
+    },
+}
+
+impl From<&CommandApdu> for CardCommand {
+    fn from(command: &CommandApdu) -> CardCommand {
+        match command.ins {
+            0xA4 => CardCommand::Select,
+            0xB2 => CardCommand::ReadRecord {
+                short_file_identifier: command.p2 >> 3,
+                record_index: command.p1,
+            },
+            0xA8 => CardCommand::GetProcessingOptions,
+            0xAE => CardCommand::GenerateAc,
+            0xCA => CardCommand::GetData,
+            0x84 => CardCommand::GetChallenge,
+            0x88 => CardCommand::InternalAuthenticate,
+            ins => CardCommand::Other { ins },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TagOrigin {
+    pub source: FieldSource,
+    // None for values not returned as such by the ICC, e.g. recovered from signed data
+    pub command: Option<CardCommand>,
+}
+
+impl TagOrigin {
+    pub fn terminal() -> TagOrigin {
+        TagOrigin {
+            source: FieldSource::Terminal,
+            command: None,
+        }
+    }
+
+    pub fn issuer() -> TagOrigin {
+        TagOrigin {
+            source: FieldSource::Issuer,
+            command: None,
+        }
+    }
+
+    pub fn icc(command: Option<CardCommand>) -> TagOrigin {
+        TagOrigin {
+            source: FieldSource::Icc,
+            command,
+        }
+    }
+
+    pub fn is_record(&self) -> bool {
+        matches!(self.command, Some(CardCommand::ReadRecord { .. }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagValue {
+    pub value: Vec<u8>,
+    pub origin: TagOrigin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagEntry {
+    pub value: Vec<u8>,
+    pub origin: TagOrigin,
+    // overwritten values, oldest first
+    pub history: Vec<TagValue>,
+}
+
+// ICC value which failed the validation against the tag dictionary and was not stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedValue {
+    pub error: TagValidationError,
+    pub origin: TagOrigin,
+}
+
+// Data objects known to the terminal with the origin of each value
+#[derive(Debug, Clone, Default)]
+pub struct TagStore {
+    entries: HashMap<String, TagEntry>,
+    duplicates: Vec<String>,
+    rejected: Vec<RejectedValue>,
+}
+
+impl TagStore {
+    pub fn new() -> TagStore {
+        TagStore::default()
+    }
+
+    pub fn get(&self, tag_name: &str) -> Option<&Vec<u8>> {
+        self.entries.get(tag_name).map(|entry| &entry.value)
+    }
+
+    pub fn get_entry(&self, tag_name: &str) -> Option<&TagEntry> {
+        self.entries.get(tag_name)
+    }
+
+    pub fn contains(&self, tag_name: &str) -> bool {
+        self.entries.contains_key(tag_name)
+    }
+
+    pub fn insert(&mut self, tag_name: &str, value: Vec<u8>, origin: TagOrigin) {
+        match self.entries.get_mut(tag_name) {
+            Some(entry) => {
+                // ref. EMV Book 3, 10.2 Read Application Data - a primitive data object shall appear only once in the records,
+                // the ICC data of a previous selection is cleared, e.g. the GPO response counts as well
+                if origin.is_record() && entry.origin.source == FieldSource::Icc {
+                    self.duplicates.push(tag_name.to_string());
+                }
+
+                let previous_value = TagValue {
+                    value: std::mem::replace(&mut entry.value, value),
+                    origin: std::mem::replace(&mut entry.origin, origin),
+                };
+                entry.history.push(previous_value);
+            }
+            None => {
+                self.entries.insert(
+                    tag_name.to_string(),
+                    TagEntry {
+                        value,
+                        origin,
+                        history: Vec::new(),
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn remove(&mut self, tag_name: &str) -> Option<TagEntry> {
+        self.entries.remove(tag_name)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.duplicates.clear();
+        self.rejected.clear();
+    }
+
+    // e.g. ICC data of a previously selected application
+    pub fn clear_source(&mut self, source: FieldSource) {
+        self.entries
+            .retain(|_, entry| entry.origin.source != source);
+        if source == FieldSource::Icc {
+            self.duplicates.clear();
+            self.rejected.clear();
+        }
+    }
+
+    // Primitive data objects of the records already returned by the ICC
+    pub fn duplicates(&self) -> &[String] {
+        &self.duplicates
+    }
+
+    pub fn reject(&mut self, rejected_value: RejectedValue) {
+        self.rejected.push(rejected_value);
+    }
+
+    pub fn rejected(&self) -> &[RejectedValue] {
+        &self.rejected
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<u8>)> {
+        self.entries
+            .iter()
+            .map(|(tag_name, entry)| (tag_name, &entry.value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// Selects the kernel specific tag dictionary of an application
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagScope {
+    // Registered Application Provider Identifier, the first 5 bytes of the AID
+    Rid(Vec<u8>),
+    KernelId(u8),
+}
+
+impl TagScope {
+    // ref. EMV Contactless Book B, 3.3.2.5 Kernel Identifier - an international kernel is identified by the short kernel ID,
+    // otherwise the kernel is the default kernel of the RID
+    pub fn of_application(application: &EmvApplication) -> TagScope {
+        match application
+            .tags
+            .get("9F2A")
+            .and_then(|kernel_identifier| international_kernel_id(kernel_identifier))
+        {
+            Some(kernel_id) => TagScope::KernelId(kernel_id),
+            None => TagScope::Rid(application.aid.iter().take(5).copied().collect()),
+        }
+    }
+}
+
+// ref. EMV Contactless Book B, 3.3.2.5 Kernel Identifier - b8-b7 '00' or '01' of the first byte mark an international
+// kernel with the short kernel ID in b6-b1, '10' and '11' a domestic kernel, zero the default kernel of the RID
+pub fn international_kernel_id(kernel_identifier: &[u8]) -> Option<u8> {
+    match kernel_identifier.first() {
+        Some(&kernel_identifier) if kernel_identifier != 0x00 && kernel_identifier & 0x80 == 0 => {
+            Some(kernel_identifier & 0x3F)
+        }
+        _ => None,
+    }
+}
+
+// Tag definitions of a contactless kernel overriding the generic EMV tags
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KernelTags {
+    pub kernel_id: u8,
+    pub rids: Vec<String>,
+    pub tags: HashMap<String, EmvTag>,
+}
+
+impl KernelTags {
+    pub fn applies_to(&self, scope: &TagScope) -> bool {
+        match scope {
+            TagScope::KernelId(kernel_id) => self.kernel_id == *kernel_id,
+            TagScope::Rid(rid) => self
+                .rids
+                .iter()
+                .any(|kernel_rid| hex::decode(kernel_rid).ok().as_ref() == Some(rid)),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlvError {
+    // Tag name is not hex or not a valid BER tag
+    InvalidTag(String),
+    // Primitive value given for a constructed tag or the other way around
+    WrongTagType(String),
+}
+
+impl fmt::Display for TlvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlvError::InvalidTag(tag) => write!(f, "invalid tag {:?}", tag),
+            TlvError::WrongTagType(tag) => {
+                write!(f, "tag {} used with a wrong data object type", tag)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TlvError {}
+
+// ref. EMV Book 3, Annex B2 Length Field
+pub fn encode_ber_length(length: usize) -> Vec<u8> {
+    if length < 0x80 {
+        return vec![length as u8];
+    }
+
+    let length_bytes: Vec<u8> = length
+        .to_be_bytes()
+        .iter()
+        .skip_while(|&&b| b == 0x00)
+        .copied()
+        .collect();
+
+    let mut output: Vec<u8> = Vec::with_capacity(1 + length_bytes.len());
+    output.push(0x80 | length_bytes.len() as u8);
+    output.extend_from_slice(&length_bytes[..]);
+
+    output
+}
+
+// ref. EMV Book 3, Annex B1 Coding of the Tag Field of BER-TLV Data Objects
+fn encode_tag(tag_name: &str) -> Result<Vec<u8>, TlvError> {
+    let invalid_tag = || TlvError::InvalidTag(tag_name.to_string());
+
+    let tag = hex::decode(tag_name).map_err(|_| invalid_tag())?;
+    let (first_byte, subsequent_bytes) = tag.split_first().ok_or_else(invalid_tag)?;
+
+    // b5-b1 all set when subsequent bytes follow, b8 set on all but the last subsequent byte
+    let multi_byte = first_byte & 0x1F == 0x1F;
+    let valid = match subsequent_bytes.split_last() {
+        None => !multi_byte,
+        Some((last_byte, other_bytes)) => {
+            multi_byte && last_byte & 0x80 == 0 && other_bytes.iter().all(|b| b & 0x80 != 0)
+        }
+    };
+    if !valid {
+        return Err(invalid_tag());
+    }
+
+    Ok(tag)
+}
+
+// b6 of the first tag byte set for constructed data objects
+fn is_constructed(tag: &[u8]) -> bool {
+    tag[0] & 0x20 != 0
+}
+
+// Encodes a sequence of (possibly nested) BER-TLV data objects, e.g.
+// TlvBuilder::new().constructed("77", TlvBuilder::new().primitive("82", &aip)).build()
+#[derive(Debug, Clone, Default)]
+pub struct TlvBuilder {
+    data: Vec<u8>,
+    error: Option<TlvError>,
+}
+
+impl TlvBuilder {
+    pub fn new() -> TlvBuilder {
+        TlvBuilder::default()
+    }
+
+    fn push(mut self, tag_name: &str, constructed: bool, value: &[u8]) -> TlvBuilder {
+        if self.error.is_some() {
+            return self;
+        }
+
+        match encode_tag(tag_name) {
+            Ok(tag) if is_constructed(&tag) == constructed => {
+                self.data.extend_from_slice(&tag[..]);
+                self.data
+                    .extend_from_slice(&encode_ber_length(value.len())[..]);
+                self.data.extend_from_slice(value);
+            }
+            Ok(_) => self.error = Some(TlvError::WrongTagType(tag_name.to_string())),
+            Err(err) => self.error = Some(err),
+        }
+
+        self
+    }
+
+    pub fn primitive(self, tag_name: &str, value: &[u8]) -> TlvBuilder {
+        self.push(tag_name, false, value)
+    }
+
+    pub fn constructed(mut self, tag_name: &str, children: TlvBuilder) -> TlvBuilder {
+        match children.build() {
+            Ok(value) => self.push(tag_name, true, &value[..]),
+            Err(err) => {
+                self.error.get_or_insert(err);
+                self
+            }
+        }
+    }
+
+    // Primitive or constructed by the tag, a constructed value is already encoded
+    pub fn data_object(self, tag_name: &str, value: &[u8]) -> TlvBuilder {
+        match encode_tag(tag_name) {
+            Ok(tag) => self.push(tag_name, is_constructed(&tag), value),
+            Err(_) => self.primitive(tag_name, value),
+        }
+    }
+
+    // Already encoded data objects, e.g. a record read from the card
+    pub fn raw(mut self, tlv: &[u8]) -> TlvBuilder {
+        self.data.extend_from_slice(tlv);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn build(self) -> Result<Vec<u8>, TlvError> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.data),
+        }
+    }
+}
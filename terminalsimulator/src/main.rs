@@ -2,18 +2,19 @@ use clap::Parser;
 use hex;
 use log::{debug, error, info, warn};
 use log4rs;
-use pcsc::{Card, Context, Protocols, Scope, ShareMode, MAX_ATR_SIZE, MAX_BUFFER_SIZE};
+use pcsc::{
+    Card, Context, Protocols, Scope, ShareMode, MAX_ATR_SIZE, MAX_BUFFER_SIZE,
+    MAX_BUFFER_SIZE_EXTENDED,
+};
 use regex::Regex;
 use std::io::{self};
 use std::path::PathBuf;
 use std::str;
+use std::sync::Arc;
 use std::{thread, time};
 
 use emvpt::*;
 
-static mut INTERACTIVE: bool = false;
-static mut PIN_OPTION: Option<String> = None;
-
 pub enum ReaderError {
     ReaderConnectionFailed(String),
     ReaderNotFound,
@@ -25,23 +26,47 @@ pub struct SmartCardConnection {
     ctx: Option<Context>,
     card: Option<Card>,
     pub contactless: bool,
+    pub extended_length: bool,
 }
 
 impl ApduInterface for SmartCardConnection {
-    fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, ()> {
+    fn send_apdu(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError> {
         let mut output: Vec<u8> = Vec::new();
 
-        let mut apdu_response_buffer = [0; MAX_BUFFER_SIZE];
+        let card = self.card.as_ref().ok_or(TransportError::CardRemoved)?;
+
+        let mut apdu_response_buffer = if self.extended_length {
+            vec![0; MAX_BUFFER_SIZE_EXTENDED]
+        } else {
+            vec![0; MAX_BUFFER_SIZE]
+        };
         output.extend_from_slice(
-            self.card
-                .as_ref()
-                .unwrap()
-                .transmit(apdu, &mut apdu_response_buffer)
-                .unwrap(),
+            card.transmit(apdu, &mut apdu_response_buffer)
+                .map_err(into_transport_error)?,
         );
 
         Ok(output)
     }
+
+    fn supports_extended_length(&self) -> bool {
+        self.extended_length
+    }
+}
+
+fn into_transport_error(err: pcsc::Error) -> TransportError {
+    match err {
+        pcsc::Error::RemovedCard
+        | pcsc::Error::ResetCard
+        | pcsc::Error::NoSmartcard
+        | pcsc::Error::UnpoweredCard => TransportError::CardRemoved,
+        pcsc::Error::ReaderUnavailable
+        | pcsc::Error::UnknownReader
+        | pcsc::Error::NoReadersAvailable
+        | pcsc::Error::NoService
+        | pcsc::Error::ServiceStopped => TransportError::ReaderUnavailable,
+        pcsc::Error::Timeout => TransportError::Timeout,
+        _ => TransportError::Protocol(err.to_string()),
+    }
 }
 
 impl SmartCardConnection {
@@ -50,6 +75,7 @@ impl SmartCardConnection {
             ctx: None,
             card: None,
             contactless: false,
+            extended_length: false,
         }
     }
 
@@ -145,65 +171,108 @@ impl SmartCardConnection {
     }
 }
 
-fn pse_application_select(applications: &Vec<EmvApplication>) -> Result<EmvApplication, ()> {
-    let user_interactive = unsafe { INTERACTIVE };
-
-    if user_interactive && applications.len() > 1 {
-        println!("Select payment application:");
-        for i in 0..applications.len() {
-            println!(
-                "{:02}. {}",
-                i + 1,
-                str::from_utf8(&applications[i].label).unwrap()
-            );
-        }
-
-        print!("> ");
+pub struct ConsoleCardholderInterface {
+    interactive: bool,
+    pin: Option<String>,
+}
 
+impl ConsoleCardholderInterface {
+    fn read_line(&self) -> String {
         let mut stdin_buffer = String::new();
         io::stdin().read_line(&mut stdin_buffer).unwrap();
 
-        return Ok(applications[stdin_buffer.trim().parse::<usize>().unwrap() - 1].clone());
+        stdin_buffer.trim().to_string()
     }
-
-    Ok(applications[0].clone())
 }
 
-fn pin_entry() -> Result<String, ()> {
-    let user_interactive = unsafe { INTERACTIVE };
-    unsafe {
-        if PIN_OPTION.is_some() {
-            return Ok(PIN_OPTION.as_ref().unwrap().to_string());
+impl CardholderInterface for ConsoleCardholderInterface {
+    fn amount_entry(&self) -> Result<u64, CardholderInterfaceError> {
+        if self.interactive {
+            println!("Enter amount:");
+            print!("> ");
+
+            let amount = self
+                .read_line()
+                .parse::<f64>()
+                .map_err(|_| CardholderInterfaceError::Cancelled)?;
+            if !amount.is_finite() || amount < 0.0 {
+                return Err(CardholderInterfaceError::Cancelled);
+            }
+
+            return format!("{:.0}", amount * 100.0)
+                .parse::<u64>()
+                .map_err(|_| CardholderInterfaceError::Cancelled);
         }
+
+        Ok(1)
     }
 
-    if user_interactive {
-        println!("Enter PIN:");
-        print!("> ");
+    fn select_application(
+        &self,
+        applications: &[EmvApplication],
+    ) -> Result<EmvApplication, CardholderInterfaceError> {
+        if self.interactive && applications.len() > 1 {
+            println!("Select payment application:");
+            for i in 0..applications.len() {
+                println!(
+                    "{:02}. {}",
+                    i + 1,
+                    String::from_utf8_lossy(&applications[i].label)
+                );
+            }
+
+            print!("> ");
 
-        return Ok(rpassword::read_password().unwrap().trim().to_string());
+            let index = self
+                .read_line()
+                .parse::<usize>()
+                .map_err(|_| CardholderInterfaceError::Cancelled)?;
+            return applications
+                .get(index.wrapping_sub(1))
+                .cloned()
+                .ok_or(CardholderInterfaceError::Cancelled);
+        }
+
+        applications
+            .first()
+            .cloned()
+            .ok_or(CardholderInterfaceError::Cancelled)
     }
 
-    Ok("".to_string())
-}
+    fn pin_entry(&self) -> Result<String, CardholderInterfaceError> {
+        if let Some(pin) = &self.pin {
+            return Ok(pin.to_string());
+        }
 
-fn amount_entry() -> Result<u64, ()> {
-    let user_interactive = unsafe { INTERACTIVE };
+        if self.interactive {
+            println!("Enter PIN:");
+            print!("> ");
 
-    if user_interactive {
-        println!("Enter amount:");
-        print!("> ");
-        let mut stdin_buffer = String::new();
-        io::stdin().read_line(&mut stdin_buffer).unwrap();
+            return Ok(rpassword::read_password().unwrap().trim().to_string());
+        }
 
-        return Ok(
-            format!("{:.0}", stdin_buffer.trim().parse::<f64>().unwrap() * 100.0)
-                .parse::<u64>()
-                .unwrap(),
-        );
+        // no PIN to enter, bypassed instead of verifying an empty PIN with the card
+        Err(CardholderInterfaceError::Cancelled)
     }
 
-    Ok(1)
+    fn confirm_application(&self, application: &EmvApplication) -> bool {
+        if self.interactive {
+            println!("Use {}? (y/n)", String::from_utf8_lossy(&application.label));
+            print!("> ");
+
+            return self.read_line().eq_ignore_ascii_case("y");
+        }
+
+        true
+    }
+
+    fn display_message(&self, message: &str) {
+        if self.interactive {
+            println!("{}", message);
+        } else {
+            info!("{}", message);
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -230,46 +299,63 @@ struct Args {
     #[arg(long = "stop-after-read", default_value_t = false)]
     stop_after_read: bool,
 
+    /// Use extended length APDUs, reader and card must support them
+    #[arg(long = "extended-length", default_value_t = false)]
+    extended_length: bool,
+
     /// Card PIN code to be used when PIN code is required
     #[arg(short, long, value_name = "PIN CODE")]
     pin: Option<String>,
 
-    /// Card PIN code to be used when PIN code is required
-    #[arg(
-        short,
-        long,
-        value_name = "settings file",
-        default_value = "config/settings.yaml"
-    )]
-    settings: PathBuf,
+    /// Settings file, bundled settings are used when not given
+    #[arg(short, long, value_name = "settings file")]
+    settings: Option<PathBuf>,
+
+    /// Use the bundled test CA public keys for offline data authentication
+    #[arg(long = "test-ca-keys", default_value_t = false)]
+    test_ca_keys: bool,
 
     /// Print TLV data in human readable form
     #[arg(long, value_name = "TLV")]
     print_tlv: Option<String>,
 }
 
+fn abort_transaction(err: EmvError) -> String {
+    match err {
+        EmvError::Transport { .. } => err.to_string(),
+        _ => format!("Transaction aborted: {}", err),
+    }
+}
+
 fn run() -> Result<Option<String>, String> {
     log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
 
     let args = Args::parse();
 
-    unsafe {
-        INTERACTIVE = args.interactive;
-        PIN_OPTION = args.pin;
-    }
-    let user_interactive = unsafe { INTERACTIVE };
+    let user_interactive = args.interactive;
     let censor_sensitive_fields = args.censor_sensitive_fields;
     let stop_after_connect = args.stop_after_connect;
     let stop_after_read = args.stop_after_read;
     let print_tags = args.print_tags;
     let print_tlv = args.print_tlv;
 
-    let mut connection = EmvConnection::new(&args.settings.as_path().to_str().unwrap()).unwrap();
+    let mut connection_builder = EmvConnection::builder();
+    if let Some(settings_file) = &args.settings {
+        connection_builder = connection_builder.settings_file(settings_file.to_str().unwrap());
+    }
+    if args.test_ca_keys {
+        warn!("Using test CA public keys");
+        connection_builder = connection_builder.ca_public_keys(CertificateAuthority::test_keys());
+    }
+    let mut connection = connection_builder.build().map_err(|err| err.to_string())?;
+
+    let cardholder_interface = Arc::new(ConsoleCardholderInterface {
+        interactive: user_interactive,
+        pin: args.pin,
+    });
 
     connection.settings.censor_sensitive_fields = censor_sensitive_fields;
-    connection.pse_application_select_callback = Some(&pse_application_select);
-    connection.pin_callback = Some(&pin_entry);
-    connection.amount_callback = Some(&amount_entry);
+    connection.cardholder_interface = cardholder_interface.clone();
 
     if print_tlv.is_some() {
         let tlv_hex_data = print_tlv
@@ -280,7 +366,9 @@ fn run() -> Result<Option<String>, String> {
         return Ok(None);
     }
 
-    let purchase_amount = connection.amount_callback.unwrap()().unwrap();
+    let purchase_amount = cardholder_interface
+        .amount_entry()
+        .map_err(|_| "Invalid amount".to_string())?;
 
     let mut smart_card_connection = SmartCardConnection::new();
 
@@ -313,50 +401,52 @@ fn run() -> Result<Option<String>, String> {
         return Ok(None);
     }
 
+    smart_card_connection.extended_length = args.extended_length;
     connection.contactless = smart_card_connection.contactless;
-    connection.interface = Some(&smart_card_connection);
-
-    let application = connection.select_payment_application().unwrap();
-
-    connection.process_settings().unwrap();
-    connection.add_tag(
-        "9F02",
-        bcdutil::ascii_to_bcd_n(format!("{}", purchase_amount).as_bytes(), 6).unwrap(),
-    );
-
-    connection.handle_get_processing_options().unwrap();
-
-    if !stop_after_read {
-        connection.handle_public_keys(&application).unwrap();
-
-        connection.handle_card_verification_methods().unwrap();
+    connection.interface = Some(Arc::new(smart_card_connection));
 
-        connection.handle_terminal_risk_management().unwrap();
+    if stop_after_read {
+        connection
+            .select_payment_application()
+            .map_err(abort_transaction)?;
 
-        connection.handle_terminal_action_analysis().unwrap();
-
-        let mut purchase_successful = false;
+        connection.process_settings().unwrap();
+        connection.add_tag(
+            "9F02",
+            bcdutil::ascii_to_bcd_n(format!("{}", purchase_amount).as_bytes(), 6).unwrap(),
+        );
 
-        match connection.handle_1st_generate_ac().unwrap() {
-            CryptogramType::AuthorisationRequestCryptogram => {
-                if let CryptogramType::TransactionCertificate =
-                    connection.handle_2nd_generate_ac().unwrap()
-                {
-                    purchase_successful = true;
-                }
-            }
-            CryptogramType::TransactionCertificate => {
-                purchase_successful = true;
-            }
-            CryptogramType::ApplicationAuthenticationCryptogram => {
-                purchase_successful = false;
+        connection
+            .handle_get_processing_options()
+            .map_err(abort_transaction)?;
+    } else {
+        let outcome = EmvTransaction::new(&mut connection, purchase_amount)
+            .run()
+            .map_err(abort_transaction)?;
+
+        debug!("TVR: {:?}", outcome.tvr);
+        debug!("TSI: {:?}", outcome.tsi);
+        debug!("CID: {:?}", outcome.cryptogram_information_data);
+
+        match outcome.result {
+            TransactionResult::ApprovedOffline | TransactionResult::ApprovedOnline => {
+                info!("Purchase successful! {}", outcome.result);
             }
+            _ => match (outcome.error, outcome.decline_reason) {
+                (Some(err), _) => warn!("Purchase unsuccessful! {}: {}", outcome.result, err),
+                (None, Some(reason)) => {
+                    warn!("Purchase unsuccessful! {}: {}", outcome.result, reason)
+                }
+                (None, None) => warn!("Purchase unsuccessful! {}", outcome.result),
+            },
         }
 
-        if purchase_successful {
-            info!("Purchase successful!");
-        } else {
-            warn!("Purchase unsuccessful!");
+        if let Some(advice) = outcome.advice {
+            info!(
+                "Advice requested by the card, reason: {:?}, ICC data: {}",
+                advice.cryptogram_information_data.reason_code,
+                hex::encode_upper(&advice.icc_data)
+            );
         }
     }
 